@@ -0,0 +1,546 @@
+use core::*;
+#[cfg(feature = "jit")]
+use csr::*;
+use decoder::*;
+use op::*;
+use xlen::*;
+
+#[cfg(feature = "jit")]
+use jit::*;
+
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+const MAX_BLOCK_LENGTH: usize = 64;
+const PAGE_SHIFT: u32 = 12;
+
+struct Block<X: Xlen> {
+    start_pc: X::Uint,
+    ops: Vec<Box<dyn Op<X>>>,
+    // Blocks reached from this block through a direct branch or fall-through, as (pc, index).
+    links: Vec<(X::Uint, usize)>,
+    chainable: bool,
+    #[cfg(feature = "jit")]
+    native: Option<NativeBlock>,
+}
+
+// Execution engine which translates guest basic blocks into pre-decoded op sequences.
+// CSR accesses, system ops and fence.i are not translated and are executed by Core::step().
+pub struct BlockEngine<X: Xlen = Rv32> {
+    blocks: Vec<Block<X>>,
+    index: HashMap<X::Uint, usize>,
+    code_pages: HashSet<u64>,
+    #[cfg(feature = "jit")]
+    jit: bool,
+    #[cfg(feature = "jit")]
+    tlb: JitTlb,
+    // Host memory which the TLB maps, which a restored snapshot replaces
+    #[cfg(feature = "jit")]
+    tlb_memory: *const u8,
+}
+
+impl<X: Xlen> BlockEngine<X> {
+    pub fn new() -> BlockEngine<X> {
+        BlockEngine {
+            blocks: Vec::new(),
+            index: HashMap::new(),
+            code_pages: HashSet::new(),
+            #[cfg(feature = "jit")]
+            jit: false,
+            #[cfg(feature = "jit")]
+            tlb: JitTlb::new(),
+            #[cfg(feature = "jit")]
+            tlb_memory: std::ptr::null(),
+        }
+    }
+
+    // Creates an engine which additionally compiles blocks to x86-64 code. Only RV32I blocks are compiled.
+    #[cfg(feature = "jit")]
+    pub fn new_with_jit() -> BlockEngine<X> {
+        let mut engine = BlockEngine::new();
+        engine.jit = true;
+        engine
+    }
+
+    pub fn flush(&mut self) {
+        self.blocks.clear();
+        self.index.clear();
+        self.code_pages.clear();
+        #[cfg(feature = "jit")]
+        self.tlb.flush();
+    }
+
+    pub fn run(&mut self, core: &mut Core<X>, max_cycle: u64) -> u64 {
+        let mut cycle = 0;
+        let mut prev: Option<usize> = None;
+
+        while cycle < max_cycle {
+            if core.read_host_io() != 0 {
+                break
+            }
+
+            core.check_interrupts();
+            // Devices may have written code to memory directly.
+            if core.bus.take_dma_written() {
+                self.flush();
+                prev = None;
+            }
+            if core.waiting {
+                // Another hart may run instead.
+                if !core.wait_on_bus {
+                    break
+                }
+                cycle += 1;
+                prev = None;
+                continue
+            }
+
+            // Blocks are indexed by physical pc and read memory directly, so code running with
+            // paging or PMP is executed by Core::step().
+            let next = match prev.and_then(|i| self.follow_link(i, core.pc)) {
+                _ if core.is_memory_protected() => None,
+                Some(index) => Some(index),
+                None => {
+                    let index = self.lookup(core);
+                    if let (Some(p), Some(n)) = (prev, index) {
+                        self.blocks[p].links.push((core.pc, n));
+                    }
+                    index
+                },
+            };
+
+            match next {
+                Some(index) => {
+                    let (executed, completed) = self.execute_block(core, index, max_cycle - cycle);
+                    cycle += executed;
+                    prev = if completed && self.blocks.len() > index && self.blocks[index].chainable {
+                        Some(index)
+                    } else {
+                        None
+                    };
+                },
+                None => {
+                    let op = decode::<X>(&core.fetch());
+                    core.execute(&*op);
+                    cycle += 1;
+                    if op.class() == OpClass::FenceI {
+                        self.flush();
+                    }
+                    prev = None;
+                },
+            }
+        }
+
+        cycle
+    }
+
+    fn follow_link(&self, index: usize, pc: X::Uint) -> Option<usize> {
+        self.blocks[index].links.iter().find(|link| link.0 == pc).map(|link| link.1)
+    }
+
+    fn lookup(&mut self, core: &Core<X>) -> Option<usize> {
+        match self.index.get(&core.pc) {
+            Some(index) => Some(*index),
+            None => self.translate(core),
+        }
+    }
+
+    fn translate(&mut self, core: &Core<X>) -> Option<usize> {
+        let start_pc = core.pc;
+        let mut ops: Vec<Box<dyn Op<X>>> = Vec::new();
+        let mut insns: Vec<u32> = Vec::new();
+        let mut chainable = true;
+        let mut pc = start_pc;
+
+        loop {
+            // Code outside of memory, e.g. in a device, is executed by Core::step().
+            if !core.bus.is_mapped(pc.to_u64(), 4) {
+                break
+            }
+            let insn = core.bus.read_u32(pc.to_u64());
+            let op = decode::<X>(&insn);
+            match op.class() {
+                OpClass::Csr | OpClass::System | OpClass::FenceI | OpClass::Unknown => break,
+                OpClass::Branch | OpClass::Jump => {
+                    ops.push(op);
+                    insns.push(insn);
+                    break
+                },
+                OpClass::IndirectJump => {
+                    ops.push(op);
+                    insns.push(insn);
+                    chainable = false;
+                    break
+                },
+                _ => {
+                    ops.push(op);
+                    insns.push(insn);
+                },
+            }
+
+            pc = pc.wrapping_add(X::Uint::from_u32(4));
+            if ops.len() >= MAX_BLOCK_LENGTH || pc.shr(PAGE_SHIFT) != start_pc.shr(PAGE_SHIFT) {
+                break
+            }
+        }
+
+        if ops.is_empty() {
+            return None
+        }
+
+        #[cfg(feature = "jit")]
+        let native = if self.jit && X::XLEN == 32 && X::NUM_INT_REG == 32 {
+            compile(&insns, start_pc.to_u32())
+        } else {
+            None
+        };
+        #[cfg(not(feature = "jit"))]
+        let _ = insns;
+
+        let index = self.blocks.len();
+        self.blocks.push(Block {
+            start_pc,
+            ops,
+            links: Vec::new(),
+            chainable,
+            #[cfg(feature = "jit")]
+            native,
+        });
+        self.index.insert(start_pc, index);
+        self.code_pages.insert(start_pc.to_u64() >> PAGE_SHIFT);
+        // Stores to code are checked by the interpreter.
+        #[cfg(feature = "jit")]
+        self.tlb.invalidate_store((start_pc.to_u64() >> PAGE_SHIFT) as u32);
+
+        Some(index)
+    }
+
+    // Returns the number of executed ops and whether the block ran to its end.
+    fn execute_block(&mut self, core: &mut Core<X>, index: usize, max_cycle: u64) -> (u64, bool) {
+        let block = &self.blocks[index];
+        let mut executed = 0;
+        let mut code_modified = false;
+        let mut completed = true;
+
+        #[cfg(feature = "jit")]
+        let start = match &block.native {
+            // Native code does not check triggers, count events other than cycles and retired ops,
+            // or update devices between ops.
+            Some(native) if native.length as u64 <= max_cycle && !core.csr.triggers_enabled() && !core.csr.is_event_counted() && !core.bus.has_devices() => {
+                if self.tlb_memory != core.bus.memory.body.as_ptr() {
+                    self.tlb.flush();
+                    self.tlb_memory = core.bus.memory.body.as_ptr();
+                }
+                // Writes which the bus has to see, e.g. to clear the reservations of other harts, are
+                // made by the interpreter.
+                if core.bus.is_tracking_writes() {
+                    self.tlb.flush_stores();
+                }
+                let start = native.execute(core, &self.tlb);
+                if let Some(access) = native.access(start) {
+                    let addr = core.int_reg.read(access.rs1).to_u32().wrapping_add(access.offset);
+                    map_page(&mut self.tlb, &self.code_pages, core, addr as u64 >> PAGE_SHIFT, access.store);
+                }
+                executed = start as u64;
+                core.cycle += start as u64;
+                core.csr.clear_counters_written();
+                core.csr.count(COUNTER_CYCLE, start as u64);
+                core.csr.count(COUNTER_INSTRET, start as u64);
+                core.pc = block.start_pc.wrapping_add(X::Uint::from_u32(4 * start as u32));
+                core.next_pc = core.pc;
+                start
+            },
+            _ => 0,
+        };
+        #[cfg(not(feature = "jit"))]
+        let start = 0;
+
+        for (i, op) in block.ops.iter().enumerate().skip(start) {
+            if executed >= max_cycle {
+                completed = false;
+                break
+            }
+
+            // Devices are updated and interrupts are taken before each op as in Core::step(). run()
+            // has done so before the first one.
+            if i > 0 && core.bus.has_devices() {
+                let pc = core.pc;
+                core.check_interrupts();
+                code_modified |= core.bus.take_dma_written();
+                if code_modified || core.pc != pc {
+                    completed = false;
+                    break
+                }
+            }
+
+            let pc = block.start_pc.wrapping_add(X::Uint::from_u32(4 * i as u32));
+            core.execute(&**op);
+            executed += 1;
+
+            if op.class() == OpClass::Store {
+                if let Some(addr) = core.bus.take_last_write_addr() {
+                    let pages = [addr >> PAGE_SHIFT, addr.wrapping_add(7) >> PAGE_SHIFT];
+                    code_modified = pages.iter().any(|page| self.code_pages.contains(page));
+                }
+                // The store may have made a device write memory directly.
+                code_modified |= core.bus.take_dma_written();
+                if code_modified || core.read_host_io() != 0 {
+                    completed = false;
+                    break
+                }
+            }
+
+            // Leave the block when an op other than the terminating one redirects control (e.g. a trap).
+            if i + 1 < block.ops.len() && core.pc != pc.wrapping_add(X::Uint::from_u32(4)) {
+                completed = false;
+                break
+            }
+        }
+
+        if code_modified {
+            self.flush();
+        }
+
+        (executed, completed)
+    }
+}
+
+// Maps a page of memory in the TLB of native code. Stores are only mapped to pages which the
+// interpreter does not have to check: neither code nor the host io.
+#[cfg(feature = "jit")]
+fn map_page<X: Xlen>(tlb: &mut JitTlb, code_pages: &HashSet<u64>, core: &mut Core<X>, page: u64, store: bool) {
+    let addr = page << PAGE_SHIFT;
+    if !core.bus.is_mapped(addr, 1 << PAGE_SHIFT) {
+        return
+    }
+    let store = store && !code_pages.contains(&page) && core.host_io_addr >> PAGE_SHIFT != page && !core.bus.is_tracking_writes();
+    let host = core.bus.memory.body[(addr - core.bus.base) as usize..].as_mut_ptr();
+    tlb.fill(page as u32, host, store);
+}
+
+#[cfg(test)]
+enum TestEngine {
+    Interpreter,
+    Block,
+    #[cfg(feature = "jit")]
+    Jit,
+}
+
+#[cfg(test)]
+fn run_program(program: &[(u64, u32)], cycle: u64, engine: TestEngine) -> Vec<u32> {
+    use bus::*;
+    use memory::*;
+
+    let mut memory = Memory::new();
+    let mut bus = Bus::new(&mut memory);
+    for (addr, insn) in program {
+        bus.write_u32(*addr, *insn);
+    }
+    bus.write_u64(0x8000_1000, 0);
+
+    let mut core: Core = Core::new(&mut bus);
+    core.host_io_addr = 0x8000_1000;
+    core.pc = 0x8000_0000;
+
+    match engine {
+        TestEngine::Interpreter => {
+            for _i in 0..cycle {
+                core.step();
+            }
+        },
+        TestEngine::Block => {
+            assert_eq!(BlockEngine::new().run(&mut core, cycle), cycle);
+        },
+        #[cfg(feature = "jit")]
+        TestEngine::Jit => {
+            assert_eq!(BlockEngine::new_with_jit().run(&mut core, cycle), cycle);
+        },
+    }
+
+    (0..32).map(|i| core.int_reg.read(i)).collect()
+}
+
+#[test]
+fn test_block_engine_loop() {
+    let program = [
+        (0x8000_0000, 0x00a00093), // addi ra,zero,10
+        (0x8000_0004, 0x00000113), // addi sp,zero,0
+        (0x8000_0008, 0x00110133), // add sp,sp,ra
+        (0x8000_000c, 0xfff08093), // addi ra,ra,-1
+        (0x8000_0010, 0xfe009ce3), // bnez ra,-8
+        (0x8000_0014, 0x0000006f), // j 0
+    ];
+
+    let expected = run_program(&program, 40, TestEngine::Interpreter);
+    let actual = run_program(&program, 40, TestEngine::Block);
+    assert_eq!(expected[2], 55);
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn test_block_engine_self_modifying_code() {
+    let program = [
+        (0x8000_0000, 0x800001b7), // lui gp,0x80000
+        (0x8000_0004, 0x0201a203), // lw tp,32(gp)
+        (0x8000_0008, 0x0041a823), // sw tp,16(gp)
+        (0x8000_000c, 0x00100293), // addi t0,zero,1
+        (0x8000_0010, 0x00100313), // addi t1,zero,1
+        (0x8000_0014, 0x0000006f), // j 0
+        (0x8000_0020, 0x00200313), // addi t1,zero,2
+    ];
+
+    let expected = run_program(&program, 8, TestEngine::Interpreter);
+    let actual = run_program(&program, 8, TestEngine::Block);
+    assert_eq!(expected[6], 2);
+    assert_eq!(actual, expected);
+}
+
+#[test]
+fn test_block_engine_interrupts() {
+    use bus::*;
+    use clint::*;
+    use memory::*;
+
+    let program = [
+        (0x8000_0000, 0x020001b7), // lui gp,0x2000
+        (0x8000_0004, 0x00100293), // addi t0,zero,1
+        (0x8000_0008, 0x0051a023), // sw t0,0(gp)
+        (0x8000_000c, 0x00100313), // addi t1,zero,1
+        (0x8000_0010, 0x0000006f), // j 0
+        (0x8000_0100, 0x0000006f), // j 0
+    ];
+
+    let run = |engine: TestEngine| {
+        let mut memory = Memory::new();
+        let mut bus = Bus::new(&mut memory);
+        for (addr, insn) in program.iter() {
+            bus.write_u32(*addr, *insn);
+        }
+        bus.add_device(0x200_0000, Box::new(Clint::new(1)));
+        let mut core: Core = Core::new(&mut bus);
+        core.pc = 0x8000_0000;
+        core.csr.write(0x305, 0x8000_0100);
+        core.csr.write(0x304, 1 << 3);
+        core.csr.write(0x300, 1 << 3);
+        match engine {
+            TestEngine::Block => assert_eq!(BlockEngine::new().run(&mut core, 8), 8),
+            _ => for _i in 0..8 {
+                core.step();
+            },
+        }
+        (core.pc, core.int_reg.read(6))
+    };
+
+    // The software interrupt raised by the store is taken before the next op.
+    assert_eq!(run(TestEngine::Interpreter), (0x8000_0100, 0));
+    assert_eq!(run(TestEngine::Block), (0x8000_0100, 0));
+}
+
+#[cfg(feature = "jit")]
+#[test]
+fn test_jit_alu_and_load() {
+    let program = [
+        (0x8000_0000, 0x800001b7), // lui gp,0x80000
+        (0x8000_0004, 0x0401a203), // lw tp,64(gp)
+        (0x8000_0008, 0x04418283), // lb t0,68(gp)
+        (0x8000_000c, 0x0441c303), // lbu t1,68(gp)
+        (0x8000_0010, 0x005203b3), // add t2,tp,t0
+        (0x8000_0014, 0x40628433), // sub s0,t0,t1
+        (0x8000_0018, 0x0062a4b3), // slt s1,t0,t1
+        (0x8000_001c, 0x0062b533), // sltu a0,t0,t1
+        (0x8000_0020, 0x4042d593), // srai a1,t0,4
+        (0x8000_0024, 0x02628633), // mul a2,t0,t1
+        (0x8000_0028, 0xfff2c693), // xori a3,t0,-1
+        (0x8000_002c, 0x0000006f), // j 0
+        (0x8000_0040, 0x12345678),
+        (0x8000_0044, 0x00000080),
+    ];
+
+    let insns: Vec<u32> = program[..12].iter().map(|entry| entry.1).collect();
+    assert_eq!(compile(&insns, 0x8000_0000).unwrap().length, 11);
+
+    let expected = run_program(&program, 16, TestEngine::Interpreter);
+    let actual = run_program(&program, 16, TestEngine::Jit);
+    assert_eq!(expected[5], 0xffffff80);
+    assert_eq!(actual, expected);
+}
+
+#[cfg(feature = "jit")]
+#[test]
+fn test_jit_memory() {
+    let program = [
+        (0x8000_0000, 0x800021b7), // lui gp,0x80002
+        (0x8000_0004, 0x00a00093), // addi ra,zero,10
+        (0x8000_0008, 0x0001a203), // lw tp,0(gp)
+        (0x8000_000c, 0x00120233), // add tp,tp,ra
+        (0x8000_0010, 0x0041a023), // sw tp,0(gp)
+        (0x8000_0014, 0x00118223), // sb ra,4(gp)
+        (0x8000_0018, 0x00119323), // sh ra,6(gp)
+        (0x8000_001c, 0xfff08093), // addi ra,ra,-1
+        (0x8000_0020, 0xfe0094e3), // bnez ra,-24
+        (0x8000_0024, 0x0001a503), // lw a0,0(gp)
+        (0x8000_0028, 0x0061d583), // lhu a1,6(gp)
+        (0x8000_002c, 0x00418603), // lb a2,4(gp)
+        // The access crosses a page, so it is left to the interpreter.
+        (0x8000_0030, 0x7ff18413), // addi s0,gp,2047
+        (0x8000_0034, 0x7ff42683), // lw a3,2047(s0)
+        (0x8000_0038, 0x0000006f), // j 0
+        (0x8000_2000, 0x00000000),
+        (0x8000_2ffc, 0x11223344),
+        (0x8000_3000, 0x55667788),
+    ];
+
+    let insns: Vec<u32> = program[2..9].iter().map(|entry| entry.1).collect();
+    let native = compile(&insns, 0x8000_0008).unwrap();
+    assert_eq!(native.length, 6);
+    assert_eq!(native.access(2), Some(NativeAccess { rs1: 3, offset: 0, store: true }));
+    assert_eq!(native.access(1), None);
+
+    let expected = run_program(&program, 90, TestEngine::Interpreter);
+    let actual = run_program(&program, 90, TestEngine::Jit);
+    assert_eq!(expected[10], 55);
+    assert_eq!(expected[11], 1);
+    assert_eq!(expected[13], 0x77881122);
+    assert_eq!(actual, expected);
+
+    // Stores to code are seen by the engine, and the block is translated again.
+    let program = [
+        (0x8000_0000, 0x800001b7), // lui gp,0x80000
+        (0x8000_0004, 0x0201a203), // lw tp,32(gp)
+        (0x8000_0008, 0x0041a823), // sw tp,16(gp)
+        (0x8000_000c, 0x00100293), // addi t0,zero,1
+        (0x8000_0010, 0x00100313), // addi t1,zero,1
+        (0x8000_0014, 0x0000006f), // j 0
+        (0x8000_0020, 0x00200313), // addi t1,zero,2
+    ];
+    assert_eq!(run_program(&program, 8, TestEngine::Jit)[6], 2);
+}
+
+#[cfg(feature = "jit")]
+#[test]
+fn test_jit_host_io() {
+    use bus::*;
+    use memory::*;
+
+    let program = [
+        (0x8000_0000, 0x800011b7), // lui gp,0x80001
+        (0x8000_0004, 0x00100293), // addi t0,zero,1
+        (0x8000_0008, 0x0051a023), // sw t0,0(gp)
+        (0x8000_000c, 0x00500313), // addi t1,zero,5
+        (0x8000_0010, 0x0000006f), // j 0
+    ];
+    let mut memory = Memory::new();
+    let mut bus = Bus::new(&mut memory);
+    for (addr, insn) in program.iter() {
+        bus.write_u32(*addr, *insn);
+    }
+    bus.write_u64(0x8000_1000, 0);
+    let mut core: Core = Core::new(&mut bus);
+    core.host_io_addr = 0x8000_1000;
+    core.pc = 0x8000_0000;
+
+    // The store to the host io stops the run right after it.
+    assert_eq!(BlockEngine::new_with_jit().run(&mut core, 100), 3);
+    assert_eq!(core.read_host_io(), 1);
+    assert_eq!(core.int_reg.read(6), 0);
+}
@@ -1,35 +1,500 @@
+use fdt::*;
 use memory::*;
+use replay::*;
+
+use std::cell::RefCell;
+use std::io;
+use std::io::Read;
+use std::mem;
+use std::thread;
+use std::time::Duration;
+
+pub const DEFAULT_MEMORY_BASE: u64 = 0x8000_0000;
+
+// Longest time skipped at once while a hart waits for an interrupt, and the time passed instead when
+// there is no device event that close, in which case the host sleeps for WAIT_SLEEP.
+const MAX_WAIT_SKIP: u64 = 100_000;
+const WAIT_TICKS: u64 = 10_000;
+const WAIT_SLEEP: Duration = Duration::from_millis(1);
+
+// Memory-mapped device. Registers are accessed by offset from the base address of the device.
+pub trait Device {
+    // Size of the register space in bytes
+    fn size(&self) -> u64;
+
+    fn read(&mut self, offset: u64, size: u32) -> u64;
+
+    fn write(&mut self, offset: u64, size: u32, value: u64);
+
+    // Takes the bytes which arrived from the host, e.g. console input. The bus passes them to
+    // receive(), so that they can be recorded and replayed.
+    fn take_host_input(&mut self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn receive(&mut self, _data: &[u8]) {}
+
+    // Advances the device to `time`, in ticks of mtime. Returns true while it requests its interrupt.
+    fn update(&mut self, _time: u64) -> bool {
+        false
+    }
+
+    // Interrupt source number of the device at the interrupt controller, or 0 if it has none.
+    fn irq(&self) -> u32 {
+        0
+    }
+
+    // Returns the bits of mip of `hart` requested by an interrupt controller, given the interrupt
+    // sources which are requesting as bits of `irqs`.
+    fn interrupt_lines(&mut self, _hart: usize, _irqs: u64) -> u64 {
+        0
+    }
+
+    // Time at which the device will next request an interrupt by itself, e.g. a timer.
+    fn next_event(&self) -> Option<u64> {
+        None
+    }
+
+    // Accesses memory directly, e.g. to serve the requests in virtqueues. Called after each write to
+    // the registers of the device and after each update.
+    fn dma(&mut self, _dma: &mut Dma) {}
+
+    // Called when the run ends, e.g. to write out the state of the device.
+    fn finish(&mut self) {}
+
+    // Returns the device to its initial state when the machine is reset. Its connections to the host,
+    // e.g. a disk image, are kept.
+    fn reset(&mut self) {}
+
+    // Returns the state of the device for a snapshot, or None if it cannot be saved.
+    fn save(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    // Restores a state returned by save().
+    fn restore(&mut self, _state: &[u8]) -> Result<(), String> {
+        Err("device does not support snapshots".to_string())
+    }
+
+    // Writes the node describing the device mapped at base, if the device tree has one.
+    fn device_tree_node(&self, _fdt: &mut Fdt, _base: u64) {}
+}
+
+// Memory as accessed directly by devices. Their writes are not writes of harts, so they are neither
+// undone nor clear reservations. Accesses outside memory fail, and reads of them return 0.
+pub struct Dma<'a> {
+    memory: &'a mut Memory,
+    base: u64,
+    // Memory has been written, which may modify code
+    written: bool,
+}
+
+impl Dma<'_> {
+    pub fn new(memory: &mut Memory, base: u64) -> Dma<'_> {
+        Dma { memory, base, written: false }
+    }
+
+    fn range(&self, addr: u64, size: usize) -> Option<std::ops::Range<usize>> {
+        let offset = addr.checked_sub(self.base)?;
+        let end = offset.checked_add(size as u64)?;
+        if end > self.memory.body.len() as u64 {
+            return None
+        }
+        Some(offset as usize..end as usize)
+    }
+
+    // Returns true if size bytes at addr are in memory.
+    pub fn is_mapped(&self, addr: u64, size: usize) -> bool {
+        self.range(addr, size).is_some()
+    }
+
+    pub fn read(&self, addr: u64, buf: &mut [u8]) -> bool {
+        match self.range(addr, buf.len()) {
+            Some(range) => {
+                buf.copy_from_slice(&self.memory.body[range]);
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn write(&mut self, addr: u64, data: &[u8]) -> bool {
+        match self.range(addr, data.len()) {
+            Some(range) => {
+                self.memory.body[range].copy_from_slice(data);
+                self.written = true;
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn read_u16(&self, addr: u64) -> u16 {
+        let mut buf = [0; 2];
+        self.read(addr, &mut buf);
+        u16::from_le_bytes(buf)
+    }
+
+    pub fn read_u32(&self, addr: u64) -> u32 {
+        let mut buf = [0; 4];
+        self.read(addr, &mut buf);
+        u32::from_le_bytes(buf)
+    }
+
+    pub fn read_u64(&self, addr: u64) -> u64 {
+        let mut buf = [0; 8];
+        self.read(addr, &mut buf);
+        u64::from_le_bytes(buf)
+    }
+
+    pub fn write_u16(&mut self, addr: u64, value: u16) {
+        self.write(addr, &value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, addr: u64, value: u32) {
+        self.write(addr, &value.to_le_bytes());
+    }
+}
+
+struct MappedDevice {
+    base: u64,
+    size: u64,
+    // Reads may change the state of a device, e.g. a receive buffer.
+    device: RefCell<Box<dyn Device>>,
+}
 
 pub struct Bus<'a> {
     pub memory: &'a mut Memory,
+    // Physical address of the first byte of memory
+    pub base: u64,
+    pub last_write_addr: Option<u64>,
+    // Previous values of written bytes as (addr, value), recorded while enabled.
+    undo_log: Option<Vec<(u64, u8)>>,
+    devices: Vec<MappedDevice>,
+    // Time skipped by waiting harts, which is added to the cycle count to give the time of devices
+    time_skipped: u64,
+    // Reservations of LR held by the harts which are not running, as (hart, addr). Writes to the
+    // reserved doubleword clear them, so that SC of the hart fails.
+    parked_reservations: Vec<(usize, u64)>,
+    // Log which the inputs from the host are recorded to or replayed from
+    pub replay: Option<Replay>,
+    // Devices have written memory directly since it was last taken
+    dma_written: bool,
 }
 
 impl Bus<'_> {
-    pub fn new(memory: &mut Memory) -> Bus {
-        Bus { memory: memory }
+    pub fn new(memory: &mut Memory) -> Bus<'_> {
+        Bus::with_base(memory, DEFAULT_MEMORY_BASE)
+    }
+
+    pub fn with_base(memory: &mut Memory, base: u64) -> Bus<'_> {
+        Bus { memory, base, last_write_addr: None, undo_log: None, devices: Vec::new(), time_skipped: 0, parked_reservations: Vec::new(), replay: None, dma_written: false }
+    }
+
+    // Returns true if [addr, addr + size) is in memory.
+    pub fn is_mapped(&self, addr: u64, size: u32) -> bool {
+        match addr.checked_sub(self.base) {
+            Some(offset) => offset.saturating_add(size as u64) <= self.memory.body.len() as u64,
+            None => false,
+        }
+    }
+
+    // Returns true if [addr, addr + size) is in memory or in the registers of a device.
+    pub fn is_accessible(&self, addr: u64, size: u32) -> bool {
+        self.is_mapped(addr, size) || self.find_device(addr, size).is_some()
+    }
+
+    // Maps a device at base. Devices must not overlap memory or each other.
+    pub fn add_device(&mut self, base: u64, device: Box<dyn Device>) {
+        let size = device.size();
+        self.devices.push(MappedDevice { base, size, device: RefCell::new(device) });
+    }
+
+    pub fn has_devices(&self) -> bool {
+        !self.devices.is_empty()
+    }
+
+    // Writes the nodes of the devices in order of address.
+    pub fn add_device_tree_nodes(&self, fdt: &mut Fdt) {
+        let mut devices: Vec<&MappedDevice> = self.devices.iter().collect();
+        devices.sort_by_key(|d| d.base);
+        for d in devices {
+            d.device.borrow().device_tree_node(fdt, d.base);
+        }
+    }
+
+    fn device_index(&self, addr: u64, size: u32) -> Option<usize> {
+        self.devices.iter().position(|d| addr >= d.base && (addr - d.base).saturating_add(size as u64) <= d.size)
+    }
+
+    fn find_device(&self, addr: u64, size: u32) -> Option<&MappedDevice> {
+        self.device_index(addr, size).map(|i| &self.devices[i])
+    }
+
+    // Accesses to addresses which are neither in memory nor in a device read as 0 and are ignored.
+    fn read_device(&self, addr: u64, size: u32) -> u64 {
+        match self.find_device(addr, size) {
+            Some(d) => d.device.borrow_mut().read(addr - d.base, size),
+            None => 0,
+        }
+    }
+
+    fn write_device(&mut self, addr: u64, size: u32, value: u64) {
+        if let Some(i) = self.device_index(addr, size) {
+            let d = &self.devices[i];
+            let mut device = d.device.borrow_mut();
+            device.write(addr - d.base, size, value);
+            // The write may be a request, e.g. a notification of a virtqueue.
+            let mut dma = Dma::new(self.memory, self.base);
+            device.dma(&mut dma);
+            self.dma_written |= dma.written;
+        }
+    }
+
+    // Returns true if devices have written memory directly since the last call, and clears it.
+    pub fn take_dma_written(&mut self) -> bool {
+        mem::replace(&mut self.dma_written, false)
+    }
+
+    // Advances the devices to the time at `cycle` and returns the bits of mip of `hart` they request.
+    pub fn update_devices(&mut self, hart: usize, cycle: u64) -> u64 {
+        let time = cycle.wrapping_add(self.time_skipped);
+        let inputs = match &mut self.replay {
+            Some(replay) if replay.is_replaying() => replay.replay_inputs(cycle),
+            _ => self.devices.iter().enumerate().map(|(i, d)| (i, d.device.borrow_mut().take_host_input())).filter(|(_, data)| !data.is_empty()).collect(),
+        };
+        for (i, data) in inputs {
+            if let Some(replay) = &mut self.replay {
+                replay.record_input(cycle, i, &data);
+            }
+            if let Some(d) = self.devices.get(i) {
+                d.device.borrow_mut().receive(&data);
+            }
+        }
+
+        let mut irqs = 0;
+        for d in &self.devices {
+            let mut device = d.device.borrow_mut();
+            let mut dma = Dma::new(self.memory, self.base);
+            device.dma(&mut dma);
+            self.dma_written |= dma.written;
+            if device.update(time) {
+                irqs |= 1 << device.irq();
+            }
+        }
+        // Source 0 means no interrupt.
+        irqs &= !1;
+        self.devices.iter().map(|d| d.device.borrow_mut().interrupt_lines(hart, irqs)).fold(0, |lines, l| lines | l)
+    }
+
+    pub fn finish_devices(&self) {
+        for d in &self.devices {
+            d.device.borrow_mut().finish();
+        }
+    }
+
+    pub fn reset_devices(&self) {
+        for d in &self.devices {
+            d.device.borrow_mut().reset();
+        }
+    }
+
+    // Returns the states of the devices in order of addition, None for those which cannot be saved.
+    pub fn save_devices(&self) -> Vec<Option<Vec<u8>>> {
+        self.devices.iter().map(|d| d.device.borrow().save()).collect()
+    }
+
+    // Restores the state of every device, or of none of them if an error is returned.
+    pub fn restore_devices(&mut self, states: &[&[u8]]) -> Result<(), String> {
+        if states.len() != self.devices.len() {
+            return Err(format!("snapshot has {} devices (machine has {})", states.len(), self.devices.len()))
+        }
+        let mut saved = Vec::new();
+        for d in &self.devices {
+            match d.device.borrow().save() {
+                Some(state) => saved.push(state),
+                None => return Err(format!("device at 0x{:x}: device does not support snapshots", d.base)),
+            }
+        }
+        for (i, (d, state)) in self.devices.iter().zip(states).enumerate() {
+            if let Err(e) = d.device.borrow_mut().restore(state) {
+                // The devices restored before are returned to their states, which they have saved.
+                for (d, state) in self.devices.iter().zip(&saved).take(i) {
+                    let _ = d.device.borrow_mut().restore(state);
+                }
+                return Err(format!("device at 0x{:x}: {}", d.base, e))
+            }
+        }
+        Ok(())
+    }
+
+    pub fn time_skipped(&self) -> u64 {
+        self.time_skipped
+    }
+
+    pub fn set_time_skipped(&mut self, time_skipped: u64) {
+        self.time_skipped = time_skipped;
+    }
+
+    // Passes the time while a hart at `cycle` waits for an interrupt: skips to the next device event if
+    // it is close, and otherwise lets the host time pass, e.g. to wait for input.
+    pub fn wait(&mut self, cycle: u64) {
+        let time = cycle.wrapping_add(self.time_skipped);
+        let event = self.devices.iter().filter_map(|d| d.device.borrow().next_event()).min();
+        match event {
+            Some(event) if event <= time => (),
+            Some(event) if event - time <= MAX_WAIT_SKIP => self.time_skipped += event - time,
+            _ => {
+                // Inputs come from the log when replaying.
+                if !self.replay.as_ref().is_some_and(|replay| replay.is_replaying()) {
+                    thread::sleep(WAIT_SLEEP);
+                }
+                self.time_skipped += WAIT_TICKS;
+            },
+        }
+    }
+
+    // Reads the host stdin for a syscall at cycle, through the replay log if any.
+    pub fn read_stdin(&mut self, cycle: u64, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.replay {
+            Some(replay) => replay.read_stdin(cycle, buf),
+            None => io::stdin().read(buf),
+        }
+    }
+
+    // Returns a value of the host clock for a syscall at cycle, through the replay log if any.
+    pub fn host_time<F: FnOnce() -> u64>(&mut self, cycle: u64, now: F) -> u64 {
+        match &mut self.replay {
+            Some(replay) => replay.time(cycle, now),
+            None => now(),
+        }
+    }
+
+    // Logs an interrupt taken by hart at cycle when recording, or checks it when replaying.
+    pub fn log_interrupt(&mut self, cycle: u64, hart: usize, cause: u32) {
+        if let Some(replay) = &mut self.replay {
+            replay.interrupt(cycle, hart, cause);
+        }
+    }
+
+    // Copies data to memory without recording it as a write, e.g. to load a program.
+    pub fn load(&mut self, addr: u64, data: &[u8]) -> Result<(), String> {
+        if !self.is_mapped(addr, data.len() as u32) {
+            return Err(format!("0x{:x}-0x{:x} is out of memory", addr, addr + data.len() as u64))
+        }
+
+        let offset = (addr - self.base) as usize;
+        self.memory.body[offset..offset + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    pub fn enable_undo_log(&mut self) {
+        self.undo_log = Some(Vec::new());
+    }
+
+    pub fn take_undo_log(&mut self) -> Vec<(u64, u8)> {
+        match &mut self.undo_log {
+            Some(log) => mem::take(log),
+            None => Vec::new(),
+        }
+    }
+
+    // Keeps the reservation of a hart which stops running.
+    pub fn park_reservation(&mut self, hart: usize, addr: u64) {
+        self.parked_reservations.push((hart, addr));
+    }
+
+    // Returns the reservation of a hart which starts running, unless it has been cleared by a write.
+    pub fn unpark_reservation(&mut self, hart: usize) -> Option<u64> {
+        let index = self.parked_reservations.iter().position(|(h, _)| *h == hart)?;
+        Some(self.parked_reservations.swap_remove(index).1)
+    }
+
+    fn record_write(&mut self, addr: u64, size: u32) {
+        self.last_write_addr = Some(addr);
+        if !self.parked_reservations.is_empty() {
+            let end = addr.wrapping_add(size as u64);
+            self.parked_reservations.retain(|(_, r)| end <= (r & !7) || addr >= (r & !7) + 8);
+        }
+
+        // Writes to devices cannot be undone.
+        if self.undo_log.is_some() && self.is_mapped(addr, size) {
+            let values: Vec<(u64, u8)> = (0..size as u64).map(|i| addr.wrapping_add(i)).map(|a| (a, self.read_u8(a))).collect();
+            if let Some(log) = &mut self.undo_log {
+                log.extend(values);
+            }
+        }
+    }
+
+    // Returns true when writes of harts must go through the bus, which records them or clears the
+    // reservations of other harts by them.
+    #[cfg(feature = "jit")]
+    pub fn is_tracking_writes(&self) -> bool {
+        self.undo_log.is_some() || !self.parked_reservations.is_empty()
+    }
+
+    // Returns the address of the most recent write and clears it.
+    pub fn take_last_write_addr(&mut self) -> Option<u64> {
+        self.last_write_addr.take()
+    }
+
+    pub fn read_u8(&self, addr: u64) -> u8 {
+        if !self.is_mapped(addr, 1) {
+            return self.read_device(addr, 1) as u8
+        }
+        self.memory.read_u8(addr - self.base)
+    }
+
+    pub fn read_u16(&self, addr: u64) -> u16 {
+        if !self.is_mapped(addr, 2) {
+            return self.read_device(addr, 2) as u16
+        }
+        self.memory.read_u16(addr - self.base)
     }
 
-    pub fn read_u8(&self, addr: u32) -> u8 {
-        self.memory.read_u8(addr.wrapping_sub(0x8000_0000) as u64)
+    pub fn read_u32(&self, addr: u64) -> u32 {
+        if !self.is_mapped(addr, 4) {
+            return self.read_device(addr, 4) as u32
+        }
+        self.memory.read_u32(addr - self.base)
     }
 
-    pub fn read_u16(&self, addr: u32) -> u16 {
-        self.memory.read_u16(addr.wrapping_sub(0x8000_0000) as u64)
+    pub fn read_u64(&self, addr: u64) -> u64 {
+        if !self.is_mapped(addr, 8) {
+            return self.read_device(addr, 8)
+        }
+        self.memory.read_u64(addr - self.base)
     }
 
-    pub fn read_u32(&self, addr: u32) -> u32 {
-        self.memory.read_u32(addr.wrapping_sub(0x8000_0000) as u64)
+    pub fn write_u8(&mut self, addr: u64, value: u8) {
+        self.record_write(addr, 1);
+        if !self.is_mapped(addr, 1) {
+            return self.write_device(addr, 1, value as u64)
+        }
+        self.memory.write_u8(addr - self.base, value)
     }
 
-    pub fn write_u8(&mut self, addr: u32, value: u8) {
-        self.memory.write_u8(addr.wrapping_sub(0x8000_0000) as u64, value)
+    pub fn write_u16(&mut self, addr: u64, value: u16) {
+        self.record_write(addr, 2);
+        if !self.is_mapped(addr, 2) {
+            return self.write_device(addr, 2, value as u64)
+        }
+        self.memory.write_u16(addr - self.base, value)
     }
 
-    pub fn write_u16(&mut self, addr: u32, value: u16) {
-        self.memory.write_u16(addr.wrapping_sub(0x8000_0000) as u64, value)
+    pub fn write_u32(&mut self, addr: u64, value: u32) {
+        self.record_write(addr, 4);
+        if !self.is_mapped(addr, 4) {
+            return self.write_device(addr, 4, value as u64)
+        }
+        self.memory.write_u32(addr - self.base, value)
     }
 
-    pub fn write_u32(&mut self, addr: u32, value: u32) {
-        self.memory.write_u32(addr.wrapping_sub(0x8000_0000) as u64, value)
+    pub fn write_u64(&mut self, addr: u64, value: u64) {
+        self.record_write(addr, 8);
+        if !self.is_mapped(addr, 8) {
+            return self.write_device(addr, 8, value)
+        }
+        self.memory.write_u64(addr - self.base, value)
     }
 }
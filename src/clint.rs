@@ -0,0 +1,182 @@
+// Core-local interruptor (CLINT) compatible with SiFive's, which raises the machine software
+// interrupt of each hart by its msip and its machine timer interrupt when mtime reaches its mtimecmp.
+//
+// mtime counts the time of the bus, which advances by a tick per cycle and is skipped forward while
+// the hart waits for an interrupt.
+
+use bus::*;
+use fdt::*;
+use snapshot::*;
+
+use serde::{Deserialize, Serialize};
+
+pub const CLINT_SIZE: u64 = 0x1_0000;
+
+const MSIP: u64 = 0x0;
+const MTIMECMP: u64 = 0x4000;
+const MTIME: u64 = 0xbff8;
+
+const MIP_MSIP: u64 = 1 << 3;
+const MIP_MTIP: u64 = 1 << 7;
+
+#[derive(Serialize, Deserialize)]
+pub struct Clint {
+    // Registers of each hart
+    msip: Vec<u32>,
+    mtimecmp: Vec<u64>,
+    // Time of the bus, and the difference of mtime from it which is set by writes of mtime
+    time: u64,
+    mtime_offset: u64,
+}
+
+// Returns size bytes at byte `offset` of a 64-bit register.
+fn read_part(register: u64, offset: u64, size: u32) -> u64 {
+    let value = register >> (8 * offset);
+    if size >= 8 { value } else { value & ((1 << (8 * size)) - 1) }
+}
+
+// Returns the register with size bytes at byte `offset` replaced by value.
+fn write_part(register: u64, offset: u64, size: u32, value: u64) -> u64 {
+    let mask = if size >= 8 { u64::MAX } else { (1 << (8 * size)) - 1 };
+    (register & !(mask << (8 * offset))) | ((value & mask) << (8 * offset))
+}
+
+impl Clint {
+    pub fn new(num_harts: usize) -> Clint {
+        Clint { msip: vec![0; num_harts], mtimecmp: vec![u64::MAX; num_harts], time: 0, mtime_offset: 0 }
+    }
+
+    fn mtime(&self) -> u64 {
+        self.time.wrapping_add(self.mtime_offset)
+    }
+}
+
+impl Device for Clint {
+    fn size(&self) -> u64 {
+        CLINT_SIZE
+    }
+
+    fn read(&mut self, offset: u64, size: u32) -> u64 {
+        let num_harts = self.msip.len() as u64;
+        match offset {
+            MSIP..=0x3fff if offset - MSIP < 4 * num_harts => {
+                let hart = ((offset - MSIP) / 4) as usize;
+                read_part(self.msip[hart] as u64, (offset - MSIP) % 4, size)
+            },
+            MTIMECMP..=0xbff7 if offset - MTIMECMP < 8 * num_harts => {
+                let hart = ((offset - MTIMECMP) / 8) as usize;
+                read_part(self.mtimecmp[hart], (offset - MTIMECMP) % 8, size)
+            },
+            MTIME..=0xbfff => read_part(self.mtime(), offset - MTIME, size),
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u64, size: u32, value: u64) {
+        let num_harts = self.msip.len() as u64;
+        match offset {
+            MSIP..=0x3fff if offset - MSIP < 4 * num_harts => {
+                let hart = ((offset - MSIP) / 4) as usize;
+                self.msip[hart] = write_part(self.msip[hart] as u64, (offset - MSIP) % 4, size, value) as u32 & 1;
+            },
+            MTIMECMP..=0xbff7 if offset - MTIMECMP < 8 * num_harts => {
+                let hart = ((offset - MTIMECMP) / 8) as usize;
+                self.mtimecmp[hart] = write_part(self.mtimecmp[hart], (offset - MTIMECMP) % 8, size, value);
+            },
+            MTIME..=0xbfff => {
+                let mtime = write_part(self.mtime(), offset - MTIME, size, value);
+                self.mtime_offset = mtime.wrapping_sub(self.time);
+            },
+            _ => (),
+        }
+    }
+
+    fn update(&mut self, time: u64) -> bool {
+        self.time = time;
+        false
+    }
+
+    fn interrupt_lines(&mut self, hart: usize, _irqs: u64) -> u64 {
+        if hart >= self.msip.len() {
+            return 0
+        }
+        let software = if self.msip[hart] != 0 { MIP_MSIP } else { 0 };
+        let timer = if self.mtime() >= self.mtimecmp[hart] { MIP_MTIP } else { 0 };
+        software | timer
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        self.mtimecmp.iter().map(|mtimecmp| self.time.saturating_add(mtimecmp.saturating_sub(self.mtime()))).min()
+    }
+
+    fn reset(&mut self) {
+        *self = Clint::new(self.msip.len());
+    }
+
+    fn save(&self) -> Option<Vec<u8>> {
+        Some(encode_state(self, &[]))
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), String> {
+        let (clint, _): (Clint, _) = decode_state(state)?;
+        if clint.msip.len() != self.msip.len() || clint.mtimecmp.len() != self.msip.len() {
+            return Err(format!("CLINT has {} harts (machine has {})", clint.msip.len(), self.msip.len()))
+        }
+        *self = clint;
+        Ok(())
+    }
+
+    fn device_tree_node(&self, fdt: &mut Fdt, base: u64) {
+        let interrupts: Vec<u32> = (0..self.msip.len() as u32).flat_map(|hart| vec![cpu_intc_phandle(hart), 3, cpu_intc_phandle(hart), 7]).collect();
+        fdt.begin_node(&format!("clint@{:x}", base));
+        fdt.property_strings("compatible", &["sifive,clint0", "riscv,clint0"]);
+        fdt.property_cells("reg", &[(base >> 32) as u32, base as u32, 0, CLINT_SIZE as u32]);
+        fdt.property_cells("interrupts-extended", &interrupts);
+        fdt.end_node();
+    }
+}
+
+#[test]
+fn test_clint() {
+    let mut clint = Clint::new(2);
+    clint.update(100);
+    assert_eq!(clint.read(MTIME, 8), 100);
+    assert_eq!(clint.interrupt_lines(0, 0), 0);
+
+    // Word accesses to mtimecmp, as RV32 does.
+    clint.write(MTIMECMP, 4, 150);
+    clint.write(MTIMECMP + 4, 4, 0);
+    assert_eq!(clint.read(MTIMECMP, 8), 150);
+    assert_eq!(clint.next_event(), Some(150));
+    clint.update(150);
+    assert_eq!(clint.interrupt_lines(0, 0), MIP_MTIP);
+    assert_eq!(clint.interrupt_lines(1, 0), 0);
+
+    // Writing mtime moves it relative to the time of the bus.
+    clint.write(MTIME, 8, 0);
+    assert_eq!(clint.next_event(), Some(300));
+    clint.update(160);
+    assert_eq!(clint.read(MTIME + 4, 4), 0);
+    assert_eq!(clint.read(MTIME, 4), 10);
+    assert_eq!(clint.interrupt_lines(0, 0), 0);
+
+    clint.write(MSIP, 4, 0xff);
+    assert_eq!(clint.read(MSIP, 4), 1);
+    assert_eq!(clint.interrupt_lines(0, 0), MIP_MSIP);
+
+    // The registers of hart 1 follow those of hart 0.
+    clint.write(MSIP + 4, 4, 1);
+    clint.write(MTIMECMP + 8, 8, 100);
+    assert_eq!(clint.next_event(), Some(250));
+    assert_eq!(clint.interrupt_lines(1, 0), MIP_MSIP);
+    clint.update(250);
+    assert_eq!(clint.interrupt_lines(1, 0), MIP_MSIP | MIP_MTIP);
+    assert_eq!(clint.read(MSIP + 8, 4), 0);
+
+    // A reset clears the registers, and mtime follows the time of the bus again.
+    clint.reset();
+    clint.update(300);
+    assert_eq!(clint.read(MTIME, 8), 300);
+    assert_eq!(clint.read(MTIMECMP + 8, 8), u64::MAX);
+    assert_eq!(clint.interrupt_lines(1, 0), 0);
+}
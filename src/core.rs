@@ -1,54 +1,468 @@
 use bus::*;
 use csr::*;
+use decoder::*;
+use mmu::*;
+use op::*;
+use pmp::*;
+use trap::*;
+use trigger::*;
+use xlen::*;
 
-pub struct IntReg {
-    values: [u32; 32],
+pub struct IntReg<X: Xlen = Rv32> {
+    values: [X::Uint; 32],
 }
 
-impl IntReg {
-    pub fn new() -> IntReg {
-        IntReg { values: [0; 32] }
+impl<X: Xlen> IntReg<X> {
+    pub fn new() -> IntReg<X> {
+        IntReg { values: [X::Uint::ZERO; 32] }
     }
 
-    pub fn read(&self, index: usize) -> u32 {
-        self.values[index]
+    // Registers beyond X::NUM_INT_REG do not exist; they read as zero and ignore writes.
+    pub fn read(&self, index: usize) -> X::Uint {
+        if index < X::NUM_INT_REG {
+            self.values[index]
+        } else {
+            X::Uint::ZERO
+        }
     }
 
-    pub fn write(&mut self, index: usize, value: u32) {
-        if index != 0 {
+    pub fn write(&mut self, index: usize, value: X::Uint) {
+        if index != 0 && index < X::NUM_INT_REG {
             self.values[index] = value
         }
     }
+
+    #[cfg(feature = "jit")]
+    pub fn as_mut_ptr(&mut self) -> *mut X::Uint {
+        self.values.as_mut_ptr()
+    }
 }
 
 #[test]
 fn test_int_reg() {
-    let mut reg = IntReg::new();
+    let mut reg: IntReg = IntReg::new();
     reg.write(0, 100);
     reg.write(1, 200);
     assert_eq!(reg.read(0), 0);
     assert_eq!(reg.read(1), 200);
+
+    let mut reg: IntReg<Rv32E> = IntReg::new();
+    reg.write(15, 100);
+    reg.write(16, 200);
+    assert_eq!(reg.read(15), 100);
+    assert_eq!(reg.read(16), 0);
+}
+
+pub const PRIV_USER: u32 = 0;
+pub const PRIV_SUPERVISOR: u32 = 1;
+pub const PRIV_MACHINE: u32 = 3;
+
+// Emulates the environment of the program, taking its calls to it instead of the trap handler, e.g.
+// the ECALLs of a Linux program or semihosting calls.
+pub trait SyscallHandler<X: Xlen> {
+    fn syscall(&mut self, core: &mut Core<X>);
 }
 
-pub struct Core<'a> {
-    pub csr: Csr,
-    pub int_reg: IntReg,
-    pub pc: u32,
-    pub next_pc: u32,
+// A semihosting call is an EBREAK between these two ops, which are nops.
+pub const SEMIHOSTING_ENTRY: u32 = 0x01f0_1013; // slli x0, x0, 0x1f
+pub const SEMIHOSTING_EXIT: u32 = 0x4070_5013; // srai x0, x0, 7
+
+pub struct Core<'a, X: Xlen = Rv32> {
+    pub csr: Csr<X>,
+    pub int_reg: IntReg<X>,
+    pub pc: X::Uint,
+    pub next_pc: X::Uint,
+    pub privilege: u32,
+    pub cycle: u64,
     pub bus: &'a mut Bus<'a>,
-    pub host_io_addr: u32,
+    pub mmu: Mmu,
+    pub host_io_addr: u64,
+    // Cause of the exception raised by the last executed op
+    pub last_trap_cause: Option<u32>,
+    pub syscall_handler: Option<Box<dyn SyscallHandler<X>>>,
+    pub semihosting_handler: Option<Box<dyn SyscallHandler<X>>>,
+    // Physical address reserved by LR, which SC requires
+    pub reservation: Option<u64>,
+    // The hart is stopped by WFI until an interrupt is pending.
+    pub waiting: bool,
+    // While waiting, let the bus pass the time until a device event. The harts of a multi-hart
+    // machine leave it to the scheduler, which waits only when all of them do.
+    pub wait_on_bus: bool,
+    // Fault raised by a memory access of the current op
+    memory_fault: Option<Trap<X>>,
 }
 
-impl<'a> Core<'a> {
-    pub fn new(bus: &'a mut Bus<'a>) -> Core<'a> {
-        Core { csr: Csr::new(), int_reg: IntReg::new(), pc: 0, next_pc: 0, bus: bus, host_io_addr: 0 }
+impl<'a, X: Xlen> Core<'a, X> {
+    pub fn new(bus: &'a mut Bus<'a>) -> Core<'a, X> {
+        Core {
+            csr: Csr::new(),
+            int_reg: IntReg::new(),
+            pc: X::Uint::ZERO,
+            next_pc: X::Uint::ZERO,
+            privilege: PRIV_MACHINE,
+            cycle: 0,
+            bus: bus,
+            mmu: Mmu::new(),
+            host_io_addr: 0,
+            last_trap_cause: None,
+            syscall_handler: None,
+            semihosting_handler: None,
+            reservation: None,
+            waiting: false,
+            wait_on_bus: true,
+            memory_fault: None,
+        }
+    }
+
+    // Returns the hart to its state at power-on, except the cycle count, e.g. when the machine is reset.
+    pub fn reset(&mut self) {
+        self.csr = Csr::new();
+        self.int_reg = IntReg::new();
+        self.pc = X::Uint::ZERO;
+        self.next_pc = X::Uint::ZERO;
+        self.privilege = PRIV_MACHINE;
+        self.mmu = Mmu::new();
+        self.last_trap_cause = None;
+        self.reservation = None;
+        self.waiting = false;
+        self.memory_fault = None;
+    }
+
+    // Writes a CSR from an op or a debugger, applying the side effects of the write.
+    pub fn write_csr(&mut self, index: usize, value: X::Uint) {
+        self.csr.write(index, value);
+
+        if index == CSR_INDEX_SATP {
+            self.mmu.flush();
+        }
+    }
+
+    // Returns a nop when the fetch faults; the fault is raised by the following execute().
+    pub fn fetch(&mut self) -> u32 {
+        self.memory_fault = None;
+        match self.translate(self.pc, 4, MemoryAccess::Fetch) {
+            Some(addr) => self.bus.read_u32(addr),
+            None => 0x13,
+        }
+    }
+
+    // Returns true when fetches or data accesses are currently translated by the MMU.
+    pub fn is_paging_enabled(&self) -> bool {
+        let (mode, _) = decode_satp::<X>(self.csr.read_satp());
+        mode != SATP_MODE_BARE && (self.privilege != PRIV_MACHINE || self.csr.read_mstatus().mprv() == 1)
+    }
+
+    // Returns true when memory accesses may be translated or denied, so they must not bypass translate().
+    pub fn is_memory_protected(&self) -> bool {
+        self.is_paging_enabled() || is_pmp_enforced(self)
+    }
+
+    // Translates a virtual address and checks PMP for the access of size bytes. On failure the fault
+    // is recorded to be raised after the op.
+    pub fn translate(&mut self, addr: X::Uint, size: u32, access: MemoryAccess) -> Option<u64> {
+        let mstatus = self.csr.read_mstatus();
+        let privilege = match access {
+            MemoryAccess::Fetch => self.privilege,
+            _ if mstatus.mprv() == 1 => mstatus.mpp(),
+            _ => self.privilege,
+        };
+
+        let result = if privilege == PRIV_MACHINE {
+            Ok(addr.to_u64())
+        } else {
+            self.mmu.translate::<X>(self.bus, &self.csr, addr, access, privilege)
+        };
+        let result = result.and_then(|paddr| {
+            if check_pmp(&self.csr, paddr, size, access, privilege) && self.bus.is_accessible(paddr, size) {
+                Ok(paddr)
+            } else {
+                Err(MmuFault::Access)
+            }
+        });
+
+        match result {
+            Ok(paddr) => Some(paddr),
+            Err(fault) => {
+                if self.memory_fault.is_none() {
+                    self.memory_fault = Some(Trap::new_mmu_fault(self.pc, addr, access, fault));
+                }
+                None
+            },
+        }
+    }
+
+    pub fn load_u8(&mut self, addr: X::Uint) -> Option<u8> {
+        self.translate(addr, 1, MemoryAccess::Load).map(|paddr| self.bus.read_u8(paddr))
+    }
+
+    pub fn load_u16(&mut self, addr: X::Uint) -> Option<u16> {
+        self.translate(addr, 2, MemoryAccess::Load).map(|paddr| self.bus.read_u16(paddr))
+    }
+
+    pub fn load_u32(&mut self, addr: X::Uint) -> Option<u32> {
+        self.translate(addr, 4, MemoryAccess::Load).map(|paddr| self.bus.read_u32(paddr))
+    }
+
+    pub fn load_u64(&mut self, addr: X::Uint) -> Option<u64> {
+        self.translate(addr, 8, MemoryAccess::Load).map(|paddr| self.bus.read_u64(paddr))
+    }
+
+    pub fn store_u8(&mut self, addr: X::Uint, value: u8) {
+        if let Some(paddr) = self.translate(addr, 1, MemoryAccess::Store) {
+            self.bus.write_u8(paddr, value)
+        }
+    }
+
+    pub fn store_u16(&mut self, addr: X::Uint, value: u16) {
+        if let Some(paddr) = self.translate(addr, 2, MemoryAccess::Store) {
+            self.bus.write_u16(paddr, value)
+        }
+    }
+
+    pub fn store_u32(&mut self, addr: X::Uint, value: u32) {
+        if let Some(paddr) = self.translate(addr, 4, MemoryAccess::Store) {
+            self.bus.write_u32(paddr, value)
+        }
+    }
+
+    pub fn store_u64(&mut self, addr: X::Uint, value: u64) {
+        if let Some(paddr) = self.translate(addr, 8, MemoryAccess::Store) {
+            self.bus.write_u64(paddr, value)
+        }
+    }
+
+    // Translates the address of an atomic access, which must be aligned to its size. AMOs and SC are
+    // checked as stores, LR as a load.
+    pub fn translate_atomic(&mut self, addr: X::Uint, size: u32, access: MemoryAccess) -> Option<u64> {
+        if !addr.to_u64().is_multiple_of(size as u64) {
+            if self.memory_fault.is_none() {
+                self.memory_fault = Some(Trap::new_mmu_fault(self.pc, addr, access, MmuFault::Access));
+            }
+            return None
+        }
+        self.translate(addr, size, access)
+    }
+
+    // Passes an ECALL from user mode to the syscall handler. Returns false if there is none.
+    pub fn handle_syscall(&mut self) -> bool {
+        if self.privilege != PRIV_USER {
+            return false
+        }
+        match self.syscall_handler.take() {
+            Some(mut handler) => {
+                handler.syscall(self);
+                self.syscall_handler = Some(handler);
+                true
+            },
+            None => false,
+        }
     }
 
-    pub fn fetch(&self) -> u32 {
-        self.bus.read_u32(self.pc)
+    // Returns true when the EBREAK at pc is a semihosting call and there is a handler for it.
+    // Semihosting is used by bare-metal programs, so calls are not recognized while paging is enabled.
+    pub fn is_semihosting_call(&self) -> bool {
+        let pc = self.pc.to_u64();
+        self.semihosting_handler.is_some() && !self.is_paging_enabled() && pc >= 4 && self.bus.is_mapped(pc - 4, 12)
+            && self.bus.read_u32(pc - 4) == SEMIHOSTING_ENTRY && self.bus.read_u32(pc + 4) == SEMIHOSTING_EXIT
+    }
+
+    // Passes a semihosting call to its handler. Returns false if the EBREAK at pc is not one.
+    pub fn handle_semihosting(&mut self) -> bool {
+        if !self.is_semihosting_call() {
+            return false
+        }
+        let mut handler = self.semihosting_handler.take().unwrap();
+        handler.syscall(self);
+        self.semihosting_handler = Some(handler);
+        true
+    }
+
+    pub fn read_host_io(&self) -> u64 {
+        self.bus.read_u64(self.host_io_addr)
+    }
+
+    // Updates the interrupt requests of devices and takes a pending interrupt before the next op.
+    // While the hart waits for an interrupt, passes the time of a cycle instead.
+    pub fn check_interrupts(&mut self) {
+        if self.bus.has_devices() {
+            let lines = self.bus.update_devices(self.csr.hart_id(), self.cycle);
+            self.csr.set_interrupt_lines(lines);
+        }
+
+        if self.waiting {
+            // WFI resumes on a pending interrupt even if it is disabled.
+            if self.csr.read_mip().to_u64() & self.csr.read_mie().to_u64() == 0 {
+                if self.wait_on_bus {
+                    self.bus.wait(self.cycle);
+                }
+                self.cycle += 1;
+                return
+            }
+            self.waiting = false;
+        }
+
+        if let Some(cause) = pending_interrupt(self) {
+            self.bus.log_interrupt(self.cycle, self.csr.hart_id(), cause);
+            process_trap(self, &Trap::new_interrupt(self.pc, cause));
+            self.pc = self.next_pc;
+        }
+    }
+
+    pub fn step(&mut self) {
+        self.check_interrupts();
+        if self.waiting {
+            return
+        }
+
+        let insn = self.fetch();
+        let op = decode::<X>(&insn);
+
+        self.execute(&*op);
+    }
+
+    pub fn execute(&mut self, op: &dyn Op<X>) {
+        // Currently, 2-byte ops are not supported
+        self.next_pc = self.pc.wrapping_add(X::Uint::from_u32(4));
+
+        self.last_trap_cause = None;
+        self.csr.clear_counters_written();
+        let tlb_misses = self.mmu.tlb_misses;
+
+        // A fault left by fetch() is raised before the op is executed.
+        let trap = match self.memory_fault.take().or_else(|| check_guest_triggers(self, op)) {
+            Some(trap) => Some(trap),
+            None => {
+                op.execute(self);
+                self.memory_fault.take().or_else(|| op.post_check_trap(self))
+            },
+        };
+
+        self.count_events(op, &trap, tlb_misses);
+
+        if let Some(trap) = trap {
+            if let TrapType::Exception = trap.trap_type {
+                self.last_trap_cause = Some(trap.cause);
+            }
+            process_trap(self, &trap);
+        }
+
+        self.pc = self.next_pc;
+        self.cycle += 1;
     }
 
-    pub fn read_host_io(&self) -> u32 {
-        self.bus.read_u32(self.host_io_addr)
+    fn count_events(&mut self, op: &dyn Op<X>, trap: &Option<Trap<X>>, tlb_misses: u64) {
+        // Ops raising an exception do not retire.
+        let retired = !matches!(trap, Some(Trap { trap_type: TrapType::Exception, .. }));
+
+        self.csr.count(COUNTER_CYCLE, 1);
+        if !retired {
+            self.csr.count_event(EVENT_TRAP, 1);
+        } else {
+            self.csr.count(COUNTER_INSTRET, 1);
+        }
+
+        if !self.csr.is_event_counted() {
+            return
+        }
+        self.csr.count_event(EVENT_TLB_MISS, self.mmu.tlb_misses - tlb_misses);
+        if retired {
+            match op.class() {
+                OpClass::Load => self.csr.count_event(EVENT_LOAD, 1),
+                OpClass::Store => self.csr.count_event(EVENT_STORE, 1),
+                OpClass::Branch if self.next_pc != self.pc.wrapping_add(X::Uint::from_u32(4)) => self.csr.count_event(EVENT_BRANCH_TAKEN, 1),
+                _ => (),
+            }
+        }
     }
 }
+
+#[test]
+fn test_counters() {
+    use memory::*;
+
+    let mut memory = Memory::new();
+    let mut bus = Bus::new(&mut memory);
+    let mut core: Core = Core::new(&mut bus);
+    core.pc = 0x8000_0000;
+    core.int_reg.write(1, 0x8000_0100);
+
+    core.csr.write(0x323, EVENT_LOAD);
+    core.csr.write(0x324, EVENT_STORE);
+    core.csr.write(0x325, EVENT_BRANCH_TAKEN);
+    core.csr.write(0x326, EVENT_TRAP);
+    core.csr.write(0x327, 100);
+    assert_eq!(core.csr.read(0x327), EVENT_NONE);
+
+    core.execute(&LW { rd: 2, rs1: 1, imm: 0 });
+    core.execute(&SW { rs1: 1, rs2: 0, imm: 0 });
+    core.execute(&BEQ { rs1: 0, rs2: 0, imm: 8 });
+    core.execute(&BEQ { rs1: 0, rs2: 1, imm: 8 });
+    core.execute(&EBREAK {});
+    assert_eq!(core.csr.read(0xb00), 5);
+    assert_eq!(core.csr.read(0xb02), 4);
+    assert_eq!((3..7).map(|i| core.csr.read(0xb00 + i)).collect::<Vec<u32>>(), vec![1, 1, 1, 1]);
+
+    // A counter written by an op is not incremented by it.
+    core.int_reg.write(3, 0xffff_ffff);
+    core.execute(&CSRRW { csr: 0xb02, rd: 0, rs1: 3 });
+    assert_eq!(core.csr.read(0xb02), 0xffff_ffff);
+    core.execute(&CSRRS { csr: 0xc02, rd: 4, rs1: 0 });
+    assert_eq!(core.int_reg.read(4), 0xffff_ffff);
+    assert_eq!(core.csr.read(0xb82), 1);
+
+    core.csr.write(0x320, 0x7);
+    core.execute(&CSRRS { csr: 0xc00, rd: 4, rs1: 0 });
+    assert_eq!(core.csr.read(0xb00), 7);
+    assert_eq!(core.csr.read(0x320), 0x5);
+
+    // Unprivileged reads need both mcounteren and scounteren in U-mode.
+    core.privilege = PRIV_USER;
+    core.execute(&CSRRS { csr: 0xc00, rd: 4, rs1: 0 });
+    assert_eq!(core.last_trap_cause, Some(2));
+    core.csr.write(0x306, 1);
+    core.csr.write(0x106, 1);
+    core.privilege = PRIV_USER;
+    core.execute(&CSRRS { csr: 0xc00, rd: 4, rs1: 0 });
+    assert_eq!(core.last_trap_cause, None);
+}
+
+#[test]
+fn test_interrupts() {
+    use memory::*;
+
+    let mut memory = Memory::new();
+    let mut bus = Bus::new(&mut memory);
+    let mut core: Core<Rv64> = Core::new(&mut bus);
+    core.pc = 0x8000_0000;
+    core.csr.write(0x305, 0x8000_0101); // mtvec, vectored
+    core.csr.write(0x105, 0x8000_0200); // stvec
+
+    // A pending software interrupt is not taken in M-mode until mstatus.MIE is set.
+    core.csr.write(0x304, 0x2); // mie.SSIE
+    core.csr.write(0x344, 0x2); // mip.SSIP
+    core.check_interrupts();
+    assert_eq!(core.pc, 0x8000_0000);
+    core.csr.write(0x300, 0x8);
+    core.check_interrupts();
+    assert_eq!(core.pc, 0x8000_0104);
+    assert_eq!(core.csr.read(0x342), 0x8000_0000_0000_0001);
+    assert_eq!(core.csr.read(0x341), 0x8000_0000);
+
+    // Delegated interrupts are taken in S-mode from U-mode regardless of sstatus.SIE, and MRET
+    // and SRET return to the previous privilege.
+    core.csr.write(0x303, 0x2); // mideleg.SSI
+    core.csr.write(0x341, 0x8000_0010); // mepc
+    core.csr.write(0x300, 0x0); // mstatus.MPP = U
+    core.execute(&MRET {});
+    assert_eq!((core.privilege, core.pc), (PRIV_USER, 0x8000_0010));
+    core.check_interrupts();
+    assert_eq!((core.privilege, core.pc), (PRIV_SUPERVISOR, 0x8000_0200));
+    assert_eq!(core.csr.read(0x142), 0x8000_0000_0000_0001);
+    assert_eq!(core.csr.read(0x141), 0x8000_0010);
+
+    core.csr.write(0x344, 0x0);
+    core.execute(&SRET {});
+    assert_eq!((core.privilege, core.pc), (PRIV_USER, 0x8000_0010));
+    core.execute(&SRET {});
+    assert_eq!(core.last_trap_cause, Some(2));
+}
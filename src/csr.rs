@@ -1,12 +1,83 @@
+use core::*;
+use mmu::*;
+use xlen::*;
+
+use std::mem;
+
 // Num of CSRs
 const NUM_CSR: usize = 0x1000;
 
 // CSR Index definitions
+const CSR_INDEX_SSTATUS : usize = 0x100;
+const CSR_INDEX_SIE     : usize = 0x104;
+const CSR_INDEX_STVEC   : usize = 0x105;
+pub const CSR_INDEX_SCOUNTEREN: usize = 0x106;
+const CSR_INDEX_SSCRATCH: usize = 0x140;
+const CSR_INDEX_SEPC    : usize = 0x141;
+const CSR_INDEX_SCAUSE  : usize = 0x142;
+const CSR_INDEX_STVAL   : usize = 0x143;
+const CSR_INDEX_SIP     : usize = 0x144;
+pub const CSR_INDEX_SATP: usize = 0x180;
 const CSR_INDEX_MSTATUS : usize = 0x300;
+pub const CSR_INDEX_MISA: usize = 0x301;
+const CSR_INDEX_MEDELEG : usize = 0x302;
+const CSR_INDEX_MIDELEG : usize = 0x303;
+const CSR_INDEX_MIE     : usize = 0x304;
 const CSR_INDEX_MTVEC   : usize = 0x305;
+pub const CSR_INDEX_MCOUNTEREN: usize = 0x306;
+const CSR_INDEX_MSTATUSH: usize = 0x310;
+const CSR_INDEX_MCOUNTINHIBIT: usize = 0x320;
+const CSR_INDEX_MHPMEVENT3: usize = 0x323;
+const CSR_INDEX_MHPMEVENT31: usize = 0x33f;
+const CSR_INDEX_MSCRATCH: usize = 0x340;
 const CSR_INDEX_MEPC    : usize = 0x341;
 const CSR_INDEX_MCAUSE  : usize = 0x342;
 const CSR_INDEX_MTVAL   : usize = 0x343;
+const CSR_INDEX_MIP     : usize = 0x344;
+pub const CSR_INDEX_PMPCFG0: usize = 0x3a0;
+const CSR_INDEX_PMPCFG3 : usize = 0x3a3;
+const CSR_INDEX_PMPCFG15: usize = 0x3af;
+pub const CSR_INDEX_PMPADDR0: usize = 0x3b0;
+const CSR_INDEX_PMPADDR15: usize = 0x3bf;
+const CSR_INDEX_PMPADDR63: usize = 0x3ef;
+const CSR_INDEX_TSELECT : usize = 0x7a0;
+const CSR_INDEX_TDATA1  : usize = 0x7a1;
+const CSR_INDEX_TDATA2  : usize = 0x7a2;
+const CSR_INDEX_TDATA3  : usize = 0x7a3;
+const CSR_INDEX_MCYCLE  : usize = 0xb00;
+const CSR_INDEX_MCYCLEH : usize = 0xb80;
+const CSR_INDEX_CYCLE   : usize = 0xc00;
+const CSR_INDEX_TIME    : usize = 0xc01;
+const CSR_INDEX_CYCLEH  : usize = 0xc80;
+const CSR_INDEX_TIMEH   : usize = 0xc81;
+const CSR_INDEX_MVENDORID: usize = 0xf11;
+const CSR_INDEX_MHARTID : usize = 0xf14;
+const CSR_INDEX_MCONFIGPTR: usize = 0xf15;
+
+// Num of counters: cycle, time, instret and hpmcounter3-31
+const NUM_COUNTER: usize = 32;
+
+pub const COUNTER_CYCLE: usize = 0;
+pub const COUNTER_INSTRET: usize = 2;
+
+// Events selectable by mhpmevent
+pub const EVENT_NONE       : u32 = 0;
+pub const EVENT_LOAD       : u32 = 1;
+pub const EVENT_STORE      : u32 = 2;
+pub const EVENT_BRANCH_TAKEN: u32 = 3;
+pub const EVENT_TRAP       : u32 = 4;
+pub const EVENT_TLB_MISS   : u32 = 5;
+const NUM_EVENT: u64 = 6;
+// Num of triggers selectable by tselect
+pub const NUM_TRIGGER: usize = 4;
+
+// tdata1.type for an address/data match trigger
+pub const TRIGGER_TYPE_MCONTROL: u32 = 2;
+
+// mcontrol.match values
+pub const MATCH_EQUAL       : u32 = 0;
+pub const MATCH_GREATER_OR_EQUAL: u32 = 2;
+pub const MATCH_LESS        : u32 = 3;
 
 // register definitions
 bitfield! {
@@ -32,68 +103,655 @@ bitfield! {
 }
 
 bitfield! {
-    pub struct MTVEC(u32);
+    pub struct MCONTROL(u32);
     impl Debug;
-    pub base,   set_base:   31,  2;
-    pub mode,   set_mode:    1,  0;
+    pub trigger_type, set_trigger_type: 31, 28;
+    pub dmode,  set_dmode:  27, 27;
+    pub maskmax, set_maskmax: 26, 21;
+    pub hit,    set_hit:    20, 20;
+    pub select, set_select: 19, 19;
+    pub timing, set_timing: 18, 18;
+    pub sizelo, set_sizelo: 17, 16;
+    pub action, set_action: 15, 12;
+    pub chain,  set_chain:  11, 11;
+    pub match_mode, set_match_mode: 10, 7;
+    pub m,      set_m:       6,  6;
+    pub s,      set_s:       4,  4;
+    pub u,      set_u:       3,  3;
+    pub execute, set_execute: 2, 2;
+    pub store,  set_store:   1,  1;
+    pub load,   set_load:    0,  0;
+}
+
+// Num of PMP entries
+pub const NUM_PMP: usize = 16;
+
+// pmpcfg fields
+pub const PMPCFG_R: u8 = 1 << 0;
+pub const PMPCFG_W: u8 = 1 << 1;
+pub const PMPCFG_X: u8 = 1 << 2;
+pub const PMPCFG_L: u8 = 1 << 7;
+pub const PMPCFG_A_SHIFT: u32 = 3;
+
+// pmpcfg.A values
+pub const PMP_OFF  : u8 = 0;
+pub const PMP_TOR  : u8 = 1;
+pub const PMP_NA4  : u8 = 2;
+pub const PMP_NAPOT: u8 = 3;
+
+// misa.Extensions
+const MISA_A: u64 = 1 << 0;
+const MISA_E: u64 = 1 << 4;
+const MISA_I: u64 = 1 << 8;
+const MISA_M: u64 = 1 << 12;
+const MISA_S: u64 = 1 << 18;
+const MISA_U: u64 = 1 << 20;
+
+// Writable bits of mstatus: SIE, MIE, SPIE, MPIE, SPP, MPP, MPRV, SUM, MXR, TVM, TW and TSR.
+// FS and XS are hardwired to 0 because F and custom extensions are not implemented.
+const MSTATUS_WRITE_MASK: u64 = 0x007e_19aa;
+
+// Bits of mstatus visible through sstatus, and the writable ones: SIE, SPIE, SPP, SUM and MXR.
+const SSTATUS_READ_MASK_32: u64 = 0x800d_e122;
+const SSTATUS_READ_MASK_64: u64 = 0x8000_0003_000d_e122;
+const SSTATUS_WRITE_MASK: u64 = 0x000c_0122;
+
+// Supervisor interrupt bits of mie and mip (SSI, STI and SEI), and all implemented ones.
+const SUPERVISOR_INTERRUPTS: u64 = 0x222;
+const INTERRUPTS: u64 = 0xaaa;
+
+// Exceptions which can be delegated to S-mode: all but ECALL from M-mode and the reserved causes.
+const MEDELEG_WRITE_MASK: u64 = 0xb3ff;
+
+// mtvec.MODE 2 and 3 are reserved, so bit 1 is hardwired to 0. xepc bits 1:0 are 0 without C.
+const XTVEC_WRITE_MASK: u64 = !2;
+const XEPC_WRITE_MASK: u64 = !3;
+
+// mstatus.UXL and mstatus.SXL for RV64, which are read-only 2 (64-bit).
+const MSTATUS_XL_64: u64 = 0xa_0000_0000;
+
+// Returns the fields of tdata1 in the RV32 layout. The tuple field of a bitfield struct is not
+// public in every version of bitfield, so other modules build it here.
+pub fn mcontrol(tdata1: u32) -> MCONTROL {
+    MCONTROL(tdata1)
+}
+
+// Legalizes a value written to tdata1. Only mcontrol triggers raising a breakpoint exception before
+// the access are implemented.
+fn legalize_tdata1(value: u32) -> u32 {
+    let mut mcontrol = MCONTROL(value);
+
+    mcontrol.set_trigger_type(TRIGGER_TYPE_MCONTROL);
+    mcontrol.set_dmode(0);
+    mcontrol.set_maskmax(0);
+    mcontrol.set_select(0);
+    mcontrol.set_timing(0);
+    mcontrol.set_sizelo(0);
+    mcontrol.set_action(0);
+    mcontrol.set_chain(0);
+    match mcontrol.match_mode() {
+        MATCH_EQUAL | MATCH_GREATER_OR_EQUAL | MATCH_LESS => (),
+        _ => mcontrol.set_match_mode(MATCH_EQUAL),
+    }
+
+    mcontrol.0 & !(1 << 5)
+}
+
+// Legalizes a pmpcfg byte. W without R is reserved and the bits 6:5 are hardwired to 0.
+fn legalize_pmpcfg(value: u8) -> u8 {
+    let value = value & !0x60;
+    if value & PMPCFG_R == 0 {
+        value & !PMPCFG_W
+    } else {
+        value
+    }
+}
+
+// Converts tdata1 between the RV32 layout used by MCONTROL and XLEN, where type, dmode and maskmax
+// are placed at the top.
+fn tdata1_to_xlen<X: Xlen>(value: u32) -> X::Uint {
+    X::Uint::from_u64(((value as u64 >> 21) << (X::XLEN - 11)) | (value & 0x1f_ffff) as u64)
+}
+
+fn tdata1_from_xlen<X: Xlen>(value: X::Uint) -> u32 {
+    let value = value.to_u64();
+    ((value >> (X::XLEN - 11)) << 21 | (value & 0x1f_ffff)) as u32
+}
+
+// How writes to an implemented CSR are handled
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CsrKind {
+    // Writes raise an illegal instruction exception.
+    ReadOnly,
+    // Only the bits set in the mask are written (WARL, other bits keep their values).
+    Masked(u64),
+    // Legalized by Csr::write_special(), possibly with side effects.
+    Special,
+    // View of bits of another CSR, e.g. sstatus of mstatus.
+    View,
+}
+
+// Registry of the implemented CSRs. Accessing other CSRs raises an illegal instruction exception.
+fn lookup<X: Xlen>(index: usize) -> Option<CsrKind> {
+    let rv32 = X::XLEN == 32;
+    match index {
+        // time is not implemented as a CSR and is expected to be emulated from the trap by firmware.
+        CSR_INDEX_TIME | CSR_INDEX_TIMEH => None,
+        CSR_INDEX_CYCLE..=0xc1f => Some(CsrKind::ReadOnly),
+        CSR_INDEX_CYCLEH..=0xc9f if rv32 => Some(CsrKind::ReadOnly),
+
+        CSR_INDEX_SSTATUS | CSR_INDEX_SIE | CSR_INDEX_SIP => Some(CsrKind::View),
+        CSR_INDEX_STVEC => Some(CsrKind::Masked(XTVEC_WRITE_MASK)),
+        CSR_INDEX_SCOUNTEREN => Some(CsrKind::Masked(0xffff_ffff)),
+        CSR_INDEX_SSCRATCH | CSR_INDEX_SCAUSE | CSR_INDEX_STVAL => Some(CsrKind::Masked(!0)),
+        CSR_INDEX_SEPC => Some(CsrKind::Masked(XEPC_WRITE_MASK)),
+        CSR_INDEX_SATP => Some(CsrKind::Special),
+
+        CSR_INDEX_MVENDORID..=CSR_INDEX_MCONFIGPTR => Some(CsrKind::ReadOnly),
+        CSR_INDEX_MSTATUS => Some(CsrKind::Special),
+        // misa is WARL and hardwired, so that extensions cannot be disabled.
+        CSR_INDEX_MISA => Some(CsrKind::Masked(0)),
+        CSR_INDEX_MEDELEG => Some(CsrKind::Masked(MEDELEG_WRITE_MASK)),
+        CSR_INDEX_MIDELEG => Some(CsrKind::Masked(SUPERVISOR_INTERRUPTS)),
+        CSR_INDEX_MIE => Some(CsrKind::Masked(INTERRUPTS)),
+        CSR_INDEX_MTVEC => Some(CsrKind::Masked(XTVEC_WRITE_MASK)),
+        CSR_INDEX_MCOUNTEREN => Some(CsrKind::Masked(0xffff_ffff)),
+        CSR_INDEX_MSTATUSH if rv32 => Some(CsrKind::Masked(0)),
+        // mcountinhibit bit 1 corresponds to time, which cannot be inhibited.
+        CSR_INDEX_MCOUNTINHIBIT => Some(CsrKind::Masked(0xffff_fffd)),
+        CSR_INDEX_MHPMEVENT3..=CSR_INDEX_MHPMEVENT31 => Some(CsrKind::Special),
+        CSR_INDEX_MSCRATCH | CSR_INDEX_MCAUSE | CSR_INDEX_MTVAL => Some(CsrKind::Masked(!0)),
+        CSR_INDEX_MEPC => Some(CsrKind::Masked(XEPC_WRITE_MASK)),
+        CSR_INDEX_MIP => Some(CsrKind::Masked(SUPERVISOR_INTERRUPTS)),
+
+        // RV64 has only the even pmpcfgs. Entries beyond NUM_PMP are hardwired to 0.
+        CSR_INDEX_PMPCFG0..=CSR_INDEX_PMPCFG15 if rv32 || index.is_multiple_of(2) => match index {
+            CSR_INDEX_PMPCFG0..=CSR_INDEX_PMPCFG3 => Some(CsrKind::Special),
+            _ => Some(CsrKind::Masked(0)),
+        },
+        CSR_INDEX_PMPADDR0..=CSR_INDEX_PMPADDR63 => match index {
+            CSR_INDEX_PMPADDR0..=CSR_INDEX_PMPADDR15 => Some(CsrKind::Special),
+            _ => Some(CsrKind::Masked(0)),
+        },
+
+        CSR_INDEX_TSELECT..=CSR_INDEX_TDATA3 => Some(CsrKind::Special),
+
+        // mcycle, minstret and mhpmcounters. 0xb01 would be mtime, which is not a CSR.
+        0xb01 | 0xb81 => None,
+        CSR_INDEX_MCYCLE..=0xb1f => Some(CsrKind::Special),
+        CSR_INDEX_MCYCLEH..=0xb9f if rv32 => Some(CsrKind::Special),
+        _ => None,
+    }
 }
 
 // CSR struct definition
-pub struct Csr {
-    values: [u32; NUM_CSR],
+pub struct Csr<X: Xlen = Rv32> {
+    values: [X::Uint; NUM_CSR],
+    // tdata1 in the RV32 layout and tdata2 of each trigger, banked by tselect
+    tdata: [(u32, X::Uint); NUM_TRIGGER],
+    counters: [u64; NUM_COUNTER],
+    // Counters written by the current op, which are not incremented by it
+    counters_written: u32,
+    // Previous values of written CSRs as (index, value), recorded while enabled.
+    undo_log: Option<Vec<(usize, X::Uint)>>,
+    // Interrupt requests from devices (MSIP, MTIP, MEIP and SEIP), which read as set in mip.
+    interrupt_lines: u64,
 }
 
-#[allow(dead_code)]
-impl Csr {
-    pub fn new() -> Csr {
-        Csr { values: [0; NUM_CSR] }
+// Returns (counter, upper half) for the counter CSRs and their unprivileged shadows.
+fn counter_index(index: usize) -> Option<(usize, bool)> {
+    match index {
+        CSR_INDEX_MCYCLE..=0xb1f => Some((index - CSR_INDEX_MCYCLE, false)),
+        CSR_INDEX_MCYCLEH..=0xb9f => Some((index - CSR_INDEX_MCYCLEH, true)),
+        CSR_INDEX_CYCLE..=0xc1f => Some((index - CSR_INDEX_CYCLE, false)),
+        CSR_INDEX_CYCLEH..=0xc9f => Some((index - CSR_INDEX_CYCLEH, true)),
+        _ => None,
+    }
+}
+
+impl<X: Xlen> Csr<X> {
+    pub fn new() -> Csr<X> {
+        let mut values = [X::Uint::ZERO; NUM_CSR];
+        let base = if X::NUM_INT_REG == 16 { MISA_E } else { MISA_I };
+        values[CSR_INDEX_MISA] = X::Uint::from_u64((X::MXL as u64) << (X::XLEN - 2) | base | MISA_A | MISA_M | MISA_S | MISA_U);
+        if X::XLEN == 64 {
+            values[CSR_INDEX_MSTATUS] = X::Uint::from_u64(MSTATUS_XL_64);
+        }
+
+        Csr {
+            values,
+            tdata: [(legalize_tdata1(0), X::Uint::ZERO); NUM_TRIGGER],
+            counters: [0; NUM_COUNTER],
+            counters_written: 0,
+            undo_log: None,
+            interrupt_lines: 0,
+        }
+    }
+
+    // Returns false when the CSR is not implemented or may not be accessed at privilege, so that the
+    // access raises an illegal instruction exception. The privilege required by a CSR is encoded in
+    // bits 9:8 of its index, and bits 11:10 are 3 for read-only CSRs. Counters are readable below
+    // M-mode only when enabled by mcounteren, and by scounteren for U-mode. satp is not accessible in
+    // S-mode when mstatus.TVM is set.
+    pub fn is_accessible(&self, index: usize, privilege: u32, write: bool) -> bool {
+        let kind = match lookup::<X>(index) {
+            Some(kind) => kind,
+            None => return false,
+        };
+        if ((index >> 8) & 3) as u32 > privilege || (write && kind == CsrKind::ReadOnly) {
+            return false
+        }
+
+        match index {
+            CSR_INDEX_CYCLE..=0xc1f | CSR_INDEX_CYCLEH..=0xc9f if privilege < PRIV_MACHINE => {
+                let bit = 1 << (index & 0x1f);
+                let mcounteren = self.values[CSR_INDEX_MCOUNTEREN].to_u64();
+                let scounteren = self.values[CSR_INDEX_SCOUNTEREN].to_u64();
+                mcounteren & bit != 0 && (privilege != PRIV_USER || scounteren & bit != 0)
+            },
+            CSR_INDEX_SATP if privilege == PRIV_SUPERVISOR => self.read_mstatus().tvm() == 0,
+            _ => true,
+        }
+    }
+
+    fn tselect(&self) -> usize {
+        self.values[CSR_INDEX_TSELECT].to_u64() as usize
+    }
+
+    fn sstatus_read_mask() -> u64 {
+        if X::XLEN == 32 { SSTATUS_READ_MASK_32 } else { SSTATUS_READ_MASK_64 }
+    }
+
+    pub fn read(&self, index: usize) -> X::Uint {
+        match index {
+            CSR_INDEX_SSTATUS => self.values[CSR_INDEX_MSTATUS] & X::Uint::from_u64(Csr::<X>::sstatus_read_mask()),
+            CSR_INDEX_SIE => self.values[CSR_INDEX_MIE] & X::Uint::from_u64(SUPERVISOR_INTERRUPTS),
+            CSR_INDEX_SIP => self.read(CSR_INDEX_MIP) & X::Uint::from_u64(SUPERVISOR_INTERRUPTS),
+            CSR_INDEX_MIP => self.values[CSR_INDEX_MIP] | X::Uint::from_u64(self.interrupt_lines),
+            CSR_INDEX_TDATA1 => tdata1_to_xlen::<X>(self.tdata[self.tselect()].0),
+            CSR_INDEX_TDATA2 => self.tdata[self.tselect()].1,
+            CSR_INDEX_TDATA3 => X::Uint::ZERO,
+            _ => match counter_index(index) {
+                Some((counter, true)) => X::Uint::from_u64(self.counters[counter] >> 32),
+                Some((counter, false)) => X::Uint::from_u64(self.counters[counter]),
+                None => self.values[index],
+            },
+        }
+    }
+
+    // Reads a CSR as restore() sets it. The interrupt lines of devices read in mip are not stored, so
+    // that they are not set after the devices drop them.
+    pub fn read_stored(&self, index: usize) -> X::Uint {
+        match index {
+            CSR_INDEX_MIP => self.values[CSR_INDEX_MIP],
+            _ => self.read(index),
+        }
+    }
+
+    // Writes a CSR with the legalization of its kind. Writes to read-only or unimplemented CSRs
+    // are ignored; ops check is_accessible() beforehand.
+    pub fn write(&mut self, index: usize, value: X::Uint) {
+        let kind = match lookup::<X>(index) {
+            Some(CsrKind::ReadOnly) | None => return,
+            Some(kind) => kind,
+        };
+
+        // Views are recorded as writes to the underlying CSR.
+        if kind == CsrKind::View {
+            let (target, mask) = match index {
+                CSR_INDEX_SSTATUS => (CSR_INDEX_MSTATUS, SSTATUS_WRITE_MASK),
+                CSR_INDEX_SIE => (CSR_INDEX_MIE, SUPERVISOR_INTERRUPTS),
+                // Only SSIP is writable through sip.
+                _ => (CSR_INDEX_MIP, 0x2),
+            };
+            let mask = X::Uint::from_u64(mask);
+            let merged = (self.values[target] & !mask) | (value & mask);
+            return self.write(target, merged)
+        }
+
+        let org = self.read_stored(index);
+        if let Some(log) = &mut self.undo_log {
+            log.push((index, org));
+        }
+
+        match kind {
+            CsrKind::Masked(mask) => {
+                let mask = X::Uint::from_u64(mask);
+                self.values[index] = (self.values[index] & !mask) | (value & mask)
+            },
+            _ => self.write_special(index, value),
+        }
+    }
+
+    fn write_special(&mut self, index: usize, value: X::Uint) {
+        match index {
+            CSR_INDEX_MSTATUS => {
+                let org = self.values[index];
+                let mask = X::Uint::from_u64(MSTATUS_WRITE_MASK);
+                let mut mstatus = (org & !mask) | (value & mask);
+                // MPP is WARL and 2 is reserved.
+                if MSTATUS(mstatus.to_u32()).mpp() == 2 {
+                    mstatus = (mstatus & !X::Uint::from_u32(0x1800)) | (org & X::Uint::from_u32(0x1800));
+                }
+                self.values[index] = mstatus
+            },
+            // Writes selecting an unsupported satp.MODE or a trigger which does not exist have no effect.
+            CSR_INDEX_SATP if is_valid_satp_mode::<X>(decode_satp::<X>(value).0) => self.values[index] = value,
+            CSR_INDEX_SATP => (),
+            CSR_INDEX_TSELECT if value.to_u64() < NUM_TRIGGER as u64 => self.values[index] = value,
+            CSR_INDEX_TSELECT => (),
+            CSR_INDEX_TDATA1 => {
+                let tselect = self.tselect();
+                self.tdata[tselect].0 = legalize_tdata1(tdata1_from_xlen::<X>(value))
+            },
+            CSR_INDEX_TDATA2 => {
+                let tselect = self.tselect();
+                self.tdata[tselect].1 = value
+            },
+            // tdata3 is not implemented
+            CSR_INDEX_TDATA3 => (),
+            CSR_INDEX_PMPCFG0..=CSR_INDEX_PMPCFG3 => self.write_pmpcfg(index, value),
+            CSR_INDEX_MHPMEVENT3..=CSR_INDEX_MHPMEVENT31 => {
+                self.values[index] = if value.to_u64() < NUM_EVENT { value } else { X::Uint::ZERO }
+            },
+            CSR_INDEX_MCYCLE..=0xb1f | CSR_INDEX_MCYCLEH..=0xb9f => {
+                let (counter, upper) = counter_index(index).unwrap();
+                self.set_counter_half(counter, upper, value);
+                self.counters_written |= 1 << counter;
+            },
+            CSR_INDEX_PMPADDR0..=CSR_INDEX_PMPADDR15 => {
+                let i = index - CSR_INDEX_PMPADDR0;
+                let next_is_locked_tor = i + 1 < NUM_PMP && {
+                    let next = self.read_pmpcfg(i + 1);
+                    next & PMPCFG_L != 0 && (next >> PMPCFG_A_SHIFT) & 3 == PMP_TOR
+                };
+                if self.read_pmpcfg(i) & PMPCFG_L == 0 && !next_is_locked_tor {
+                    // pmpaddr holds bits 55:2 of the address in RV64.
+                    self.values[index] = value & X::Uint::from_u64(0x3f_ffff_ffff_ffff)
+                }
+            },
+            // All Special CSRs of lookup() are handled above, so no other write reaches here.
+            _ => (),
+        }
+    }
+
+    // Sets a CSR bypassing legalization and PMP locks, to restore a value which was read before.
+    pub fn restore(&mut self, index: usize, value: X::Uint) {
+        match lookup::<X>(index) {
+            // Read-only CSRs and views are restored through the CSRs they reflect.
+            Some(CsrKind::ReadOnly) | Some(CsrKind::View) | None => (),
+            _ => match index {
+                CSR_INDEX_MISA | CSR_INDEX_TDATA1 | CSR_INDEX_TDATA2 | CSR_INDEX_TDATA3 => self.write(index, value),
+                CSR_INDEX_MCYCLE..=0xb1f | CSR_INDEX_MCYCLEH..=0xb9f => {
+                    let (counter, upper) = counter_index(index).unwrap();
+                    self.set_counter_half(counter, upper, value);
+                },
+                _ => self.values[index] = value,
+            },
+        }
+    }
+
+    // Sets the lower XLEN bits, or the upper 32 bits in RV32.
+    fn set_counter_half(&mut self, counter: usize, upper: bool, value: X::Uint) {
+        let org = self.counters[counter];
+        self.counters[counter] = match (upper, X::XLEN) {
+            (true, _) => (org & 0xffff_ffff) | (value.to_u64() << 32),
+            (false, 32) => (org & !0xffff_ffff) | value.to_u64(),
+            (false, _) => value.to_u64(),
+        };
     }
 
-    pub fn read(&self, index: usize) -> u32 {
-        self.values[index]
+    // Must be called before each op so that counters written by it are not incremented.
+    pub fn clear_counters_written(&mut self) {
+        self.counters_written = 0;
     }
 
-    pub fn write(&mut self, index: usize, value: u32) {
-        self.values[index] = value
+    // Adds n to a counter unless it is inhibited or has been written by the current op.
+    pub fn count(&mut self, counter: usize, n: u64) {
+        let inhibit = self.values[CSR_INDEX_MCOUNTINHIBIT].to_u64() as u32 | self.counters_written;
+        if n == 0 || inhibit & (1 << counter) != 0 {
+            return
+        }
+
+        if let Some(log) = &mut self.undo_log {
+            log.push((CSR_INDEX_MCYCLE + counter, X::Uint::from_u64(self.counters[counter])));
+            if X::XLEN == 32 {
+                log.push((CSR_INDEX_MCYCLEH + counter, X::Uint::from_u64(self.counters[counter] >> 32)));
+            }
+        }
+        self.counters[counter] = self.counters[counter].wrapping_add(n);
     }
 
+    // Adds n to the hpmcounters selecting event.
+    pub fn count_event(&mut self, event: u32, n: u64) {
+        for counter in 3..NUM_COUNTER {
+            if self.values[CSR_INDEX_MHPMEVENT3 + counter - 3].to_u64() == event as u64 {
+                self.count(counter, n);
+            }
+        }
+    }
+
+    // Returns true when an hpmcounter counts an event, which requires every op to be observed.
+    pub fn is_event_counted(&self) -> bool {
+        (CSR_INDEX_MHPMEVENT3..=CSR_INDEX_MHPMEVENT31).any(|i| self.values[i].to_u64() != EVENT_NONE as u64)
+    }
+
+    // Writes the bytes of pmpcfg whose entries are not locked.
+    fn write_pmpcfg(&mut self, index: usize, value: X::Uint) {
+        let org = self.values[index].to_u64();
+        let value = value.to_u64();
+        let mut result = 0;
+        for byte in 0..(X::XLEN / 8) {
+            let shift = byte * 8;
+            let cfg = if (org >> shift) as u8 & PMPCFG_L != 0 {
+                (org >> shift) as u8
+            } else {
+                legalize_pmpcfg((value >> shift) as u8)
+            };
+            result |= (cfg as u64) << shift;
+        }
+        self.values[index] = X::Uint::from_u64(result);
+    }
+
+    // Returns the configuration byte of PMP entry i. RV64 packs 8 entries into the even pmpcfgs.
+    pub fn read_pmpcfg(&self, i: usize) -> u8 {
+        let per_csr = X::XLEN as usize / 8;
+        let index = CSR_INDEX_PMPCFG0 + (i / per_csr) * (per_csr / 4);
+        (self.values[index].to_u64() >> ((i % per_csr) * 8)) as u8
+    }
+
+    pub fn read_pmpaddr(&self, i: usize) -> u64 {
+        self.values[CSR_INDEX_PMPADDR0 + i].to_u64()
+    }
+
+    // Returns (tdata1 in the RV32 layout, tdata2) of a trigger.
+    pub fn read_trigger(&self, index: usize) -> (u32, X::Uint) {
+        self.tdata[index]
+    }
+
+    pub fn write_trigger(&mut self, index: usize, tdata1: u32, tdata2: X::Uint) {
+        self.tdata[index] = (legalize_tdata1(tdata1), tdata2);
+    }
+
+    pub fn triggers_enabled(&self) -> bool {
+        self.tdata.iter().any(|t| t.0 & 0x7 != 0)
+    }
+
+    pub fn enable_undo_log(&mut self) {
+        self.undo_log = Some(Vec::new());
+    }
+
+    pub fn take_undo_log(&mut self) -> Vec<(usize, X::Uint)> {
+        match &mut self.undo_log {
+            Some(log) => mem::take(log),
+            None => Vec::new(),
+        }
+    }
+
+    // MSTATUS covers the lower 32 bits, the upper bits are kept on write.
     pub fn read_mstatus(&self) -> MSTATUS {
-        MSTATUS(self.read(CSR_INDEX_MSTATUS))
+        MSTATUS(self.read(CSR_INDEX_MSTATUS).to_u32())
     }
 
     pub fn write_mstatus(&mut self, value: MSTATUS) {
-        self.write(CSR_INDEX_MSTATUS, value.0)
+        let upper = self.read(CSR_INDEX_MSTATUS).to_u64() & !0xffff_ffff;
+        self.write(CSR_INDEX_MSTATUS, X::Uint::from_u64(upper | value.0 as u64))
     }
 
-    pub fn read_mtvec(&self) -> MTVEC {
-        MTVEC(self.read(CSR_INDEX_MTVEC))
+    pub fn hart_id(&self) -> usize {
+        self.values[CSR_INDEX_MHARTID].to_u64() as usize
     }
 
-    pub fn write_mtvec(&mut self, value: MTVEC) {
-        self.write(CSR_INDEX_MTVEC, value.0)
+    pub fn set_hart_id(&mut self, id: usize) {
+        self.values[CSR_INDEX_MHARTID] = X::Uint::from_u64(id as u64);
     }
 
-    pub fn read_mepc(&self) -> u32 {
-        self.read(CSR_INDEX_MEPC)
+    // Sets the interrupt requests from devices. Requests are not recorded in the undo log.
+    pub fn set_interrupt_lines(&mut self, lines: u64) {
+        self.interrupt_lines = lines & INTERRUPTS;
     }
 
-    pub fn write_mepc(&mut self, value: u32) {
-        self.write(CSR_INDEX_MEPC, value)
+    pub fn read_mip(&self) -> X::Uint {
+        self.read(CSR_INDEX_MIP)
     }
 
-    pub fn read_mcause(&self) -> u32 {
-        self.read(CSR_INDEX_MCAUSE)
+    pub fn read_mie(&self) -> X::Uint {
+        self.read(CSR_INDEX_MIE)
     }
 
-    pub fn write_mcause(&mut self, value: u32) {
-        self.write(CSR_INDEX_MCAUSE, value)
+    pub fn read_medeleg(&self) -> X::Uint {
+        self.read(CSR_INDEX_MEDELEG)
+    }
+
+    pub fn read_mideleg(&self) -> X::Uint {
+        self.read(CSR_INDEX_MIDELEG)
+    }
+
+    pub fn read_stvec(&self) -> X::Uint {
+        self.read(CSR_INDEX_STVEC)
+    }
+
+    pub fn read_sepc(&self) -> X::Uint {
+        self.read(CSR_INDEX_SEPC)
+    }
+
+    pub fn write_sepc(&mut self, value: X::Uint) {
+        self.write(CSR_INDEX_SEPC, value)
     }
 
-    pub fn read_mtval(&self) -> u32 {
-        self.read(CSR_INDEX_MTVAL)
+    pub fn write_scause(&mut self, value: X::Uint) {
+        self.write(CSR_INDEX_SCAUSE, value)
     }
 
-    pub fn write_mtval(&mut self, value: u32) {
+    pub fn write_stval(&mut self, value: X::Uint) {
+        self.write(CSR_INDEX_STVAL, value)
+    }
+
+    pub fn read_satp(&self) -> X::Uint {
+        self.read(CSR_INDEX_SATP)
+    }
+
+    pub fn read_mtvec(&self) -> X::Uint {
+        self.read(CSR_INDEX_MTVEC)
+    }
+
+    pub fn read_mepc(&self) -> X::Uint {
+        self.read(CSR_INDEX_MEPC)
+    }
+
+    pub fn write_mepc(&mut self, value: X::Uint) {
+        self.write(CSR_INDEX_MEPC, value)
+    }
+
+    pub fn write_mcause(&mut self, value: X::Uint) {
+        self.write(CSR_INDEX_MCAUSE, value)
+    }
+
+    pub fn write_mtval(&mut self, value: X::Uint) {
         self.write(CSR_INDEX_MTVAL, value)
     }
 }
+
+#[test]
+fn test_csr_registry() {
+    let mut csr: Csr = Csr::new();
+
+    assert!(!csr.is_accessible(0x7c0, PRIV_MACHINE, false));
+    assert!(!csr.is_accessible(CSR_INDEX_TIME, PRIV_MACHINE, false));
+    assert!(csr.is_accessible(CSR_INDEX_MVENDORID, PRIV_MACHINE, false));
+    assert!(!csr.is_accessible(CSR_INDEX_MVENDORID, PRIV_MACHINE, true));
+    assert!(!csr.is_accessible(CSR_INDEX_MSCRATCH, PRIV_SUPERVISOR, false));
+    assert!(csr.is_accessible(CSR_INDEX_SSCRATCH, PRIV_SUPERVISOR, true));
+    assert!(!Csr::<Rv64>::new().is_accessible(0x3a1, PRIV_MACHINE, false));
+
+    csr.write(CSR_INDEX_MSTATUS, 1 << 20);
+    assert!(!csr.is_accessible(CSR_INDEX_SATP, PRIV_SUPERVISOR, false));
+    assert!(!csr.is_accessible(CSR_INDEX_SATP, PRIV_SUPERVISOR, true));
+    assert!(csr.is_accessible(CSR_INDEX_SATP, PRIV_MACHINE, true));
+    csr.write(CSR_INDEX_MSTATUS, 0);
+    assert!(csr.is_accessible(CSR_INDEX_SATP, PRIV_SUPERVISOR, true));
+
+    let misa = csr.read(CSR_INDEX_MISA);
+    assert_eq!(misa, 0x4014_1101);
+    csr.write(CSR_INDEX_MISA, 0);
+    assert_eq!(csr.read(CSR_INDEX_MISA), misa);
+
+    // MPP=2 is reserved and FS is hardwired to 0.
+    csr.write(CSR_INDEX_MSTATUS, 0x0000_1800);
+    csr.write(CSR_INDEX_MSTATUS, 0x0000_7000);
+    assert_eq!(csr.read(CSR_INDEX_MSTATUS), 0x0000_1800);
+
+    // sstatus only writes the supervisor bits of mstatus.
+    csr.write(CSR_INDEX_SSTATUS, 0x0004_010a);
+    assert_eq!(csr.read(CSR_INDEX_MSTATUS), 0x0004_1902);
+    assert_eq!(csr.read(CSR_INDEX_SSTATUS), 0x0004_0102);
+
+    csr.write(CSR_INDEX_MTVEC, 0x8000_0102);
+    assert_eq!(csr.read(CSR_INDEX_MTVEC), 0x8000_0100);
+    csr.write(CSR_INDEX_MEPC, 0x8000_0003);
+    assert_eq!(csr.read(CSR_INDEX_MEPC), 0x8000_0000);
+    csr.write(CSR_INDEX_MIE, 0xffff_ffff);
+    assert_eq!(csr.read(CSR_INDEX_SIE), 0x222);
+}
+
+#[test]
+fn test_csr_rv64() {
+    let mut csr: Csr<Rv64> = Csr::new();
+
+    // misa.MXL reports 64 bits, and the upper halves of RV32 do not exist.
+    assert_eq!(csr.read(CSR_INDEX_MISA) >> 62, 2);
+    assert!(!csr.is_accessible(CSR_INDEX_MSTATUSH, PRIV_MACHINE, false));
+    assert!(!csr.is_accessible(CSR_INDEX_CYCLEH, PRIV_MACHINE, false));
+    assert!(!csr.is_accessible(CSR_INDEX_MCYCLEH, PRIV_MACHINE, false));
+    assert!(Csr::<Rv32>::new().is_accessible(CSR_INDEX_MCYCLEH, PRIV_MACHINE, true));
+
+    csr.write(CSR_INDEX_MCYCLE, 0x1_0000_0002);
+    assert_eq!(csr.read(CSR_INDEX_MCYCLE), 0x1_0000_0002);
+
+    // pmpcfg0 holds entries 0-7, and pmpaddr bits 55:2 of the address.
+    csr.write(CSR_INDEX_PMPCFG0, 0x1f00_0000_0000_0000);
+    assert_eq!(csr.read_pmpcfg(7), 0x1f);
+    csr.write(CSR_INDEX_PMPADDR0, !0);
+    assert_eq!(csr.read(CSR_INDEX_PMPADDR0), (1 << 54) - 1);
+}
+
+#[test]
+fn test_csr_interrupt_lines() {
+    let mut csr: Csr = Csr::new();
+    csr.enable_undo_log();
+
+    // Lines read as set in mip while devices request them, but are not stored by writes.
+    csr.set_interrupt_lines(0x80);
+    csr.write(CSR_INDEX_MIP, 0x2);
+    assert_eq!(csr.read(CSR_INDEX_MIP), 0x82);
+    csr.set_interrupt_lines(0);
+    assert_eq!(csr.read(CSR_INDEX_MIP), 0x2);
+
+    // Undoing the write does not set the line either.
+    csr.set_interrupt_lines(0x80);
+    for (index, value) in csr.take_undo_log().into_iter().rev() {
+        csr.restore(index, value);
+    }
+    csr.set_interrupt_lines(0);
+    assert_eq!(csr.read(CSR_INDEX_MIP), 0);
+}
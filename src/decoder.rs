@@ -1,7 +1,27 @@
 use op::*;
 use util::*;
+use xlen::*;
 
-pub fn decode(insn: &u32) -> Box<dyn Op> {
+// Checks that the register fields used by the instruction refer to existing registers.
+fn has_valid_registers<X: Xlen>(opcode: u32, funct3: u32, rd: usize, rs1: usize, rs2: usize) -> bool {
+    let (use_rd, use_rs1, use_rs2) = match opcode {
+        0b0110111 | 0b0010111 | 0b1101111 => (true, false, false),
+        0b1100111 | 0b0000011 | 0b0010011 | 0b0011011 => (true, true, false),
+        0b1100011 | 0b0100011 => (false, true, true),
+        0b0110011 | 0b0111011 | 0b0101111 => (true, true, true),
+        // sfence.vma, or CSR ops whose rs1 field is an immediate for funct3 >= 0b101
+        0b1110011 => match funct3 {
+            0b000 => (false, true, true),
+            0b001..=0b011 => (true, true, false),
+            _ => (true, false, false),
+        },
+        _ => (false, false, false),
+    };
+
+    !((use_rd && rd >= X::NUM_INT_REG) || (use_rs1 && rs1 >= X::NUM_INT_REG) || (use_rs2 && rs2 >= X::NUM_INT_REG))
+}
+
+pub fn decode<X: Xlen>(insn: &u32) -> Box<dyn Op<X>> {
     let opcode  = pick(insn, 0, 7);
     let rd      = pick(insn, 7, 5) as usize;
     let funct3  = pick(insn, 12, 3);
@@ -9,6 +29,10 @@ pub fn decode(insn: &u32) -> Box<dyn Op> {
     let rs2     = pick(insn, 20, 5) as usize;
     let funct7  = pick(insn, 25, 7);
 
+    if X::NUM_INT_REG < 32 && !has_valid_registers::<X>(opcode, funct3, rd, rs1, rs2) {
+        return Box::new(IllegalOp{})
+    }
+
     match opcode {
         0b0110111 => {
             let imm = pick(insn, 12, 20) << 12;
@@ -22,7 +46,7 @@ pub fn decode(insn: &u32) -> Box<dyn Op> {
             let imm = sign_extend(21,
                 pick(insn, 31, 1) << 20 |
                 pick(insn, 21, 10) << 1 |
-                pick(insn, 20, 1) << 12 |
+                pick(insn, 20, 1) << 11 |
                 pick(insn, 12, 8) << 12);
             Box::new(JAL{ rd: rd, imm: imm })
         },
@@ -57,6 +81,8 @@ pub fn decode(insn: &u32) -> Box<dyn Op> {
                 0b010 => Box::new(LW { imm: imm, rd: rd, rs1: rs1 }),
                 0b100 => Box::new(LBU{ imm: imm, rd: rd, rs1: rs1 }),
                 0b101 => Box::new(LHU{ imm: imm, rd: rd, rs1: rs1 }),
+                0b110 if X::XLEN == 64 => Box::new(LWU{ imm: imm, rd: rd, rs1: rs1 }),
+                0b011 if X::XLEN == 64 => Box::new(LD { imm: imm, rd: rd, rs1: rs1 }),
                 _ => Box::new(UnknownOp{}),
             }
         },
@@ -66,13 +92,17 @@ pub fn decode(insn: &u32) -> Box<dyn Op> {
                 0b000 => Box::new(SB{ imm: imm, rs1: rs1, rs2: rs2 }),
                 0b001 => Box::new(SH{ imm: imm, rs1: rs1, rs2: rs2 }),
                 0b010 => Box::new(SW{ imm: imm, rs1: rs1, rs2: rs2 }),
+                0b011 if X::XLEN == 64 => Box::new(SD{ imm: imm, rs1: rs1, rs2: rs2 }),
                 _ => Box::new(UnknownOp{}),
             }
         },
         0b0010011 => {
             let imm = sign_extend(12, pick(insn, 20, 12));
-            let shamt = pick(insn, 20, 5);
-            match (funct3, funct7) {
+            // RV64 has 6-bit shift amounts. funct is placed like funct7 with the shamt bits cleared.
+            let shamt_width = if X::XLEN == 64 { 6 } else { 5 };
+            let shamt = pick(insn, 20, shamt_width);
+            let funct = pick(insn, 20 + shamt_width, 12 - shamt_width) << (shamt_width - 5);
+            match (funct3, funct) {
                 (0b000, _) => Box::new(ADDI { imm: imm, rd: rd, rs1: rs1 }),
                 (0b010, _) => Box::new(SLTI { imm: imm, rd: rd, rs1: rs1 }),
                 (0b011, _) => Box::new(SLTIU{ imm: imm, rd: rd, rs1: rs1 }),
@@ -108,6 +138,54 @@ pub fn decode(insn: &u32) -> Box<dyn Op> {
                 _ => Box::new(UnknownOp{}),
             }
         },
+        0b0011011 if X::XLEN == 64 => {
+            let imm = sign_extend(12, pick(insn, 20, 12));
+            let shamt = pick(insn, 20, 5);
+            match (funct3, funct7) {
+                (0b000, _) => Box::new(ADDIW{ imm: imm, rd: rd, rs1: rs1 }),
+                (0b001, 0b0000000) => Box::new(SLLIW{ rd: rd, rs1: rs1, shamt: shamt }),
+                (0b101, 0b0000000) => Box::new(SRLIW{ rd: rd, rs1: rs1, shamt: shamt }),
+                (0b101, 0b0100000) => Box::new(SRAIW{ rd: rd, rs1: rs1, shamt: shamt }),
+                _ => Box::new(UnknownOp{}),
+            }
+        },
+        0b0111011 if X::XLEN == 64 => {
+            match (funct3, funct7) {
+                (0b000, 0b0000000) => Box::new(ADDW  { rd: rd, rs1: rs1, rs2: rs2 }),
+                (0b000, 0b0100000) => Box::new(SUBW  { rd: rd, rs1: rs1, rs2: rs2 }),
+                (0b001, 0b0000000) => Box::new(SLLW  { rd: rd, rs1: rs1, rs2: rs2 }),
+                (0b101, 0b0000000) => Box::new(SRLW  { rd: rd, rs1: rs1, rs2: rs2 }),
+                (0b101, 0b0100000) => Box::new(SRAW  { rd: rd, rs1: rs1, rs2: rs2 }),
+                (0b000, 0b0000001) => Box::new(MULW  { rd: rd, rs1: rs1, rs2: rs2 }),
+                (0b100, 0b0000001) => Box::new(DIVW  { rd: rd, rs1: rs1, rs2: rs2 }),
+                (0b101, 0b0000001) => Box::new(DIVUW { rd: rd, rs1: rs1, rs2: rs2 }),
+                (0b110, 0b0000001) => Box::new(REMW  { rd: rd, rs1: rs1, rs2: rs2 }),
+                (0b111, 0b0000001) => Box::new(REMUW { rd: rd, rs1: rs1, rs2: rs2 }),
+                _ => Box::new(UnknownOp{}),
+            }
+        },
+        0b0101111 => {
+            let size = match funct3 {
+                0b010 => 4,
+                0b011 if X::XLEN == 64 => 8,
+                _ => return Box::new(UnknownOp{}),
+            };
+            let kind = match pick(insn, 27, 5) {
+                0b00010 if rs2 == 0 => return Box::new(LR{ rd: rd, rs1: rs1, size: size }),
+                0b00011 => return Box::new(SC{ rd: rd, rs1: rs1, rs2: rs2, size: size }),
+                0b00001 => AmoKind::Swap,
+                0b00000 => AmoKind::Add,
+                0b00100 => AmoKind::Xor,
+                0b01100 => AmoKind::And,
+                0b01000 => AmoKind::Or,
+                0b10000 => AmoKind::Min,
+                0b10100 => AmoKind::Max,
+                0b11000 => AmoKind::Minu,
+                0b11100 => AmoKind::Maxu,
+                _ => return Box::new(UnknownOp{}),
+            };
+            Box::new(AMO{ kind: kind, rd: rd, rs1: rs1, rs2: rs2, size: size })
+        },
         0b0001111 => {
             let head = pick(insn, 28, 4);
             let pred = pick(insn, 28, 4);
@@ -141,3 +219,19 @@ pub fn decode(insn: &u32) -> Box<dyn Op> {
         _ => Box::new(UnknownOp{}),
     }
 }
+
+#[test]
+fn test_decode_jal() {
+    // j -4 / jal ra,2048
+    assert_eq!(decode::<Rv32>(&0xffdff06f).to_string(), format!("j #{}", -4i32 as u32));
+    assert_eq!(decode::<Rv32>(&0x001000ef).to_string(), "jal ra,2048");
+}
+
+#[test]
+fn test_decode_rv32e() {
+    // add a5,ra,sp / add a6,ra,sp / csrrwi a6,mscratch,16
+    assert_eq!(decode::<Rv32E>(&0x002087b3).to_string(), "add a5,ra,sp");
+    assert_eq!(decode::<Rv32E>(&0x00208833).to_string(), "illegal");
+    assert_eq!(decode::<Rv32>(&0x00208833).to_string(), "add a6,ra,sp");
+    assert_eq!(decode::<Rv32E>(&0x34085873).to_string(), "illegal");
+}
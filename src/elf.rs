@@ -0,0 +1,184 @@
+// Minimal reader of little-endian ELF32/ELF64 executables, which loads the program segments and
+// looks up symbols such as `tohost` and `begin_signature`.
+
+const ELFCLASS32: u8 = 1;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+
+const PT_LOAD: u32 = 1;
+const PT_PHDR: u32 = 6;
+const SHT_SYMTAB: u32 = 2;
+
+pub struct Segment {
+    // Physical address, where bare-metal programs are loaded
+    pub addr: u64,
+    // Virtual address, where user programs are loaded
+    pub vaddr: u64,
+    pub data: Vec<u8>,
+    // Bytes after data up to mem_size are zero-filled (.bss).
+    pub mem_size: u64,
+}
+
+pub struct Elf {
+    pub entry: u64,
+    pub segments: Vec<Segment>,
+    // Virtual address, entry size and number of the program headers, passed to user programs in auxv
+    pub phdr: Option<u64>,
+    pub ph_size: u64,
+    pub ph_num: u64,
+    symbols: Vec<(String, u64)>,
+}
+
+fn read_bytes(data: &[u8], offset: u64, size: u64) -> Result<&[u8], String> {
+    offset.checked_add(size)
+        .and_then(|end| data.get(offset as usize..end as usize))
+        .ok_or_else(|| format!("truncated at offset 0x{:x}", offset))
+}
+
+fn read_u16(data: &[u8], offset: u64) -> Result<u64, String> {
+    read_bytes(data, offset, 2).map(|b| u16::from_le_bytes([b[0], b[1]]) as u64)
+}
+
+fn read_u32(data: &[u8], offset: u64) -> Result<u64, String> {
+    read_bytes(data, offset, 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as u64)
+}
+
+fn read_u64(data: &[u8], offset: u64) -> Result<u64, String> {
+    read_bytes(data, offset, 8).map(|b| u64::from_le_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]]))
+}
+
+// Reads an address-sized field, whose offset differs between ELF32 and ELF64.
+fn read_addr(data: &[u8], is64: bool, offset32: u64, offset64: u64) -> Result<u64, String> {
+    if is64 { read_u64(data, offset64) } else { read_u32(data, offset32) }
+}
+
+fn read_string(data: &[u8], offset: u64) -> Result<String, String> {
+    let bytes = data.get(offset as usize..).ok_or_else(|| format!("truncated at offset 0x{:x}", offset))?;
+    let length = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    Ok(String::from_utf8_lossy(&bytes[..length]).into_owned())
+}
+
+impl Elf {
+    pub fn is_elf(data: &[u8]) -> bool {
+        data.starts_with(b"\x7fELF")
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Elf, String> {
+        if !Elf::is_elf(data) {
+            return Err("not an ELF file".to_string())
+        }
+        let is64 = match data.get(4) {
+            Some(&ELFCLASS32) => false,
+            Some(&ELFCLASS64) => true,
+            _ => return Err("unsupported ELF class".to_string()),
+        };
+        if data.get(5) != Some(&ELFDATA2LSB) {
+            return Err("not a little-endian ELF file".to_string())
+        }
+
+        let entry = read_addr(data, is64, 0x18, 0x18)?;
+        let ph_offset = read_addr(data, is64, 0x1c, 0x20)?;
+        let sh_offset = read_addr(data, is64, 0x20, 0x28)?;
+        let (ph_size, ph_num) = if is64 { (read_u16(data, 0x36)?, read_u16(data, 0x38)?) } else { (read_u16(data, 0x2a)?, read_u16(data, 0x2c)?) };
+        let (sh_size, sh_num) = if is64 { (read_u16(data, 0x3a)?, read_u16(data, 0x3c)?) } else { (read_u16(data, 0x2e)?, read_u16(data, 0x30)?) };
+
+        let mut segments = Vec::new();
+        let mut phdr = None;
+        for i in 0..ph_num {
+            let ph = ph_offset + i * ph_size;
+            let kind = read_u32(data, ph)?;
+            let offset = read_addr(data, is64, ph + 0x04, ph + 0x08)?;
+            let vaddr = read_addr(data, is64, ph + 0x08, ph + 0x10)?;
+            if kind == PT_PHDR as u64 {
+                phdr = Some(vaddr);
+            }
+            if kind != PT_LOAD as u64 {
+                continue
+            }
+
+            let addr = read_addr(data, is64, ph + 0x0c, ph + 0x18)?;
+            let file_size = read_addr(data, is64, ph + 0x10, ph + 0x20)?;
+            let mem_size = read_addr(data, is64, ph + 0x14, ph + 0x28)?;
+            // Without PT_PHDR, the program headers are found in the segment which loads them.
+            if phdr.is_none() && offset <= ph_offset && ph_offset + ph_num * ph_size <= offset + file_size {
+                phdr = Some(vaddr + ph_offset - offset);
+            }
+            segments.push(Segment { addr, vaddr, data: read_bytes(data, offset, file_size)?.to_vec(), mem_size });
+        }
+
+        let mut symbols = Vec::new();
+        for i in 0..sh_num {
+            let sh = sh_offset + i * sh_size;
+            if read_u32(data, sh + 4)? != SHT_SYMTAB as u64 {
+                continue
+            }
+
+            let offset = read_addr(data, is64, sh + 0x10, sh + 0x18)?;
+            let size = read_addr(data, is64, sh + 0x14, sh + 0x20)?;
+            let link = if is64 { read_u32(data, sh + 0x28)? } else { read_u32(data, sh + 0x18)? };
+            let entry_size = if is64 { 24 } else { 16 };
+
+            // The linked section holds the symbol names.
+            let strtab = sh_offset + link * sh_size;
+            let strtab_offset = read_addr(data, is64, strtab + 0x10, strtab + 0x18)?;
+
+            for j in 0..size / entry_size {
+                let sym = offset + j * entry_size;
+                let name = read_string(data, strtab_offset + read_u32(data, sym)?)?;
+                let value = read_addr(data, is64, sym + 4, sym + 8)?;
+                if !name.is_empty() {
+                    symbols.push((name, value));
+                }
+            }
+        }
+
+        Ok(Elf { entry, segments, phdr, ph_size, ph_num, symbols })
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<u64> {
+        self.symbols.iter().find(|(n, _)| n == name).map(|(_, value)| *value)
+    }
+}
+
+#[test]
+fn test_elf() {
+    // ELF32 with a 4-byte segment at 0x8000_0000 and symbols "" and "tohost" at 0x8000_1000.
+    let mut data = vec![0u8; 0x100];
+    data[0..6].copy_from_slice(&[0x7f, b'E', b'L', b'F', ELFCLASS32, ELFDATA2LSB]);
+    let mut put = |offset: usize, bytes: &[u8]| data[offset..offset + bytes.len()].copy_from_slice(bytes);
+    put(0x18, &0x8000_0000u32.to_le_bytes());
+    put(0x1c, &0x34u32.to_le_bytes());
+    put(0x20, &0x60u32.to_le_bytes());
+    put(0x2a, &[32, 0, 1, 0, 40, 0, 2, 0]);
+
+    // Program header at 0x34
+    put(0x34, &PT_LOAD.to_le_bytes());
+    put(0x38, &0xe0u32.to_le_bytes());
+    put(0x3c, &0x8000_0000u32.to_le_bytes());
+    put(0x40, &0x8000_0000u32.to_le_bytes());
+    put(0x44, &4u32.to_le_bytes());
+    put(0x48, &8u32.to_le_bytes());
+
+    // Section headers at 0x60: symtab at 0xb0 linked to strtab (section 1) at 0xd0
+    put(0x64, &SHT_SYMTAB.to_le_bytes());
+    put(0x70, &0xb0u32.to_le_bytes());
+    put(0x74, &32u32.to_le_bytes());
+    put(0x78, &1u32.to_le_bytes());
+    put(0x88 + 0x10, &0xd0u32.to_le_bytes());
+
+    put(0xc0, &1u32.to_le_bytes());
+    put(0xc4, &0x8000_1000u32.to_le_bytes());
+    put(0xd0, b"\0tohost\0");
+    put(0xe0, &0x0000_0013u32.to_le_bytes());
+
+    let elf = Elf::parse(&data).unwrap();
+    assert_eq!(elf.entry, 0x8000_0000);
+    assert_eq!(elf.segments.len(), 1);
+    assert_eq!((elf.segments[0].addr, elf.segments[0].mem_size), (0x8000_0000, 8));
+    assert_eq!(elf.segments[0].data, vec![0x13, 0, 0, 0]);
+    assert_eq!((elf.segments[0].vaddr, elf.phdr), (0x8000_0000, None));
+    assert_eq!(elf.symbol("tohost"), Some(0x8000_1000));
+    assert_eq!(elf.symbol("begin_signature"), None);
+
+    assert!(Elf::parse(&data[..0x40]).is_err());
+}
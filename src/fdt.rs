@@ -0,0 +1,198 @@
+// Writer of flattened device trees (DTB), version 17. Nodes are written in order: begin a node, add
+// its properties, then its child nodes, and end it.
+
+use std::collections::HashMap;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+
+// Phandles of the nodes which the nodes of other devices refer to, e.g. interrupt controllers
+pub const PHANDLE_PLIC: u32 = 1;
+pub const PHANDLE_TEST: u32 = 2;
+
+pub fn cpu_intc_phandle(hart: u32) -> u32 {
+    0x100 + hart
+}
+
+const HEADER_SIZE: usize = 40;
+// The memory reservation block is empty, which is its terminating entry.
+const MEM_RSVMAP_SIZE: usize = 16;
+
+pub struct Fdt {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+    // Offsets of property names in strings
+    string_offsets: HashMap<String, u32>,
+    depth: usize,
+}
+
+impl Fdt {
+    pub fn new() -> Fdt {
+        Fdt { structure: Vec::new(), strings: Vec::new(), string_offsets: HashMap::new(), depth: 0 }
+    }
+
+    fn push_u32(&mut self, value: u32) {
+        self.structure.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn align(&mut self) {
+        while !self.structure.len().is_multiple_of(4) {
+            self.structure.push(0);
+        }
+    }
+
+    // The root node is named "".
+    pub fn begin_node(&mut self, name: &str) {
+        self.push_u32(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.align();
+        self.depth += 1;
+    }
+
+    pub fn end_node(&mut self) {
+        assert!(self.depth > 0, "no node to end");
+        self.push_u32(FDT_END_NODE);
+        self.depth -= 1;
+    }
+
+    pub fn property(&mut self, name: &str, value: &[u8]) {
+        let offset = match self.string_offsets.get(name) {
+            Some(offset) => *offset,
+            None => {
+                let offset = self.strings.len() as u32;
+                self.strings.extend_from_slice(name.as_bytes());
+                self.strings.push(0);
+                self.string_offsets.insert(name.to_string(), offset);
+                offset
+            },
+        };
+        self.push_u32(FDT_PROP);
+        self.push_u32(value.len() as u32);
+        self.push_u32(offset);
+        self.structure.extend_from_slice(value);
+        self.align();
+    }
+
+    // Property without a value, e.g. "interrupt-controller"
+    pub fn property_null(&mut self, name: &str) {
+        self.property(name, &[]);
+    }
+
+    pub fn property_u32(&mut self, name: &str, value: u32) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    pub fn property_u64(&mut self, name: &str, value: u64) {
+        self.property(name, &value.to_be_bytes());
+    }
+
+    pub fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        let value: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes().to_vec()).collect();
+        self.property(name, &value);
+    }
+
+    pub fn property_string(&mut self, name: &str, value: &str) {
+        self.property_strings(name, &[value]);
+    }
+
+    pub fn property_strings(&mut self, name: &str, values: &[&str]) {
+        let value: Vec<u8> = values.iter().flat_map(|s| s.bytes().chain(Some(0))).collect();
+        self.property(name, &value);
+    }
+
+    // Returns the blob. All nodes must have been ended.
+    pub fn finish(mut self) -> Vec<u8> {
+        assert_eq!(self.depth, 0, "nodes are not ended");
+        self.push_u32(FDT_END);
+
+        let off_dt_struct = HEADER_SIZE + MEM_RSVMAP_SIZE;
+        let off_dt_strings = off_dt_struct + self.structure.len();
+        let total_size = off_dt_strings + self.strings.len();
+
+        let header = [
+            FDT_MAGIC,
+            total_size as u32,
+            off_dt_struct as u32,
+            off_dt_strings as u32,
+            HEADER_SIZE as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            0, // boot_cpuid_phys
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+        let mut blob: Vec<u8> = header.iter().flat_map(|field| field.to_be_bytes().to_vec()).collect();
+        blob.extend_from_slice(&[0; MEM_RSVMAP_SIZE]);
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+}
+
+// Returns the value of a property of the node at path, e.g. "/chosen", in a blob written by Fdt.
+#[cfg(test)]
+pub fn find_property(blob: &[u8], path: &str, name: &str) -> Option<Vec<u8>> {
+    let read_u32 = |offset: usize| u32::from_be_bytes([blob[offset], blob[offset + 1], blob[offset + 2], blob[offset + 3]]);
+    let read_string = |offset: usize| blob[offset..].iter().take_while(|b| **b != 0).map(|b| *b as char).collect::<String>();
+    let align = |offset: usize| (offset + 3) & !3;
+
+    let (off_dt_struct, off_dt_strings) = (read_u32(8) as usize, read_u32(12) as usize);
+    let mut nodes: Vec<String> = Vec::new();
+    let mut offset = off_dt_struct;
+    loop {
+        match read_u32(offset) {
+            FDT_BEGIN_NODE => {
+                let node = read_string(offset + 4);
+                offset = align(offset + 4 + node.len() + 1);
+                nodes.push(node);
+            },
+            FDT_END_NODE => {
+                nodes.pop();
+                offset += 4;
+            },
+            FDT_PROP => {
+                let (len, nameoff) = (read_u32(offset + 4) as usize, read_u32(offset + 8) as usize);
+                let node_path = if nodes.len() <= 1 { "/".to_string() } else { nodes[1..].iter().map(|n| format!("/{}", n)).collect() };
+                if node_path == path && read_string(off_dt_strings + nameoff) == name {
+                    return Some(blob[offset + 12..offset + 12 + len].to_vec())
+                }
+                offset = align(offset + 12 + len);
+            },
+            _ => return None,
+        }
+    }
+}
+
+#[test]
+fn test_fdt() {
+    let mut fdt = Fdt::new();
+    fdt.begin_node("");
+    fdt.property_u32("#address-cells", 2);
+    fdt.begin_node("chosen");
+    fdt.property_string("bootargs", "console=ttyS0");
+    fdt.property_null("ranges");
+    fdt.end_node();
+    fdt.begin_node("memory@80000000");
+    fdt.property_cells("reg", &[0, 0x8000_0000, 0, 0x0800_0000]);
+    fdt.property_strings("ranges", &["a", "bc"]);
+    fdt.end_node();
+    fdt.end_node();
+    let blob = fdt.finish();
+
+    assert_eq!(&blob[0..4], &[0xd0, 0x0d, 0xfe, 0xed]);
+    assert_eq!(&blob[4..8], &(blob.len() as u32).to_be_bytes());
+    assert!(blob.ends_with(b"#address-cells\0bootargs\0ranges\0reg\0"));
+    assert_eq!(find_property(&blob, "/", "#address-cells"), Some(vec![0, 0, 0, 2]));
+    assert_eq!(find_property(&blob, "/chosen", "bootargs"), Some(b"console=ttyS0\0".to_vec()));
+    assert_eq!(find_property(&blob, "/chosen", "ranges"), Some(vec![]));
+    assert_eq!(find_property(&blob, "/memory@80000000", "ranges"), Some(b"a\0bc\0".to_vec()));
+    assert_eq!(find_property(&blob, "/memory@80000000", "reg").map(|v| v.len()), Some(16));
+    assert_eq!(find_property(&blob, "/chosen", "reg"), None);
+}
@@ -0,0 +1,300 @@
+// Linear framebuffer, described as a simple-framebuffer in the device tree. A page of registers is
+// followed by the pixels, row by row from the top left.
+//
+// Its contents are dumped to a PNG or PPM file when the guest writes the dump register, every n
+// frames of the refresh rate and at the end of the run. A "%d" in the path of the dumps is replaced by
+// the frame number, so that they do not overwrite each other.
+
+use bus::*;
+use fdt::*;
+use snapshot::*;
+
+use std::fs;
+
+// Registers, which are read-only except DUMP. FORMAT is the PixelFormat as a number.
+const WIDTH: u64 = 0x00;
+const HEIGHT: u64 = 0x04;
+const STRIDE: u64 = 0x08;
+const FORMAT: u64 = 0x0c;
+const DUMP: u64 = 0x10;
+const FRAME: u64 = 0x14;
+
+const PIXELS: u64 = 0x1000;
+
+// Where the framebuffer is mapped, which is free in the memory map of the virt machine
+pub const FRAMEBUFFER_BASE: u64 = 0x3000_0000;
+
+pub const REFRESH_RATE: u64 = 60;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PixelFormat {
+    R5G6B5 = 0,
+    R8G8B8 = 1,
+    X8R8G8B8 = 2,
+}
+
+impl PixelFormat {
+    // Parses the format name of simple-framebuffer, e.g. "x8r8g8b8".
+    pub fn parse(name: &str) -> Result<PixelFormat, String> {
+        match name {
+            "r5g6b5" => Ok(PixelFormat::R5G6B5),
+            "r8g8b8" => Ok(PixelFormat::R8G8B8),
+            "x8r8g8b8" => Ok(PixelFormat::X8R8G8B8),
+            _ => Err(format!("unknown pixel format {}", name)),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            PixelFormat::R5G6B5 => "r5g6b5",
+            PixelFormat::R8G8B8 => "r8g8b8",
+            PixelFormat::X8R8G8B8 => "x8r8g8b8",
+        }
+    }
+
+    fn bytes_per_pixel(&self) -> usize {
+        match self {
+            PixelFormat::R5G6B5 => 2,
+            PixelFormat::R8G8B8 => 3,
+            PixelFormat::X8R8G8B8 => 4,
+        }
+    }
+
+    // Returns the RGB components of a pixel, whose bytes are little-endian.
+    fn to_rgb(self, pixel: &[u8]) -> [u8; 3] {
+        match self {
+            PixelFormat::R5G6B5 => {
+                let value = u16::from_le_bytes([pixel[0], pixel[1]]);
+                let (r, g, b) = ((value >> 11) as u8, (value >> 5) as u8 & 0x3f, value as u8 & 0x1f);
+                [r << 3 | r >> 2, g << 2 | g >> 4, b << 3 | b >> 2]
+            },
+            PixelFormat::R8G8B8 | PixelFormat::X8R8G8B8 => [pixel[2], pixel[1], pixel[0]],
+        }
+    }
+}
+
+pub struct Framebuffer {
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+    pixels: Vec<u8>,
+    // Length of a frame in ticks of mtime
+    frame_ticks: u64,
+    frame: u64,
+    dump_path: Option<String>,
+    // Dump every this many frames, if not 0
+    dump_interval: u64,
+}
+
+impl Framebuffer {
+    pub fn new(width: u32, height: u32, format: PixelFormat, timebase_frequency: u64) -> Framebuffer {
+        Framebuffer {
+            width,
+            height,
+            format,
+            pixels: vec![0; width as usize * height as usize * format.bytes_per_pixel()],
+            frame_ticks: (timebase_frequency / REFRESH_RATE).max(1),
+            frame: 0,
+            dump_path: None,
+            dump_interval: 0,
+        }
+    }
+
+    // Dumps to path, whose extension chooses PNG or PPM, on request and at the end of the run, and
+    // every interval frames if it is not 0.
+    pub fn dump_to(&mut self, path: &str, interval: u64) {
+        self.dump_path = Some(path.to_string());
+        self.dump_interval = interval;
+    }
+
+    fn stride(&self) -> u32 {
+        self.width * self.format.bytes_per_pixel() as u32
+    }
+
+    // Returns the pixels as 8-bit RGB.
+    fn rgb(&self) -> Vec<u8> {
+        self.pixels.chunks(self.format.bytes_per_pixel()).flat_map(|pixel| self.format.to_rgb(pixel).to_vec()).collect()
+    }
+
+    fn dump(&self) {
+        let path = match &self.dump_path {
+            Some(path) => path.replace("%d", &self.frame.to_string()),
+            None => return,
+        };
+        let image = if path.ends_with(".png") {
+            encode_png(self.width, self.height, &self.rgb())
+        } else {
+            encode_ppm(self.width, self.height, &self.rgb())
+        };
+        if let Err(e) = fs::write(&path, image) {
+            eprintln!("Failed to dump the framebuffer to {}: {}", path, e);
+        }
+    }
+}
+
+impl Device for Framebuffer {
+    fn size(&self) -> u64 {
+        PIXELS + self.pixels.len() as u64
+    }
+
+    fn read(&mut self, offset: u64, size: u32) -> u64 {
+        match offset {
+            WIDTH => self.width as u64,
+            HEIGHT => self.height as u64,
+            STRIDE => self.stride() as u64,
+            FORMAT => self.format as u64,
+            FRAME => self.frame,
+            _ if offset >= PIXELS => {
+                let start = (offset - PIXELS) as usize;
+                self.pixels[start..start + size as usize].iter().rev().fold(0, |value, b| value << 8 | *b as u64)
+            },
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u64, size: u32, value: u64) {
+        match offset {
+            DUMP => self.dump(),
+            _ if offset >= PIXELS => {
+                let start = (offset - PIXELS) as usize;
+                self.pixels[start..start + size as usize].copy_from_slice(&value.to_le_bytes()[..size as usize]);
+            },
+            _ => (),
+        }
+    }
+
+    fn update(&mut self, time: u64) -> bool {
+        let frame = time / self.frame_ticks;
+        if frame != self.frame {
+            // Frames may be skipped while harts wait.
+            let dump = self.dump_interval != 0 && frame / self.dump_interval != self.frame / self.dump_interval;
+            self.frame = frame;
+            if dump {
+                self.dump();
+            }
+        }
+        false
+    }
+
+    fn finish(&mut self) {
+        self.dump();
+    }
+
+    fn reset(&mut self) {
+        self.pixels.iter_mut().for_each(|b| *b = 0);
+    }
+
+    fn save(&self) -> Option<Vec<u8>> {
+        Some(encode_state(&self.frame, &self.pixels))
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), String> {
+        let (frame, pixels) = decode_state(state)?;
+        if pixels.len() != self.pixels.len() {
+            return Err(format!("framebuffer has {} bytes (machine has {})", pixels.len(), self.pixels.len()))
+        }
+        self.frame = frame;
+        self.pixels.copy_from_slice(pixels);
+        Ok(())
+    }
+
+    fn device_tree_node(&self, fdt: &mut Fdt, base: u64) {
+        let pixels = base + PIXELS;
+        fdt.begin_node(&format!("framebuffer@{:x}", pixels));
+        fdt.property_string("compatible", "simple-framebuffer");
+        fdt.property_cells("reg", &[(pixels >> 32) as u32, pixels as u32, 0, self.pixels.len() as u32]);
+        fdt.property_u32("width", self.width);
+        fdt.property_u32("height", self.height);
+        fdt.property_u32("stride", self.stride());
+        fdt.property_string("format", self.format.name());
+        fdt.end_node();
+    }
+}
+
+pub fn encode_ppm(width: u32, height: u32, rgb: &[u8]) -> Vec<u8> {
+    let mut image = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    image.extend_from_slice(rgb);
+    image
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, b| (0..8).fold(crc ^ *b as u32, |crc, _| if crc & 1 != 0 { crc >> 1 ^ 0xedb8_8320 } else { crc >> 1 }))
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), d| {
+        let a = (a + *d as u32) % 65521;
+        (a, (b + a) % 65521)
+    });
+    b << 16 | a
+}
+
+// Encodes an 8-bit RGB image as PNG, with the data in uncompressed deflate blocks.
+pub fn encode_png(width: u32, height: u32, rgb: &[u8]) -> Vec<u8> {
+    let chunk = |image: &mut Vec<u8>, chunk_type: &[u8], data: &[u8]| {
+        image.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        let start = image.len();
+        image.extend_from_slice(chunk_type);
+        image.extend_from_slice(data);
+        let crc = crc32(&image[start..]);
+        image.extend_from_slice(&crc.to_be_bytes());
+    };
+
+    // Each row starts with filter type 0 (none).
+    let row_size = 3 * width as usize;
+    let raw: Vec<u8> = rgb.chunks(row_size.max(1)).flat_map(|row| Some(0).into_iter().chain(row.iter().cloned())).collect();
+    let mut zlib = vec![0x78, 0x01];
+    let blocks: Vec<&[u8]> = raw.chunks(0xffff).collect();
+    for (i, block) in blocks.iter().enumerate() {
+        zlib.push((i + 1 == blocks.len()) as u8);
+        zlib.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        zlib.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // Bit depth 8, color type 2 (RGB), deflate, no filtering and no interlace
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let mut image = b"\x89PNG\r\n\x1a\n".to_vec();
+    chunk(&mut image, b"IHDR", &header);
+    chunk(&mut image, b"IDAT", &zlib);
+    chunk(&mut image, b"IEND", &[]);
+    image
+}
+
+#[test]
+fn test_framebuffer() {
+    let mut framebuffer = Framebuffer::new(2, 2, PixelFormat::R5G6B5, 600);
+    assert_eq!(framebuffer.size(), PIXELS + 8);
+    assert_eq!(framebuffer.read(STRIDE, 4), 4);
+
+    // Red, green, blue and white
+    framebuffer.write(PIXELS, 4, 0x07e0_f800);
+    framebuffer.write(PIXELS + 4, 2, 0x001f);
+    framebuffer.write(PIXELS + 6, 2, 0xffff);
+    assert_eq!(framebuffer.read(PIXELS + 2, 2), 0x07e0);
+    assert_eq!(framebuffer.rgb(), vec![255, 0, 0, 0, 255, 0, 0, 0, 255, 255, 255, 255]);
+
+    // Frames are 10 ticks at 60 Hz, and every other one is dumped.
+    let path = std::env::temp_dir().join(format!("rafi-emu-fb-{}-%d.ppm", std::process::id()));
+    let path = path.to_str().unwrap();
+    framebuffer.dump_to(path, 2);
+    framebuffer.update(15);
+    assert_eq!(framebuffer.read(FRAME, 4), 1);
+    assert!(fs::metadata(path.replace("%d", "1")).is_err());
+    framebuffer.update(20);
+    let image = fs::read(path.replace("%d", "2")).unwrap();
+    fs::remove_file(path.replace("%d", "2")).unwrap();
+    assert_eq!(image, encode_ppm(2, 2, &framebuffer.rgb()));
+    assert!(image.starts_with(b"P6\n2 2\n255\n"));
+
+    let png = encode_png(2, 2, &framebuffer.rgb());
+    assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+    assert_eq!(crc32(b"IEND"), 0xae42_6082);
+    assert_eq!(&png[png.len() - 4..], &[0xae, 0x42, 0x60, 0x82]);
+    assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+}
@@ -0,0 +1,406 @@
+// GDB remote serial protocol stub.
+//
+// Supports register and memory access, breakpoints, watchpoints, step and continue, and the reverse
+// execution commands (bs/bc) on top of History.
+
+use core::*;
+use history::*;
+use trigger::*;
+use xlen::*;
+
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+const NUM_GDB_INT_REG: usize = 32;
+const GDB_REG_PC: usize = 32;
+const GDB_REG_CSR_BASE: usize = 65;
+const NUM_CSR: usize = 0x1000;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+// Number of ops executed between checks for an interrupt request from GDB.
+const INTERRUPT_CHECK_INTERVAL: u32 = 4096;
+
+struct Connection {
+    stream: TcpStream,
+}
+
+impl Connection {
+    fn read_byte(&mut self) -> io::Result<u8> {
+        let mut buf = [0; 1];
+        self.stream.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    // Returns None when GDB sent an interrupt request (Ctrl-C).
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.read_byte()? {
+                b'$' => break,
+                0x03 => return Ok(None),
+                _ => (),
+            }
+        }
+
+        let mut data = Vec::new();
+        loop {
+            match self.read_byte()? {
+                b'#' => break,
+                b => data.push(b),
+            }
+        }
+        let checksum = [self.read_byte()?, self.read_byte()?];
+
+        let expected = u8::from_str_radix(&String::from_utf8_lossy(&checksum), 16).unwrap_or(0);
+        let actual = data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        if expected != actual {
+            self.stream.write_all(b"-")?;
+            return self.read_packet()
+        }
+
+        self.stream.write_all(b"+")?;
+        Ok(Some(String::from_utf8_lossy(&data).to_string()))
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        self.stream.write_all(format!("${}#{:02x}", data, checksum).as_bytes())?;
+
+        // Wait for the acknowledgement.
+        loop {
+            match self.read_byte()? {
+                b'+' => return Ok(()),
+                b'-' => self.stream.write_all(format!("${}#{:02x}", data, checksum).as_bytes())?,
+                _ => (),
+            }
+        }
+    }
+
+    // Checks for an interrupt request without blocking.
+    fn interrupted(&mut self) -> bool {
+        let mut buf = [0; 1];
+        if self.stream.set_nonblocking(true).is_err() {
+            return false
+        }
+        let result = self.stream.peek(&mut buf);
+        let _ = self.stream.set_nonblocking(false);
+
+        match result {
+            Ok(1) if buf[0] == 0x03 => {
+                let _ = self.read_byte();
+                true
+            },
+            _ => false,
+        }
+    }
+}
+
+// Registers are XLEN bits in target byte order.
+fn encode_word<X: Xlen>(value: X::Uint) -> String {
+    let size = X::XLEN as usize / 8;
+    value.to_u64().to_le_bytes()[..size].iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_word<X: Xlen>(hex: &str) -> Option<X::Uint> {
+    let bytes = decode_bytes(hex)?;
+    if bytes.len() != X::XLEN as usize / 8 {
+        return None
+    }
+    let value = bytes.iter().rev().fold(0u64, |value, b| value << 8 | *b as u64);
+    Some(X::Uint::from_u64(value))
+}
+
+fn decode_bytes(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+// Parses "addr,len" with hex numbers.
+fn parse_range(args: &str) -> Option<(u64, u32)> {
+    let mut iter = args.splitn(2, ',');
+    let addr = u64::from_str_radix(iter.next()?, 16).ok()?;
+    let len = u32::from_str_radix(iter.next()?, 16).ok()?;
+    Some((addr, len))
+}
+
+enum Resume {
+    Continue,
+    Step,
+    ReverseContinue,
+    ReverseStep,
+}
+
+struct GdbServer<X: Xlen> {
+    connection: Connection,
+    history: History<X>,
+    breakpoints: Vec<X::Uint>,
+    debugger: Debugger,
+    // Watchpoints as (type, addr, len, trigger id)
+    watchpoints: Vec<(u32, u64, u64, usize)>,
+}
+
+impl<X: Xlen> GdbServer<X> {
+    fn read_register(&self, core: &Core<X>, index: usize) -> Option<X::Uint> {
+        match index {
+            _ if index < NUM_GDB_INT_REG => Some(core.int_reg.read(index)),
+            GDB_REG_PC => Some(core.pc),
+            _ if (GDB_REG_CSR_BASE..GDB_REG_CSR_BASE + NUM_CSR).contains(&index) => Some(core.csr.read(index - GDB_REG_CSR_BASE)),
+            _ => None,
+        }
+    }
+
+    fn write_register(&mut self, core: &mut Core<X>, index: usize, value: X::Uint) -> bool {
+        match index {
+            _ if index < NUM_GDB_INT_REG => core.int_reg.write(index, value),
+            GDB_REG_PC => {
+                core.pc = value;
+                core.next_pc = value;
+            },
+            _ if (GDB_REG_CSR_BASE..GDB_REG_CSR_BASE + NUM_CSR).contains(&index) => {
+                core.write_csr(index - GDB_REG_CSR_BASE, value);
+                core.csr.take_undo_log();
+            },
+            _ => return false,
+        }
+        self.history.discard_future(core);
+        true
+    }
+
+    fn read_registers(&self, core: &Core<X>) -> String {
+        (0..=GDB_REG_PC).map(|i| encode_word::<X>(self.read_register(core, i).unwrap())).collect()
+    }
+
+    fn write_registers(&mut self, core: &mut Core<X>, hex: &str) -> &'static str {
+        let width = X::XLEN as usize / 4;
+        for i in 0..=GDB_REG_PC {
+            match hex.get(i * width..(i + 1) * width).and_then(decode_word::<X>) {
+                Some(value) => { self.write_register(core, i, value); },
+                None => return "E01",
+            }
+        }
+        "OK"
+    }
+
+    fn read_memory(&self, core: &Core<X>, args: &str) -> String {
+        match parse_range(args) {
+            Some((addr, len)) if core.bus.is_mapped(addr, len) => {
+                (0..len as u64).map(|i| format!("{:02x}", core.bus.read_u8(addr.wrapping_add(i)))).collect()
+            },
+            _ => "E14".to_string(),
+        }
+    }
+
+    fn write_memory(&mut self, core: &mut Core<X>, args: &str) -> &'static str {
+        let mut iter = args.splitn(2, ':');
+        let range = iter.next().and_then(parse_range);
+        let data = iter.next().and_then(decode_bytes);
+
+        match (range, data) {
+            (Some((addr, len)), Some(data)) if data.len() == len as usize && core.bus.is_mapped(addr, len) => {
+                for (i, b) in data.iter().enumerate() {
+                    core.bus.write_u8(addr.wrapping_add(i as u64), *b);
+                }
+                self.history.discard_future(core);
+                "OK"
+            },
+            _ => "E14",
+        }
+    }
+
+    fn update_breakpoint(&mut self, args: &str, insert: bool) -> &'static str {
+        let mut iter = args.split(',');
+        let kind = iter.next();
+        let addr = iter.next().and_then(|a| u64::from_str_radix(a, 16).ok());
+        let len = iter.next().and_then(|l| u64::from_str_radix(l, 16).ok());
+
+        match (kind, addr) {
+            (Some("0"), Some(addr)) | (Some("1"), Some(addr)) => {
+                let addr = X::Uint::from_u64(addr);
+                self.breakpoints.retain(|b| *b != addr);
+                if insert {
+                    self.breakpoints.push(addr);
+                }
+                "OK"
+            },
+            (Some(kind @ "2"), Some(addr)) | (Some(kind @ "3"), Some(addr)) | (Some(kind @ "4"), Some(addr)) => {
+                let kind = kind.parse().unwrap();
+                let len = len.unwrap_or(1).max(1);
+                if let Some(i) = self.watchpoints.iter().position(|w| w.0 == kind && w.1 == addr && w.2 == len) {
+                    self.debugger.remove(self.watchpoints.remove(i).3);
+                }
+                if insert {
+                    let end = addr.wrapping_add(len - 1);
+                    let trigger_kind = match kind {
+                        2 => TriggerKind::Write(addr, end),
+                        3 => TriggerKind::Read(addr, end),
+                        _ => TriggerKind::Access(addr, end),
+                    };
+                    let id = self.debugger.add(Trigger::new(trigger_kind));
+                    self.watchpoints.push((kind, addr, len, id));
+                }
+                "OK"
+            },
+            _ => "",
+        }
+    }
+
+    // Returns the stop reply for a watchpoint.
+    fn watchpoint_reply(&self, id: usize) -> String {
+        let (name, addr) = match self.debugger.get(id).map(|trigger| trigger.kind) {
+            Some(TriggerKind::Write(addr, _)) => ("watch", addr),
+            Some(TriggerKind::Read(addr, _)) => ("rwatch", addr),
+            Some(TriggerKind::Access(addr, _)) => ("awatch", addr),
+            _ => return format!("S{:02x}", SIGTRAP),
+        };
+        format!("T{:02x}{}:{:x};", SIGTRAP, name, addr)
+    }
+
+    // Handles "monitor" commands. Returns the console output.
+    fn monitor(&mut self, core: &mut Core<X>, command: &str) -> String {
+        let words: Vec<&str> = command.split_whitespace().collect();
+        match words.as_slice() {
+            ["who-wrote", addr] => {
+                let addr = u64::from_str_radix(addr.trim_start_matches("0x"), 16);
+                match addr {
+                    Ok(addr) => match self.history.last_writer(core, addr) {
+                        Some((cycle, pc)) => format!("0x{:08x} was last written by pc 0x{:08x} at cycle {}\n", addr, pc, cycle),
+                        None => format!("0x{:08x} is not written in the history\n", addr),
+                    },
+                    Err(_) => "Invalid address\n".to_string(),
+                }
+            },
+            _ => "Commands:\n  who-wrote <addr>  show the op which last wrote the byte at addr\n".to_string(),
+        }
+    }
+
+    // Returns the stop reply.
+    fn resume(&mut self, core: &mut Core<X>, resume: Resume) -> io::Result<String> {
+        match resume {
+            Resume::Step => {
+                self.history.step(core);
+            },
+            Resume::Continue => {
+                let mut count = 0;
+                loop {
+                    if core.read_host_io() != 0 {
+                        return Ok(format!("W{:02x}", (core.read_host_io() >> 1) & 0xff))
+                    }
+
+                    // Watchpoints are reported after the access, as GDB expects.
+                    let debugger = &mut self.debugger;
+                    let watchpoint = self.history.step_checked(core, |core, op| debugger.check(core, op));
+
+                    if let Some(id) = watchpoint {
+                        return Ok(self.watchpoint_reply(id))
+                    }
+                    if self.breakpoints.contains(&core.pc) {
+                        break
+                    }
+
+                    count += 1;
+                    if count % INTERRUPT_CHECK_INTERVAL == 0 && self.connection.interrupted() {
+                        return Ok(format!("S{:02x}", SIGINT))
+                    }
+                }
+            },
+            Resume::ReverseStep => {
+                if !self.history.reverse_step(core) {
+                    return Ok(format!("T{:02x}replaylog:begin;", SIGTRAP))
+                }
+            },
+            Resume::ReverseContinue => {
+                if !self.history.reverse_continue(core, &self.breakpoints) {
+                    return Ok(format!("T{:02x}replaylog:begin;", SIGTRAP))
+                }
+            },
+        }
+
+        Ok(format!("S{:02x}", SIGTRAP))
+    }
+
+    // Returns None when the session is finished.
+    fn handle_packet(&mut self, core: &mut Core<X>, packet: &str) -> io::Result<Option<String>> {
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, |c| c.len_utf8()));
+
+        let reply = match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => self.read_registers(core),
+            "G" => self.write_registers(core, args).to_string(),
+            "p" => {
+                let value = usize::from_str_radix(args, 16).ok().and_then(|i| self.read_register(core, i));
+                value.map_or("E01".to_string(), encode_word::<X>)
+            },
+            "P" => {
+                let mut iter = args.splitn(2, '=');
+                let index = iter.next().and_then(|i| usize::from_str_radix(i, 16).ok());
+                let value = iter.next().and_then(decode_word::<X>);
+                match (index, value) {
+                    (Some(index), Some(value)) if self.write_register(core, index, value) => "OK".to_string(),
+                    _ => "E01".to_string(),
+                }
+            },
+            "m" => self.read_memory(core, args),
+            "M" => self.write_memory(core, args).to_string(),
+            "Z" => self.update_breakpoint(args, true).to_string(),
+            "z" => self.update_breakpoint(args, false).to_string(),
+            "c" => self.resume(core, Resume::Continue)?,
+            "s" => self.resume(core, Resume::Step)?,
+            "b" if args == "c" => self.resume(core, Resume::ReverseContinue)?,
+            "b" if args == "s" => self.resume(core, Resume::ReverseStep)?,
+            "H" => "OK".to_string(),
+            "q" if args.starts_with("Supported") => "PacketSize=4000;ReverseStep+;ReverseContinue+".to_string(),
+            "q" if args == "Attached" => "1".to_string(),
+            "q" if args.starts_with("Rcmd,") => {
+                let command = decode_bytes(&args[5..]).map(|b| String::from_utf8_lossy(&b).to_string());
+                match command {
+                    Some(command) => self.monitor(core, &command).bytes().map(|b| format!("{:02x}", b)).collect(),
+                    None => "E01".to_string(),
+                }
+            },
+            "D" => {
+                self.connection.write_packet("OK")?;
+                return Ok(None)
+            },
+            "k" => return Ok(None),
+            _ => String::new(),
+        };
+
+        Ok(Some(reply))
+    }
+}
+
+// Waits for a connection from GDB on `port` and serves it until GDB detaches.
+pub fn serve<X: Xlen>(core: &mut Core<X>, port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    println!("Waiting for GDB connection on port {}", port);
+
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+
+    let mut server = GdbServer {
+        connection: Connection { stream },
+        history: History::new(core),
+        breakpoints: Vec::new(),
+        debugger: Debugger::new(),
+        watchpoints: Vec::new(),
+    };
+
+    loop {
+        let packet = match server.connection.read_packet() {
+            Ok(Some(packet)) => packet,
+            Ok(None) => continue,
+            // GDB closed the connection.
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        match server.handle_packet(core, &packet)? {
+            Some(reply) => server.connection.write_packet(&reply)?,
+            None => return Ok(()),
+        }
+    }
+}
@@ -0,0 +1,449 @@
+// Test harness which runs riscv-tests and riscv-arch-test binaries in parallel and reports the results.
+//
+// riscv-tests pass when they write 1 to tohost. riscv-arch-test binaries are ELF files which must halt
+// by writing tohost, and whose memory between begin_signature and end_signature must match the
+// reference output next to the binary.
+
+use serde::Serialize;
+
+use std::fs;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::panic;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const RISCV_TESTS_SUITE: &str = "riscv-tests";
+pub const ARCH_TEST_SUITE: &str = "riscv-arch-test";
+
+// Test groups of riscv-tests, e.g. ui of rv32ui-p-add, for the implemented ISA: the unprivileged I, M
+// and A tests and the machine and supervisor mode tests. Others, e.g. uc, uf and ud, are not run.
+const RISCV_TESTS_GROUPS: [&str; 5] = ["ui", "um", "ua", "mi", "si"];
+
+// Extension directories of riscv-arch-test for the implemented ISA, e.g. M of rv32i_m/M/mul-01.
+const ARCH_TEST_EXTENSIONS: [&str; 5] = ["I", "E", "M", "A", "Zifencei"];
+
+// Base ISA selected from the prefix of a test name, e.g. rv64ui-p-add or rv32e_m/I/add-01.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Base {
+    Rv32I,
+    Rv32E,
+    Rv64I,
+}
+
+impl Base {
+    fn from_name(name: &str) -> Option<Base> {
+        if name.starts_with("rv32e") {
+            Some(Base::Rv32E)
+        } else if name.starts_with("rv32") {
+            Some(Base::Rv32I)
+        } else if name.starts_with("rv64") {
+            Some(Base::Rv64I)
+        } else {
+            None
+        }
+    }
+}
+
+pub enum Expectation {
+    HostIo,
+    // Path of the reference signature.
+    Signature(PathBuf),
+}
+
+pub struct TestCase {
+    pub suite: &'static str,
+    pub name: String,
+    pub path: PathBuf,
+    pub base: Base,
+    pub expectation: Expectation,
+}
+
+// State of the machine at the end of a test.
+pub struct TestRun {
+    pub host_io: u64,
+    pub cycle: u64,
+    pub timed_out: bool,
+    // Start address and contents of the signature, if the binary defines one.
+    pub signature: Option<(u64, Vec<u8>)>,
+    // pc followed by x1-x31
+    pub registers: Vec<u64>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Status {
+    Pass,
+    Fail(String),
+    Timeout,
+}
+
+pub struct TestResult {
+    pub suite: &'static str,
+    pub name: String,
+    pub status: Status,
+    pub cycle: u64,
+    pub duration: Duration,
+}
+
+fn list_dir(dir: &Path) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|e| e.ok()).map(|e| e.path()).collect(),
+        Err(_) => Vec::new(),
+    };
+    paths.sort();
+    paths
+}
+
+// Finds the -p (physical memory) and -v (virtual memory) environment tests of RISCV_TESTS_GROUPS,
+// e.g. rv32ui-p-add.bin.
+pub fn find_riscv_tests(dir: &Path) -> Vec<TestCase> {
+    let mut cases = Vec::new();
+
+    for path in list_dir(dir) {
+        if path.extension().is_none_or(|e| e != "bin") {
+            continue
+        }
+        let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+        let env = name.split('-').nth(1);
+        let supported = name.get(4..).and_then(|rest| rest.split('-').next()).is_some_and(|group| RISCV_TESTS_GROUPS.contains(&group));
+        if let (Some(base), Some("p" | "v"), true) = (Base::from_name(&name), env, supported) {
+            cases.push(TestCase { suite: RISCV_TESTS_SUITE, name, path, base, expectation: Expectation::HostIo });
+        }
+    }
+
+    cases
+}
+
+// Finds <isa>/<extension>/<test>.elf files of ARCH_TEST_EXTENSIONS which have <test>.reference_output
+// next to them.
+pub fn find_arch_tests(dir: &Path) -> Vec<TestCase> {
+    let mut cases = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+
+    while let Some(current) = dirs.pop() {
+        for path in list_dir(&current) {
+            if path.is_dir() {
+                dirs.push(path);
+                continue
+            }
+
+            let reference = path.with_extension("reference_output");
+            if path.extension().is_none_or(|e| e != "elf") || !reference.is_file() {
+                continue
+            }
+            let name = path.strip_prefix(dir).unwrap().with_extension("").to_string_lossy().replace('\\', "/");
+            let supported = name.split('/').nth(1).is_some_and(|extension| ARCH_TEST_EXTENSIONS.contains(&extension));
+            if let (Some(base), true) = (Base::from_name(&name), supported) {
+                cases.push(TestCase { suite: ARCH_TEST_SUITE, name, path, base, expectation: Expectation::Signature(reference) });
+            }
+        }
+    }
+
+    cases.sort_by(|a, b| a.name.cmp(&b.name));
+    cases
+}
+
+// Compares a signature starting at addr with a reference holding one hex word per line.
+// The width of each line gives the word size, so both 4-byte and 8-byte granularity are accepted.
+pub fn compare_signature(addr: u64, signature: &[u8], reference: &str) -> Result<(), String> {
+    let mut offset = 0;
+
+    for line in reference.lines().map(|l| l.trim()).filter(|l| !l.is_empty()) {
+        let size = line.len() / 2;
+        let expected = match u64::from_str_radix(line, 16) {
+            Ok(value) if line.len() % 2 == 0 && size <= 8 => value,
+            _ => return Err(format!("invalid reference word '{}'", line)),
+        };
+
+        let bytes = signature.get(offset..offset + size)
+            .ok_or_else(|| format!("signature has {} bytes, which is shorter than the reference", signature.len()))?;
+        let actual = bytes.iter().rev().fold(0, |value, b| (value << 8) | *b as u64);
+        if actual != expected {
+            return Err(format!("signature mismatch at 0x{:x}: expected {}, got {:0width$x}", addr + offset as u64, line, actual, width = size * 2))
+        }
+
+        offset += size;
+    }
+
+    if offset != signature.len() {
+        return Err(format!("signature has {} bytes, but the reference has {}", signature.len(), offset))
+    }
+    Ok(())
+}
+
+fn judge(case: &TestCase, run: &TestRun) -> Status {
+    if run.timed_out {
+        return Status::Timeout
+    }
+    if run.host_io == 0 {
+        return Status::Fail(format!("did not finish in {} cycles", run.cycle))
+    }
+
+    match &case.expectation {
+        Expectation::HostIo if run.host_io == 1 => Status::Pass,
+        Expectation::HostIo => Status::Fail(format!("test #{} failed", run.host_io >> 1)),
+        Expectation::Signature(reference) => {
+            let (addr, signature) = match &run.signature {
+                Some(signature) => signature,
+                None => return Status::Fail("begin_signature or end_signature is not defined".to_string()),
+            };
+            let result = fs::read_to_string(reference)
+                .map_err(|e| format!("{}: {}", reference.display(), e))
+                .and_then(|text| compare_signature(*addr, signature, &text));
+
+            match result {
+                Ok(()) => Status::Pass,
+                Err(message) => Status::Fail(message),
+            }
+        },
+    }
+}
+
+// Compares the run of a test with another engine against the run with the interpreter, which must end
+// at the same cycle in the same state. Runs which timed out are not compared, as they stop anywhere.
+pub fn compare_runs(reference: &TestRun, run: &TestRun) -> Result<(), String> {
+    if reference.timed_out || run.timed_out {
+        return Ok(())
+    }
+    if run.cycle != reference.cycle {
+        return Err(format!("ended at cycle {}, not {}", run.cycle, reference.cycle))
+    }
+    if run.host_io != reference.host_io {
+        return Err(format!("tohost is {}, not {}", run.host_io, reference.host_io))
+    }
+    let mut registers = reference.registers.iter().zip(run.registers.iter()).enumerate();
+    if let Some((index, (expected, actual))) = registers.find(|(_, (expected, actual))| expected != actual) {
+        let name = if index == 0 { "pc".to_string() } else { format!("x{}", index) };
+        return Err(format!("{} is 0x{:x}, not 0x{:x}", name, actual, expected))
+    }
+    if run.signature != reference.signature {
+        return Err("the signatures differ".to_string())
+    }
+    Ok(())
+}
+
+fn panic_message(payload: Box<dyn std::any::Any + Send>) -> String {
+    match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => payload.downcast_ref::<&str>().map(|m| m.to_string()).unwrap_or_default(),
+    }
+}
+
+fn run_test<F>(case: &TestCase, run: &F) -> TestResult
+    where F: Fn(&TestCase) -> Result<TestRun, String>
+{
+    let start = Instant::now();
+    // A panicking emulator fails the test instead of the whole run.
+    let (status, cycle) = match panic::catch_unwind(panic::AssertUnwindSafe(|| run(case))) {
+        Ok(Ok(test_run)) => (judge(case, &test_run), test_run.cycle),
+        Ok(Err(message)) => (Status::Fail(message), 0),
+        Err(payload) => (Status::Fail(format!("emulator panicked: {}", panic_message(payload))), 0),
+    };
+
+    TestResult { suite: case.suite, name: case.name.clone(), status, cycle, duration: start.elapsed() }
+}
+
+// Runs the tests on `jobs` threads, printing each result as it completes.
+// Results are returned in the order of `cases`.
+pub fn run_tests<F>(cases: &[TestCase], jobs: usize, run: F) -> Vec<TestResult>
+    where F: Fn(&TestCase) -> Result<TestRun, String> + Sync
+{
+    let next = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::channel();
+    let mut results: Vec<Option<TestResult>> = (0..cases.len()).map(|_| None).collect();
+
+    thread::scope(|scope| {
+        for _i in 0..jobs.max(1) {
+            let sender = sender.clone();
+            let (next, run) = (&next, &run);
+            scope.spawn(move || {
+                loop {
+                    let index = next.fetch_add(1, Ordering::SeqCst);
+                    if index >= cases.len() {
+                        break
+                    }
+                    sender.send((index, run_test(&cases[index], run))).unwrap();
+                }
+            });
+        }
+        drop(sender);
+
+        for (index, result) in receiver {
+            match &result.status {
+                Status::Pass => println!("PASS    {} ({} cycles)", result.name, result.cycle),
+                Status::Fail(message) => println!("FAIL    {}: {}", result.name, message),
+                Status::Timeout => println!("TIMEOUT {} ({} cycles)", result.name, result.cycle),
+            }
+            results[index] = Some(result);
+        }
+    });
+
+    results.into_iter().map(|r| r.unwrap()).collect()
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+pub fn write_junit(path: &str, results: &[TestResult]) -> Result<(), String> {
+    let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+    let mut writer = BufWriter::new(file);
+    let mut xml = String::new();
+
+    let failures = results.iter().filter(|r| r.status != Status::Pass).count();
+    let time: f64 = results.iter().map(|r| r.duration.as_secs_f64()).sum();
+    xml += "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n";
+    xml += &format!("<testsuites tests=\"{}\" failures=\"{}\" time=\"{:.3}\">\n", results.len(), failures, time);
+
+    let mut suites: Vec<&str> = results.iter().map(|r| r.suite).collect();
+    suites.dedup();
+    for suite in suites {
+        let suite_results: Vec<&TestResult> = results.iter().filter(|r| r.suite == suite).collect();
+        let suite_failures = suite_results.iter().filter(|r| r.status != Status::Pass).count();
+        xml += &format!("  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\">\n", suite, suite_results.len(), suite_failures);
+
+        for result in suite_results {
+            let head = format!("    <testcase classname=\"{}\" name=\"{}\" time=\"{:.3}\"", suite, escape_xml(&result.name), result.duration.as_secs_f64());
+            xml += &match &result.status {
+                Status::Pass => format!("{}/>\n", head),
+                Status::Fail(message) => format!("{}>\n      <failure message=\"{}\"/>\n    </testcase>\n", head, escape_xml(message)),
+                Status::Timeout => format!("{}>\n      <failure type=\"timeout\" message=\"timed out after {} cycles\"/>\n    </testcase>\n", head, result.cycle),
+            };
+        }
+        xml += "  </testsuite>\n";
+    }
+    xml += "</testsuites>\n";
+
+    writer.write_all(xml.as_bytes()).map_err(|e| format!("{}: {}", path, e))
+}
+
+#[derive(Serialize)]
+struct JsonResult<'a> {
+    suite: &'a str,
+    name: &'a str,
+    status: &'a str,
+    message: Option<&'a str>,
+    cycle: u64,
+    seconds: f64,
+}
+
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    tests: usize,
+    passed: usize,
+    failed: usize,
+    timed_out: usize,
+    results: Vec<JsonResult<'a>>,
+}
+
+pub fn write_json(path: &str, results: &[TestResult]) -> Result<(), String> {
+    let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+    let writer = BufWriter::new(file);
+
+    let json_results: Vec<JsonResult> = results.iter().map(|r| {
+        let (status, message) = match &r.status {
+            Status::Pass => ("pass", None),
+            Status::Fail(message) => ("fail", Some(message.as_str())),
+            Status::Timeout => ("timeout", None),
+        };
+        JsonResult { suite: r.suite, name: &r.name, status, message, cycle: r.cycle, seconds: r.duration.as_secs_f64() }
+    }).collect();
+
+    let report = JsonReport {
+        tests: results.len(),
+        passed: json_results.iter().filter(|r| r.status == "pass").count(),
+        failed: json_results.iter().filter(|r| r.status == "fail").count(),
+        timed_out: json_results.iter().filter(|r| r.status == "timeout").count(),
+        results: json_results,
+    };
+
+    serde_json::to_writer_pretty(writer, &report).map_err(|e| format!("{}: {}", path, e))
+}
+
+#[test]
+fn test_compare_signature() {
+    let signature = [0x78, 0x56, 0x34, 0x12, 0xef, 0xbe, 0xad, 0xde];
+
+    assert_eq!(compare_signature(0x8000_2000, &signature, "12345678\ndeadbeef\n"), Ok(()));
+    assert_eq!(compare_signature(0x8000_2000, &signature, "deadbeef12345678\n"), Ok(()));
+    assert_eq!(compare_signature(0x8000_2000, &signature, "12345678\ndeadbeee\n"),
+        Err("signature mismatch at 0x80002004: expected deadbeee, got deadbeef".to_string()));
+    assert!(compare_signature(0x8000_2000, &signature, "12345678\n").is_err());
+    assert!(compare_signature(0x8000_2000, &signature, "12345678\ndeadbeef\n00000000\n").is_err());
+    assert!(compare_signature(0x8000_2000, &signature, "1234567\n").is_err());
+}
+
+#[test]
+fn test_find_tests() {
+    let dir = std::env::temp_dir().join(format!("rafi-emu-harness-{}", std::process::id()));
+    let arch_dir = dir.join("rv32i_m");
+    for extension in ["I", "C", "F"] {
+        fs::create_dir_all(arch_dir.join(extension)).unwrap();
+        fs::write(arch_dir.join(extension).join("add-01.elf"), []).unwrap();
+        fs::write(arch_dir.join(extension).join("add-01.reference_output"), []).unwrap();
+    }
+    for name in ["rv32ui-p-add.bin", "rv32ui-p-add.dump", "rv32uc-p-rvc.bin", "rv64ud-v-fadd.bin", "rv64mi-p-csr.bin", "rv32ua-px-amoadd_w.bin"] {
+        fs::write(dir.join(name), []).unwrap();
+    }
+
+    let names = |cases: Vec<TestCase>| cases.into_iter().map(|case| case.name).collect::<Vec<String>>();
+    assert_eq!(names(find_riscv_tests(&dir)), ["rv32ui-p-add", "rv64mi-p-csr"]);
+    assert_eq!(names(find_arch_tests(&dir)), ["rv32i_m/I/add-01"]);
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_run_tests() {
+    let case = |name: &str| TestCase {
+        suite: RISCV_TESTS_SUITE,
+        name: name.to_string(),
+        path: PathBuf::from(name),
+        base: Base::from_name(name).unwrap(),
+        expectation: Expectation::HostIo,
+    };
+    let cases = vec![case("rv32ui-p-add"), case("rv64ui-v-add"), case("rv32ui-p-sub"), case("rv32ui-p-xor"), case("rv32ui-p-or")];
+    assert_eq!(cases[1].base, Base::Rv64I);
+
+    let results = run_tests(&cases, 3, |case| {
+        match case.name.as_str() {
+            "rv32ui-p-sub" => Ok(TestRun { host_io: 7, cycle: 10, timed_out: false, signature: None, registers: Vec::new() }),
+            "rv32ui-p-xor" => Ok(TestRun { host_io: 0, cycle: 50, timed_out: true, signature: None, registers: Vec::new() }),
+            "rv32ui-p-or" => Err("no such file".to_string()),
+            _ => Ok(TestRun { host_io: 1, cycle: 20, timed_out: false, signature: None, registers: Vec::new() }),
+        }
+    });
+
+    let statuses: Vec<&Status> = results.iter().map(|r| &r.status).collect();
+    assert_eq!(statuses, vec![
+        &Status::Pass,
+        &Status::Pass,
+        &Status::Fail("test #3 failed".to_string()),
+        &Status::Timeout,
+        &Status::Fail("no such file".to_string()),
+    ]);
+    assert_eq!(results[1].name, "rv64ui-v-add");
+}
+
+#[test]
+fn test_compare_runs() {
+    let run = |cycle: u64, registers: &[u64], timed_out: bool| TestRun { host_io: 1, cycle, timed_out, signature: None, registers: registers.to_vec() };
+
+    assert_eq!(compare_runs(&run(100, &[0x8000_0040, 5], false), &run(100, &[0x8000_0040, 5], false)), Ok(()));
+    assert_eq!(compare_runs(&run(100, &[0x8000_0040, 5], false), &run(99, &[0x8000_0040, 5], false)),
+        Err("ended at cycle 99, not 100".to_string()));
+    assert_eq!(compare_runs(&run(100, &[0x8000_0040, 5], false), &run(100, &[0x8000_0040, 6], false)),
+        Err("x1 is 0x6, not 0x5".to_string()));
+    assert_eq!(compare_runs(&run(100, &[0x8000_0040, 5], false), &run(100, &[0x8000_0044, 5], false)),
+        Err("pc is 0x80000044, not 0x80000040".to_string()));
+    assert_eq!(compare_runs(&run(100, &[0x8000_0040], false), &run(70, &[0x8000_0000], true)), Ok(()));
+
+    let mut other = run(100, &[], false);
+    other.signature = Some((0x8000_2000, vec![1]));
+    assert!(compare_runs(&run(100, &[], false), &other).is_err());
+    other.signature = None;
+    other.host_io = 3;
+    assert_eq!(compare_runs(&run(100, &[], false), &other), Err("tohost is 3, not 1".to_string()));
+}
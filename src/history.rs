@@ -0,0 +1,455 @@
+use core::*;
+use csr::*;
+use decoder::*;
+use op::*;
+use xlen::*;
+
+use std::collections::{HashMap, VecDeque};
+
+const DEFAULT_CHECKPOINT_INTERVAL: u64 = 100_000;
+const DEFAULT_MAX_CHECKPOINTS: usize = 256;
+
+const NUM_INT_REG: usize = 32;
+const NUM_CSR: usize = 0x1000;
+const PAGE_SIZE: u64 = 4096;
+
+// Previous state overwritten by the op executed at `cycle`.
+struct UndoEntry<X: Xlen> {
+    cycle: u64,
+    pc: X::Uint,
+    privilege: u32,
+    reservation: Option<u64>,
+    waiting: bool,
+    int_reg: Vec<(usize, X::Uint)>,
+    csr: Vec<(usize, X::Uint)>,
+    memory: Vec<(u64, u8)>,
+}
+
+// State of the hart at `cycle`, and the contents at that cycle of the memory pages which are written
+// before the next checkpoint. Pages are added as they are first written, so a checkpoint only holds
+// the pages its interval dirties.
+struct Checkpoint<X: Xlen> {
+    cycle: u64,
+    pc: X::Uint,
+    privilege: u32,
+    reservation: Option<u64>,
+    waiting: bool,
+    int_reg: Vec<X::Uint>,
+    // Non-zero CSRs as (index, value).
+    csr: Vec<(usize, X::Uint)>,
+    triggers: Vec<(u32, X::Uint)>,
+    // Pages by address
+    pages: HashMap<u64, Vec<u8>>,
+}
+
+impl<X: Xlen> Checkpoint<X> {
+    fn take(core: &Core<X>) -> Checkpoint<X> {
+        Checkpoint {
+            cycle: core.cycle,
+            pc: core.pc,
+            privilege: core.privilege,
+            reservation: core.reservation,
+            waiting: core.waiting,
+            int_reg: (0..NUM_INT_REG).map(|i| core.int_reg.read(i)).collect(),
+            csr: (0..NUM_CSR).map(|i| (i, core.csr.read_stored(i))).filter(|(_, value)| *value != X::Uint::ZERO).collect(),
+            triggers: (0..NUM_TRIGGER).map(|i| core.csr.read_trigger(i)).collect(),
+            pages: HashMap::new(),
+        }
+    }
+
+    fn restore_pages(&self, core: &mut Core<X>) {
+        for (page, data) in &self.pages {
+            for (i, value) in data.iter().enumerate() {
+                core.bus.write_u8(page + i as u64, *value);
+            }
+        }
+    }
+
+    fn restore_hart(&self, core: &mut Core<X>) {
+        for (i, value) in self.int_reg.iter().enumerate() {
+            core.int_reg.write(i, *value);
+        }
+        let mut csr_values = [X::Uint::ZERO; NUM_CSR];
+        for (index, value) in &self.csr {
+            csr_values[*index] = *value;
+        }
+        for (i, value) in csr_values.iter().enumerate() {
+            core.csr.restore(i, *value);
+        }
+        for (i, (tdata1, tdata2)) in self.triggers.iter().enumerate() {
+            core.csr.write_trigger(i, *tdata1, *tdata2);
+        }
+        core.csr.take_undo_log();
+        core.bus.take_undo_log();
+        core.bus.last_write_addr = None;
+        core.mmu.flush();
+
+        core.cycle = self.cycle;
+        core.pc = self.pc;
+        core.next_pc = self.pc;
+        core.privilege = self.privilege;
+        core.reservation = self.reservation;
+        core.waiting = self.waiting;
+    }
+}
+
+// Execution history for reverse execution.
+//
+// A checkpoint is made every `interval` cycles and the undo log holds the ops executed since the
+// checkpoint of the current interval. Going back further undoes the log, restores the pages of the
+// checkpoints in between, newest first, and re-executes forward from the older checkpoint, which
+// reproduces the same state because execution is deterministic. The state of devices is not rewound.
+// Ops must be executed through History::step() to be recorded.
+pub struct History<X: Xlen = Rv32> {
+    interval: u64,
+    max_checkpoints: usize,
+    checkpoints: VecDeque<Checkpoint<X>>,
+    // Index of the checkpoint at log_start
+    current: usize,
+    log_start: u64,
+    log: Vec<UndoEntry<X>>,
+}
+
+impl<X: Xlen> History<X> {
+    pub fn new(core: &mut Core<X>) -> History<X> {
+        History::with_interval(core, DEFAULT_CHECKPOINT_INTERVAL, DEFAULT_MAX_CHECKPOINTS)
+    }
+
+    pub fn with_interval(core: &mut Core<X>, interval: u64, max_checkpoints: usize) -> History<X> {
+        core.csr.enable_undo_log();
+        core.bus.enable_undo_log();
+
+        let mut checkpoints = VecDeque::new();
+        checkpoints.push_back(Checkpoint::take(core));
+
+        History { interval, max_checkpoints, checkpoints, current: 0, log_start: core.cycle, log: Vec::new() }
+    }
+
+    // Returns the oldest cycle which can be reached.
+    pub fn begin_cycle(&self) -> u64 {
+        self.checkpoints[0].cycle
+    }
+
+    pub fn step(&mut self, core: &mut Core<X>) {
+        self.step_checked(core, |_, _| None);
+    }
+
+    // Executes an op like step(), passing it to `check` after interrupts are taken and before it is
+    // executed. Returns the result of `check`, e.g. a trigger which fired on the op.
+    pub fn step_checked<F>(&mut self, core: &mut Core<X>, check: F) -> Option<usize>
+        where F: FnOnce(&Core<X>, &dyn Op<X>) -> Option<usize>
+    {
+        if core.cycle >= self.log_start + self.interval {
+            // Checkpoints after the current one are kept while the same execution reaches them again.
+            let next = self.current + 1;
+            if self.checkpoints.get(next).is_none_or(|c| c.cycle != core.cycle) {
+                self.checkpoints.truncate(next);
+                self.checkpoints.push_back(Checkpoint::take(core));
+                if self.checkpoints.len() > self.max_checkpoints {
+                    self.checkpoints.pop_front();
+                    self.current -= 1;
+                }
+            }
+            self.current += 1;
+            self.log.clear();
+            self.log_start = core.cycle;
+        }
+
+        let int_reg: Vec<X::Uint> = (0..NUM_INT_REG).map(|i| core.int_reg.read(i)).collect();
+        let cycle = core.cycle;
+        let pc = core.pc;
+        let privilege = core.privilege;
+        let reservation = core.reservation;
+        let waiting = core.waiting;
+
+        core.check_interrupts();
+        let mut result = None;
+        if !core.waiting {
+            let op = decode::<X>(&core.fetch());
+            result = check(core, &*op);
+            core.execute(&*op);
+        }
+
+        let memory = core.bus.take_undo_log();
+        self.record_pages(core, &memory);
+        self.log.push(UndoEntry {
+            cycle,
+            pc,
+            privilege,
+            reservation,
+            waiting,
+            int_reg: int_reg.into_iter().enumerate().filter(|(i, value)| core.int_reg.read(*i) != *value).collect(),
+            csr: core.csr.take_undo_log(),
+            memory,
+        });
+
+        result
+    }
+
+    // Adds the pages written for the first time in the current interval to its checkpoint, given the
+    // previous values of the written bytes.
+    fn record_pages(&mut self, core: &Core<X>, memory: &[(u64, u8)]) {
+        let pages = &mut self.checkpoints[self.current].pages;
+        for (addr, _) in memory {
+            let page = addr & !(PAGE_SIZE - 1);
+            if pages.contains_key(&page) {
+                continue
+            }
+            let mut data: Vec<u8> = (page..page + PAGE_SIZE).take_while(|a| core.bus.is_mapped(*a, 1)).map(|a| core.bus.read_u8(a)).collect();
+            for (a, value) in memory.iter().rev().filter(|(a, _)| a & !(PAGE_SIZE - 1) == page) {
+                data[(a - page) as usize] = *value;
+            }
+            pages.insert(page, data);
+        }
+    }
+
+    // Moves the machine to `cycle`. Returns false if `cycle` is older than the history.
+    pub fn seek(&mut self, core: &mut Core<X>, cycle: u64) -> bool {
+        if cycle < self.begin_cycle() {
+            return false
+        }
+
+        if cycle < self.log_start {
+            while !self.log.is_empty() {
+                self.undo(core);
+            }
+            let target = self.checkpoints.iter().rposition(|c| c.cycle <= cycle).unwrap();
+            for checkpoint in self.checkpoints.range(target..self.current).rev() {
+                checkpoint.restore_pages(core);
+            }
+            self.checkpoints[target].restore_hart(core);
+            self.current = target;
+            self.log_start = core.cycle;
+        }
+
+        while core.cycle > cycle {
+            self.undo(core);
+        }
+        while core.cycle < cycle {
+            self.step(core);
+        }
+
+        true
+    }
+
+    // Returns false if the machine is at the beginning of the history.
+    pub fn reverse_step(&mut self, core: &mut Core<X>) -> bool {
+        core.cycle > self.begin_cycle() && self.seek(core, core.cycle - 1)
+    }
+
+    // Goes back to the latest preceding op whose pc is in `breakpoints`.
+    // Returns false and stops at the beginning of the history if there is no such op.
+    pub fn reverse_continue(&mut self, core: &mut Core<X>, breakpoints: &[X::Uint]) -> bool {
+        let origin = core.cycle;
+        let found = self.find_backward(core, origin, |entry| breakpoints.contains(&entry.pc)).map(|entry| entry.0);
+
+        match found {
+            Some(cycle) => self.seek(core, cycle),
+            None => {
+                let begin = self.begin_cycle();
+                self.seek(core, begin);
+                false
+            },
+        }
+    }
+
+    // Returns (cycle, pc) of the latest op which wrote the byte at `addr` before the current cycle.
+    // The machine state is left unchanged.
+    pub fn last_writer(&mut self, core: &mut Core<X>, addr: u64) -> Option<(u64, X::Uint)> {
+        let origin = core.cycle;
+        let found = self.find_backward(core, origin, |entry| entry.memory.iter().any(|(a, _)| *a == addr));
+
+        self.seek(core, origin);
+        found
+    }
+
+    // Searches the history backward from `origin` for an op matching `pred` and returns its (cycle, pc).
+    // The undo log may be replaced by the one of an older interval.
+    fn find_backward<F>(&mut self, core: &mut Core<X>, origin: u64, pred: F) -> Option<(u64, X::Uint)>
+        where F: Fn(&UndoEntry<X>) -> bool
+    {
+        let mut end = origin;
+        loop {
+            let found = self.log.iter().rev().filter(|entry| entry.cycle < end).find(|entry| pred(entry));
+            if let Some(entry) = found {
+                return Some((entry.cycle, entry.pc))
+            }
+
+            if self.log_start <= self.begin_cycle() {
+                return None
+            }
+
+            // Rebuild the undo log of the previous interval up to the op at `end - 1`.
+            end = self.log_start;
+            self.seek(core, end - 1);
+            self.step(core);
+        }
+    }
+
+    fn undo(&mut self, core: &mut Core<X>) {
+        let entry = self.log.pop().unwrap();
+
+        for (addr, value) in entry.memory.iter().rev() {
+            core.bus.write_u8(*addr, *value);
+        }
+        for (index, value) in entry.csr.iter().rev() {
+            core.csr.restore(*index, *value);
+        }
+        for (index, value) in &entry.int_reg {
+            core.int_reg.write(*index, *value);
+        }
+        core.csr.take_undo_log();
+        core.bus.take_undo_log();
+        core.bus.last_write_addr = None;
+        // Undone page table writes may leave stale translations.
+        core.mmu.flush();
+
+        core.cycle = entry.cycle;
+        core.pc = entry.pc;
+        core.next_pc = entry.pc;
+        core.privilege = entry.privilege;
+        core.reservation = entry.reservation;
+        core.waiting = entry.waiting;
+    }
+
+    // Drops the checkpoints after the current one, and keeps the pages written in memory. Must be
+    // called when the machine state is modified from outside, e.g. by a debugger.
+    pub fn discard_future(&mut self, core: &mut Core<X>) {
+        self.checkpoints.truncate(self.current + 1);
+        let memory = core.bus.take_undo_log();
+        self.record_pages(core, &memory);
+    }
+}
+
+#[cfg(test)]
+fn read_int_regs(core: &Core) -> Vec<u32> {
+    (0..NUM_INT_REG).map(|i| core.int_reg.read(i)).collect()
+}
+
+#[test]
+fn test_history() {
+    use bus::*;
+    use memory::*;
+
+    let program = [
+        0x800001b7, // lui gp,0x80000
+        0x00a00093, // addi ra,zero,10
+        0x00000113, // addi sp,zero,0
+        0x00110133, // add sp,sp,ra
+        0x1021a023, // sw sp,256(gp)
+        0xfff08093, // addi ra,ra,-1
+        0xfe0098e3, // bnez ra,-16
+        0x0000006f, // j 0
+    ];
+
+    let mut memory = Memory::new();
+    let mut bus = Bus::new(&mut memory);
+    for (i, insn) in program.iter().enumerate() {
+        bus.write_u32(0x8000_0000 + 4 * i as u64, *insn);
+    }
+    let mut core: Core = Core::new(&mut bus);
+    core.pc = 0x8000_0000;
+
+    let mut history = History::with_interval(&mut core, 7, 16);
+    let mut states = Vec::new();
+    for _i in 0..45 {
+        states.push((core.pc, read_int_regs(&core), core.bus.read_u32(0x8000_0100)));
+        history.step(&mut core);
+    }
+
+    // Reverse step across several intervals.
+    for cycle in (0..45).rev() {
+        assert!(history.reverse_step(&mut core));
+        assert_eq!(core.cycle, cycle);
+        assert_eq!((core.pc, read_int_regs(&core), core.bus.read_u32(0x8000_0100)), states[cycle as usize]);
+    }
+    assert!(!history.reverse_step(&mut core));
+
+    // Forward again, then reverse continue to the store.
+    assert!(history.seek(&mut core, 40));
+    assert!(history.reverse_continue(&mut core, &[0x8000_0010]));
+    assert_eq!(core.cycle, 39);
+    assert!(history.reverse_continue(&mut core, &[0x8000_0010]));
+    assert_eq!(core.cycle, 34);
+
+    // The store at cycle 29 is the last one before cycle 34.
+    assert_eq!(history.last_writer(&mut core, 0x8000_0102), Some((29, 0x8000_0010)));
+    assert_eq!(core.cycle, 34);
+    assert_eq!(history.last_writer(&mut core, 0x8000_0200), None);
+
+    assert!(!history.reverse_continue(&mut core, &[0x8000_0100]));
+    assert_eq!(core.cycle, 0);
+
+    // Checkpoints hold only the page of the stores.
+    assert!(history.checkpoints.len() > 1);
+    assert!(history.checkpoints.iter().all(|c| c.pages.keys().all(|page| *page == 0x8000_0000)));
+}
+
+#[test]
+fn test_history_step_checked() {
+    use bus::*;
+    use memory::*;
+
+    let mut memory = Memory::new();
+    let mut bus = Bus::new(&mut memory);
+    bus.write_u32(0x8000_0000, 0x00100093); // addi ra,zero,1
+    bus.write_u32(0x8000_0100, 0x00200093); // addi ra,zero,2
+    let mut core: Core = Core::new(&mut bus);
+    core.pc = 0x8000_0000;
+    let mut history = History::new(&mut core);
+
+    // The op is checked after the pending interrupt is taken, so it is the first op of the handler.
+    core.csr.write(0x305, 0x8000_0100);
+    core.csr.write(0x304, 0x8);
+    core.csr.write(0x300, 0x8);
+    core.csr.set_interrupt_lines(0x8);
+    let checked = history.step_checked(&mut core, |core, _op| Some(core.pc as usize));
+    assert_eq!(checked, Some(0x8000_0100));
+    assert_eq!(core.int_reg.read(1), 2);
+
+    assert!(history.reverse_step(&mut core));
+    assert_eq!(core.pc, 0x8000_0000);
+}
+
+#[test]
+fn test_history_lr_sc() {
+    use bus::*;
+    use clint::*;
+    use memory::*;
+
+    let program = [
+        0x800001b7, // lui gp,0x80000
+        0x1001a0af, // lr.w ra,(gp)
+        0x1811a12f, // sc.w sp,ra,(gp)
+        0x10500073, // wfi
+    ];
+
+    let mut memory = Memory::new();
+    let mut bus = Bus::new(&mut memory);
+    for (i, insn) in program.iter().enumerate() {
+        bus.write_u32(0x8000_0000 + 4 * i as u64, *insn);
+    }
+    // WFI waits only if a device can wake the hart.
+    bus.add_device(0x200_0000, Box::new(Clint::new(1)));
+    let mut core: Core = Core::new(&mut bus);
+    core.pc = 0x8000_0000;
+    core.int_reg.write(2, 1);
+
+    let mut history = History::new(&mut core);
+    for _i in 0..4 {
+        history.step(&mut core);
+    }
+    assert_eq!(core.int_reg.read(2), 0);
+    assert!(core.waiting);
+
+    // Stepping back over WFI and SC restores the wait and the reservation, so that they are
+    // executed the same way again.
+    assert!(history.reverse_step(&mut core));
+    assert!(!core.waiting);
+    assert!(history.reverse_step(&mut core));
+    assert_eq!(core.reservation, Some(0x8000_0000));
+    history.step(&mut core);
+    assert_eq!(core.int_reg.read(2), 0);
+    history.step(&mut core);
+    assert!(core.waiting);
+}
@@ -0,0 +1,176 @@
+// Host-target interface (HTIF) compatible with Spike's frontend server.
+//
+// The target writes a command to tohost as device (bits 63:56), command (55:48) and payload (47:0).
+// The host clears tohost when it takes the command, and answers commands which have a response
+// through fromhost. Device 0 exits when the payload is odd and otherwise proxies the syscall
+// described by the 8 doublewords at the payload address, device 1 is the console.
+//
+// RV32 targets write tohost with two stores, lower word first. As the engines stop at the first one,
+// one more op is executed before the command is taken, like QEMU which takes it at the upper word.
+
+use core::*;
+use syscall::*;
+use xlen::*;
+
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+
+const DEVICE_SYSCALL: u64 = 0;
+const DEVICE_CONSOLE: u64 = 1;
+
+const CONSOLE_GETCHAR: u64 = 0;
+const CONSOLE_PUTCHAR: u64 = 1;
+
+pub struct Htif {
+    pub fromhost_addr: u64,
+    files: HostFiles,
+    // The op following the write of tohost has been executed (RV32).
+    upper_written: bool,
+}
+
+impl Htif {
+    // Files are accessible in `root` only.
+    pub fn new(fromhost_addr: u64, root: Option<PathBuf>) -> Htif {
+        Htif { fromhost_addr, files: HostFiles::new(root), upper_written: false }
+    }
+
+    // Serves the command in tohost. Returns false if there is none or the target has exited, in which
+    // case tohost is left as it is.
+    pub fn serve<X: Xlen>(&mut self, core: &mut Core<X>) -> bool {
+        if core.read_host_io() == 0 {
+            return false
+        }
+        if X::XLEN == 32 && !self.upper_written {
+            self.upper_written = true;
+            core.step();
+        }
+
+        let tohost = core.read_host_io();
+        let (device, command, payload) = (tohost >> 56, (tohost >> 48) & 0xff, tohost & 0xffff_ffff_ffff);
+
+        if device == DEVICE_SYSCALL && payload & 1 == 1 {
+            return false
+        }
+        core.bus.write_u64(core.host_io_addr, 0);
+        self.upper_written = false;
+
+        match (device, command) {
+            (DEVICE_SYSCALL, _) => {
+                if let Some(code) = self.syscall(core, payload) {
+                    // Report the exit as an exit command, which stops the execution engines.
+                    core.bus.write_u64(core.host_io_addr, (code << 1) | 1);
+                    return false
+                }
+                self.respond(core, device, command, 1);
+            },
+            (DEVICE_CONSOLE, CONSOLE_PUTCHAR) => {
+                let mut stdout = io::stdout();
+                let _ = stdout.write_all(&[payload as u8]).and_then(|_| stdout.flush());
+            },
+            (DEVICE_CONSOLE, CONSOLE_GETCHAR) => {
+                // Like Spike, the request stays unanswered at the end of input.
+                let mut c = [0];
+                if let Ok(1) = core.bus.read_stdin(core.cycle, &mut c) {
+                    self.respond(core, device, command, 0x100 | c[0] as u64);
+                }
+            },
+            _ => {},
+        }
+        true
+    }
+
+    fn respond<X: Xlen>(&self, core: &mut Core<X>, device: u64, command: u64, payload: u64) {
+        if core.bus.is_mapped(self.fromhost_addr, 8) {
+            core.bus.write_u64(self.fromhost_addr, (device << 56) | (command << 48) | payload);
+        }
+    }
+
+    // Runs the syscall described at addr and writes back its result. Returns the exit code on exit.
+    fn syscall<X: Xlen>(&mut self, core: &mut Core<X>, addr: u64) -> Option<u64> {
+        if !core.bus.is_mapped(addr, 64) {
+            return None
+        }
+
+        let args: Vec<u64> = (0..8).map(|i| core.bus.read_u64(addr + 8 * i)).collect();
+        let result = match args[0] {
+            SYS_EXIT | SYS_EXIT_GROUP => return Some(args[1]),
+            number => self.files.syscall(core, number, &args[1..]).unwrap_or(-ENOSYS),
+        };
+
+        core.bus.write_u64(addr, result as u64);
+        None
+    }
+}
+
+#[test]
+fn test_htif() {
+    use bus::*;
+    use memory::*;
+    use std::fs;
+
+    const MAGIC_MEM: u64 = 0x8000_2000;
+    const PATH: u64 = 0x8000_2100;
+    const BUF: u64 = 0x8000_2200;
+
+    let root = std::env::temp_dir().join(format!("rafi-emu-test-htif-{}", std::process::id()));
+    fs::create_dir_all(&root).unwrap();
+
+    let mut memory = Memory::new();
+    let mut bus = Bus::new(&mut memory);
+    let mut core: Core = Core::new(&mut bus);
+    core.host_io_addr = 0x8000_1000;
+    // The op executed before taking a command on RV32 is `j 0`.
+    core.bus.write_u32(0x8000_0000, 0x0000_006f);
+    core.pc = 0x8000_0000;
+    let mut htif = Htif::new(0x8000_1040, Some(root.clone()));
+
+    let mut syscall = |core: &mut Core, args: &[u64]| {
+        for (i, arg) in args.iter().enumerate() {
+            core.bus.write_u64(MAGIC_MEM + 8 * i as u64, *arg);
+        }
+        core.bus.write_u64(0x8000_1000, MAGIC_MEM);
+        core.bus.write_u64(0x8000_1040, 0);
+        assert!(htif.serve(core));
+        assert_eq!(core.bus.read_u64(0x8000_1000), 0);
+        assert_eq!(core.bus.read_u64(0x8000_1040), 1);
+        core.bus.read_u64(MAGIC_MEM) as i64
+    };
+
+    for (i, b) in b"/dir/../a.txt\0".iter().enumerate() {
+        core.bus.write_u8(PATH + i as u64, *b);
+    }
+    assert_eq!(syscall(&mut core, &[SYS_OPENAT, AT_FDCWD as u32 as u64, PATH, 0x42 /* O_CREAT | O_RDWR */, 0o644]), -EACCES);
+
+    // Open "/a.txt" as root/a.txt, write, seek back and read.
+    for (i, b) in b"/a.txt\0".iter().enumerate() {
+        core.bus.write_u8(PATH + i as u64, *b);
+    }
+    for (i, b) in b"hello".iter().enumerate() {
+        core.bus.write_u8(BUF + i as u64, *b);
+    }
+    assert_eq!(syscall(&mut core, &[SYS_OPENAT, AT_FDCWD as u32 as u64, PATH, 0x42 /* O_CREAT | O_RDWR */, 0o644]), 3);
+    assert_eq!(syscall(&mut core, &[SYS_WRITE, 3, BUF, 5]), 5);
+    assert_eq!(syscall(&mut core, &[SYS_LSEEK, 3, 1, 0]), 1);
+    assert_eq!(syscall(&mut core, &[SYS_READ, 3, BUF + 0x10, 0x10]), 4);
+    assert_eq!(core.bus.read_u32(BUF + 0x10), u32::from_le_bytes(*b"ello"));
+    assert_eq!(syscall(&mut core, &[SYS_FSTAT, 3, BUF + 0x100]), 0);
+    assert_eq!(core.bus.read_u64(BUF + 0x100 + 48), 5);
+    assert_eq!(syscall(&mut core, &[SYS_CLOSE, 3]), 0);
+    assert_eq!(syscall(&mut core, &[SYS_CLOSE, 3]), -EBADF);
+    assert_eq!(fs::read(root.join("a.txt")).unwrap(), b"hello");
+    assert_eq!(syscall(&mut core, &[SYS_UNLINKAT, AT_FDCWD as u32 as u64, PATH, 0]), 0);
+    assert_eq!(syscall(&mut core, &[SYS_OPEN, PATH, 0, 0]), -ENOENT);
+    assert_eq!(syscall(&mut core, &[9999]), -ENOSYS);
+    assert_eq!(syscall(&mut core, &[SYS_OPEN, u64::MAX - 8, 0, 0]), -EFAULT);
+
+    // exit(3) becomes an exit command, and exit commands are not served.
+    core.bus.write_u64(MAGIC_MEM, SYS_EXIT);
+    core.bus.write_u64(MAGIC_MEM + 8, 3);
+    core.bus.write_u64(0x8000_1000, MAGIC_MEM);
+    assert!(!htif.serve(&mut core));
+    assert_eq!(core.read_host_io(), 7);
+    assert!(!htif.serve(&mut core));
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,396 @@
+// x86-64 code generator for the block engine.
+//
+// A block's leading run of integer ALU ops, loads and stores is compiled to native code operating
+// directly on IntReg. Memory is accessed through a fast-path TLB which maps guest pages onto the host
+// memory buffer. When an access misses it or crosses a page, the native code returns before the op and
+// the interpreter resumes at that op, so exceptions stay precise at instruction granularity. The
+// engine then maps the page, so that the next execution of the block hits.
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+compile_error!("the jit feature is only supported on x86-64 Linux");
+
+use core::*;
+use util::*;
+use xlen::*;
+
+use std::mem;
+use std::ptr;
+
+extern "C" {
+    fn mmap(addr: *mut u8, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut u8;
+    fn mprotect(addr: *mut u8, len: usize, prot: i32) -> i32;
+    fn munmap(addr: *mut u8, len: usize) -> i32;
+}
+
+const PROT_READ     : i32 = 0x1;
+const PROT_WRITE    : i32 = 0x2;
+const PROT_EXEC     : i32 = 0x4;
+const MAP_PRIVATE   : i32 = 0x02;
+const MAP_ANONYMOUS : i32 = 0x20;
+
+// x86 register numbers
+const EAX: u8 = 0;
+const ECX: u8 = 1;
+
+const PAGE_SHIFT: u32 = 12;
+const PAGE_SIZE: u32 = 1 << PAGE_SHIFT;
+
+const TLB_SIZE: usize = 64;
+// Tags are page numbers, which have 20 bits, so this one never matches.
+const TLB_INVALID: u32 = !0;
+// Size of TlbEntry and offset of TlbEntry::addend
+const TLB_ENTRY_SHIFT: u8 = 4;
+const TLB_ADDEND_OFFSET: u32 = 8;
+
+type NativeFn = extern "sysv64" fn(regs: *mut u32, tlb: *const JitTlb) -> u32;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct TlbEntry {
+    tag: u32,
+    _padding: u32,
+    // Host address of the page minus its guest address
+    addend: u64,
+}
+
+// Direct-mapped TLB of the native code, indexed by the low bits of the page number. Loads and stores
+// have separate entries, so that stores to pages which must be checked by the interpreter, e.g. of code,
+// are not mapped while loads from them are.
+#[repr(C)]
+pub struct JitTlb {
+    load: [TlbEntry; TLB_SIZE],
+    store: [TlbEntry; TLB_SIZE],
+}
+
+impl JitTlb {
+    pub fn new() -> JitTlb {
+        let invalid = TlbEntry { tag: TLB_INVALID, _padding: 0, addend: 0 };
+        JitTlb { load: [invalid; TLB_SIZE], store: [invalid; TLB_SIZE] }
+    }
+
+    pub fn flush(&mut self) {
+        *self = JitTlb::new();
+    }
+
+    pub fn flush_stores(&mut self) {
+        self.store.iter_mut().for_each(|entry| entry.tag = TLB_INVALID);
+    }
+
+    pub fn invalidate_store(&mut self, page: u32) {
+        let entry = &mut self.store[page as usize % TLB_SIZE];
+        if entry.tag == page {
+            entry.tag = TLB_INVALID;
+        }
+    }
+
+    // Maps the guest page to the host memory at `host` for loads, or for stores as well.
+    pub fn fill(&mut self, page: u32, host: *mut u8, store: bool) {
+        let entry = TlbEntry { tag: page, _padding: 0, addend: (host as u64).wrapping_sub((page as u64) << PAGE_SHIFT) };
+        self.load[page as usize % TLB_SIZE] = entry;
+        if store {
+            self.store[page as usize % TLB_SIZE] = entry;
+        }
+    }
+}
+
+// Memory access of an op, which tells the engine the page to map when the native code misses it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NativeAccess {
+    pub rs1: usize,
+    pub offset: u32,
+    pub store: bool,
+}
+
+pub struct NativeBlock {
+    code: *mut u8,
+    size: usize,
+    // Number of guest ops covered by the native code.
+    pub length: usize,
+    // Memory accesses of the covered ops
+    accesses: Vec<Option<NativeAccess>>,
+}
+
+impl NativeBlock {
+    // Returns the number of guest ops executed. Native code is only generated for RV32I.
+    pub fn execute<X: Xlen>(&self, core: &mut Core<X>, tlb: &JitTlb) -> usize {
+        assert!(X::XLEN == 32 && X::NUM_INT_REG == 32);
+
+        let f: NativeFn = unsafe { mem::transmute(self.code) };
+        let regs = core.int_reg.as_mut_ptr() as *mut u32;
+
+        f(regs, tlb) as usize
+    }
+
+    // Returns the memory access of the op at index, e.g. the one which the native code stopped at.
+    pub fn access(&self, index: usize) -> Option<NativeAccess> {
+        self.accesses.get(index).cloned().unwrap_or(None)
+    }
+}
+
+impl Drop for NativeBlock {
+    fn drop(&mut self) {
+        unsafe { munmap(self.code, self.size) };
+    }
+}
+
+struct Assembler {
+    code: Vec<u8>,
+}
+
+impl Assembler {
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn emit_u32(&mut self, value: u32) {
+        self.code.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // mov reg, [rdi + index * 4]
+    fn load_int_reg(&mut self, reg: u8, index: usize) {
+        self.emit(&[0x8b, 0x87 | reg << 3]);
+        self.emit_u32(index as u32 * 4);
+    }
+
+    // mov [rdi + index * 4], eax
+    fn store_int_reg(&mut self, index: usize) {
+        if index != 0 {
+            self.emit(&[0x89, 0x87]);
+            self.emit_u32(index as u32 * 4);
+        }
+    }
+
+    // mov eax, imm32
+    fn mov_eax(&mut self, imm: u32) {
+        self.emit(&[0xb8]);
+        self.emit_u32(imm);
+    }
+
+    // op eax, imm32 (short form for eax)
+    fn alu_eax_imm(&mut self, opcode: u8, imm: u32) {
+        self.emit(&[opcode]);
+        self.emit_u32(imm);
+    }
+
+    // setcc al; movzx eax, al
+    fn set_eax(&mut self, cc: u8) {
+        self.emit(&[0x0f, cc, 0xc0, 0x0f, 0xb6, 0xc0]);
+    }
+
+    // mov eax, exit_value; ret
+    fn exit(&mut self, exit_value: usize) {
+        self.mov_eax(exit_value as u32);
+        self.emit(&[0xc3]);
+    }
+
+    // Exits with exit_value unless the flags satisfy cc, the condition code of a short jcc.
+    fn exit_unless(&mut self, cc: u8, exit_value: usize) {
+        self.emit(&[cc, 0x06]); // jcc +6
+        self.exit(exit_value);
+    }
+
+    // Computes the host address of the guest address rs1 + offset in rdx, exiting with exit_value if
+    // the access of width bytes crosses a page or misses the TLB. Clobbers eax and ecx.
+    fn translate(&mut self, rs1: usize, offset: u32, width: u32, store: bool, exit_value: usize) {
+        let entries = if store { (TLB_SIZE as u32) << TLB_ENTRY_SHIFT } else { 0 };
+
+        self.load_int_reg(EAX, rs1);
+        self.alu_eax_imm(0x05, offset);                 // add eax, offset
+        self.emit(&[0x89, 0xc1]);                       // mov ecx, eax
+        self.emit(&[0x81, 0xe1]);                       // and ecx, PAGE_SIZE - 1
+        self.emit_u32(PAGE_SIZE - 1);
+        self.emit(&[0x81, 0xf9]);                       // cmp ecx, PAGE_SIZE - width
+        self.emit_u32(PAGE_SIZE - width);
+        self.exit_unless(0x76, exit_value);             // jbe
+
+        self.emit(&[0x89, 0xc1]);                       // mov ecx, eax
+        self.emit(&[0xc1, 0xe9, PAGE_SHIFT as u8]);     // shr ecx, PAGE_SHIFT
+        self.emit(&[0x89, 0xca]);                       // mov edx, ecx
+        self.emit(&[0x83, 0xe2, TLB_SIZE as u8 - 1]);   // and edx, TLB_SIZE - 1
+        self.emit(&[0xc1, 0xe2, TLB_ENTRY_SHIFT]);      // shl edx, TLB_ENTRY_SHIFT
+        self.emit(&[0x3b, 0x8c, 0x16]);                 // cmp ecx, [rsi + rdx + tag]
+        self.emit_u32(entries);
+        self.exit_unless(0x74, exit_value);             // je
+        self.emit(&[0x48, 0x8b, 0x94, 0x16]);           // mov rdx, [rsi + rdx + addend]
+        self.emit_u32(entries + TLB_ADDEND_OFFSET);
+        self.emit(&[0x48, 0x01, 0xc2]);                 // add rdx, rax
+    }
+}
+
+
+// Emits an op-imm instruction. Returns false if the op is not supported.
+fn emit_op_imm(asm: &mut Assembler, insn: u32) -> bool {
+    let rd = pick(&insn, 7, 5) as usize;
+    let funct3 = pick(&insn, 12, 3);
+    let rs1 = pick(&insn, 15, 5) as usize;
+    let funct7 = pick(&insn, 25, 7);
+    let imm = sign_extend(12, pick(&insn, 20, 12));
+    let shamt = pick(&insn, 20, 5) as u8;
+
+    match (funct3, funct7) {
+        (0b000, _) | (0b010, _) | (0b011, _) | (0b100, _) | (0b110, _) | (0b111, _) => (),
+        (0b001, 0b0000000) | (0b101, 0b0000000) | (0b101, 0b0100000) => (),
+        _ => return false,
+    }
+
+    asm.load_int_reg(EAX, rs1);
+    match funct3 {
+        0b000 => asm.alu_eax_imm(0x05, imm),  // add eax, imm
+        0b010 => {
+            asm.alu_eax_imm(0x3d, imm);       // cmp eax, imm
+            asm.set_eax(0x9c);                // setl
+        },
+        0b011 => {
+            asm.alu_eax_imm(0x3d, imm);       // cmp eax, imm
+            asm.set_eax(0x92);                // setb
+        },
+        0b100 => asm.alu_eax_imm(0x35, imm),  // xor eax, imm
+        0b110 => asm.alu_eax_imm(0x0d, imm),  // or eax, imm
+        0b111 => asm.alu_eax_imm(0x25, imm),  // and eax, imm
+        0b001 => asm.emit(&[0xc1, 0xe0, shamt]), // shl eax, shamt
+        _ if funct7 == 0 => asm.emit(&[0xc1, 0xe8, shamt]), // shr eax, shamt
+        _ => asm.emit(&[0xc1, 0xf8, shamt]),     // sar eax, shamt
+    }
+    asm.store_int_reg(rd);
+
+    true
+}
+
+// Emits an op instruction. Returns false if the op is not supported.
+fn emit_op(asm: &mut Assembler, insn: u32) -> bool {
+    let rd = pick(&insn, 7, 5) as usize;
+    let funct3 = pick(&insn, 12, 3);
+    let rs1 = pick(&insn, 15, 5) as usize;
+    let rs2 = pick(&insn, 20, 5) as usize;
+    let funct7 = pick(&insn, 25, 7);
+
+    let body: &[u8] = match (funct3, funct7) {
+        (0b000, 0b0000000) => &[0x01, 0xc8],                         // add eax, ecx
+        (0b000, 0b0100000) => &[0x29, 0xc8],                         // sub eax, ecx
+        (0b001, 0b0000000) => &[0xd3, 0xe0],                         // shl eax, cl
+        (0b010, 0b0000000) => &[0x39, 0xc8, 0x0f, 0x9c, 0xc0, 0x0f, 0xb6, 0xc0], // cmp; setl; movzx
+        (0b011, 0b0000000) => &[0x39, 0xc8, 0x0f, 0x92, 0xc0, 0x0f, 0xb6, 0xc0], // cmp; setb; movzx
+        (0b100, 0b0000000) => &[0x31, 0xc8],                         // xor eax, ecx
+        (0b101, 0b0000000) => &[0xd3, 0xe8],                         // shr eax, cl
+        (0b101, 0b0100000) => &[0xd3, 0xf8],                         // sar eax, cl
+        (0b110, 0b0000000) => &[0x09, 0xc8],                         // or eax, ecx
+        (0b111, 0b0000000) => &[0x21, 0xc8],                         // and eax, ecx
+        (0b000, 0b0000001) => &[0x0f, 0xaf, 0xc1],                   // imul eax, ecx
+        _ => return false,
+    };
+
+    asm.load_int_reg(EAX, rs1);
+    asm.load_int_reg(ECX, rs2);
+    asm.emit(body);
+    asm.store_int_reg(rd);
+
+    true
+}
+
+// Emits a load instruction. Returns its access, or None if the op is not supported.
+fn emit_load(asm: &mut Assembler, insn: u32, index: usize) -> Option<NativeAccess> {
+    let rd = pick(&insn, 7, 5) as usize;
+    let funct3 = pick(&insn, 12, 3);
+    let rs1 = pick(&insn, 15, 5) as usize;
+    let imm = sign_extend(12, pick(&insn, 20, 12));
+
+    // load eax, [rdx]
+    let (width, body): (u32, &[u8]) = match funct3 {
+        0b000 => (1, &[0x0f, 0xbe, 0x02]), // movsx eax, byte
+        0b001 => (2, &[0x0f, 0xbf, 0x02]), // movsx eax, word
+        0b010 => (4, &[0x8b, 0x02]),       // mov eax, dword
+        0b100 => (1, &[0x0f, 0xb6, 0x02]), // movzx eax, byte
+        0b101 => (2, &[0x0f, 0xb7, 0x02]), // movzx eax, word
+        _ => return None,
+    };
+
+    asm.translate(rs1, imm, width, false, index);
+    asm.emit(body);
+    asm.store_int_reg(rd);
+
+    Some(NativeAccess { rs1, offset: imm, store: false })
+}
+
+// Emits a store instruction. Returns its access, or None if the op is not supported.
+fn emit_store(asm: &mut Assembler, insn: u32, index: usize) -> Option<NativeAccess> {
+    let funct3 = pick(&insn, 12, 3);
+    let rs1 = pick(&insn, 15, 5) as usize;
+    let rs2 = pick(&insn, 20, 5) as usize;
+    let imm = sign_extend(12, pick(&insn, 25, 7) << 5 | pick(&insn, 7, 5));
+
+    // store [rdx], ecx
+    let (width, body): (u32, &[u8]) = match funct3 {
+        0b000 => (1, &[0x88, 0x0a]),       // mov byte, cl
+        0b001 => (2, &[0x66, 0x89, 0x0a]), // mov word, cx
+        0b010 => (4, &[0x89, 0x0a]),       // mov dword, ecx
+        _ => return None,
+    };
+
+    asm.translate(rs1, imm, width, true, index);
+    asm.load_int_reg(ECX, rs2);
+    asm.emit(body);
+
+    Some(NativeAccess { rs1, offset: imm, store: true })
+}
+
+// Compiles the leading supported ops of a block. `insns` are the instructions of the block starting at `pc`.
+pub fn compile(insns: &[u32], pc: u32) -> Option<NativeBlock> {
+    let mut asm = Assembler { code: Vec::new() };
+    let mut accesses = Vec::new();
+    let mut length = 0;
+
+    for (i, insn) in insns.iter().enumerate() {
+        let insn = *insn;
+        let supported = match pick(&insn, 0, 7) {
+            0b0110111 => {
+                asm.mov_eax(pick(&insn, 12, 20) << 12);
+                asm.store_int_reg(pick(&insn, 7, 5) as usize);
+                true
+            },
+            0b0010111 => {
+                let op_pc = pc.wrapping_add(4 * i as u32);
+                asm.mov_eax(op_pc.wrapping_add(pick(&insn, 12, 20) << 12));
+                asm.store_int_reg(pick(&insn, 7, 5) as usize);
+                true
+            },
+            0b0010011 => emit_op_imm(&mut asm, insn),
+            0b0110011 => emit_op(&mut asm, insn),
+            0b0000011 | 0b0100011 => {
+                let access = if pick(&insn, 5, 1) == 0 { emit_load(&mut asm, insn, i) } else { emit_store(&mut asm, insn, i) };
+                if access.is_some() {
+                    accesses.resize(i, None);
+                    accesses.push(access);
+                }
+                access.is_some()
+            },
+            _ => false,
+        };
+
+        if !supported {
+            break
+        }
+        length += 1;
+    }
+    if length == 0 {
+        return None
+    }
+
+    asm.exit(length);
+
+    let size = asm.code.len();
+    unsafe {
+        let code = mmap(ptr::null_mut(), size, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+        if code as isize == -1 {
+            return None
+        }
+
+        ptr::copy_nonoverlapping(asm.code.as_ptr(), code, size);
+
+        if mprotect(code, size, PROT_READ | PROT_EXEC) != 0 {
+            munmap(code, size);
+            return None
+        }
+
+        Some(NativeBlock { code, size, length, accesses })
+    }
+}
@@ -0,0 +1,475 @@
+// Linux user mode, which runs statically linked Linux programs without a kernel.
+//
+// The program runs in U-mode with flat memory at address 0, and its ECALLs are taken by the syscall
+// handler, which translates them to host calls. Memory is laid out as follows, from the top:
+//
+//   host I/O page   exit_group writes (code << 1) | 1 here, which stops the execution engines
+//   stack           argc, argv, envp and auxv as set up by execve
+//   mmap area       anonymous and file mappings, allocated downwards
+//   heap            grown upwards by brk from the end of the program
+
+use core::*;
+use csr::*;
+use elf::*;
+use syscall::*;
+use xlen::*;
+
+use std::path::PathBuf;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+pub const LINUX_MEMORY_SIZE: usize = 64 * 1024 * 1024;
+
+const PAGE_SIZE: u64 = 4096;
+const STACK_SIZE: u64 = 8 * 1024 * 1024;
+
+const PID: i64 = 1000;
+
+const SYS_GETCWD: u64 = 17;
+const SYS_IOCTL: u64 = 29;
+const SYS_READV: u64 = 65;
+const SYS_WRITEV: u64 = 66;
+const SYS_SET_TID_ADDRESS: u64 = 96;
+const SYS_FUTEX: u64 = 98;
+const SYS_SET_ROBUST_LIST: u64 = 99;
+const SYS_CLOCK_GETTIME: u64 = 113;
+const SYS_KILL: u64 = 129;
+const SYS_TKILL: u64 = 130;
+const SYS_TGKILL: u64 = 131;
+const SYS_RT_SIGACTION: u64 = 134;
+const SYS_RT_SIGPROCMASK: u64 = 135;
+const SYS_UNAME: u64 = 160;
+const SYS_GETTIMEOFDAY: u64 = 169;
+const SYS_GETPID: u64 = 172;
+const SYS_GETPPID: u64 = 173;
+const SYS_GETUID: u64 = 174;
+const SYS_GETEUID: u64 = 175;
+const SYS_GETGID: u64 = 176;
+const SYS_GETEGID: u64 = 177;
+const SYS_GETTID: u64 = 178;
+const SYS_BRK: u64 = 214;
+const SYS_MUNMAP: u64 = 215;
+// mmap2 on RV32, whose offset is in pages
+const SYS_MMAP: u64 = 222;
+const SYS_MPROTECT: u64 = 226;
+const SYS_MADVISE: u64 = 233;
+const SYS_PRLIMIT64: u64 = 261;
+const SYS_GETRANDOM: u64 = 278;
+const SYS_CLOCK_GETTIME64: u64 = 403;
+
+const MAP_FIXED: u64 = 0x10;
+const MAP_ANONYMOUS: u64 = 0x20;
+
+const ERANGE: i64 = 34;
+
+const CLOCK_REALTIME: u64 = 0;
+
+const RLIMIT_STACK: u64 = 3;
+
+const AT_NULL: u64 = 0;
+const AT_PHDR: u64 = 3;
+const AT_PHENT: u64 = 4;
+const AT_PHNUM: u64 = 5;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+const AT_UID: u64 = 11;
+const AT_EUID: u64 = 12;
+const AT_GID: u64 = 13;
+const AT_EGID: u64 = 14;
+const AT_HWCAP: u64 = 16;
+const AT_CLKTCK: u64 = 17;
+const AT_RANDOM: u64 = 25;
+const AT_EXECFN: u64 = 31;
+
+// Rounds addr up to a page boundary, or returns None if it is in the last page.
+fn page_align(addr: u64) -> Option<u64> {
+    addr.checked_add(PAGE_SIZE - 1).map(|addr| addr & !(PAGE_SIZE - 1))
+}
+
+fn write_addr<X: Xlen>(core: &mut Core<X>, addr: u64, value: u64) {
+    if X::XLEN == 32 {
+        core.bus.write_u32(addr, value as u32);
+    } else {
+        core.bus.write_u64(addr, value);
+    }
+}
+
+fn read_addr<X: Xlen>(core: &Core<X>, addr: u64) -> u64 {
+    if X::XLEN == 32 { core.bus.read_u32(addr) as u64 } else { core.bus.read_u64(addr) }
+}
+
+// Writes a struct of address-sized fields, e.g. timeval.
+fn write_addrs<X: Xlen>(core: &mut Core<X>, addr: u64, values: &[u64]) -> i64 {
+    let size = X::XLEN as u64 / 8;
+    if !is_mapped_range(core, addr, size * values.len() as u64) {
+        return -EFAULT
+    }
+    for (i, value) in values.iter().enumerate() {
+        write_addr(core, addr + size * i as u64, *value);
+    }
+    0
+}
+
+pub struct Linux {
+    files: HostFiles,
+    brk_start: u64,
+    brk: u64,
+    // Lowest address of the mmap area, which ends at the stack
+    mmap_bottom: u64,
+    mmap_top: u64,
+    // State of the generator of getrandom, which is deterministic so that runs can be reproduced.
+    random: u64,
+    start: Instant,
+}
+
+impl Linux {
+    // Loads a program into zero-filled memory at address 0, sets up its stack with args and env,
+    // and installs the syscall handler. Files are accessible in `root` only.
+    pub fn load<X: Xlen>(core: &mut Core<X>, elf: &Elf, args: &[String], env: &[String], root: Option<PathBuf>) -> Result<(), String> {
+        let memory_end = core.bus.base + core.bus.memory.body.len() as u64;
+        let stack_top = memory_end - PAGE_SIZE;
+        core.bus.memory.body.iter_mut().for_each(|b| *b = 0);
+
+        let mut program_end = 0;
+        for segment in &elf.segments {
+            core.bus.load(segment.vaddr, &segment.data)?;
+            program_end = program_end.max(segment.vaddr + segment.mem_size);
+        }
+        let brk_start = match page_align(program_end) {
+            Some(end) if end <= stack_top - STACK_SIZE => end,
+            _ => return Err("program does not fit in memory".to_string()),
+        };
+
+        let mut linux = Linux {
+            files: HostFiles::new(root),
+            brk_start,
+            brk: brk_start,
+            mmap_bottom: stack_top - STACK_SIZE,
+            mmap_top: stack_top - STACK_SIZE,
+            random: 0x2545_f491_4f6c_dd1d,
+            start: Instant::now(),
+        };
+        let sp = linux.setup_stack(core, elf, stack_top, args, env);
+
+        core.int_reg.write(2, X::Uint::from_u64(sp));
+        core.pc = X::Uint::from_u64(elf.entry);
+        core.host_io_addr = stack_top;
+        core.privilege = PRIV_USER;
+        // Allow all accesses from U-mode with a PMP entry covering the whole address space, and
+        // let it read the counters.
+        core.csr.write(CSR_INDEX_PMPADDR0, X::Uint::from_u64(u64::MAX));
+        core.csr.write(CSR_INDEX_PMPCFG0, X::Uint::from_u32((PMPCFG_R | PMPCFG_W | PMPCFG_X | (PMP_NAPOT << PMPCFG_A_SHIFT)) as u32));
+        core.csr.write(CSR_INDEX_MCOUNTEREN, X::Uint::from_u32(0x7));
+        core.csr.write(CSR_INDEX_SCOUNTEREN, X::Uint::from_u32(0x7));
+        core.syscall_handler = Some(Box::new(linux));
+        Ok(())
+    }
+
+    // Pushes the strings, then argc, argv, envp and auxv as execve does. Returns the stack pointer.
+    fn setup_stack<X: Xlen>(&mut self, core: &mut Core<X>, elf: &Elf, stack_top: u64, args: &[String], env: &[String]) -> u64 {
+        let mut sp = stack_top;
+        let mut push_bytes = |core: &mut Core<X>, data: &[u8]| {
+            sp -= data.len() as u64;
+            write_bytes(core, sp, data);
+            sp
+        };
+
+        let mut random = [0; 16];
+        self.fill_random(&mut random);
+        let random_addr = push_bytes(core, &random);
+        let mut string_addrs = |strings: &[String]| -> Vec<u64> {
+            strings.iter().map(|s| push_bytes(core, format!("{}\0", s).as_bytes())).collect()
+        };
+        let arg_addrs = string_addrs(args);
+        let env_addrs = string_addrs(env);
+        let execfn = arg_addrs.first().cloned().unwrap_or(0);
+
+        let hwcap = core.csr.read(CSR_INDEX_MISA).to_u64() & 0x3ff_ffff;
+        let mut auxv = vec![
+            (AT_PHENT, elf.ph_size),
+            (AT_PHNUM, elf.ph_num),
+            (AT_PAGESZ, PAGE_SIZE),
+            (AT_ENTRY, elf.entry),
+            (AT_UID, 0),
+            (AT_EUID, 0),
+            (AT_GID, 0),
+            (AT_EGID, 0),
+            (AT_HWCAP, hwcap),
+            (AT_CLKTCK, 100),
+            (AT_RANDOM, random_addr),
+            (AT_EXECFN, execfn),
+        ];
+        if let Some(phdr) = elf.phdr {
+            auxv.push((AT_PHDR, phdr));
+        }
+        auxv.push((AT_NULL, 0));
+
+        let mut table = vec![args.len() as u64];
+        table.extend(arg_addrs);
+        table.push(0);
+        table.extend(env_addrs);
+        table.push(0);
+        table.extend(auxv.iter().flat_map(|(key, value)| vec![*key, *value]));
+
+        // The stack pointer is 16-byte aligned at the entry point.
+        let size = X::XLEN as u64 / 8;
+        let sp = (sp - size * table.len() as u64) & !0xf;
+        for (i, value) in table.iter().enumerate() {
+            write_addr(core, sp + size * i as u64, *value);
+        }
+        sp
+    }
+
+    fn fill_random(&mut self, data: &mut [u8]) {
+        for b in data.iter_mut() {
+            // xorshift64
+            self.random ^= self.random << 13;
+            self.random ^= self.random >> 7;
+            self.random ^= self.random << 17;
+            *b = self.random as u8;
+        }
+    }
+
+    // Exits with a status as the shell reports it, e.g. 134 for abort().
+    fn exit<X: Xlen>(&self, core: &mut Core<X>, code: u64) -> i64 {
+        core.bus.write_u64(core.host_io_addr, ((code & 0xff) << 1) | 1);
+        0
+    }
+
+    fn brk<X: Xlen>(&mut self, core: &mut Core<X>, addr: u64) -> i64 {
+        if addr >= self.brk_start && addr <= self.mmap_bottom {
+            // Memory freed by shrinking the heap reads as zero when it grows again.
+            if addr > self.brk {
+                let _ = core.bus.load(self.brk, &vec![0; (addr - self.brk) as usize]);
+            }
+            self.brk = addr;
+        }
+        self.brk as i64
+    }
+
+    fn mmap<X: Xlen>(&mut self, core: &mut Core<X>, addr: u64, length: u64, flags: u64, fd: u64, offset: u64) -> i64 {
+        if length == 0 {
+            return -EINVAL
+        }
+        let length = match page_align(length) {
+            Some(length) => length,
+            None => return -ENOMEM,
+        };
+
+        let addr = if flags & MAP_FIXED != 0 {
+            if !addr.is_multiple_of(PAGE_SIZE) || addr.checked_add(length).is_none_or(|end| end > self.mmap_top) {
+                return -EINVAL
+            }
+            addr
+        } else {
+            match self.mmap_bottom.checked_sub(length) {
+                Some(bottom) if bottom >= self.brk => self.mmap_bottom = bottom,
+                _ => return -ENOMEM,
+            }
+            self.mmap_bottom
+        };
+
+        let mut data = vec![0; length as usize];
+        if flags & MAP_ANONYMOUS == 0 {
+            let offset = if X::XLEN == 32 { offset * PAGE_SIZE } else { offset };
+            match self.files.read_at(fd, offset, length) {
+                Ok(file_data) => data[..file_data.len()].copy_from_slice(&file_data),
+                Err(e) => return e,
+            }
+        }
+        match core.bus.load(addr, &data) {
+            Ok(()) => addr as i64,
+            Err(_) => -ENOMEM,
+        }
+    }
+
+    // Runs read or write for each struct iovec until one transfers less than requested.
+    fn transfer_vector<X: Xlen>(&mut self, core: &mut Core<X>, fd: u64, iov: u64, count: u64, write: bool) -> i64 {
+        let size = X::XLEN as u64 / 8;
+        if !is_mapped_range(core, iov, count.saturating_mul(2 * size)) {
+            return -EFAULT
+        }
+
+        let mut total = 0;
+        for i in 0..count {
+            let (base, length) = (read_addr(core, iov + 2 * size * i), read_addr(core, iov + 2 * size * i + size));
+            let result = if write { self.files.write(core, fd, base, length) } else { self.files.read(core, fd, base, length) };
+            if result < 0 {
+                return if total > 0 { total } else { result }
+            }
+            total += result;
+            if (result as u64) < length {
+                break
+            }
+        }
+        total
+    }
+
+    // llseek of RV32, which returns the 64-bit offset through result.
+    fn llseek<X: Xlen>(&mut self, core: &mut Core<X>, fd: u64, high: u64, low: u64, result: u64, whence: u64) -> i64 {
+        let offset = self.files.seek(fd, ((high << 32) | (low & 0xffff_ffff)) as i64, whence);
+        if offset < 0 {
+            return offset
+        }
+        if write_bytes(core, result, &(offset as u64).to_le_bytes()) { 0 } else { -EFAULT }
+    }
+
+    // Returns the time of clock as (seconds, nanoseconds), the monotonic clocks counting from the start.
+    fn clock<X: Xlen>(&self, core: &mut Core<X>, clock: u64) -> (u64, u64) {
+        let time = core.bus.host_time(core.cycle, || {
+            let time = match clock {
+                CLOCK_REALTIME => SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default(),
+                _ => self.start.elapsed(),
+            };
+            time.as_nanos() as u64
+        });
+        (time / 1_000_000_000, time % 1_000_000_000)
+    }
+
+    fn uname<X: Xlen>(&self, core: &mut Core<X>, buf: u64) -> i64 {
+        let machine = if X::XLEN == 32 { "riscv32" } else { "riscv64" };
+        let fields = ["Linux", "rafi-emu", "6.1.0", "#1", machine, ""];
+
+        // struct utsname has 6 fields of 65 bytes.
+        let mut utsname = [0; 6 * 65];
+        for (i, field) in fields.iter().enumerate() {
+            utsname[i * 65..i * 65 + field.len()].copy_from_slice(field.as_bytes());
+        }
+        if write_bytes(core, buf, &utsname) { 0 } else { -EFAULT }
+    }
+
+    fn prlimit<X: Xlen>(&self, core: &mut Core<X>, resource: u64, old_limit: u64) -> i64 {
+        if old_limit == 0 {
+            return 0
+        }
+        let limit = if resource == RLIMIT_STACK { STACK_SIZE } else { u64::MAX };
+        let mut rlimit = [0; 16];
+        rlimit[0..8].copy_from_slice(&limit.to_le_bytes());
+        rlimit[8..16].copy_from_slice(&limit.to_le_bytes());
+        if write_bytes(core, old_limit, &rlimit) { 0 } else { -EFAULT }
+    }
+
+    fn getcwd<X: Xlen>(&self, core: &mut Core<X>, buf: u64, size: u64) -> i64 {
+        // The root directory is the working directory.
+        if size < 2 {
+            return -ERANGE
+        }
+        if write_bytes(core, buf, b"/\0") { 2 } else { -EFAULT }
+    }
+
+    fn getrandom<X: Xlen>(&mut self, core: &mut Core<X>, buf: u64, length: u64) -> i64 {
+        if !is_mapped_range(core, buf, length) {
+            return -EFAULT
+        }
+        let mut data = vec![0; length as usize];
+        self.fill_random(&mut data);
+        write_bytes(core, buf, &data);
+        length as i64
+    }
+}
+
+impl<X: Xlen> SyscallHandler<X> for Linux {
+    // The syscall number is in a7, the arguments in a0-a5 and the result is returned in a0.
+    fn syscall(&mut self, core: &mut Core<X>) {
+        let number = core.int_reg.read(17).to_u64();
+        let args: Vec<u64> = (10..16).map(|i| core.int_reg.read(i).to_u64()).collect();
+
+        let result = match number {
+            SYS_EXIT | SYS_EXIT_GROUP => self.exit(core, args[0]),
+            SYS_BRK => self.brk(core, args[0]),
+            SYS_MMAP => self.mmap(core, args[0], args[1], args[3], args[4], args[5]),
+            // Memory is neither protected nor released.
+            SYS_MUNMAP | SYS_MPROTECT | SYS_MADVISE => 0,
+            SYS_READV => self.transfer_vector(core, args[0], args[1], args[2], false),
+            SYS_WRITEV => self.transfer_vector(core, args[0], args[1], args[2], true),
+            SYS_LSEEK if X::XLEN == 32 => self.llseek(core, args[0], args[1], args[2], args[3], args[4]),
+            SYS_IOCTL => -ENOTTY,
+            SYS_GETCWD => self.getcwd(core, args[0], args[1]),
+            SYS_CLOCK_GETTIME => {
+                let (seconds, nanoseconds) = self.clock(core, args[0]);
+                write_addrs(core, args[1], &[seconds, nanoseconds])
+            },
+            SYS_CLOCK_GETTIME64 => {
+                let (seconds, nanoseconds) = self.clock(core, args[0]);
+                let mut timespec = [0; 16];
+                timespec[0..8].copy_from_slice(&seconds.to_le_bytes());
+                timespec[8..16].copy_from_slice(&nanoseconds.to_le_bytes());
+                if write_bytes(core, args[1], &timespec) { 0 } else { -EFAULT }
+            },
+            SYS_GETTIMEOFDAY if args[0] != 0 => {
+                let (seconds, nanoseconds) = self.clock(core, CLOCK_REALTIME);
+                write_addrs(core, args[0], &[seconds, nanoseconds / 1000])
+            },
+            SYS_GETTIMEOFDAY => 0,
+            SYS_UNAME => self.uname(core, args[0]),
+            SYS_PRLIMIT64 => self.prlimit(core, args[1], args[3]),
+            SYS_GETRANDOM => self.getrandom(core, args[0], args[1]),
+            SYS_GETPID | SYS_GETTID | SYS_SET_TID_ADDRESS => PID,
+            SYS_GETPPID => 1,
+            SYS_GETUID | SYS_GETEUID | SYS_GETGID | SYS_GETEGID => 0,
+            // There is a single thread without signal delivery.
+            SYS_FUTEX | SYS_SET_ROBUST_LIST | SYS_RT_SIGACTION | SYS_RT_SIGPROCMASK => 0,
+            SYS_KILL | SYS_TKILL if args[1] != 0 => self.exit(core, 128 + args[1]),
+            SYS_TGKILL if args[2] != 0 => self.exit(core, 128 + args[2]),
+            SYS_KILL | SYS_TKILL | SYS_TGKILL => 0,
+            _ => self.files.syscall(core, number, &args).unwrap_or(-ENOSYS),
+        };
+
+        core.int_reg.write(10, X::Uint::from_u64(result as u64));
+    }
+}
+
+#[test]
+fn test_linux() {
+    use bus::*;
+    use memory::*;
+    use xlen::Rv64;
+
+    // ELF64 whose segment at 0x10000 loads the headers and the code at 0x10078:
+    // brk(0), then exit_group(3) with the result in s0.
+    let code: [u32; 7] = [0x0d600893, 0x00000513, 0x00000073, 0x00050413, 0x05e00893, 0x00300513, 0x00000073];
+    let mut data = vec![0u8; 0x78];
+    data[0..6].copy_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1]);
+    let mut put = |offset: usize, bytes: &[u8]| data[offset..offset + bytes.len()].copy_from_slice(bytes);
+    put(0x18, &0x10078u64.to_le_bytes());
+    put(0x20, &0x40u64.to_le_bytes());
+    put(0x36, &[56, 0, 1, 0]);
+    put(0x40, &1u32.to_le_bytes());
+    put(0x50, &0x10000u64.to_le_bytes());
+    put(0x60, &0x94u64.to_le_bytes());
+    put(0x68, &0x94u64.to_le_bytes());
+    data.extend(code.iter().flat_map(|insn| insn.to_le_bytes().to_vec()));
+    let elf = Elf::parse(&data).unwrap();
+    assert_eq!(elf.phdr, Some(0x10040));
+
+    let mut memory = Memory::with_size(16 * 1024 * 1024);
+    let mut bus = Bus::with_base(&mut memory, 0);
+    let mut core: Core<Rv64> = Core::new(&mut bus);
+    let args = ["prog".to_string(), "arg".to_string()];
+    Linux::load(&mut core, &elf, &args, &["A=B".to_string()], None).unwrap();
+
+    // argc, argv, envp and auxv
+    let sp = core.int_reg.read(2);
+    assert_eq!(sp % 16, 0);
+    assert_eq!(core.bus.read_u64(sp), 2);
+    let strings: Vec<String> = [8, 16, 32].iter().map(|i| read_string(&core, core.bus.read_u64(sp + i)).unwrap()).collect();
+    assert_eq!(strings, vec!["prog", "arg", "A=B"]);
+    assert_eq!((core.bus.read_u64(sp + 24), core.bus.read_u64(sp + 40)), (0, 0));
+    let auxv: Vec<(u64, u64)> = (0..14).map(|i| (core.bus.read_u64(sp + 48 + 16 * i), core.bus.read_u64(sp + 56 + 16 * i))).collect();
+    assert!(auxv.contains(&(AT_PHDR, 0x10040)) && auxv.contains(&(AT_PAGESZ, PAGE_SIZE)));
+    assert_eq!(auxv.last(), Some(&(AT_NULL, 0)));
+
+    while core.read_host_io() == 0 && core.cycle < 100 {
+        core.step();
+    }
+    assert_eq!(core.int_reg.read(8), 0x11000);
+    assert_eq!(core.read_host_io(), 7);
+    assert_eq!(core.csr.read(0x342), 0);
+
+    // mmap(NULL, (size_t)-1, ...) cannot be page aligned.
+    for (i, arg) in [0, !0, 3, MAP_ANONYMOUS | 0x2 /* MAP_PRIVATE */, !0, 0].iter().enumerate() {
+        core.int_reg.write(10 + i, *arg);
+    }
+    core.int_reg.write(17, SYS_MMAP);
+    assert!(core.handle_syscall());
+    assert_eq!(core.int_reg.read(10), -ENOMEM as u64);
+}
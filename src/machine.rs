@@ -0,0 +1,266 @@
+// Machine compatible with the memory map of QEMU's virt machine, which boots firmware such as OpenSBI
+// followed by a kernel. Memory is laid out as follows, from the bottom of RAM:
+//
+//   firmware   at the base of RAM, entered in M-mode with a0 = hartid and a1 = the DTB
+//   kernel     where OpenSBI's fw_jump jumps by default, which is 2MiB into RAM on RV64 and 4MiB on RV32
+//   initrd     at the middle of RAM
+//   DTB        in the last 2MiB of RAM, generated from the devices on the bus unless one is given
+
+use bus::*;
+use clint::*;
+use core::*;
+use csr::*;
+use fdt::*;
+use plic::*;
+use sifive_test::*;
+use uart::*;
+use virtio::*;
+use xlen::*;
+
+pub const VIRT_TEST_BASE: u64 = 0x0010_0000;
+pub const VIRT_CLINT_BASE: u64 = 0x0200_0000;
+pub const VIRT_PLIC_BASE: u64 = 0x0c00_0000;
+pub const VIRT_UART_BASE: u64 = 0x1000_0000;
+pub const VIRT_UART_IRQ: u32 = 10;
+// Virtio devices are in slots of VIRTIO_MMIO_SIZE bytes from VIRT_VIRTIO_BASE, with interrupts from
+// VIRT_VIRTIO_IRQ.
+pub const VIRT_VIRTIO_BASE: u64 = 0x1000_1000;
+pub const VIRT_VIRTIO_IRQ: u32 = 1;
+pub const VIRT_VIRTIO_SLOTS: usize = 8;
+pub const VIRT_RAM_BASE: u64 = 0x8000_0000;
+// Frequency of mtime, which advances by a tick per cycle
+pub const VIRT_TIMEBASE_FREQUENCY: u32 = 10_000_000;
+
+pub const DEFAULT_VIRT_MEMORY_SIZE: usize = 128 * 1024 * 1024;
+
+const DTB_AREA_SIZE: u64 = 2 * 1024 * 1024;
+
+// Images to boot. The firmware is an ELF file or a flat binary, the others are flat binaries.
+pub struct VirtImages {
+    pub firmware: Vec<u8>,
+    pub kernel: Option<Vec<u8>>,
+    pub initrd: Option<Vec<u8>>,
+    pub dtb: Option<Vec<u8>>,
+    // Kernel command line in /chosen of the generated DTB
+    pub bootargs: Option<String>,
+}
+
+// Where the images were loaded
+pub struct Virt {
+    // Range of the initrd, if any
+    pub initrd: Option<(u64, u64)>,
+    // The DTB passed to the firmware, which is generated unless one is given
+    pub dtb: Vec<u8>,
+    pub dtb_addr: u64,
+}
+
+impl Virt {
+    // Maps the devices for num_harts harts on the bus, whose memory must be at VIRT_RAM_BASE, with the
+    // virtio devices in slot order.
+    pub fn add_devices(bus: &mut Bus, num_harts: usize, uart: Uart, virtio: Vec<Box<dyn VirtioDevice>>) -> Result<(), String> {
+        if virtio.len() > VIRT_VIRTIO_SLOTS {
+            return Err(format!("too many virtio devices ({}, the maximum is {})", virtio.len(), VIRT_VIRTIO_SLOTS))
+        }
+        bus.add_device(VIRT_CLINT_BASE, Box::new(Clint::new(num_harts)));
+        bus.add_device(VIRT_PLIC_BASE, Box::new(Plic::new(num_harts)));
+        bus.add_device(VIRT_UART_BASE, Box::new(uart));
+        for (i, device) in virtio.into_iter().enumerate() {
+            let base = VIRT_VIRTIO_BASE + i as u64 * VIRTIO_MMIO_SIZE;
+            bus.add_device(base, Box::new(VirtioMmio::new(device, VIRT_VIRTIO_IRQ + i as u32)));
+        }
+        bus.add_device(VIRT_TEST_BASE, Box::new(SifiveTest::new()));
+        Ok(())
+    }
+
+    // Loads the images and sets up hart 0 to enter the firmware. The DTB describes num_harts harts.
+    pub fn load<X: Xlen>(core: &mut Core<X>, images: &VirtImages, num_harts: usize) -> Result<Virt, String> {
+        let ram_end = core.bus.base + core.bus.memory.body.len() as u64;
+        let kernel_addr = VIRT_RAM_BASE + if X::XLEN == 32 { 0x40_0000 } else { 0x20_0000 };
+        let dtb_area = ram_end - DTB_AREA_SIZE;
+
+        let entry = load_firmware(core, &images.firmware).map_err(|e| format!("firmware: {}", e))?;
+        if let Some(kernel) = &images.kernel {
+            core.bus.load(kernel_addr, kernel).map_err(|e| format!("kernel: {}", e))?;
+        }
+
+        let initrd = match &images.initrd {
+            Some(initrd) => {
+                let start = VIRT_RAM_BASE + (ram_end - VIRT_RAM_BASE) / 2;
+                if start + initrd.len() as u64 > dtb_area {
+                    return Err("initrd: does not fit in memory".to_string())
+                }
+                core.bus.load(start, initrd)?;
+                Some((start, start + initrd.len() as u64))
+            },
+            None => None,
+        };
+
+        let dtb = match &images.dtb {
+            Some(dtb) => dtb.clone(),
+            None => device_tree(core, num_harts, initrd, &images.bootargs),
+        };
+        if dtb.len() as u64 > DTB_AREA_SIZE {
+            return Err("dtb: too large".to_string())
+        }
+        core.bus.load(dtb_area, &dtb)?;
+
+        core.pc = X::Uint::from_u64(entry);
+        core.privilege = PRIV_MACHINE;
+        core.int_reg.write(10, X::Uint::ZERO);
+        core.int_reg.write(11, X::Uint::from_u64(dtb_area));
+        Ok(Virt { initrd, dtb, dtb_addr: dtb_area })
+    }
+}
+
+// Returns the ISA string of the hart for riscv,isa, e.g. "rv64ima", from the extensions in misa.
+fn isa_string<X: Xlen>(core: &Core<X>) -> String {
+    let misa = core.csr.read(CSR_INDEX_MISA).to_u64();
+    let extensions: String = "iemafdqcbvh".chars().filter(|c| misa & (1 << (*c as u8 - b'a')) != 0).collect();
+    format!("rv{}{}", X::XLEN, extensions)
+}
+
+// Generates the DTB describing the harts, the memory and the devices on the bus, with /chosen for the
+// initrd and the kernel command line.
+fn device_tree<X: Xlen>(core: &Core<X>, num_harts: usize, initrd: Option<(u64, u64)>, bootargs: &Option<String>) -> Vec<u8> {
+    let cells = |value: u64| [(value >> 32) as u32, value as u32];
+    let mut fdt = Fdt::new();
+    fdt.begin_node("");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "riscv-virtio");
+    fdt.property_string("model", "riscv-virtio,qemu");
+
+    fdt.begin_node("chosen");
+    if let Some(bootargs) = bootargs {
+        fdt.property_string("bootargs", bootargs);
+    }
+    fdt.property_string("stdout-path", &format!("/soc/serial@{:x}", VIRT_UART_BASE));
+    if let Some((start, end)) = initrd {
+        fdt.property_u64("linux,initrd-start", start);
+        fdt.property_u64("linux,initrd-end", end);
+    }
+    fdt.end_node();
+
+    let isa = isa_string(core);
+    let mut extensions: Vec<String> = isa[4..].chars().map(|c| c.to_string()).collect();
+    extensions.extend(["zicsr", "zifencei"].iter().map(|e| e.to_string()));
+    let extensions: Vec<&str> = extensions.iter().map(|e| e.as_str()).collect();
+    fdt.begin_node("cpus");
+    fdt.property_u32("#address-cells", 1);
+    fdt.property_u32("#size-cells", 0);
+    fdt.property_u32("timebase-frequency", VIRT_TIMEBASE_FREQUENCY);
+    for hart in 0..num_harts as u32 {
+        fdt.begin_node(&format!("cpu@{:x}", hart));
+        fdt.property_string("device_type", "cpu");
+        fdt.property_u32("reg", hart);
+        fdt.property_string("status", "okay");
+        fdt.property_string("compatible", "riscv");
+        fdt.property_string("riscv,isa", &isa);
+        fdt.property_string("riscv,isa-base", &isa[..4]);
+        fdt.property_strings("riscv,isa-extensions", &extensions);
+        fdt.property_string("mmu-type", if X::XLEN == 32 { "riscv,sv32" } else { "riscv,sv48" });
+        fdt.begin_node("interrupt-controller");
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_null("interrupt-controller");
+        fdt.property_string("compatible", "riscv,cpu-intc");
+        fdt.property_u32("phandle", cpu_intc_phandle(hart));
+        fdt.end_node();
+        fdt.end_node();
+    }
+    fdt.end_node();
+
+    let size = core.bus.memory.body.len() as u64;
+    fdt.begin_node(&format!("memory@{:x}", core.bus.base));
+    fdt.property_string("device_type", "memory");
+    fdt.property_cells("reg", &[cells(core.bus.base), cells(size)].concat());
+    fdt.end_node();
+
+    fdt.begin_node("soc");
+    fdt.property_u32("#address-cells", 2);
+    fdt.property_u32("#size-cells", 2);
+    fdt.property_string("compatible", "simple-bus");
+    fdt.property_null("ranges");
+    core.bus.add_device_tree_nodes(&mut fdt);
+    fdt.end_node();
+
+    fdt.end_node();
+    fdt.finish()
+}
+
+// Loads the firmware and returns its entry point.
+fn load_firmware<X: Xlen>(core: &mut Core<X>, data: &[u8]) -> Result<u64, String> {
+    if !::elf::Elf::is_elf(data) {
+        core.bus.load(VIRT_RAM_BASE, data)?;
+        return Ok(VIRT_RAM_BASE)
+    }
+
+    let elf = ::elf::Elf::parse(data)?;
+    for segment in &elf.segments {
+        let bss = vec![0; segment.mem_size.saturating_sub(segment.data.len() as u64) as usize];
+        core.bus.load(segment.addr, &segment.data)?;
+        core.bus.load(segment.addr + segment.data.len() as u64, &bss)?;
+    }
+    Ok(elf.entry)
+}
+
+#[test]
+fn test_virt() {
+    use memory::*;
+    use std::sync::mpsc::channel;
+
+    let mut memory = Memory::with_size(8 * 1024 * 1024);
+    let mut bus = Bus::new(&mut memory);
+    let (sender, receiver) = channel();
+    bus.add_device(VIRT_CLINT_BASE, Box::new(Clint::new(2)));
+    bus.add_device(VIRT_PLIC_BASE, Box::new(Plic::new(2)));
+    bus.add_device(VIRT_UART_BASE, Box::new(Uart::new(VIRT_UART_IRQ, receiver, Box::new(::std::io::sink()))));
+    let mut core: Core<Rv64> = Core::new(&mut bus);
+
+    // The firmware waits for an interrupt.
+    let firmware = [
+        0x1050_0073u32, // wfi
+        0x0000_006f,    // j 0
+    ];
+    let data: Vec<u8> = firmware.iter().flat_map(|insn| insn.to_le_bytes().to_vec()).collect();
+    let images = VirtImages { firmware: data, kernel: None, initrd: Some(vec![1; 16]), dtb: None, bootargs: Some("console=ttyS0".to_string()) };
+    let virt = Virt::load(&mut core, &images, 2).unwrap();
+    assert_eq!(virt.initrd, Some((0x8040_0000, 0x8040_0010)));
+    assert_eq!(virt.dtb_addr, 0x8060_0000);
+    assert_eq!(core.int_reg.read(11), 0x8060_0000);
+    assert_eq!(core.bus.read_u32(0x8060_0000), 0xedfe_0dd0);
+
+    // The generated DTB describes the hart, the memory, the devices and the images.
+    let dtb = &virt.dtb;
+    assert_eq!(find_property(dtb, "/chosen", "bootargs"), Some(b"console=ttyS0\0".to_vec()));
+    assert_eq!(find_property(dtb, "/chosen", "linux,initrd-end"), Some(0x8040_0010u64.to_be_bytes().to_vec()));
+    assert_eq!(find_property(dtb, "/cpus/cpu@0", "riscv,isa"), Some(b"rv64ima\0".to_vec()));
+    assert_eq!(find_property(dtb, "/cpus/cpu@1", "reg"), Some(vec![0, 0, 0, 1]));
+    assert_eq!(find_property(dtb, "/memory@80000000", "reg"), Some(vec![0, 0, 0, 0, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0x80, 0, 0]));
+    assert_eq!(find_property(dtb, "/soc/plic@c000000", "phandle"), Some(PHANDLE_PLIC.to_be_bytes().to_vec()));
+    assert_eq!(find_property(dtb, "/soc/serial@10000000", "interrupts"), Some(VIRT_UART_IRQ.to_be_bytes().to_vec()));
+    assert!(find_property(dtb, "/soc/clint@2000000", "interrupts-extended").is_some());
+
+    // Enable the UART receive interrupt through context 0 of the PLIC, which is the M-mode one.
+    core.bus.write_u32(VIRT_PLIC_BASE + 4 * VIRT_UART_IRQ as u64, 1);
+    core.bus.write_u32(VIRT_PLIC_BASE + 0x2000, 1 << VIRT_UART_IRQ);
+    core.bus.write_u8(VIRT_UART_BASE + 1, 1);
+    core.csr.write(0x304, 0x800); // mie.MEIE
+    core.csr.write(0x300, 0x8); // mstatus.MIE
+    core.csr.write(0x305, 0x8000_1000); // mtvec
+    core.bus.write_u32(0x8000_1000, 0x0000_006f); // j 0
+    for _ in 0..10 {
+        core.step();
+    }
+    assert!(core.waiting);
+    assert_eq!(core.pc, 0x8000_0004);
+
+    // The interrupt is taken before the op following WFI, and claimed from the PLIC.
+    sender.send(b'x').unwrap();
+    core.step();
+    assert!(!core.waiting);
+    assert_eq!(core.csr.read(0x342), 0x8000_0000_0000_000b);
+    assert_eq!(core.csr.read(0x341), 0x8000_0004);
+    assert_eq!(core.pc, 0x8000_1000);
+    assert_eq!(core.bus.read_u32(VIRT_PLIC_BASE + 0x20_0004), VIRT_UART_IRQ);
+    assert_eq!(core.bus.read_u8(VIRT_UART_BASE), b'x');
+}
@@ -5,85 +5,875 @@ extern crate byteorder;
 extern crate serde;
 extern crate serde_json;
 
+mod block;
 mod bus;
+mod clint;
 mod core;
 mod csr;
 mod decoder;
+mod elf;
+mod fdt;
+mod framebuffer;
+mod gdb;
+mod harness;
+mod htif;
+mod history;
+#[cfg(feature = "jit")]
+mod jit;
+mod linux;
+mod machine;
 mod memory;
+mod mmu;
+mod net;
 mod op;
+mod plic;
+mod pmp;
+mod replay;
+mod semihosting;
+mod sifive_test;
+mod smp;
+mod snapshot;
+mod syscall;
 mod trap;
+mod trigger;
+mod uart;
 mod util;
+mod virtio;
+mod virtio_blk;
+mod virtio_console;
+mod virtio_net;
+mod virtio_rng;
+mod xlen;
 
+use block::*;
 use bus::*;
 use core::*;
-use decoder::*;
+use elf::*;
+use framebuffer::*;
+use harness::*;
+use htif::*;
+use linux::*;
+use machine::*;
 use memory::*;
-use trap::*;
+use net::*;
+use replay::*;
+use semihosting::*;
+use sifive_test::*;
+use smp::*;
+use snapshot::*;
+use trigger::*;
+use uart::*;
+use virtio::*;
+use virtio_blk::*;
+use virtio_console::*;
+use virtio_net::*;
+use virtio_rng::*;
+use xlen::*;
 
-use std::fs::File;
-use std::io::BufReader;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-fn emulate(path: String) -> u32 {
-    const MAX_CYCLE: u32 = 1000;
-    const HOST_IO_ADDR: u32 = 0x80001000;
-    const INITIAL_PC: u32 = 0x8000_0000;
+const DEFAULT_MAX_CYCLE: u64 = 1000;
+const DEFAULT_TIMEOUT_SECONDS: u64 = 10;
 
-    let mut memory = Memory::new();
-    memory.load_file(path);
+const HOST_IO_ADDR: u64 = 0x80001000;
+const INITIAL_PC: u64 = 0x8000_0000;
+// fromhost follows tohost in the .tohost section of riscv-tests.
+const FROMHOST_OFFSET: u64 = 0x40;
 
-    let mut bus = Bus::new(&mut memory);
-    let mut core = Core::new(&mut bus);
+const RISCV_TESTS_DIR: &str = "rafi-prebuilt-binary/riscv-tests/isa";
+const ARCH_TEST_DIR: &str = "rafi-prebuilt-binary/riscv-arch-test";
+// Large enough for the page pool of the -v tests and the signatures of riscv-arch-test.
+const TEST_MEMORY_SIZE: usize = 4 * 1024 * 1024;
+// Number of cycles run between checks of the test timeout.
+const TIMEOUT_CHECK_INTERVAL: u64 = 100_000;
 
-    core.host_io_addr = HOST_IO_ADDR;
-    core.pc = INITIAL_PC;
+enum Engine {
+    Interpreter,
+    Block,
+    #[cfg(feature = "jit")]
+    Jit,
+}
+
+struct Options {
+    xlen: u32,
+    rv32e: bool,
+    linux: bool,
+    semihosting: bool,
+    // Boot the binary as the firmware of the virt machine
+    virt: bool,
+    memory_size: Option<usize>,
+    kernel: Option<String>,
+    initrd: Option<String>,
+    dtb: Option<String>,
+    append: Option<String>,
+    dump_dtb: Option<String>,
+    // Disk image of the virtio block device, whose writes are kept in memory with disk_cow
+    disk: Option<String>,
+    disk_cow: bool,
+    // Connect stdin to a virtio console instead of the UART
+    virtio_console: bool,
+    // Add a virtio entropy device, whose seed is from the host clock unless given
+    rng: bool,
+    rng_seed: Option<u64>,
+    // Backends of the virtio network devices
+    nets: Vec<String>,
+    // Framebuffer as (width, height, format), dumped to fb_dump every fb_dump_every frames if not 0
+    framebuffer: Option<(u32, u32, PixelFormat)>,
+    fb_dump: Option<String>,
+    fb_dump_every: u64,
+    // Number of harts, which run in turn for quantum cycles each
+    harts: usize,
+    quantum: u64,
+    record: Option<String>,
+    replay: Option<String>,
+    engine: Engine,
+    max_cycle: Option<u64>,
+    binary: Option<String>,
+    // Arguments passed to the program in Linux user mode or with semihosting, following the binary
+    guest_args: Vec<String>,
+    save_snapshot: Option<(u64, String)>,
+    restore_snapshot: Option<String>,
+    gdb_port: Option<u16>,
+    triggers: Vec<Trigger>,
+    htif_root: Option<PathBuf>,
+    jobs: usize,
+    timeout: Duration,
+    filters: Vec<String>,
+    // Run each test with the interpreter as well and fail it if the engines end differently
+    compare: bool,
+    junit: Option<String>,
+    json: Option<String>,
+}
+
+fn new_block_engine<X: Xlen>(engine: &Engine) -> Option<BlockEngine<X>> {
+    match engine {
+        Engine::Interpreter => None,
+        Engine::Block => Some(BlockEngine::new()),
+        #[cfg(feature = "jit")]
+        Engine::Jit => Some(BlockEngine::new_with_jit()),
+    }
+}
+
+// Loads a flat binary at INITIAL_PC, or the segments of an ELF file which is returned for symbol lookup.
+// ELF files start at their entry point and use `tohost` and `fromhost` for HTIF if they define them.
+fn load_program<X: Xlen>(core: &mut Core<X>, path: &str) -> Result<Option<Elf>, String> {
+    let data = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    if !Elf::is_elf(&data) {
+        core.bus.load(INITIAL_PC, &data).map_err(|e| format!("{}: {}", path, e))?;
+        return Ok(None)
+    }
+
+    let elf = Elf::parse(&data).map_err(|e| format!("{}: {}", path, e))?;
+    for segment in &elf.segments {
+        let bss = vec![0; segment.mem_size.saturating_sub(segment.data.len() as u64) as usize];
+        core.bus.load(segment.addr, &segment.data)
+            .and_then(|_| core.bus.load(segment.addr + segment.data.len() as u64, &bss))
+            .map_err(|e| format!("{}: {}", path, e))?;
+    }
+
+    core.pc = X::Uint::from_u64(elf.entry);
+    if let Some(addr) = elf.symbol("tohost") {
+        core.host_io_addr = addr;
+    }
+    Ok(Some(elf))
+}
+
+// Loads a statically linked Linux program, which is started with the binary path and guest_args as
+// its arguments and the host environment.
+fn load_linux_program<X: Xlen>(core: &mut Core<X>, path: &str, options: &Options) -> Result<(), String> {
+    let data = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    let elf = Elf::parse(&data).map_err(|e| format!("{}: {}", path, e))?;
+
+    let mut args = vec![path.to_string()];
+    args.extend(options.guest_args.iter().cloned());
+    let env: Vec<String> = env::vars().map(|(key, value)| format!("{}={}", key, value)).collect();
+    Linux::load(core, &elf, &args, &env, options.htif_root.clone()).map_err(|e| format!("{}: {}", path, e))
+}
+
+// Loads the firmware at path and the other images of the virt machine.
+fn load_virt_images<X: Xlen>(core: &mut Core<X>, path: &str, options: &Options) -> Result<(), String> {
+    let read = |path: &String| fs::read(path).map_err(|e| format!("{}: {}", path, e));
+    let images = VirtImages {
+        firmware: read(&path.to_string())?,
+        kernel: options.kernel.as_ref().map(read).transpose()?,
+        initrd: options.initrd.as_ref().map(read).transpose()?,
+        dtb: options.dtb.as_ref().map(read).transpose()?,
+        bootargs: options.append.clone(),
+    };
+    let virt = Virt::load(core, &images, options.harts)?;
+    if let Some((start, end)) = virt.initrd {
+        eprintln!("initrd: 0x{:x}-0x{:x}", start, end);
+    }
+    eprintln!("dtb: 0x{:x}-0x{:x}", virt.dtb_addr, virt.dtb_addr + virt.dtb.len() as u64);
+    if let Some(path) = &options.dump_dtb {
+        fs::write(path, &virt.dtb).map_err(|e| format!("{}: {}", path, e))?;
+    }
+    Ok(())
+}
+
+// Loads the binary as the mode in the options requires. Returns the ELF file of a bare-metal program.
+fn load_binary<X: Xlen>(core: &mut Core<X>, path: &str, options: &Options) -> Result<Option<Elf>, String> {
+    if options.linux {
+        load_linux_program(core, path, options).map(|_| None)
+    } else if options.virt {
+        load_virt_images(core, path, options).map(|_| None)
+    } else {
+        load_program(core, path)
+    }
+}
+
+fn new_htif<X: Xlen>(core: &Core<X>, elf: &Option<Elf>, root: &Option<PathBuf>) -> Htif {
+    let fromhost_addr = elf.as_ref().and_then(|elf| elf.symbol("fromhost")).unwrap_or(core.host_io_addr + FROMHOST_OFFSET);
+    Htif::new(fromhost_addr, root.clone())
+}
+
+// Runs until max_cycle or until the target exits, serving HTIF commands which stop the engines.
+fn run<X: Xlen>(core: &mut Core<X>, smp: &mut Option<Smp<X>>, block_engine: &mut Option<BlockEngine<X>>, debugger: &mut Debugger, htif: &mut Option<Htif>, max_cycle: u64) {
+    loop {
+        run_engine(core, smp, block_engine, debugger, max_cycle);
 
-    for _i in 0..MAX_CYCLE {
-        if core.read_host_io() != 0 {
+        let served = match htif {
+            Some(htif) => htif.serve(core),
+            None => false,
+        };
+        if !served || core.cycle >= max_cycle {
             break
         }
+    }
+}
+
+fn run_engine<X: Xlen>(core: &mut Core<X>, smp: &mut Option<Smp<X>>, block_engine: &mut Option<BlockEngine<X>>, debugger: &mut Debugger, max_cycle: u64) {
+    if let Some(smp) = smp {
+        return smp.run(core, block_engine, max_cycle)
+    }
+
+    if !debugger.is_empty() {
+        // Triggers are checked on every op, so they are handled by the interpreter.
+        while let Some(id) = debugger.run(core, max_cycle) {
+            let trigger = debugger.get(id).unwrap();
+            println!("Trigger {} hit at pc 0x{:08x}, cycle {}: {}", id, core.pc, core.cycle, trigger.kind);
+        }
+        return
+    }
+
+    match block_engine {
+        Some(block_engine) => {
+            block_engine.run(core, max_cycle.saturating_sub(core.cycle));
+        },
+        None => {
+            while core.cycle < max_cycle {
+                if core.read_host_io() != 0 {
+                    break
+                }
+
+                core.step();
+            }
+        },
+    }
+}
+
+fn emulate(path: Option<String>, options: &Options) -> u64 {
+    match options.xlen {
+        64 => emulate_xlen::<Rv64>(path, options),
+        _ if options.rv32e => emulate_xlen::<Rv32E>(path, options),
+        _ => emulate_xlen::<Rv32>(path, options),
+    }
+}
+
+// Opens the backends of the virtio devices of the virt machine.
+fn new_virtio_devices(bus: &mut Bus, options: &Options) -> Result<Vec<Box<dyn VirtioDevice>>, String> {
+    let mut devices: Vec<Box<dyn VirtioDevice>> = Vec::new();
+    if let Some(path) = &options.disk {
+        devices.push(Box::new(VirtioBlk::open(path, options.disk_cow)?));
+    }
+    if options.virtio_console {
+        devices.push(Box::new(VirtioConsole::with_stdio()));
+    }
+    if options.rng {
+        // The seed from the host clock is logged, so that a recorded run can be replayed.
+        let seed = options.rng_seed.unwrap_or_else(|| bus.host_time(0, || SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0)));
+        devices.push(Box::new(VirtioRng::new(seed)));
+    }
+    // The devices using a switch are connected to the same one.
+    let switch = Switch::new();
+    for (i, net) in options.nets.iter().enumerate() {
+        let backend: Box<dyn NetBackend> = match net.split_once(':') {
+            None if net == "loopback" => Box::new(Loopback::new()),
+            None if net == "switch" => Box::new(Switch::connect(&switch)),
+            Some(("unix", path)) => Box::new(UnixSocket::open(path)?),
+            Some(("pcap", path)) => Box::new(Pcap::create(path, VIRT_TIMEBASE_FREQUENCY as u64)?),
+            _ => return Err(format!("unknown network backend {}", net)),
+        };
+        let mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56 + i as u8];
+        devices.push(Box::new(VirtioNet::new(mac, backend)));
+    }
+    Ok(devices)
+}
+
+// Returns the value of tohost, which is (code << 1) | 1 when the program exited.
+fn emulate_xlen<X: Xlen>(path: Option<String>, options: &Options) -> u64 {
+    let mut memory = if options.linux {
+        Memory::with_size(LINUX_MEMORY_SIZE)
+    } else if options.virt {
+        Memory::with_size(options.memory_size.unwrap_or(DEFAULT_VIRT_MEMORY_SIZE))
+    } else {
+        Memory::new()
+    };
+    let mut bus = if options.linux { Bus::with_base(&mut memory, 0) } else { Bus::new(&mut memory) };
+    let replay = match (&options.record, &options.replay) {
+        (Some(path), _) => Some(Replay::record(path)),
+        (_, Some(path)) => Some(Replay::load(path)),
+        _ => None,
+    };
+    bus.replay = match replay.transpose() {
+        Ok(replay) => replay,
+        Err(message) => {
+            eprintln!("Failed to open the replay log: {}", message);
+            exit(1)
+        },
+    };
+    if options.virt {
+        let uart = if options.virtio_console { Uart::with_stdout(VIRT_UART_IRQ) } else { Uart::with_stdio(VIRT_UART_IRQ) };
+        let result = new_virtio_devices(&mut bus, options).and_then(|devices| Virt::add_devices(&mut bus, options.harts, uart, devices));
+        if let Err(message) = result {
+            eprintln!("Failed to add devices: {}", message);
+            exit(1)
+        }
+    }
+    if let Some((width, height, format)) = options.framebuffer {
+        // mtime advances a tick per cycle at the frequency of the virt machine.
+        let mut framebuffer = Framebuffer::new(width, height, format, VIRT_TIMEBASE_FREQUENCY as u64);
+        if let Some(path) = &options.fb_dump {
+            framebuffer.dump_to(path, options.fb_dump_every);
+        }
+        bus.add_device(FRAMEBUFFER_BASE, Box::new(framebuffer));
+    }
+    let mut core: Core<X> = Core::new(&mut bus);
+
+    // The virt machine has no HTIF, and its test device gives the host io instead.
+    core.host_io_addr = if options.virt { VIRT_TEST_BASE } else { HOST_IO_ADDR };
+    core.pc = X::Uint::from_u64(INITIAL_PC);
+
+    let elf = match path.as_ref().map(|path| load_binary(&mut core, path, options)) {
+        Some(Ok(elf)) => elf,
+        Some(Err(message)) => {
+            eprintln!("Failed to load program: {}", message);
+            exit(1)
+        },
+        None => None,
+    };
+
+    if let Some(path) = &options.restore_snapshot {
+        let result = Snapshot::load(path).and_then(|snapshot| snapshot.restore(&mut core));
+        if let Err(message) = result {
+            eprintln!("Failed to restore snapshot: {}", message);
+            exit(1)
+        }
+    }
 
-        let insn = core.fetch();
-        let op = decode(&insn);
+    if options.semihosting {
+        let cmdline: Vec<String> = options.binary.iter().chain(options.guest_args.iter()).cloned().collect();
+        core.semihosting_handler = Some(Box::new(Semihosting::new(options.htif_root.clone(), cmdline.join(" "))));
+    }
+
+    let mut block_engine = new_block_engine(&options.engine);
+
+    if let Some(port) = options.gdb_port {
+        if let Err(e) = gdb::serve(&mut core, port) {
+            eprintln!("GDB connection error: {}", e);
+        }
+        core.bus.finish_devices();
+        return core.read_host_io()
+    }
 
-        // Currently, 2-byte ops are not supported
-        core.next_pc = core.pc + 4;
+    let mut debugger = Debugger::new();
+    for trigger in &options.triggers {
+        debugger.add(trigger.clone());
+    }
+    // Linux programs exit through the syscall handler instead.
+    let mut htif = if options.linux || options.virt { None } else { Some(new_htif(&core, &elf, &options.htif_root)) };
 
-        op.execute(&mut core);
+    if let Some((cycle, path)) = &options.save_snapshot {
+        run(&mut core, &mut None, &mut block_engine, &mut debugger, &mut htif, *cycle);
 
-        match op.post_check_trap(&mut core) {
-            Some(trap) => process_trap(&mut core, &trap),
-            None => (),
+        if let Err(message) = Snapshot::take(&core).save(path) {
+            eprintln!("Failed to save snapshot: {}", message);
+            exit(1)
         }
+    }
 
-        core.pc = core.next_pc;
+    // Programs which exit through an HTIF or semihosting channel run until they do, as Linux and virt.
+    let has_exit_channel = options.semihosting || elf.as_ref().is_some_and(|elf| elf.symbol("tohost").is_some());
+    let max_cycle = options.max_cycle.unwrap_or(if options.linux || options.virt || has_exit_channel { u64::MAX } else { DEFAULT_MAX_CYCLE });
+    let mut smp = if options.harts > 1 { Some(Smp::new(&mut core, options.harts, options.quantum)) } else { None };
+    run(&mut core, &mut smp, &mut block_engine, &mut debugger, &mut htif, max_cycle);
+    // A reset of the virt machine starts the harts again from the reloaded images, with the devices in
+    // their initial state. The cycle count goes on, so max_cycle limits all the runs together.
+    while options.virt && core.read_host_io() == HOST_IO_RESET && core.cycle < max_cycle {
+        core.bus.reset_devices();
+        core.reset();
+        if let Err(message) = path.as_ref().map_or(Ok(()), |path| load_virt_images(&mut core, path, options)) {
+            eprintln!("Failed to reset: {}", message);
+            exit(1)
+        }
+        if let Some(block_engine) = &mut block_engine {
+            block_engine.flush();
+        }
+        smp = if options.harts > 1 { Some(Smp::new(&mut core, options.harts, options.quantum)) } else { None };
+        run(&mut core, &mut smp, &mut block_engine, &mut debugger, &mut htif, max_cycle);
     }
+    core.bus.finish_devices();
 
-    println!("HostIo: {}", core.read_host_io());
+    if !options.linux && !options.virt {
+        println!("HostIo: {}", core.read_host_io());
+    }
     core.read_host_io()
 }
 
+fn run_test_case(case: &TestCase, options: &Options) -> Result<TestRun, String> {
+    let run = run_test_case_with(case, &options.engine, options)?;
+    if options.compare {
+        let reference = run_test_case_with(case, &Engine::Interpreter, options)?;
+        compare_runs(&reference, &run).map_err(|message| format!("differs from the interpreter: {}", message))?;
+    }
+    Ok(run)
+}
 
-fn get_tests() -> Vec<String> {
-    let file = File::open("riscv_tests.json").unwrap();
-    let reader = BufReader::new(file);
-    let tests: Vec<String> = serde_json::from_reader(reader).unwrap();
-    
-    tests
+fn run_test_case_with(case: &TestCase, engine: &Engine, options: &Options) -> Result<TestRun, String> {
+    match case.base {
+        Base::Rv32I => run_test_case_xlen::<Rv32>(case, engine, options),
+        Base::Rv32E => run_test_case_xlen::<Rv32E>(case, engine, options),
+        Base::Rv64I => run_test_case_xlen::<Rv64>(case, engine, options),
+    }
 }
 
-fn main() {
-    let mut failed_test_num: i32 = 0;
+// Runs a test with engine until it exits through HTIF, the cycle limit or the timeout.
+fn run_test_case_xlen<X: Xlen>(case: &TestCase, engine: &Engine, options: &Options) -> Result<TestRun, String> {
+    let mut memory = Memory::with_size(TEST_MEMORY_SIZE);
+    let mut bus = Bus::new(&mut memory);
+    let mut core: Core<X> = Core::new(&mut bus);
 
-    let tests = get_tests();
-    for test in tests {
-        let path = format!("rafi-prebuilt-binary/riscv-tests/isa/{}.bin", test);
-        println!("{}", path);
+    core.host_io_addr = HOST_IO_ADDR;
+    core.pc = X::Uint::from_u64(INITIAL_PC);
+    let elf = load_program(&mut core, &case.path.to_string_lossy())?;
+
+    let mut block_engine = new_block_engine(engine);
+    let mut debugger = Debugger::new();
+    // Tests may print through HTIF, but cannot access host files.
+    let mut htif = Some(new_htif(&core, &elf, &None));
+    let max_cycle = options.max_cycle.unwrap_or(u64::MAX);
+    let deadline = Instant::now() + options.timeout;
+    let mut timed_out = false;
 
-        if emulate(path.to_string()) != 1 {
-            failed_test_num += 1;
+    while core.cycle < max_cycle && core.read_host_io() == 0 {
+        if Instant::now() >= deadline {
+            timed_out = true;
+            break
         }
+        let cycle = max_cycle.min(core.cycle.saturating_add(TIMEOUT_CHECK_INTERVAL));
+        run(&mut core, &mut None, &mut block_engine, &mut debugger, &mut htif, cycle);
+    }
+
+    let range = elf.as_ref().and_then(|elf| Some((elf.symbol("begin_signature")?, elf.symbol("end_signature")?)));
+    let signature = match range {
+        Some((begin, end)) if core.bus.is_mapped(begin, end.saturating_sub(begin) as u32) => {
+            Some((begin, (begin..end).map(|addr| core.bus.read_u8(addr)).collect()))
+        },
+        Some((begin, end)) => return Err(format!("signature 0x{:x}-0x{:x} is out of memory", begin, end)),
+        None => None,
+    };
+
+    let registers = Some(core.pc).into_iter().chain((1..32).map(|i| core.int_reg.read(i))).map(|value| value.to_u64()).collect();
+    Ok(TestRun { host_io: core.read_host_io(), cycle: core.cycle, timed_out, signature, registers })
+}
+
+// Runs the tests found in the prebuilt binary directories and writes the requested reports.
+// Returns the number of tests which did not pass.
+fn run_test_suites(options: &Options) -> usize {
+    let mut cases = find_riscv_tests(Path::new(RISCV_TESTS_DIR));
+    cases.extend(find_arch_tests(Path::new(ARCH_TEST_DIR)));
+    cases.retain(|case| options.filters.is_empty() || options.filters.iter().any(|f| case.name.contains(f.as_str())));
+
+    if cases.is_empty() {
+        eprintln!("No tests found in {} or {}", RISCV_TESTS_DIR, ARCH_TEST_DIR);
+        exit(1)
+    }
+
+    let results = run_tests(&cases, options.jobs, |case| run_test_case(case, options));
+
+    let passed = results.iter().filter(|r| r.status == Status::Pass).count();
+    let timed_out = results.iter().filter(|r| r.status == Status::Timeout).count();
+    println!("{} tests, {} passed, {} failed, {} timed out", results.len(), passed, results.len() - passed - timed_out, timed_out);
+
+    let reports = [(&options.junit, write_junit as fn(&str, &[TestResult]) -> Result<(), String>), (&options.json, write_json)];
+    for (path, write) in reports.iter() {
+        if let Some(path) = path {
+            if let Err(message) = write(path, &results) {
+                eprintln!("Failed to write report: {}", message);
+                exit(1)
+            }
+        }
+    }
+
+    results.len() - passed
+}
+fn usage(program: &str) -> ! {
+    eprintln!("Usage: {} [options] [binary [args...]]", program);
+    eprintln!("Runs riscv-tests and riscv-arch-test from rafi-prebuilt-binary when neither binary nor --restore is given.");
+    eprintln!();
+    eprintln!("Options:");
+    eprintln!("  --xlen <32|64>                    register width (default: 32)");
+    eprintln!("  --rv32e                           RV32E base ISA with x0-x15 only");
+    eprintln!("  --linux                           run a static Linux program in user mode with args");
+    eprintln!("  --semihosting                     serve semihosting calls, passing args in the command line");
+    eprintln!("  --machine virt                    boot binary as the firmware of a virt machine with a UART console");
+    eprintln!("  --harts <n>                       number of harts, which start at the entry with a0 = hartid (default: 1)");
+    eprintln!("  --quantum <n>                     cycles each hart runs before the next one (default: {})", DEFAULT_QUANTUM);
+    eprintln!("  --memory <MiB>                    memory size of the virt machine (default: {})", DEFAULT_VIRT_MEMORY_SIZE >> 20);
+    eprintln!("  --kernel <path>                   kernel image loaded for the firmware of the virt machine");
+    eprintln!("  --initrd <path>                   initrd image of the virt machine");
+    eprintln!("  --dtb <path>                      device tree blob passed to the firmware in a1 (default: generated)");
+    eprintln!("  --append <args>                   kernel command line in the generated device tree");
+    eprintln!("  --dump-dtb <path>                 write the device tree blob passed to the firmware");
+    eprintln!("  --disk <path>                     disk image of a virtio block device of the virt machine");
+    eprintln!("  --disk-cow                        keep writes to the disk in memory, leaving the image unmodified");
+    eprintln!("  --console <uart|virtio>           device of the virt machine which receives stdin (default: uart)");
+    eprintln!("  --rng                             add a virtio entropy device to the virt machine");
+    eprintln!("  --rng-seed <n>                    seed of the entropy device (default: from the host clock)");
+    eprintln!("  --net <backend>                   add a virtio network device (repeatable), backend is loopback,");
+    eprintln!("                                    switch (shared by the devices), unix:<socket path> or pcap:<path>");
+    eprintln!("  --framebuffer <w>x<h>[,<format>]  framebuffer at 0x{:x}, format is r5g6b5, r8g8b8 or x8r8g8b8 (default)", FRAMEBUFFER_BASE);
+    eprintln!("  --fb-dump <path>                  dump the framebuffer at exit and on request to a .png or .ppm file,");
+    eprintln!("                                    with %d in path replaced by the frame number");
+    eprintln!("  --fb-dump-every <n>               also dump every n frames at {} Hz", REFRESH_RATE);
+    eprintln!("  --engine <interpreter|block|jit>  execution engine (jit compiles RV32 only)");
+    eprintln!("  --max-cycle <n>                   stop after n cycles (default: {}, unlimited for tests, --linux, virt, --semihosting and ELF files with tohost)", DEFAULT_MAX_CYCLE);
+    eprintln!("  --record <path>                   log the inputs from the host, e.g. console input and time");
+    eprintln!("  --replay <path>                   repeat a recorded run, taking the inputs from its log");
+    eprintln!("  --save <cycle> <path>             save a snapshot when reaching cycle");
+    eprintln!("  --restore <path>                  start from a snapshot");
+    eprintln!("  --htif-root <dir>                 host directory which HTIF, semihosting and --linux files are in");
+    eprintln!("  --gdb <port>                      wait for GDB on port (supports reverse execution)");
+    eprintln!("  --trigger <spec>                  report when a trigger hits (repeatable), spec is");
+    eprintln!("                                    comma separated: pc=A, exec=A[-B], read=A[-B], write=A[-B],");
+    eprintln!("                                    access=A[-B], trap=CAUSE, class=CLASS, if=REG=VALUE, hit=N");
+    eprintln!();
+    eprintln!("Test options:");
+    eprintln!("  --jobs <n>                        number of tests run in parallel (default: number of CPUs)");
+    eprintln!("  --timeout <seconds>               time limit of each test (default: {})", DEFAULT_TIMEOUT_SECONDS);
+    eprintln!("  --filter <text>                   run tests whose name contains text (repeatable)");
+    eprintln!("  --compare                         also run each test with the interpreter and fail it if the");
+    eprintln!("                                    state at the end differs from the one with --engine");
+    eprintln!("  --junit <path>                    write the results as JUnit XML");
+    eprintln!("  --json <path>                     write the results as JSON");
+    exit(1)
+}
+
+// Parses <width>x<height>[,<format>], where the format is x8r8g8b8 by default.
+fn parse_framebuffer(spec: &str) -> Result<(u32, u32, PixelFormat), String> {
+    let (size, format) = match spec.split_once(',') {
+        Some((size, format)) => (size, PixelFormat::parse(format)?),
+        None => (spec, PixelFormat::X8R8G8B8),
+    };
+    let (width, height) = size.split_once('x').ok_or_else(|| format!("{} is not <width>x<height>", size))?;
+    match (width.parse(), height.parse()) {
+        (Ok(width), Ok(height)) if width > 0 && height > 0 => Ok((width, height, format)),
+        _ => Err(format!("{} is not <width>x<height>", size)),
+    }
+}
+
+fn get_options() -> Options {
+    let args: Vec<String> = env::args().collect();
+    let mut options = Options {
+        xlen: 32,
+        rv32e: false,
+        linux: false,
+        semihosting: false,
+        virt: false,
+        memory_size: None,
+        kernel: None,
+        initrd: None,
+        dtb: None,
+        append: None,
+        dump_dtb: None,
+        disk: None,
+        disk_cow: false,
+        virtio_console: false,
+        rng: false,
+        rng_seed: None,
+        nets: Vec::new(),
+        framebuffer: None,
+        fb_dump: None,
+        fb_dump_every: 0,
+        harts: 1,
+        quantum: DEFAULT_QUANTUM,
+        record: None,
+        replay: None,
+        engine: Engine::Interpreter,
+        max_cycle: None,
+        binary: None,
+        guest_args: Vec::new(),
+        save_snapshot: None,
+        restore_snapshot: None,
+        gdb_port: None,
+        triggers: Vec::new(),
+        htif_root: None,
+        jobs: thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+        timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECONDS),
+        filters: Vec::new(),
+        compare: false,
+        junit: None,
+        json: None,
+    };
+
+    let mut i = 1;
+    while i < args.len() {
+        let value = |n: usize| args.get(i + n).unwrap_or_else(|| usage(&args[0]));
+        match args[i].as_str() {
+            "--xlen" => {
+                options.xlen = match value(1).as_str() {
+                    "32" => 32,
+                    "64" => 64,
+                    _ => usage(&args[0]),
+                };
+                i += 2;
+            },
+            "--rv32e" => {
+                options.rv32e = true;
+                i += 1;
+            },
+            "--linux" => {
+                options.linux = true;
+                i += 1;
+            },
+            "--semihosting" => {
+                options.semihosting = true;
+                i += 1;
+            },
+            "--machine" => {
+                match value(1).as_str() {
+                    "virt" => options.virt = true,
+                    _ => usage(&args[0]),
+                }
+                i += 2;
+            },
+            "--record" => {
+                options.record = Some(value(1).clone());
+                i += 2;
+            },
+            "--replay" => {
+                options.replay = Some(value(1).clone());
+                i += 2;
+            },
+            "--harts" => {
+                options.harts = value(1).parse().unwrap_or_else(|_| usage(&args[0]));
+                i += 2;
+            },
+            "--quantum" => {
+                options.quantum = value(1).parse().unwrap_or_else(|_| usage(&args[0]));
+                i += 2;
+            },
+            "--memory" => {
+                let mib: usize = value(1).parse().unwrap_or_else(|_| usage(&args[0]));
+                options.memory_size = Some(mib << 20);
+                i += 2;
+            },
+            "--kernel" => {
+                options.kernel = Some(value(1).clone());
+                i += 2;
+            },
+            "--initrd" => {
+                options.initrd = Some(value(1).clone());
+                i += 2;
+            },
+            "--append" => {
+                options.append = Some(value(1).clone());
+                i += 2;
+            },
+            "--dump-dtb" => {
+                options.dump_dtb = Some(value(1).clone());
+                i += 2;
+            },
+            "--disk" => {
+                options.disk = Some(value(1).clone());
+                i += 2;
+            },
+            "--disk-cow" => {
+                options.disk_cow = true;
+                i += 1;
+            },
+            "--console" => {
+                options.virtio_console = match value(1).as_str() {
+                    "uart" => false,
+                    "virtio" => true,
+                    _ => usage(&args[0]),
+                };
+                i += 2;
+            },
+            "--rng" => {
+                options.rng = true;
+                i += 1;
+            },
+            "--rng-seed" => {
+                options.rng_seed = Some(value(1).parse().unwrap_or_else(|_| usage(&args[0])));
+                i += 2;
+            },
+            "--net" => {
+                options.nets.push(value(1).clone());
+                i += 2;
+            },
+            "--framebuffer" => {
+                options.framebuffer = match parse_framebuffer(value(1)) {
+                    Ok(framebuffer) => Some(framebuffer),
+                    Err(message) => {
+                        eprintln!("Invalid framebuffer: {}", message);
+                        usage(&args[0])
+                    },
+                };
+                i += 2;
+            },
+            "--fb-dump" => {
+                options.fb_dump = Some(value(1).clone());
+                i += 2;
+            },
+            "--fb-dump-every" => {
+                options.fb_dump_every = value(1).parse().unwrap_or_else(|_| usage(&args[0]));
+                i += 2;
+            },
+            "--dtb" => {
+                options.dtb = Some(value(1).clone());
+                i += 2;
+            },
+            "--engine" => {
+                options.engine = match value(1).as_str() {
+                    "interpreter" => Engine::Interpreter,
+                    "block" => Engine::Block,
+                    #[cfg(feature = "jit")]
+                    "jit" => Engine::Jit,
+                    _ => usage(&args[0]),
+                };
+                i += 2;
+            },
+            "--max-cycle" => {
+                options.max_cycle = Some(value(1).parse().unwrap_or_else(|_| usage(&args[0])));
+                i += 2;
+            },
+            "--save" => {
+                let cycle = value(1).parse().unwrap_or_else(|_| usage(&args[0]));
+                options.save_snapshot = Some((cycle, value(2).clone()));
+                i += 3;
+            },
+            "--restore" => {
+                options.restore_snapshot = Some(value(1).clone());
+                i += 2;
+            },
+            "--htif-root" => {
+                options.htif_root = Some(PathBuf::from(value(1)));
+                i += 2;
+            },
+            "--gdb" => {
+                options.gdb_port = Some(value(1).parse().unwrap_or_else(|_| usage(&args[0])));
+                i += 2;
+            },
+            "--trigger" => {
+                match Trigger::parse(value(1)) {
+                    Ok(trigger) => options.triggers.push(trigger),
+                    Err(message) => {
+                        eprintln!("Invalid trigger: {}", message);
+                        usage(&args[0])
+                    },
+                }
+                i += 2;
+            },
+            "--jobs" => {
+                options.jobs = value(1).parse().unwrap_or_else(|_| usage(&args[0]));
+                i += 2;
+            },
+            "--timeout" => {
+                options.timeout = Duration::from_secs(value(1).parse().unwrap_or_else(|_| usage(&args[0])));
+                i += 2;
+            },
+            "--filter" => {
+                options.filters.push(value(1).clone());
+                i += 2;
+            },
+            "--compare" => {
+                options.compare = true;
+                i += 1;
+            },
+            "--junit" => {
+                options.junit = Some(value(1).clone());
+                i += 2;
+            },
+            "--json" => {
+                options.json = Some(value(1).clone());
+                i += 2;
+            },
+            // Options may follow the binary, so args starting with '-' are passed after "--".
+            "--" => {
+                let mut rest = args[i + 1..].iter().cloned();
+                if options.binary.is_none() {
+                    options.binary = rest.next();
+                }
+                options.guest_args.extend(rest);
+                break
+            },
+            arg if arg.starts_with('-') => usage(&args[0]),
+            arg if options.binary.is_some() => {
+                options.guest_args.push(arg.to_string());
+                i += 1;
+            },
+            arg => {
+                options.binary = Some(arg.to_string());
+                i += 1;
+            },
+        }
+    }
+
+    if (options.rv32e && options.xlen != 32) || (options.linux && (options.rv32e || options.binary.is_none())) || (!options.linux && !options.semihosting && !options.guest_args.is_empty()) {
+        usage(&args[0])
+    }
+    let virt_images = options.memory_size.is_some() || options.kernel.is_some() || options.initrd.is_some() || options.dtb.is_some()
+        || options.append.is_some() || options.dump_dtb.is_some() || options.disk.is_some() || (options.disk_cow && options.disk.is_none())
+        || options.virtio_console || options.rng || (options.rng_seed.is_some() && !options.rng) || !options.nets.is_empty();
+    if (options.virt && (options.linux || options.binary.is_none())) || (!options.virt && virt_images) {
+        usage(&args[0])
+    }
+    if (options.record.is_some() && options.replay.is_some()) || ((options.record.is_some() || options.replay.is_some()) && options.binary.is_none()) {
+        usage(&args[0])
+    }
+    if (options.framebuffer.is_none() && (options.fb_dump.is_some() || options.fb_dump_every != 0)) || (options.fb_dump.is_none() && options.fb_dump_every != 0)
+        || (options.framebuffer.is_some() && options.linux) {
+        usage(&args[0])
+    }
+    // Snapshots do not have the state of the Linux and semihosting handlers, e.g. open files.
+    if (options.save_snapshot.is_some() || options.restore_snapshot.is_some()) && (options.linux || options.semihosting) {
+        usage(&args[0])
+    }
+    // Debugging, snapshots and Linux programs support a single hart.
+    let single_hart = options.linux || options.gdb_port.is_some() || !options.triggers.is_empty() || options.save_snapshot.is_some() || options.restore_snapshot.is_some();
+    if options.harts == 0 || options.quantum == 0 || (options.harts > 1 && (single_hart || options.binary.is_none())) {
+        usage(&args[0])
+    }
+
+    options
+}
+
+fn main() {
+    let options = get_options();
+
+    if options.binary.is_some() || options.restore_snapshot.is_some() {
+        // The exit code of the program, or 1 at the cycle limit. Codes beyond the exit status, e.g. the
+        // number of a failed test, are 255 so that they are not truncated to success.
+        let host_io = emulate(options.binary.clone(), &options);
+        exit(if host_io & 1 == 1 { (host_io >> 1).min(255) as i32 } else { 1 })
     }
 
-    exit(failed_test_num)
+    let failed_test_num = run_test_suites(&options);
+    exit(if failed_test_num == 0 { 0 } else { 1 })
 }
@@ -1,7 +1,8 @@
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use std::io::prelude::*;
 use std::io::Cursor;
-use std::fs::File;
+
+pub const MEMORY_INITIAL_VALUE: u8 = 0xff;
+pub const DEFAULT_MEMORY_SIZE: usize = 65536;
 
 pub struct Memory {
     pub body: Vec<u8>,
@@ -9,15 +10,11 @@ pub struct Memory {
 
 impl Memory {
     pub fn new() -> Memory {
-        let memory_size = 65536;
-
-        Memory { body: vec![0xff; memory_size] }
+        Memory::with_size(DEFAULT_MEMORY_SIZE)
     }
 
-    pub fn load_file(&mut self, path: String) {
-        let mut f = File::open(path).unwrap();
-
-        f.read(&mut self.body[..]).unwrap();    
+    pub fn with_size(memory_size: usize) -> Memory {
+        Memory { body: vec![MEMORY_INITIAL_VALUE; memory_size] }
     }
 
     pub fn read_u8(&self, addr: u64) -> u8 {
@@ -41,6 +38,13 @@ impl Memory {
         cursor.read_u32::<LittleEndian>().unwrap()
     }
 
+    pub fn read_u64(&self, addr: u64) -> u64 {
+        let mut cursor = Cursor::new(&self.body);
+        Cursor::set_position(&mut cursor, addr);
+    
+        cursor.read_u64::<LittleEndian>().unwrap()
+    }
+
     pub fn write_u8(&mut self, addr: u64, value: u8) {
         let mut cursor = Cursor::new(&mut self.body);
         Cursor::set_position(&mut cursor, addr);
@@ -61,4 +65,11 @@ impl Memory {
     
         cursor.write_u32::<LittleEndian>(value).unwrap();
     }
+
+    pub fn write_u64(&mut self, addr: u64, value: u64) {
+        let mut cursor = Cursor::new(&mut self.body);
+        Cursor::set_position(&mut cursor, addr);
+    
+        cursor.write_u64::<LittleEndian>(value).unwrap();
+    }
 }
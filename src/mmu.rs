@@ -0,0 +1,401 @@
+// Virtual memory translation for Sv32, Sv39 and Sv48.
+//
+// All modes share the page-table walk, the permission checks and the TLB, and differ only in the
+// parameters of PagingMode.
+
+use bus::*;
+use core::*;
+use csr::*;
+use pmp::*;
+use xlen::*;
+
+use std::collections::HashMap;
+
+// satp.MODE values
+pub const SATP_MODE_BARE: u64 = 0;
+pub const SATP_MODE_SV32: u64 = 1;
+pub const SATP_MODE_SV39: u64 = 8;
+pub const SATP_MODE_SV48: u64 = 9;
+
+const PAGE_SHIFT: u32 = 12;
+
+// Translations are cached per 4KiB page; the TLB is flushed when it grows beyond this.
+const MAX_TLB_ENTRIES: usize = 4096;
+
+// PTE flags
+const PTE_V: u64 = 1 << 0;
+const PTE_R: u64 = 1 << 1;
+const PTE_W: u64 = 1 << 2;
+const PTE_X: u64 = 1 << 3;
+const PTE_U: u64 = 1 << 4;
+const PTE_A: u64 = 1 << 6;
+const PTE_D: u64 = 1 << 7;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MemoryAccess {
+    Fetch,
+    Load,
+    Store,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MmuFault {
+    // Page table entry is not accessible, or denied by PMP
+    Access,
+    Page,
+}
+
+struct PagingMode {
+    levels: u32,
+    vpn_bits: u32,
+    pte_size: u32,
+    ppn_mask: u64,
+    // PTE bits which must be zero
+    reserved_mask: u64,
+}
+
+const SV32: PagingMode = PagingMode { levels: 2, vpn_bits: 10, pte_size: 4, ppn_mask: 0x3f_ffff, reserved_mask: 0 };
+const SV39: PagingMode = PagingMode { levels: 3, vpn_bits: 9, pte_size: 8, ppn_mask: 0xfff_ffff_ffff, reserved_mask: 0xffc0_0000_0000_0000 };
+const SV48: PagingMode = PagingMode { levels: 4, vpn_bits: 9, pte_size: 8, ppn_mask: 0xfff_ffff_ffff, reserved_mask: 0xffc0_0000_0000_0000 };
+
+impl PagingMode {
+    fn va_bits(&self) -> u32 {
+        PAGE_SHIFT + self.levels * self.vpn_bits
+    }
+
+    fn vpn(&self, vaddr: u64, level: u32) -> u64 {
+        (vaddr >> (PAGE_SHIFT + level * self.vpn_bits)) & ((1 << self.vpn_bits) - 1)
+    }
+
+    fn ppn(&self, pte: u64) -> u64 {
+        (pte >> 10) & self.ppn_mask
+    }
+}
+
+// Splits satp into (MODE, PPN).
+pub fn decode_satp<X: Xlen>(satp: X::Uint) -> (u64, u64) {
+    let satp = satp.to_u64();
+    match X::XLEN {
+        32 => (satp >> 31, satp & 0x3f_ffff),
+        _ => (satp >> 60, satp & 0xfff_ffff_ffff),
+    }
+}
+
+pub fn is_valid_satp_mode<X: Xlen>(mode: u64) -> bool {
+    match X::XLEN {
+        32 => mode == SATP_MODE_BARE || mode == SATP_MODE_SV32,
+        _ => mode == SATP_MODE_BARE || mode == SATP_MODE_SV39 || mode == SATP_MODE_SV48,
+    }
+}
+
+fn paging_mode(mode: u64) -> Option<&'static PagingMode> {
+    match mode {
+        SATP_MODE_SV32 => Some(&SV32),
+        SATP_MODE_SV39 => Some(&SV39),
+        SATP_MODE_SV48 => Some(&SV48),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Copy)]
+struct TlbEntry {
+    // Physical address of the 4KiB page
+    page: u64,
+    pte: u64,
+}
+
+// Checks the leaf PTE flags against the access.
+fn check_permission(pte: u64, access: MemoryAccess, privilege: u32, mstatus: &MSTATUS) -> bool {
+    let user_page = pte & PTE_U != 0;
+    let allowed = match access {
+        MemoryAccess::Fetch => pte & PTE_X != 0,
+        MemoryAccess::Load => pte & PTE_R != 0 || (mstatus.mxr() == 1 && pte & PTE_X != 0),
+        MemoryAccess::Store => pte & PTE_W != 0,
+    };
+
+    let privilege_ok = if privilege == PRIV_USER {
+        user_page
+    } else {
+        // Supervisor may access user pages only with SUM, and may never execute them.
+        !user_page || (access != MemoryAccess::Fetch && mstatus.sum() == 1)
+    };
+
+    allowed && privilege_ok
+}
+
+pub struct Mmu {
+    tlb: HashMap<u64, TlbEntry>,
+    // Num of page table walks
+    pub tlb_misses: u64,
+}
+
+impl Mmu {
+    pub fn new() -> Mmu {
+        Mmu { tlb: HashMap::new(), tlb_misses: 0 }
+    }
+
+    pub fn flush(&mut self) {
+        self.tlb.clear();
+    }
+
+    // Translates vaddr for an access made at privilege. Accesses are translated by their start
+    // address only, so a misaligned access crossing a page continues in the next physical page.
+    // The TLB is not tagged by satp and must be flushed when satp is written.
+    pub fn translate<X: Xlen>(&mut self, bus: &mut Bus, csr: &Csr<X>, vaddr: X::Uint, access: MemoryAccess, privilege: u32) -> Result<u64, MmuFault> {
+        let satp = csr.read_satp();
+        let mstatus = &csr.read_mstatus();
+        let (mode, root) = decode_satp::<X>(satp);
+        let vaddr = vaddr.to_u64();
+        let paging = match paging_mode(mode) {
+            Some(paging) => paging,
+            None => return Ok(vaddr),
+        };
+
+        // Sv39 and Sv48 addresses must be sign-extended from the top VA bit.
+        if X::XLEN > paging.va_bits() {
+            let upper = (vaddr as i64 >> (paging.va_bits() - 1)) as u64;
+            if upper != 0 && upper != !0 {
+                return Err(MmuFault::Page)
+            }
+        }
+
+        let offset = vaddr & ((1 << PAGE_SHIFT) - 1);
+        let key = (vaddr & ((1u64 << paging.va_bits()) - 1)) >> PAGE_SHIFT;
+
+        if let Some(entry) = self.tlb.get(&key) {
+            if !check_permission(entry.pte, access, privilege, mstatus) {
+                return Err(MmuFault::Page)
+            }
+            // A store to a page not yet marked dirty walks the table again to set D.
+            if access != MemoryAccess::Store || entry.pte & PTE_D != 0 {
+                return Ok(entry.page | offset)
+            }
+        }
+
+        self.tlb_misses += 1;
+        let (pte_addr, pte, level) = self.walk(bus, csr, paging, root, vaddr)?;
+        if !check_permission(pte, access, privilege, mstatus) {
+            return Err(MmuFault::Page)
+        }
+
+        // Superpages must be aligned to their size.
+        let ppn = paging.ppn(pte);
+        let superpage_mask = (1 << (level * paging.vpn_bits)) - 1;
+        if ppn & superpage_mask != 0 {
+            return Err(MmuFault::Page)
+        }
+
+        let updated = pte | PTE_A | if access == MemoryAccess::Store { PTE_D } else { 0 };
+        if updated != pte {
+            if !check_pmp(csr, pte_addr, paging.pte_size, MemoryAccess::Store, PRIV_SUPERVISOR) {
+                return Err(MmuFault::Access)
+            }
+            match paging.pte_size {
+                4 => bus.write_u32(pte_addr, updated as u32),
+                _ => bus.write_u64(pte_addr, updated),
+            }
+        }
+
+        let vpn = (vaddr >> PAGE_SHIFT) & superpage_mask;
+        let entry = TlbEntry { page: (ppn | vpn) << PAGE_SHIFT, pte: updated };
+        if self.tlb.len() >= MAX_TLB_ENTRIES {
+            self.tlb.clear();
+        }
+        self.tlb.insert(key, entry);
+
+        Ok(entry.page | offset)
+    }
+
+    // Returns (address, value, level) of the leaf PTE mapping vaddr.
+    fn walk<X: Xlen>(&self, bus: &Bus, csr: &Csr<X>, paging: &PagingMode, root: u64, vaddr: u64) -> Result<(u64, u64, u32), MmuFault> {
+        let mut table = root << PAGE_SHIFT;
+        let mut level = paging.levels - 1;
+
+        loop {
+            let pte_addr = table + paging.vpn(vaddr, level) * paging.pte_size as u64;
+            // Page table accesses are checked by PMP as S-mode loads.
+            if !bus.is_mapped(pte_addr, paging.pte_size) || !check_pmp(csr, pte_addr, paging.pte_size, MemoryAccess::Load, PRIV_SUPERVISOR) {
+                return Err(MmuFault::Access)
+            }
+            let pte = match paging.pte_size {
+                4 => bus.read_u32(pte_addr) as u64,
+                _ => bus.read_u64(pte_addr),
+            };
+
+            if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) || pte & paging.reserved_mask != 0 {
+                return Err(MmuFault::Page)
+            }
+
+            if pte & (PTE_R | PTE_X) != 0 {
+                return Ok((pte_addr, pte, level))
+            }
+
+            // Pointer to the next level
+            if level == 0 {
+                return Err(MmuFault::Page)
+            }
+            table = paging.ppn(pte) << PAGE_SHIFT;
+            level -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+use op::*;
+
+#[cfg(test)]
+fn access(core: &mut Core<Rv64>, op: &dyn Op<Rv64>, addr: u64) -> Result<u64, u32> {
+    core.privilege = PRIV_SUPERVISOR;
+    core.int_reg.write(1, addr);
+    core.execute(op);
+    match core.last_trap_cause {
+        Some(cause) => {
+            assert_eq!(core.csr.read(0x343), addr);
+            Err(cause)
+        },
+        None => Ok(core.int_reg.read(2)),
+    }
+}
+
+#[test]
+fn test_sv39_sv48() {
+    use memory::*;
+
+    let mut memory = Memory::new();
+    let mut bus = Bus::new(&mut memory);
+    for addr in (0x8000_4000..0x8000_9000).step_by(8) {
+        bus.write_u64(addr, 0);
+    }
+    bus.write_u64(0x8000_8000, 0x1234);
+
+    // Sv39 root: gigapages at 0 and 0xffff_ffc0_0000_0000, and a 4KiB page at 0x4000_2000 through
+    // two tables, mapped read-only.
+    let rwx = PTE_V | PTE_R | PTE_W | PTE_X;
+    bus.write_u64(0x8000_4000, (0x80000 << 10) | rwx);
+    bus.write_u64(0x8000_4000 + 256 * 8, (0x80000 << 10) | rwx);
+    bus.write_u64(0x8000_4008, (0x80005 << 10) | PTE_V);
+    bus.write_u64(0x8000_5000, (0x80006 << 10) | PTE_V);
+    bus.write_u64(0x8000_6010, (0x80008 << 10) | PTE_V | PTE_R);
+    // Sv48 root pointing to the Sv39 root
+    bus.write_u64(0x8000_7000, (0x80004 << 10) | PTE_V);
+
+    let mut core: Core<Rv64> = Core::new(&mut bus);
+    // PMP entry 0 grants RWX on the whole address space.
+    core.csr.write(0x3b0, !0);
+    core.csr.write(0x3a0, 0x1f);
+    let ld = LD { rd: 2, rs1: 1, imm: 0 };
+    let sd = SD { rs1: 1, rs2: 0, imm: 0 };
+
+    core.write_csr(0x180, (SATP_MODE_SV39 << 60) | 0x80004);
+    assert_eq!(access(&mut core, &ld, 0x4000_2000), Ok(0x1234));
+    assert_eq!(core.bus.read_u64(0x8000_6010) & (PTE_A | PTE_D), PTE_A);
+    assert_eq!(access(&mut core, &sd, 0x4000_2000), Err(15));
+    assert_eq!(access(&mut core, &ld, 0x8000), Ok(0x1234));
+    assert_eq!(access(&mut core, &ld, 0xffff_ffc0_0000_8000), Ok(0x1234));
+    assert_eq!(access(&mut core, &ld, 0x40_0000_8000), Err(13));
+    assert_eq!(access(&mut core, &ld, 0x8000_0000), Err(13));
+
+    // The dirty bit is set by the first store.
+    assert!(access(&mut core, &sd, 0x8008).is_ok());
+    assert_eq!(core.bus.read_u64(0x8000_8008), 0);
+    assert_eq!(core.bus.read_u64(0x8000_4000) & (PTE_A | PTE_D), PTE_A | PTE_D);
+
+    // User mode cannot access supervisor pages.
+    core.int_reg.write(1, 0x8000);
+    core.privilege = PRIV_USER;
+    core.execute(&ld);
+    assert_eq!(core.last_trap_cause, Some(13));
+
+    // Unsupported modes are ignored.
+    core.write_csr(0x180, 10 << 60);
+    assert_eq!(core.csr.read_satp(), (SATP_MODE_SV39 << 60) | 0x80004);
+
+    core.write_csr(0x180, (SATP_MODE_SV48 << 60) | 0x80007);
+    assert_eq!(access(&mut core, &ld, 0x40_0000_8000), Ok(0x1234));
+    assert_eq!(access(&mut core, &ld, 0xffff_ffc0_0000_8000), Err(13));
+    assert_eq!(access(&mut core, &ld, 0x8000_0000_0000), Err(13));
+
+    core.write_csr(0x180, 0);
+    assert_eq!(access(&mut core, &ld, 0x8000_8000), Ok(0x1234));
+}
+
+#[test]
+fn test_sv32() {
+    use memory::*;
+
+    let mut memory = Memory::new();
+    let mut bus = Bus::new(&mut memory);
+    for addr in (0x8000_4000..0x8000_5000).step_by(4) {
+        bus.write_u32(addr, 0);
+    }
+    // Megapage at 0x8000_0000 which is identity mapped, and a misaligned megapage at 0x0
+    bus.write_u32(0x8000_4000 + 0x200 * 4, ((0x80000 << 10) | PTE_V | PTE_R | PTE_X) as u32);
+    bus.write_u32(0x8000_4000, ((0x80001 << 10) | PTE_V | PTE_R) as u32);
+
+    let mut core: Core = Core::new(&mut bus);
+    core.csr.write(0x3b0, !0);
+    core.csr.write(0x3a0, 0x1f);
+    core.write_csr(0x180, (1 << 31) | 0x80004);
+    core.privilege = PRIV_SUPERVISOR;
+
+    assert_eq!(core.translate(0x8000_1234, 4, MemoryAccess::Load), Some(0x8000_1234));
+    assert_eq!(core.translate(0x8000_1234, 4, MemoryAccess::Store), None);
+    assert_eq!(core.translate(0x1234, 4, MemoryAccess::Fetch), None);
+}
+
+#[test]
+fn test_mmu_faults() {
+    use memory::*;
+
+    let mut memory = Memory::new();
+    let mut bus = Bus::new(&mut memory);
+    for addr in (0x8000_4000..0x8000_7000).step_by(8) {
+        bus.write_u64(addr, 0);
+    }
+    bus.write_u64(0x8000_8000, 0x1234);
+
+    // Sv39 root with gigapages to 0x8000_0000: 0 a user page, 1 invalid, 2 W without R, 3 through
+    // tables ending with a pointer at level 0, 4 through a table outside memory, 5 execute-only,
+    // 6 with a reserved bit.
+    let ppn = 0x80000 << 10;
+    bus.write_u64(0x8000_4000, ppn | PTE_V | PTE_R | PTE_U);
+    bus.write_u64(0x8000_4010, ppn | PTE_V | PTE_W);
+    bus.write_u64(0x8000_4018, (0x80005 << 10) | PTE_V);
+    bus.write_u64(0x8000_5000, (0x80006 << 10) | PTE_V);
+    bus.write_u64(0x8000_6000, ppn | PTE_V);
+    bus.write_u64(0x8000_4020, (0x1 << 10) | PTE_V);
+    bus.write_u64(0x8000_4028, ppn | PTE_V | PTE_X);
+    bus.write_u64(0x8000_4030, (1 << 60) | ppn | PTE_V | PTE_R);
+
+    let mut core: Core<Rv64> = Core::new(&mut bus);
+    core.csr.write(0x3b0, !0);
+    core.csr.write(0x3a0, 0x1f);
+    core.write_csr(0x180, (SATP_MODE_SV39 << 60) | 0x80004);
+    let ld = LD { rd: 2, rs1: 1, imm: 0 };
+    let sd = SD { rs1: 1, rs2: 0, imm: 0 };
+
+    // Supervisor accesses user pages only with SUM, which is checked on TLB hits too.
+    assert_eq!(access(&mut core, &ld, 0x8000), Err(13));
+    core.csr.write(0x300, 1 << 18);
+    assert_eq!(access(&mut core, &ld, 0x8000), Ok(0x1234));
+    core.csr.write(0x300, 0);
+    assert_eq!(access(&mut core, &ld, 0x8000), Err(13));
+
+    assert_eq!(access(&mut core, &ld, 0x4000_8000), Err(13));
+    assert_eq!(access(&mut core, &sd, 0x4000_8000), Err(15));
+    assert_eq!(access(&mut core, &ld, 0x8000_8000), Err(13));
+    assert_eq!(access(&mut core, &sd, 0x8000_8000), Err(15));
+    assert_eq!(access(&mut core, &ld, 0xc000_8000), Err(13));
+    assert_eq!(access(&mut core, &ld, 0x1_0000_8000), Err(5));
+    assert_eq!(access(&mut core, &sd, 0x1_0000_8000), Err(7));
+    assert_eq!(access(&mut core, &ld, 0x1_8000_8000), Err(13));
+
+    // Execute-only pages are readable only with MXR.
+    assert_eq!(access(&mut core, &ld, 0x1_4000_8000), Err(13));
+    core.csr.write(0x300, 1 << 19);
+    assert_eq!(access(&mut core, &ld, 0x1_4000_8000), Ok(0x1234));
+
+    // Supervisor never executes user pages, even with SUM.
+    core.csr.write(0x300, 1 << 18);
+    assert_eq!(core.translate(0x8000, 4, MemoryAccess::Fetch), None);
+}
@@ -0,0 +1,279 @@
+// Backends of network devices, which carry Ethernet frames without the host network:
+//
+//   loopback  frames come back to the device which sent them
+//   switch    frames go between the devices connected to a switch in the emulator, by MAC address
+//   unix      frames go to a peer, e.g. another emulator, over a Unix socket with a 4-byte big-endian
+//             length before each frame. The first instance listens at the path and the second connects.
+//   pcap      frames are written to a pcap file, and none are received
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::rc::Rc;
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// Largest frame carried, which is a full Ethernet frame with a VLAN tag but without the FCS
+pub const MAX_FRAME_SIZE: usize = 1518;
+
+pub trait NetBackend {
+    // Sends a frame at `time`, in ticks of mtime.
+    fn send(&mut self, frame: &[u8], time: u64);
+
+    // Takes the frames which arrived.
+    fn receive(&mut self) -> Vec<Vec<u8>>;
+}
+
+pub struct Loopback {
+    frames: VecDeque<Vec<u8>>,
+}
+
+impl Loopback {
+    pub fn new() -> Loopback {
+        Loopback { frames: VecDeque::new() }
+    }
+}
+
+impl NetBackend for Loopback {
+    fn send(&mut self, frame: &[u8], _time: u64) {
+        self.frames.push_back(frame.to_vec());
+    }
+
+    fn receive(&mut self) -> Vec<Vec<u8>> {
+        self.frames.drain(..).collect()
+    }
+}
+
+// Switch which learns the port of each source address. Frames to other addresses go to every port
+// but the one they came from.
+pub struct Switch {
+    // Frames waiting at each port
+    ports: Vec<VecDeque<Vec<u8>>>,
+    ports_by_mac: HashMap<[u8; 6], usize>,
+}
+
+pub struct SwitchPort {
+    switch: Rc<RefCell<Switch>>,
+    port: usize,
+}
+
+impl Switch {
+    pub fn new() -> Rc<RefCell<Switch>> {
+        Rc::new(RefCell::new(Switch { ports: Vec::new(), ports_by_mac: HashMap::new() }))
+    }
+
+    pub fn connect(switch: &Rc<RefCell<Switch>>) -> SwitchPort {
+        let mut s = switch.borrow_mut();
+        s.ports.push(VecDeque::new());
+        SwitchPort { switch: switch.clone(), port: s.ports.len() - 1 }
+    }
+
+    fn forward(&mut self, from: usize, frame: &[u8]) {
+        if frame.len() < 12 {
+            return
+        }
+        let mut destination = [0; 6];
+        let mut source = [0; 6];
+        destination.copy_from_slice(&frame[0..6]);
+        source.copy_from_slice(&frame[6..12]);
+        // Multicast addresses are not learnt.
+        if source[0] & 1 == 0 {
+            self.ports_by_mac.insert(source, from);
+        }
+        match self.ports_by_mac.get(&destination) {
+            Some(&port) if port != from => self.ports[port].push_back(frame.to_vec()),
+            Some(_) => (),
+            None => {
+                for (port, frames) in self.ports.iter_mut().enumerate() {
+                    if port != from {
+                        frames.push_back(frame.to_vec());
+                    }
+                }
+            },
+        }
+    }
+}
+
+impl NetBackend for SwitchPort {
+    fn send(&mut self, frame: &[u8], _time: u64) {
+        self.switch.borrow_mut().forward(self.port, frame);
+    }
+
+    fn receive(&mut self) -> Vec<Vec<u8>> {
+        self.switch.borrow_mut().ports[self.port].drain(..).collect()
+    }
+}
+
+pub struct UnixSocket {
+    // The connection to the peer, once there is one
+    stream: Arc<Mutex<Option<UnixStream>>>,
+    frames: Receiver<Vec<u8>>,
+}
+
+impl UnixSocket {
+    // Connects to the peer listening at path, or listens there for one. Frames sent before the peer
+    // connects are dropped.
+    pub fn open(path: &str) -> Result<UnixSocket, String> {
+        let stream = Arc::new(Mutex::new(None));
+        let (sender, frames) = channel();
+        let reader_stream = stream.clone();
+        let read_frames = move |mut reader: UnixStream| {
+            let mut length = [0; 4];
+            while reader.read_exact(&mut length).is_ok() {
+                // The peer does not speak the protocol.
+                if u32::from_be_bytes(length) as usize > MAX_FRAME_SIZE {
+                    break
+                }
+                let mut frame = vec![0; u32::from_be_bytes(length) as usize];
+                if reader.read_exact(&mut frame).is_err() || sender.send(frame).is_err() {
+                    break
+                }
+            }
+        };
+
+        match UnixStream::connect(path) {
+            Ok(peer) => {
+                let reader = peer.try_clone().map_err(|e| format!("{}: {}", path, e))?;
+                *stream.lock().unwrap() = Some(peer);
+                thread::spawn(move || read_frames(reader));
+            },
+            Err(_) => {
+                // A socket left by a listener which exited is replaced.
+                if fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+                    let _ = fs::remove_file(path);
+                }
+                let listener = UnixListener::bind(path).map_err(|e| format!("{}: {}", path, e))?;
+                thread::spawn(move || {
+                    if let Ok((peer, _)) = listener.accept() {
+                        if let Ok(reader) = peer.try_clone() {
+                            *reader_stream.lock().unwrap() = Some(peer);
+                            read_frames(reader);
+                        }
+                    }
+                });
+            },
+        }
+        Ok(UnixSocket { stream, frames })
+    }
+}
+
+impl NetBackend for UnixSocket {
+    fn send(&mut self, frame: &[u8], _time: u64) {
+        let mut stream = self.stream.lock().unwrap();
+        let mut data = (frame.len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(frame);
+        let failed = match &mut *stream {
+            Some(peer) => peer.write_all(&data).is_err(),
+            None => false,
+        };
+        // The peer has gone.
+        if failed {
+            *stream = None;
+        }
+    }
+
+    fn receive(&mut self) -> Vec<Vec<u8>> {
+        self.frames.try_iter().collect()
+    }
+}
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const PCAP_LINKTYPE_ETHERNET: u32 = 1;
+
+// Writer of sent frames to a pcap file, timestamped by the time of the machine
+pub struct Pcap {
+    path: String,
+    // The file, which is closed when a write fails
+    writer: Option<BufWriter<File>>,
+    // Frequency of the ticks of mtime
+    timebase_frequency: u64,
+}
+
+impl Pcap {
+    pub fn create(path: &str, timebase_frequency: u64) -> Result<Pcap, String> {
+        let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+        let mut pcap = Pcap { path: path.to_string(), writer: Some(BufWriter::new(file)), timebase_frequency };
+        // Version 2.4, GMT, accuracy, snaplen and link type
+        let header = [PCAP_MAGIC, 0x0004_0002, 0, 0, MAX_FRAME_SIZE as u32, PCAP_LINKTYPE_ETHERNET];
+        pcap.write(&header.iter().flat_map(|field| field.to_le_bytes().to_vec()).collect::<Vec<u8>>());
+        Ok(pcap)
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        // Records are flushed so that the file is complete when the emulator is killed.
+        let result = match &mut self.writer {
+            Some(writer) => writer.write_all(data).and_then(|_| writer.flush()),
+            None => Ok(()),
+        };
+        if let Err(e) = result {
+            eprintln!("Failed to write {}: {}", self.path, e);
+            self.writer = None;
+        }
+    }
+}
+
+impl NetBackend for Pcap {
+    fn send(&mut self, frame: &[u8], time: u64) {
+        let seconds = time / self.timebase_frequency;
+        let microseconds = time % self.timebase_frequency * 1_000_000 / self.timebase_frequency;
+        let header = [seconds as u32, microseconds as u32, frame.len() as u32, frame.len() as u32];
+        let mut record: Vec<u8> = header.iter().flat_map(|field| field.to_le_bytes().to_vec()).collect();
+        record.extend_from_slice(frame);
+        self.write(&record);
+    }
+
+    fn receive(&mut self) -> Vec<Vec<u8>> {
+        Vec::new()
+    }
+}
+
+#[test]
+fn test_net() {
+    let frame = |destination: u8, source: u8| vec![2, 0, 0, 0, 0, destination, 2, 0, 0, 0, 0, source, 8, 0];
+
+    let mut loopback = Loopback::new();
+    loopback.send(&frame(1, 1), 0);
+    assert_eq!(loopback.receive(), vec![frame(1, 1)]);
+    assert!(loopback.receive().is_empty());
+
+    // Frames to unknown addresses are flooded, and those to learnt ones go to their port only.
+    let switch = Switch::new();
+    let mut ports: Vec<SwitchPort> = (0..3).map(|_| Switch::connect(&switch)).collect();
+    ports[0].send(&frame(2, 1), 0);
+    assert_eq!(ports[1].receive(), vec![frame(2, 1)]);
+    assert_eq!(ports[2].receive(), vec![frame(2, 1)]);
+    ports[2].send(&frame(1, 3), 0);
+    assert_eq!(ports[0].receive(), vec![frame(1, 3)]);
+    assert!(ports[1].receive().is_empty());
+
+    // Two sockets at a path talk to each other.
+    let path = std::env::temp_dir().join(format!("rafi-emu-net-{}.sock", std::process::id()));
+    let path = path.to_str().unwrap();
+    let mut listener = UnixSocket::open(path).unwrap();
+    let mut peer = UnixSocket::open(path).unwrap();
+    while listener.stream.lock().unwrap().is_none() {
+        thread::yield_now();
+    }
+    peer.send(&frame(1, 2), 0);
+    listener.send(&frame(2, 1), 0);
+    assert_eq!(listener.frames.recv().unwrap(), frame(1, 2));
+    assert_eq!(peer.frames.recv().unwrap(), frame(2, 1));
+    fs::remove_file(path).unwrap();
+
+    let path = std::env::temp_dir().join(format!("rafi-emu-net-{}.pcap", std::process::id()));
+    let path = path.to_str().unwrap();
+    let mut pcap = Pcap::create(path, 10_000_000).unwrap();
+    pcap.send(&frame(1, 2), 25_000_010);
+    drop(pcap);
+    let data = fs::read(path).unwrap();
+    fs::remove_file(path).unwrap();
+    assert_eq!(data.len(), 24 + 16 + 14);
+    assert_eq!(&data[0..4], &PCAP_MAGIC.to_le_bytes());
+    assert_eq!(&data[24..32], &[2, 0, 0, 0, 0x21, 0xa1, 0x07, 0]);
+    assert_eq!(&data[40..], &frame(1, 2)[..]);
+}
@@ -1,13 +1,41 @@
 use core::*;
+use mmu::*;
 use trap::*;
 use util::*;
+use xlen::*;
 
 use std::string::ToString;
 
-pub trait Op : ToString {
-    fn execute(&self, core: &mut Core);
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OpClass {
+    Alu,
+    Load,
+    Store,
+    Branch,
+    Jump,
+    IndirectJump,
+    Fence,
+    FenceI,
+    Csr,
+    System,
+    Unknown,
+}
+
+pub trait Op<X: Xlen = Rv32> : ToString {
+    fn execute(&self, core: &mut Core<X>);
+
+    fn class(&self) -> OpClass
+    {
+        OpClass::Alu
+    }
+
+    // Returns (address, size) of the memory access performed by the op.
+    fn memory_access(&self, _core: &Core<X>) -> Option<(u64, u32)>
+    {
+        None
+    }
 
-    fn post_check_trap(&self, _core: &mut Core) -> Option<Trap>
+    fn post_check_trap(&self, _core: &mut Core<X>) -> Option<Trap<X>>
     {
         None
     }
@@ -16,9 +44,16 @@ pub trait Op : ToString {
 pub struct UnknownOp {
 }
 
-impl Op for UnknownOp {
-    fn execute(&self, _core: &mut Core) {
-        panic!("execute unknown op.");
+impl<X: Xlen> Op<X> for UnknownOp {
+    fn execute(&self, _core: &mut Core<X>) {
+    }
+
+    fn post_check_trap(&self, core: &mut Core<X>) -> Option<Trap<X>> {
+        Some(Trap::new_illegal_instruction(core.pc))
+    }
+
+    fn class(&self) -> OpClass {
+        OpClass::Unknown
     }
 }
 
@@ -28,14 +63,37 @@ impl ToString for UnknownOp {
     }
 }
 
+// Op which raises an illegal instruction exception, e.g. an op referring to x16-x31 in RV32E.
+pub struct IllegalOp {
+}
+
+impl<X: Xlen> Op<X> for IllegalOp {
+    fn execute(&self, _core: &mut Core<X>) {
+    }
+
+    fn post_check_trap(&self, core: &mut Core<X>) -> Option<Trap<X>> {
+        Some(Trap::new_illegal_instruction(core.pc))
+    }
+
+    fn class(&self) -> OpClass {
+        OpClass::System
+    }
+}
+
+impl ToString for IllegalOp {
+    fn to_string(&self) -> String {
+        "illegal".to_string()
+    }
+}
+
 pub struct LUI {
     pub rd: usize,
     pub imm: u32,
 }
 
-impl Op for LUI {
-    fn execute(&self, core: &mut Core) {
-        core.int_reg.write(self.rd, self.imm);
+impl<X: Xlen> Op<X> for LUI {
+    fn execute(&self, core: &mut Core<X>) {
+        core.int_reg.write(self.rd, X::Uint::sign_extend(self.imm));
     }
 }
 
@@ -52,7 +110,7 @@ fn test_lui() {
 
     let mut memory = Memory::new();
     let mut bus = Bus::new(&mut memory);
-    let mut core = Core::new(&mut bus);
+    let mut core: Core = Core::new(&mut bus);
 
     let op = LUI { rd: 1, imm: 0x12340000 };
     assert_eq!(op.to_string(), "lui ra,0x12340000");
@@ -66,9 +124,9 @@ pub struct AUIPC {
     pub imm: u32,
 }
 
-impl Op for AUIPC {
-    fn execute(&self, core: &mut Core) {
-        let value = core.pc.wrapping_add(self.imm);
+impl<X: Xlen> Op<X> for AUIPC {
+    fn execute(&self, core: &mut Core<X>) {
+        let value = core.pc.wrapping_add(X::Uint::sign_extend(self.imm));
 
         core.int_reg.write(self.rd, value);
     }
@@ -87,7 +145,7 @@ fn test_auipc() {
 
     let mut memory = Memory::new();
     let mut bus = Bus::new(&mut memory);
-    let mut core = Core::new(&mut bus);
+    let mut core: Core = Core::new(&mut bus);
 
     let op = AUIPC { rd: 1, imm: 0x80000000 };
     assert_eq!(op.to_string(), "auipc ra,0x80000000");
@@ -106,13 +164,17 @@ pub struct JAL {
     pub imm: u32,
 }
 
-impl Op for JAL {
-    fn execute(&self, core: &mut Core) {
+impl<X: Xlen> Op<X> for JAL {
+    fn execute(&self, core: &mut Core<X>) {
         let next_pc = core.next_pc;
 
-        core.next_pc = core.pc.wrapping_add(self.imm);
+        core.next_pc = core.pc.wrapping_add(X::Uint::sign_extend(self.imm));
         core.int_reg.write(self.rd, next_pc);
     }
+
+    fn class(&self) -> OpClass {
+        OpClass::Jump
+    }
 }
 
 impl ToString for JAL {
@@ -130,14 +192,18 @@ pub struct JALR {
     pub imm: u32,
 }
 
-impl Op for JALR {
-    fn execute(&self, core: &mut Core) {
+impl<X: Xlen> Op<X> for JALR {
+    fn execute(&self, core: &mut Core<X>) {
         let src1 = core.int_reg.read(self.rs1);
         let next_pc = core.next_pc;
 
-        core.next_pc = src1.wrapping_add(self.imm);
+        core.next_pc = src1.wrapping_add(X::Uint::sign_extend(self.imm));
         core.int_reg.write(self.rd, next_pc);
     }
+
+    fn class(&self) -> OpClass {
+        OpClass::IndirectJump
+    }
 }
 
 impl ToString for JALR {
@@ -155,15 +221,19 @@ pub struct BEQ {
     pub imm: u32,
 }
 
-impl Op for BEQ {
-    fn execute(&self, core: &mut Core) {
+impl<X: Xlen> Op<X> for BEQ {
+    fn execute(&self, core: &mut Core<X>) {
         let src1 = core.int_reg.read(self.rs1);
         let src2 = core.int_reg.read(self.rs2);
 
         if src1 == src2 {
-            core.next_pc = core.pc.wrapping_add(self.imm);
+            core.next_pc = core.pc.wrapping_add(X::Uint::sign_extend(self.imm));
         }
     }
+
+    fn class(&self) -> OpClass {
+        OpClass::Branch
+    }
 }
 
 impl ToString for BEQ {
@@ -182,15 +252,19 @@ pub struct BNE {
     pub imm: u32,
 }
 
-impl Op for BNE {
-    fn execute(&self, core: &mut Core) {
+impl<X: Xlen> Op<X> for BNE {
+    fn execute(&self, core: &mut Core<X>) {
         let src1 = core.int_reg.read(self.rs1);
         let src2 = core.int_reg.read(self.rs2);
 
         if src1 != src2 {
-            core.next_pc = core.pc.wrapping_add(self.imm);
+            core.next_pc = core.pc.wrapping_add(X::Uint::sign_extend(self.imm));
         }
     }
+
+    fn class(&self) -> OpClass {
+        OpClass::Branch
+    }
 }
 
 impl ToString for BNE {
@@ -209,15 +283,19 @@ pub struct BLT {
     pub imm: u32,
 }
 
-impl Op for BLT {
-    fn execute(&self, core: &mut Core) {
-        let src1 = core.int_reg.read(self.rs1) as i32;
-        let src2 = core.int_reg.read(self.rs2) as i32;
+impl<X: Xlen> Op<X> for BLT {
+    fn execute(&self, core: &mut Core<X>) {
+        let src1 = core.int_reg.read(self.rs1);
+        let src2 = core.int_reg.read(self.rs2);
 
-        if src1 < src2 {
-            core.next_pc = core.pc.wrapping_add(self.imm);
+        if src1.lt_signed(src2) {
+            core.next_pc = core.pc.wrapping_add(X::Uint::sign_extend(self.imm));
         }
     }
+
+    fn class(&self) -> OpClass {
+        OpClass::Branch
+    }
 }
 
 impl ToString for BLT {
@@ -236,15 +314,19 @@ pub struct BGE {
     pub imm: u32,
 }
 
-impl Op for BGE {
-    fn execute(&self, core: &mut Core) {
-        let src1 = core.int_reg.read(self.rs1) as i32;
-        let src2 = core.int_reg.read(self.rs2) as i32;
+impl<X: Xlen> Op<X> for BGE {
+    fn execute(&self, core: &mut Core<X>) {
+        let src1 = core.int_reg.read(self.rs1);
+        let src2 = core.int_reg.read(self.rs2);
 
-        if src1 >= src2 {
-            core.next_pc = core.pc.wrapping_add(self.imm);
+        if !src1.lt_signed(src2) {
+            core.next_pc = core.pc.wrapping_add(X::Uint::sign_extend(self.imm));
         }
     }
+
+    fn class(&self) -> OpClass {
+        OpClass::Branch
+    }
 }
 
 impl ToString for BGE {
@@ -263,15 +345,19 @@ pub struct BLTU {
     pub imm: u32,
 }
 
-impl Op for BLTU {
-    fn execute(&self, core: &mut Core) {
+impl<X: Xlen> Op<X> for BLTU {
+    fn execute(&self, core: &mut Core<X>) {
         let src1 = core.int_reg.read(self.rs1);
         let src2 = core.int_reg.read(self.rs2);
 
         if src1 < src2 {
-            core.next_pc = core.pc.wrapping_add(self.imm);
+            core.next_pc = core.pc.wrapping_add(X::Uint::sign_extend(self.imm));
         }
     }
+
+    fn class(&self) -> OpClass {
+        OpClass::Branch
+    }
 }
 
 impl ToString for BLTU {
@@ -286,15 +372,19 @@ pub struct BGEU {
     pub imm: u32,
 }
 
-impl Op for BGEU {
-    fn execute(&self, core: &mut Core) {
+impl<X: Xlen> Op<X> for BGEU {
+    fn execute(&self, core: &mut Core<X>) {
         let src1 = core.int_reg.read(self.rs1);
         let src2 = core.int_reg.read(self.rs2);
 
         if src1 >= src2 {
-            core.next_pc = core.pc.wrapping_add(self.imm);
+            core.next_pc = core.pc.wrapping_add(X::Uint::sign_extend(self.imm));
         }
     }
+
+    fn class(&self) -> OpClass {
+        OpClass::Branch
+    }
 }
 
 impl ToString for BGEU {
@@ -309,12 +399,20 @@ pub struct LB {
     pub imm: u32,
 }
 
-impl Op for LB {
-    fn execute(&self, core: &mut Core) {
-        let addr = core.int_reg.read(self.rs1).wrapping_add(self.imm);
-        let value = sign_extend(8, core.bus.read_u8(addr) as u32);
+impl<X: Xlen> Op<X> for LB {
+    fn execute(&self, core: &mut Core<X>) {
+        let addr = core.int_reg.read(self.rs1).wrapping_add(X::Uint::sign_extend(self.imm));
+        if let Some(value) = core.load_u8(addr) {
+            core.int_reg.write(self.rd, X::Uint::sign_extend(sign_extend(8, value as u32)));
+        }
+    }
 
-        core.int_reg.write(self.rd, value);
+    fn class(&self) -> OpClass {
+        OpClass::Load
+    }
+
+    fn memory_access(&self, core: &Core<X>) -> Option<(u64, u32)> {
+        Some((core.int_reg.read(self.rs1).wrapping_add(X::Uint::sign_extend(self.imm)).to_u64(), 1))
     }
 }
 
@@ -330,12 +428,20 @@ pub struct LH {
     pub imm: u32,
 }
 
-impl Op for LH {
-    fn execute(&self, core: &mut Core) {
-        let addr = core.int_reg.read(self.rs1).wrapping_add(self.imm);
-        let value = sign_extend(16, core.bus.read_u16(addr) as u32);
+impl<X: Xlen> Op<X> for LH {
+    fn execute(&self, core: &mut Core<X>) {
+        let addr = core.int_reg.read(self.rs1).wrapping_add(X::Uint::sign_extend(self.imm));
+        if let Some(value) = core.load_u16(addr) {
+            core.int_reg.write(self.rd, X::Uint::sign_extend(sign_extend(16, value as u32)));
+        }
+    }
+
+    fn class(&self) -> OpClass {
+        OpClass::Load
+    }
 
-        core.int_reg.write(self.rd, value);
+    fn memory_access(&self, core: &Core<X>) -> Option<(u64, u32)> {
+        Some((core.int_reg.read(self.rs1).wrapping_add(X::Uint::sign_extend(self.imm)).to_u64(), 2))
     }
 }
 
@@ -351,12 +457,20 @@ pub struct LW {
     pub imm: u32,
 }
 
-impl Op for LW {
-    fn execute(&self, core: &mut Core) {
-        let addr = core.int_reg.read(self.rs1).wrapping_add(self.imm);
-        let value = core.bus.read_u32(addr);
+impl<X: Xlen> Op<X> for LW {
+    fn execute(&self, core: &mut Core<X>) {
+        let addr = core.int_reg.read(self.rs1).wrapping_add(X::Uint::sign_extend(self.imm));
+        if let Some(value) = core.load_u32(addr) {
+            core.int_reg.write(self.rd, X::Uint::sign_extend(value));
+        }
+    }
+
+    fn class(&self) -> OpClass {
+        OpClass::Load
+    }
 
-        core.int_reg.write(self.rd, value);
+    fn memory_access(&self, core: &Core<X>) -> Option<(u64, u32)> {
+        Some((core.int_reg.read(self.rs1).wrapping_add(X::Uint::sign_extend(self.imm)).to_u64(), 4))
     }
 }
 
@@ -372,12 +486,20 @@ pub struct LBU {
     pub imm: u32,
 }
 
-impl Op for LBU {
-    fn execute(&self, core: &mut Core) {
-        let addr = core.int_reg.read(self.rs1).wrapping_add(self.imm);
-        let value = core.bus.read_u8(addr) as u32;
+impl<X: Xlen> Op<X> for LBU {
+    fn execute(&self, core: &mut Core<X>) {
+        let addr = core.int_reg.read(self.rs1).wrapping_add(X::Uint::sign_extend(self.imm));
+        if let Some(value) = core.load_u8(addr) {
+            core.int_reg.write(self.rd, X::Uint::from_u32(value as u32));
+        }
+    }
 
-        core.int_reg.write(self.rd, value);
+    fn class(&self) -> OpClass {
+        OpClass::Load
+    }
+
+    fn memory_access(&self, core: &Core<X>) -> Option<(u64, u32)> {
+        Some((core.int_reg.read(self.rs1).wrapping_add(X::Uint::sign_extend(self.imm)).to_u64(), 1))
     }
 }
 
@@ -393,12 +515,20 @@ pub struct LHU {
     pub imm: u32,
 }
 
-impl Op for LHU {
-    fn execute(&self, core: &mut Core) {
-        let addr = core.int_reg.read(self.rs1).wrapping_add(self.imm);
-        let value = core.bus.read_u16(addr) as u32;
+impl<X: Xlen> Op<X> for LHU {
+    fn execute(&self, core: &mut Core<X>) {
+        let addr = core.int_reg.read(self.rs1).wrapping_add(X::Uint::sign_extend(self.imm));
+        if let Some(value) = core.load_u16(addr) {
+            core.int_reg.write(self.rd, X::Uint::from_u32(value as u32));
+        }
+    }
 
-        core.int_reg.write(self.rd, value);
+    fn class(&self) -> OpClass {
+        OpClass::Load
+    }
+
+    fn memory_access(&self, core: &Core<X>) -> Option<(u64, u32)> {
+        Some((core.int_reg.read(self.rs1).wrapping_add(X::Uint::sign_extend(self.imm)).to_u64(), 2))
     }
 }
 
@@ -414,12 +544,20 @@ pub struct SB {
     pub imm: u32,
 }
 
-impl Op for SB {
-    fn execute(&self, core: &mut Core) {
-        let addr = core.int_reg.read(self.rs1).wrapping_add(self.imm);
-        let value = core.int_reg.read(self.rs2) as u8;
+impl<X: Xlen> Op<X> for SB {
+    fn execute(&self, core: &mut Core<X>) {
+        let addr = core.int_reg.read(self.rs1).wrapping_add(X::Uint::sign_extend(self.imm));
+        let value = core.int_reg.read(self.rs2).to_u32() as u8;
+
+        core.store_u8(addr, value);
+    }
+
+    fn class(&self) -> OpClass {
+        OpClass::Store
+    }
 
-        core.bus.write_u8(addr, value);
+    fn memory_access(&self, core: &Core<X>) -> Option<(u64, u32)> {
+        Some((core.int_reg.read(self.rs1).wrapping_add(X::Uint::sign_extend(self.imm)).to_u64(), 1))
     }
 }
 
@@ -435,12 +573,20 @@ pub struct SH {
     pub imm: u32,
 }
 
-impl Op for SH {
-    fn execute(&self, core: &mut Core) {
-        let addr = core.int_reg.read(self.rs1).wrapping_add(self.imm);
-        let value = core.int_reg.read(self.rs2) as u16;
+impl<X: Xlen> Op<X> for SH {
+    fn execute(&self, core: &mut Core<X>) {
+        let addr = core.int_reg.read(self.rs1).wrapping_add(X::Uint::sign_extend(self.imm));
+        let value = core.int_reg.read(self.rs2).to_u32() as u16;
+
+        core.store_u16(addr, value);
+    }
+
+    fn class(&self) -> OpClass {
+        OpClass::Store
+    }
 
-        core.bus.write_u16(addr, value);
+    fn memory_access(&self, core: &Core<X>) -> Option<(u64, u32)> {
+        Some((core.int_reg.read(self.rs1).wrapping_add(X::Uint::sign_extend(self.imm)).to_u64(), 2))
     }
 }
 
@@ -456,12 +602,20 @@ pub struct SW {
     pub imm: u32,
 }
 
-impl Op for SW {
-    fn execute(&self, core: &mut Core) {
-        let addr = core.int_reg.read(self.rs1).wrapping_add(self.imm);
-        let value = core.int_reg.read(self.rs2) as u32;
+impl<X: Xlen> Op<X> for SW {
+    fn execute(&self, core: &mut Core<X>) {
+        let addr = core.int_reg.read(self.rs1).wrapping_add(X::Uint::sign_extend(self.imm));
+        let value = core.int_reg.read(self.rs2).to_u32();
+
+        core.store_u32(addr, value);
+    }
+
+    fn class(&self) -> OpClass {
+        OpClass::Store
+    }
 
-        core.bus.write_u32(addr, value);
+    fn memory_access(&self, core: &Core<X>) -> Option<(u64, u32)> {
+        Some((core.int_reg.read(self.rs1).wrapping_add(X::Uint::sign_extend(self.imm)).to_u64(), 4))
     }
 }
 
@@ -477,9 +631,9 @@ pub struct ADDI {
     pub imm: u32,
 }
 
-impl Op for ADDI {
-    fn execute(&self, core: &mut Core) {
-        let value = core.int_reg.read(self.rs1).wrapping_add(self.imm);
+impl<X: Xlen> Op<X> for ADDI {
+    fn execute(&self, core: &mut Core<X>) {
+        let value = core.int_reg.read(self.rs1).wrapping_add(X::Uint::sign_extend(self.imm));
 
         core.int_reg.write(self.rd, value);
     }
@@ -497,10 +651,10 @@ pub struct SLTI {
     pub imm: u32,
 }
 
-impl Op for SLTI {
-    fn execute(&self, core: &mut Core) {
+impl<X: Xlen> Op<X> for SLTI {
+    fn execute(&self, core: &mut Core<X>) {
         let src1 = core.int_reg.read(self.rs1);
-        let value = if (src1 as i32) < (self.imm as i32) { 1 } else { 0 };
+        let value = X::Uint::from_u32(if src1.lt_signed(X::Uint::sign_extend(self.imm)) { 1 } else { 0 });
 
         core.int_reg.write(self.rd, value);
     }
@@ -518,10 +672,10 @@ pub struct SLTIU {
     pub imm: u32,
 }
 
-impl Op for SLTIU {
-    fn execute(&self, core: &mut Core) {
+impl<X: Xlen> Op<X> for SLTIU {
+    fn execute(&self, core: &mut Core<X>) {
         let src1 = core.int_reg.read(self.rs1);
-        let value = if src1 < self.imm { 1 } else { 0 };
+        let value = X::Uint::from_u32(if src1 < X::Uint::sign_extend(self.imm) { 1 } else { 0 });
 
         core.int_reg.write(self.rd, value);
     }
@@ -539,10 +693,10 @@ pub struct XORI {
     pub imm: u32,
 }
 
-impl Op for XORI {
-    fn execute(&self, core: &mut Core) {
+impl<X: Xlen> Op<X> for XORI {
+    fn execute(&self, core: &mut Core<X>) {
         let src1 = core.int_reg.read(self.rs1);
-        let value = src1 ^ self.imm;
+        let value = src1 ^ X::Uint::sign_extend(self.imm);
 
         core.int_reg.write(self.rd, value);
     }
@@ -560,10 +714,10 @@ pub struct ORI {
     pub imm: u32,
 }
 
-impl Op for ORI {
-    fn execute(&self, core: &mut Core) {
+impl<X: Xlen> Op<X> for ORI {
+    fn execute(&self, core: &mut Core<X>) {
         let src1 = core.int_reg.read(self.rs1);
-        let value = src1 | self.imm;
+        let value = src1 | X::Uint::sign_extend(self.imm);
 
         core.int_reg.write(self.rd, value);
     }
@@ -581,10 +735,10 @@ pub struct ANDI {
     pub imm: u32,
 }
 
-impl Op for ANDI {
-    fn execute(&self, core: &mut Core) {
+impl<X: Xlen> Op<X> for ANDI {
+    fn execute(&self, core: &mut Core<X>) {
         let src1 = core.int_reg.read(self.rs1);
-        let value = src1 & self.imm;
+        let value = src1 & X::Uint::sign_extend(self.imm);
 
         core.int_reg.write(self.rd, value);
     }
@@ -602,10 +756,10 @@ pub struct SLLI {
     pub shamt: u32,
 }
 
-impl Op for SLLI {
-    fn execute(&self, core: &mut Core) {
+impl<X: Xlen> Op<X> for SLLI {
+    fn execute(&self, core: &mut Core<X>) {
         let src1 = core.int_reg.read(self.rs1);
-        let value = src1.wrapping_shl(self.shamt);
+        let value = src1.shl(self.shamt);
 
         core.int_reg.write(self.rd, value);
     }
@@ -623,10 +777,10 @@ pub struct SRLI {
     pub shamt: u32,
 }
 
-impl Op for SRLI {
-    fn execute(&self, core: &mut Core) {
+impl<X: Xlen> Op<X> for SRLI {
+    fn execute(&self, core: &mut Core<X>) {
         let src1 = core.int_reg.read(self.rs1);
-        let value = src1.wrapping_shr(self.shamt);
+        let value = src1.shr(self.shamt);
 
         core.int_reg.write(self.rd, value);
     }
@@ -644,12 +798,12 @@ pub struct SRAI {
     pub shamt: u32,
 }
 
-impl Op for SRAI {
-    fn execute(&self, core: &mut Core) {
-        let src1 = core.int_reg.read(self.rs1) as i32;
-        let value = src1.wrapping_shr(self.shamt);
+impl<X: Xlen> Op<X> for SRAI {
+    fn execute(&self, core: &mut Core<X>) {
+        let src1 = core.int_reg.read(self.rs1);
+        let value = src1.sra(self.shamt);
 
-        core.int_reg.write(self.rd, value as u32);
+        core.int_reg.write(self.rd, value);
     }
 }
 
@@ -665,8 +819,8 @@ pub struct ADD {
     pub rs2: usize,
 }
 
-impl Op for ADD {
-    fn execute(&self, core: &mut Core) {
+impl<X: Xlen> Op<X> for ADD {
+    fn execute(&self, core: &mut Core<X>) {
         let src1 = core.int_reg.read(self.rs1);
         let src2 = core.int_reg.read(self.rs2);
         let value = src1.wrapping_add(src2);
@@ -687,8 +841,8 @@ pub struct SUB {
     pub rs2: usize,
 }
 
-impl Op for SUB {
-    fn execute(&self, core: &mut Core) {
+impl<X: Xlen> Op<X> for SUB {
+    fn execute(&self, core: &mut Core<X>) {
         let src1 = core.int_reg.read(self.rs1);
         let src2 = core.int_reg.read(self.rs2);
         let value = src1.wrapping_sub(src2);
@@ -709,11 +863,11 @@ pub struct SLL {
     pub rs2: usize,
 }
 
-impl Op for SLL {
-    fn execute(&self, core: &mut Core) {
+impl<X: Xlen> Op<X> for SLL {
+    fn execute(&self, core: &mut Core<X>) {
         let src1 = core.int_reg.read(self.rs1);
         let src2 = core.int_reg.read(self.rs2);
-        let value = src1.wrapping_shl(src2);
+        let value = src1.shl(src2.to_u32());
 
         core.int_reg.write(self.rd, value);
     }
@@ -731,11 +885,11 @@ pub struct SLT {
     pub rs2: usize,
 }
 
-impl Op for SLT {
-    fn execute(&self, core: &mut Core) {
+impl<X: Xlen> Op<X> for SLT {
+    fn execute(&self, core: &mut Core<X>) {
         let src1 = core.int_reg.read(self.rs1);
         let src2 = core.int_reg.read(self.rs2);
-        let value = if (src1 as i32) < (src2 as i32) { 1 } else { 0 };
+        let value = X::Uint::from_u32(if src1.lt_signed(src2) { 1 } else { 0 });
 
         core.int_reg.write(self.rd, value);
     }
@@ -753,11 +907,11 @@ pub struct SLTU {
     pub rs2: usize,
 }
 
-impl Op for SLTU {
-    fn execute(&self, core: &mut Core) {
+impl<X: Xlen> Op<X> for SLTU {
+    fn execute(&self, core: &mut Core<X>) {
         let src1 = core.int_reg.read(self.rs1);
         let src2 = core.int_reg.read(self.rs2);
-        let value = if src1 < src2 { 1 } else { 0 };
+        let value = X::Uint::from_u32(if src1 < src2 { 1 } else { 0 });
 
         core.int_reg.write(self.rd, value);
     }
@@ -775,8 +929,8 @@ pub struct XOR {
     pub rs2: usize,
 }
 
-impl Op for XOR {
-    fn execute(&self, core: &mut Core) {
+impl<X: Xlen> Op<X> for XOR {
+    fn execute(&self, core: &mut Core<X>) {
         let src1 = core.int_reg.read(self.rs1);
         let src2 = core.int_reg.read(self.rs2);
         let value = src1 ^ src2;
@@ -797,11 +951,11 @@ pub struct SRL {
     pub rs2: usize,
 }
 
-impl Op for SRL {
-    fn execute(&self, core: &mut Core) {
+impl<X: Xlen> Op<X> for SRL {
+    fn execute(&self, core: &mut Core<X>) {
         let src1 = core.int_reg.read(self.rs1);
         let src2 = core.int_reg.read(self.rs2);
-        let value = src1.wrapping_shr(src2);
+        let value = src1.shr(src2.to_u32());
 
         core.int_reg.write(self.rd, value);
     }
@@ -819,13 +973,13 @@ pub struct SRA {
     pub rs2: usize,
 }
 
-impl Op for SRA {
-    fn execute(&self, core: &mut Core) {
+impl<X: Xlen> Op<X> for SRA {
+    fn execute(&self, core: &mut Core<X>) {
         let src1 = core.int_reg.read(self.rs1);
         let src2 = core.int_reg.read(self.rs2);
-        let value = (src1 as i32).wrapping_shr(src2);
+        let value = src1.sra(src2.to_u32());
 
-        core.int_reg.write(self.rd, value as u32);
+        core.int_reg.write(self.rd, value);
     }
 }
 
@@ -841,8 +995,8 @@ pub struct OR {
     pub rs2: usize,
 }
 
-impl Op for OR {
-    fn execute(&self, core: &mut Core) {
+impl<X: Xlen> Op<X> for OR {
+    fn execute(&self, core: &mut Core<X>) {
         let src1 = core.int_reg.read(self.rs1);
         let src2 = core.int_reg.read(self.rs2);
         let value = src1 | src2;
@@ -863,8 +1017,8 @@ pub struct AND {
     pub rs2: usize,
 }
 
-impl Op for AND {
-    fn execute(&self, core: &mut Core) {
+impl<X: Xlen> Op<X> for AND {
+    fn execute(&self, core: &mut Core<X>) {
         let src1 = core.int_reg.read(self.rs1);
         let src2 = core.int_reg.read(self.rs2);
         let value = src1 & src2;
@@ -884,8 +1038,12 @@ pub struct FENCE {
     pub succ: u32,
 }
 
-impl Op for FENCE {
-    fn execute(&self, _core: &mut Core) {
+impl<X: Xlen> Op<X> for FENCE {
+    fn execute(&self, _core: &mut Core<X>) {
+    }
+
+    fn class(&self) -> OpClass {
+        OpClass::Fence
     }
 }
 
@@ -898,8 +1056,12 @@ impl ToString for FENCE {
 pub struct FENCEI {
 }
 
-impl Op for FENCEI {
-    fn execute(&self, _core: &mut Core) {
+impl<X: Xlen> Op<X> for FENCEI {
+    fn execute(&self, _core: &mut Core<X>) {
+    }
+
+    fn class(&self) -> OpClass {
+        OpClass::FenceI
     }
 }
 
@@ -912,12 +1074,21 @@ impl ToString for FENCEI {
 pub struct ECALL {
 }
 
-impl Op for ECALL {
-    fn execute(&self, _core: &mut Core) {
+impl<X: Xlen> Op<X> for ECALL {
+    fn execute(&self, core: &mut Core<X>) {
+        core.handle_syscall();
+    }
+
+    fn post_check_trap(&self, core: &mut Core<X>) -> Option<Trap<X>>  {
+        // ECALLs taken by the syscall handler do not trap.
+        if core.privilege == PRIV_USER && core.syscall_handler.is_some() {
+            return None
+        }
+        Some(Trap::new_ecall(core.pc, core.privilege))
     }
 
-    fn post_check_trap(&self, core: &mut Core) -> Option<Trap>  {
-        Some(Trap::new_ecall_from_machine(core.pc))
+    fn class(&self) -> OpClass {
+        OpClass::System
     }
 }
 
@@ -930,13 +1101,22 @@ impl ToString for ECALL {
 pub struct EBREAK {
 }
 
-impl Op for EBREAK {
-    fn execute(&self, _core: &mut Core) {
+impl<X: Xlen> Op<X> for EBREAK {
+    fn execute(&self, core: &mut Core<X>) {
+        core.handle_semihosting();
     }
 
-    fn post_check_trap(&self, core: &mut Core) -> Option<Trap> {
+    fn post_check_trap(&self, core: &mut Core<X>) -> Option<Trap<X>> {
+        // Semihosting calls taken by the handler do not trap.
+        if core.is_semihosting_call() {
+            return None
+        }
         Some(Trap::new_ebreak(core.pc))
     }
+
+    fn class(&self) -> OpClass {
+        OpClass::System
+    }
 }
 
 impl ToString for EBREAK {
@@ -945,19 +1125,44 @@ impl ToString for EBREAK {
     }
 }
 
+fn check_csr_access<X: Xlen>(core: &Core<X>, csr: usize, write: bool) -> Option<Trap<X>> {
+    if core.csr.is_accessible(csr, core.privilege, write) {
+        None
+    } else {
+        Some(Trap::new_illegal_instruction(core.pc))
+    }
+}
+
 pub struct CSRRW {
     pub csr: usize,
     pub rd: usize,
     pub rs1: usize,
 }
 
-impl Op for CSRRW {
-    fn execute(&self, core: &mut Core) {
-        let org = core.csr.read(self.csr);
+impl<X: Xlen> Op<X> for CSRRW {
+    fn execute(&self, core: &mut Core<X>) {
+        if !core.csr.is_accessible(self.csr, core.privilege, true) {
+            return
+        }
+
         let value = core.int_reg.read(self.rs1);
 
-        core.csr.write(self.csr, value);
-        core.int_reg.write(self.rd, org);
+        // The CSR is not read when rd is x0, so that no read side effects occur.
+        if self.rd == 0 {
+            core.write_csr(self.csr, value);
+        } else {
+            let org = core.csr.read(self.csr);
+            core.write_csr(self.csr, value);
+            core.int_reg.write(self.rd, org);
+        }
+    }
+
+    fn post_check_trap(&self, core: &mut Core<X>) -> Option<Trap<X>> {
+        check_csr_access(core, self.csr, true)
+    }
+
+    fn class(&self) -> OpClass {
+        OpClass::Csr
     }
 }
 
@@ -976,14 +1181,29 @@ pub struct CSRRS {
     pub rs1: usize,
 }
 
-impl Op for CSRRS {
-    fn execute(&self, core: &mut Core) {
+impl<X: Xlen> Op<X> for CSRRS {
+    fn execute(&self, core: &mut Core<X>) {
+        if !core.csr.is_accessible(self.csr, core.privilege, self.rs1 != 0) {
+            return
+        }
+
         let org = core.csr.read(self.csr);
-        let value = org | core.int_reg.read(self.rs1);
 
-        core.csr.write(self.csr, value);
+        // The CSR is not written when rs1 is x0 is 0, so that read-only CSRs can be read.
+        if self.rs1 != 0 {
+            let value = org | core.int_reg.read(self.rs1);
+            core.write_csr(self.csr, value);
+        }
         core.int_reg.write(self.rd, org);
     }
+
+    fn post_check_trap(&self, core: &mut Core<X>) -> Option<Trap<X>> {
+        check_csr_access(core, self.csr, self.rs1 != 0)
+    }
+
+    fn class(&self) -> OpClass {
+        OpClass::Csr
+    }
 }
 
 impl ToString for CSRRS {
@@ -1002,14 +1222,29 @@ pub struct CSRRC {
     pub rs1: usize,
 }
 
-impl Op for CSRRC {
-    fn execute(&self, core: &mut Core) {
+impl<X: Xlen> Op<X> for CSRRC {
+    fn execute(&self, core: &mut Core<X>) {
+        if !core.csr.is_accessible(self.csr, core.privilege, self.rs1 != 0) {
+            return
+        }
+
         let org = core.csr.read(self.csr);
-        let value = org & !core.int_reg.read(self.rs1);
 
-        core.csr.write(self.csr, value);
+        // The CSR is not written when rs1 is x0 is 0, so that read-only CSRs can be read.
+        if self.rs1 != 0 {
+            let value = org & !core.int_reg.read(self.rs1);
+            core.write_csr(self.csr, value);
+        }
         core.int_reg.write(self.rd, org);
     }
+
+    fn post_check_trap(&self, core: &mut Core<X>) -> Option<Trap<X>> {
+        check_csr_access(core, self.csr, self.rs1 != 0)
+    }
+
+    fn class(&self) -> OpClass {
+        OpClass::Csr
+    }
 }
 
 impl ToString for CSRRC {
@@ -1027,13 +1262,30 @@ pub struct CSRRWI {
     pub zimm: u32,
 }
 
-impl Op for CSRRWI {
-    fn execute(&self, core: &mut Core) {
-        let org = core.csr.read(self.csr);
-        let value = self.zimm;
+impl<X: Xlen> Op<X> for CSRRWI {
+    fn execute(&self, core: &mut Core<X>) {
+        if !core.csr.is_accessible(self.csr, core.privilege, true) {
+            return
+        }
 
-        core.csr.write(self.csr, value);
-        core.int_reg.write(self.rd, org);
+        let value = X::Uint::from_u32(self.zimm);
+
+        // The CSR is not read when rd is x0, so that no read side effects occur.
+        if self.rd == 0 {
+            core.write_csr(self.csr, value);
+        } else {
+            let org = core.csr.read(self.csr);
+            core.write_csr(self.csr, value);
+            core.int_reg.write(self.rd, org);
+        }
+    }
+
+    fn post_check_trap(&self, core: &mut Core<X>) -> Option<Trap<X>> {
+        check_csr_access(core, self.csr, true)
+    }
+
+    fn class(&self) -> OpClass {
+        OpClass::Csr
     }
 }
 
@@ -1052,14 +1304,29 @@ pub struct CSRRSI {
     pub zimm: u32,
 }
 
-impl Op for CSRRSI {
-    fn execute(&self, core: &mut Core) {
+impl<X: Xlen> Op<X> for CSRRSI {
+    fn execute(&self, core: &mut Core<X>) {
+        if !core.csr.is_accessible(self.csr, core.privilege, self.zimm != 0) {
+            return
+        }
+
         let org = core.csr.read(self.csr);
-        let value = org | self.zimm;
 
-        core.csr.write(self.csr, value);
+        // The CSR is not written when zimm is 0, so that read-only CSRs can be read.
+        if self.zimm != 0 {
+            let value = org | X::Uint::from_u32(self.zimm);
+            core.write_csr(self.csr, value);
+        }
         core.int_reg.write(self.rd, org);
     }
+
+    fn post_check_trap(&self, core: &mut Core<X>) -> Option<Trap<X>> {
+        check_csr_access(core, self.csr, self.zimm != 0)
+    }
+
+    fn class(&self) -> OpClass {
+        OpClass::Csr
+    }
 }
 
 impl ToString for CSRRSI {
@@ -1077,14 +1344,29 @@ pub struct CSRRCI {
     pub zimm: u32,
 }
 
-impl Op for CSRRCI {
-    fn execute(&self, core: &mut Core) {
+impl<X: Xlen> Op<X> for CSRRCI {
+    fn execute(&self, core: &mut Core<X>) {
+        if !core.csr.is_accessible(self.csr, core.privilege, self.zimm != 0) {
+            return
+        }
+
         let org = core.csr.read(self.csr);
-        let value = org & !self.zimm;
 
-        core.csr.write(self.csr, value);
+        // The CSR is not written when zimm is 0, so that read-only CSRs can be read.
+        if self.zimm != 0 {
+            let value = org & !X::Uint::from_u32(self.zimm);
+            core.write_csr(self.csr, value);
+        }
         core.int_reg.write(self.rd, org);
     }
+
+    fn post_check_trap(&self, core: &mut Core<X>) -> Option<Trap<X>> {
+        check_csr_access(core, self.csr, self.zimm != 0)
+    }
+
+    fn class(&self) -> OpClass {
+        OpClass::Csr
+    }
 }
 
 impl ToString for CSRRCI {
@@ -1096,15 +1378,61 @@ impl ToString for CSRRCI {
     }
 }
 
+#[test]
+fn test_csr_x0_operands() {
+    use bus::*;
+    use memory::*;
+
+    let mut memory = Memory::new();
+    let mut bus = Bus::new(&mut memory);
+    let mut core: Core = Core::new(&mut bus);
+
+    // csrw (rd=x0) writes without reading.
+    core.int_reg.write(1, 0x1234);
+    core.execute(&CSRRW { csr: 0x340, rd: 0, rs1: 1 });
+    assert_eq!(core.last_trap_cause, None);
+    assert_eq!(core.csr.read(0x340), 0x1234);
+
+    // Reading a read-only CSR is legal only when the op does not write it.
+    core.execute(&CSRRS { csr: 0xf11, rd: 2, rs1: 0 });
+    assert_eq!(core.last_trap_cause, None);
+    core.execute(&CSRRC { csr: 0xf11, rd: 2, rs1: 0 });
+    assert_eq!(core.last_trap_cause, None);
+    core.execute(&CSRRSI { csr: 0xf11, rd: 2, zimm: 0 });
+    assert_eq!(core.last_trap_cause, None);
+    core.execute(&CSRRCI { csr: 0xf11, rd: 2, zimm: 0 });
+    assert_eq!(core.last_trap_cause, None);
+
+    // rs1 other than x0 writes even if its value is 0.
+    core.int_reg.write(3, 0);
+    core.execute(&CSRRS { csr: 0xf11, rd: 2, rs1: 3 });
+    assert_eq!(core.last_trap_cause, Some(2));
+    core.execute(&CSRRCI { csr: 0xf11, rd: 2, zimm: 1 });
+    assert_eq!(core.last_trap_cause, Some(2));
+    core.execute(&CSRRWI { csr: 0xf11, rd: 0, zimm: 0 });
+    assert_eq!(core.last_trap_cause, Some(2));
+
+    // csrr does not write, so it does not stop the counter from being incremented.
+    let cycle = core.csr.read(0xb00);
+    core.execute(&CSRRS { csr: 0xb00, rd: 2, rs1: 0 });
+    assert_eq!(core.int_reg.read(2), cycle);
+    assert_eq!(core.csr.read(0xb00), cycle + 1);
+}
+
 pub struct URET {
 }
 
-impl Op for URET {
-    fn execute(&self, _core: &mut Core) {
+impl<X: Xlen> Op<X> for URET {
+    fn execute(&self, _core: &mut Core<X>) {
+    }
+
+    fn post_check_trap(&self, core: &mut Core<X>) -> Option<Trap<X>> {
+        // User-level interrupts (the N extension) are not implemented.
+        Some(Trap::new_illegal_instruction(core.pc))
     }
 
-    fn post_check_trap(&self, core: &mut Core) -> Option<Trap> {
-        Some(Trap::new_trap_return(core.pc))
+    fn class(&self) -> OpClass {
+        OpClass::System
     }
 }
 
@@ -1117,12 +1445,19 @@ impl ToString for URET {
 pub struct SRET {
 }
 
-impl Op for SRET {
-    fn execute(&self, _core: &mut Core) {
+impl<X: Xlen> Op<X> for SRET {
+    fn execute(&self, _core: &mut Core<X>) {
     }
 
-    fn post_check_trap(&self, core: &mut Core) -> Option<Trap> {
-        Some(Trap::new_trap_return(core.pc))
+    fn post_check_trap(&self, core: &mut Core<X>) -> Option<Trap<X>> {
+        if core.privilege < PRIV_SUPERVISOR || (core.privilege == PRIV_SUPERVISOR && core.csr.read_mstatus().tsr() == 1) {
+            return Some(Trap::new_illegal_instruction(core.pc))
+        }
+        Some(Trap::new_trap_return(core.pc, PRIV_SUPERVISOR))
+    }
+
+    fn class(&self) -> OpClass {
+        OpClass::System
     }
 }
 
@@ -1135,12 +1470,19 @@ impl ToString for SRET {
 pub struct MRET {
 }
 
-impl Op for MRET {
-    fn execute(&self, _core: &mut Core) {
+impl<X: Xlen> Op<X> for MRET {
+    fn execute(&self, _core: &mut Core<X>) {
+    }
+
+    fn post_check_trap(&self, core: &mut Core<X>) -> Option<Trap<X>> {
+        if core.privilege < PRIV_MACHINE {
+            return Some(Trap::new_illegal_instruction(core.pc))
+        }
+        Some(Trap::new_trap_return(core.pc, PRIV_MACHINE))
     }
 
-    fn post_check_trap(&self, core: &mut Core) -> Option<Trap> {
-        Some(Trap::new_trap_return(core.pc))
+    fn class(&self) -> OpClass {
+        OpClass::System
     }
 }
 
@@ -1153,8 +1495,23 @@ impl ToString for MRET {
 pub struct WFI {
 }
 
-impl Op for WFI {
-    fn execute(&self, _core: &mut Core) {
+impl<X: Xlen> Op<X> for WFI {
+    fn execute(&self, core: &mut Core<X>) {
+        // Without devices nothing can raise an interrupt while waiting, so WFI is a nop.
+        if core.bus.has_devices() && (core.privilege == PRIV_MACHINE || core.csr.read_mstatus().tw() == 0) {
+            core.waiting = true;
+        }
+    }
+
+    fn post_check_trap(&self, core: &mut Core<X>) -> Option<Trap<X>> {
+        if core.privilege < PRIV_MACHINE && core.csr.read_mstatus().tw() == 1 {
+            return Some(Trap::new_illegal_instruction(core.pc))
+        }
+        None
+    }
+
+    fn class(&self) -> OpClass {
+        OpClass::System
     }
 }
 
@@ -1167,8 +1524,20 @@ impl ToString for WFI {
 pub struct SFENCEVMA {
 }
 
-impl Op for SFENCEVMA {
-    fn execute(&self, _core: &mut Core) {
+impl<X: Xlen> Op<X> for SFENCEVMA {
+    fn execute(&self, core: &mut Core<X>) {
+        core.mmu.flush();
+    }
+
+    fn post_check_trap(&self, core: &mut Core<X>) -> Option<Trap<X>> {
+        if core.privilege < PRIV_SUPERVISOR || (core.privilege == PRIV_SUPERVISOR && core.csr.read_mstatus().tvm() == 1) {
+            return Some(Trap::new_illegal_instruction(core.pc))
+        }
+        None
+    }
+
+    fn class(&self) -> OpClass {
+        OpClass::System
     }
 }
 
@@ -1184,8 +1553,8 @@ pub struct MUL {
     pub rs2: usize,
 }
 
-impl Op for MUL {
-    fn execute(&self, core: &mut Core) {
+impl<X: Xlen> Op<X> for MUL {
+    fn execute(&self, core: &mut Core<X>) {
         let src1 = core.int_reg.read(self.rs1);
         let src2 = core.int_reg.read(self.rs2);
         let value = src1.wrapping_mul(src2);
@@ -1206,13 +1575,13 @@ pub struct MULH {
     pub rs2: usize,
 }
 
-impl Op for MULH {
-    fn execute(&self, core: &mut Core) {
-        let src1 = core.int_reg.read(self.rs1) as i32;
-        let src2 = core.int_reg.read(self.rs2) as i32;
-        let value = (src1 as i64) * (src2 as i64) >> 32;
+impl<X: Xlen> Op<X> for MULH {
+    fn execute(&self, core: &mut Core<X>) {
+        let src1 = core.int_reg.read(self.rs1);
+        let src2 = core.int_reg.read(self.rs2);
+        let value = src1.mulh(src2);
 
-        core.int_reg.write(self.rd, value as u32);
+        core.int_reg.write(self.rd, value);
     }
 }
 
@@ -1228,13 +1597,13 @@ pub struct MULHSU {
     pub rs2: usize,
 }
 
-impl Op for MULHSU {
-    fn execute(&self, core: &mut Core) {
-        let src1 = core.int_reg.read(self.rs1) as i32;
+impl<X: Xlen> Op<X> for MULHSU {
+    fn execute(&self, core: &mut Core<X>) {
+        let src1 = core.int_reg.read(self.rs1);
         let src2 = core.int_reg.read(self.rs2);
-        let value = (src1 as i64) * (src2 as i64) >> 32;
+        let value = src1.mulhsu(src2);
 
-        core.int_reg.write(self.rd, value as u32);
+        core.int_reg.write(self.rd, value);
     }
 }
 
@@ -1250,13 +1619,13 @@ pub struct MULHU {
     pub rs2: usize,
 }
 
-impl Op for MULHU {
-    fn execute(&self, core: &mut Core) {
+impl<X: Xlen> Op<X> for MULHU {
+    fn execute(&self, core: &mut Core<X>) {
         let src1 = core.int_reg.read(self.rs1);
         let src2 = core.int_reg.read(self.rs2);
-        let value = (src1 as u64) * (src2 as u64) >> 32;
+        let value = src1.mulhu(src2);
 
-        core.int_reg.write(self.rd, value as u32);
+        core.int_reg.write(self.rd, value);
     }
 }
 
@@ -1272,20 +1641,12 @@ pub struct DIV {
     pub rs2: usize,
 }
 
-impl Op for DIV {
-    fn execute(&self, core: &mut Core) {
+impl<X: Xlen> Op<X> for DIV {
+    fn execute(&self, core: &mut Core<X>) {
         let src1 = core.int_reg.read(self.rs1);
         let src2 = core.int_reg.read(self.rs2);
 
-        let value = if src1 == 0x80000000 && src2 == 0xffffffff {
-            0x80000000
-        }
-        else if src2 == 0 {
-            0xffffffff
-        }
-        else {
-            (src1 as i32).wrapping_div(src2 as i32) as u32
-        };
+        let value = src1.div_signed(src2);
 
         core.int_reg.write(self.rd, value);
     }
@@ -1303,17 +1664,12 @@ pub struct DIVU {
     pub rs2: usize,
 }
 
-impl Op for DIVU {
-    fn execute(&self, core: &mut Core) {
+impl<X: Xlen> Op<X> for DIVU {
+    fn execute(&self, core: &mut Core<X>) {
         let src1 = core.int_reg.read(self.rs1);
         let src2 = core.int_reg.read(self.rs2);
 
-        let value = if src2 == 0 {
-            0xffffffff
-        }
-        else {
-            src1.wrapping_div(src2)
-        };
+        let value = src1.div_unsigned(src2);
 
         core.int_reg.write(self.rd, value);
     }
@@ -1331,20 +1687,12 @@ pub struct REM {
     pub rs2: usize,
 }
 
-impl Op for REM {
-    fn execute(&self, core: &mut Core) {
+impl<X: Xlen> Op<X> for REM {
+    fn execute(&self, core: &mut Core<X>) {
         let src1 = core.int_reg.read(self.rs1);
         let src2 = core.int_reg.read(self.rs2);
 
-        let value = if src1 == 0x80000000 && src2 == 0xffffffff {
-            0
-        }
-        else if src2 == 0 {
-            src1
-        }
-        else {
-            (src1 as i32).wrapping_rem(src2 as i32) as u32
-        };
+        let value = src1.rem_signed(src2);
 
         core.int_reg.write(self.rd, value);
     }
@@ -1362,17 +1710,12 @@ pub struct REMU {
     pub rs2: usize,
 }
 
-impl Op for REMU {
-    fn execute(&self, core: &mut Core) {
+impl<X: Xlen> Op<X> for REMU {
+    fn execute(&self, core: &mut Core<X>) {
         let src1 = core.int_reg.read(self.rs1);
         let src2 = core.int_reg.read(self.rs2);
 
-        let value = if src2 == 0 {
-            src1
-        }
-        else {
-            src1.wrapping_rem(src2)
-        };
+        let value = src1.rem_unsigned(src2);
 
         core.int_reg.write(self.rd, value);
     }
@@ -1382,4 +1725,663 @@ impl ToString for REMU {
     fn to_string(&self) -> String {
         format!("remu {},{},{}", get_int_reg_name(self.rd), get_int_reg_name(self.rs1), get_int_reg_name(self.rs2))
     }
-}
\ No newline at end of file
+}
+
+pub struct LWU {
+    pub rd: usize,
+    pub rs1: usize,
+    pub imm: u32,
+}
+
+impl<X: Xlen> Op<X> for LWU {
+    fn execute(&self, core: &mut Core<X>) {
+        let addr = core.int_reg.read(self.rs1).wrapping_add(X::Uint::sign_extend(self.imm));
+        if let Some(value) = core.load_u32(addr) {
+            core.int_reg.write(self.rd, X::Uint::from_u32(value));
+        }
+    }
+
+    fn class(&self) -> OpClass {
+        OpClass::Load
+    }
+
+    fn memory_access(&self, core: &Core<X>) -> Option<(u64, u32)> {
+        Some((core.int_reg.read(self.rs1).wrapping_add(X::Uint::sign_extend(self.imm)).to_u64(), 4))
+    }
+}
+
+impl ToString for LWU {
+    fn to_string(&self) -> String {
+        format!("lwu {},{}({})", get_int_reg_name(self.rd), self.imm, get_int_reg_name(self.rs1))
+    }
+}
+
+pub struct LD {
+    pub rd: usize,
+    pub rs1: usize,
+    pub imm: u32,
+}
+
+impl<X: Xlen> Op<X> for LD {
+    fn execute(&self, core: &mut Core<X>) {
+        let addr = core.int_reg.read(self.rs1).wrapping_add(X::Uint::sign_extend(self.imm));
+        if let Some(value) = core.load_u64(addr) {
+            core.int_reg.write(self.rd, X::Uint::from_u64(value));
+        }
+    }
+
+    fn class(&self) -> OpClass {
+        OpClass::Load
+    }
+
+    fn memory_access(&self, core: &Core<X>) -> Option<(u64, u32)> {
+        Some((core.int_reg.read(self.rs1).wrapping_add(X::Uint::sign_extend(self.imm)).to_u64(), 8))
+    }
+}
+
+impl ToString for LD {
+    fn to_string(&self) -> String {
+        format!("ld {},{}({})", get_int_reg_name(self.rd), self.imm, get_int_reg_name(self.rs1))
+    }
+}
+
+pub struct SD {
+    pub rs1: usize,
+    pub rs2: usize,
+    pub imm: u32,
+}
+
+impl<X: Xlen> Op<X> for SD {
+    fn execute(&self, core: &mut Core<X>) {
+        let addr = core.int_reg.read(self.rs1).wrapping_add(X::Uint::sign_extend(self.imm));
+        let value = core.int_reg.read(self.rs2).to_u64();
+
+        core.store_u64(addr, value);
+    }
+
+    fn class(&self) -> OpClass {
+        OpClass::Store
+    }
+
+    fn memory_access(&self, core: &Core<X>) -> Option<(u64, u32)> {
+        Some((core.int_reg.read(self.rs1).wrapping_add(X::Uint::sign_extend(self.imm)).to_u64(), 8))
+    }
+}
+
+impl ToString for SD {
+    fn to_string(&self) -> String {
+        format!("sd {},{}({})", get_int_reg_name(self.rs2), self.imm, get_int_reg_name(self.rs1))
+    }
+}
+
+pub struct ADDIW {
+    pub rd: usize,
+    pub rs1: usize,
+    pub imm: u32,
+}
+
+impl<X: Xlen> Op<X> for ADDIW {
+    fn execute(&self, core: &mut Core<X>) {
+        let src1 = core.int_reg.read(self.rs1).to_u32();
+        let value = src1.wrapping_add(self.imm);
+
+        core.int_reg.write(self.rd, X::Uint::sign_extend(value));
+    }
+}
+
+impl ToString for ADDIW {
+    fn to_string(&self) -> String {
+        format!("addiw {},{},{}", get_int_reg_name(self.rd), get_int_reg_name(self.rs1), self.imm)
+    }
+}
+
+pub struct SLLIW {
+    pub rd: usize,
+    pub rs1: usize,
+    pub shamt: u32,
+}
+
+impl<X: Xlen> Op<X> for SLLIW {
+    fn execute(&self, core: &mut Core<X>) {
+        let src1 = core.int_reg.read(self.rs1).to_u32();
+        let value = src1.wrapping_shl(self.shamt);
+
+        core.int_reg.write(self.rd, X::Uint::sign_extend(value));
+    }
+}
+
+impl ToString for SLLIW {
+    fn to_string(&self) -> String {
+        format!("slliw {},{},{}", get_int_reg_name(self.rd), get_int_reg_name(self.rs1), self.shamt)
+    }
+}
+
+pub struct SRLIW {
+    pub rd: usize,
+    pub rs1: usize,
+    pub shamt: u32,
+}
+
+impl<X: Xlen> Op<X> for SRLIW {
+    fn execute(&self, core: &mut Core<X>) {
+        let src1 = core.int_reg.read(self.rs1).to_u32();
+        let value = src1.wrapping_shr(self.shamt);
+
+        core.int_reg.write(self.rd, X::Uint::sign_extend(value));
+    }
+}
+
+impl ToString for SRLIW {
+    fn to_string(&self) -> String {
+        format!("srliw {},{},{}", get_int_reg_name(self.rd), get_int_reg_name(self.rs1), self.shamt)
+    }
+}
+
+pub struct SRAIW {
+    pub rd: usize,
+    pub rs1: usize,
+    pub shamt: u32,
+}
+
+impl<X: Xlen> Op<X> for SRAIW {
+    fn execute(&self, core: &mut Core<X>) {
+        let src1 = core.int_reg.read(self.rs1).to_u32();
+        let value = (src1 as i32).wrapping_shr(self.shamt) as u32;
+
+        core.int_reg.write(self.rd, X::Uint::sign_extend(value));
+    }
+}
+
+impl ToString for SRAIW {
+    fn to_string(&self) -> String {
+        format!("sraiw {},{},{}", get_int_reg_name(self.rd), get_int_reg_name(self.rs1), self.shamt)
+    }
+}
+
+pub struct ADDW {
+    pub rd: usize,
+    pub rs1: usize,
+    pub rs2: usize,
+}
+
+impl<X: Xlen> Op<X> for ADDW {
+    fn execute(&self, core: &mut Core<X>) {
+        let src1 = core.int_reg.read(self.rs1).to_u32();
+        let src2 = core.int_reg.read(self.rs2).to_u32();
+        let value = src1.wrapping_add(src2);
+
+        core.int_reg.write(self.rd, X::Uint::sign_extend(value));
+    }
+}
+
+impl ToString for ADDW {
+    fn to_string(&self) -> String {
+        format!("addw {},{},{}", get_int_reg_name(self.rd), get_int_reg_name(self.rs1), get_int_reg_name(self.rs2))
+    }
+}
+
+pub struct SUBW {
+    pub rd: usize,
+    pub rs1: usize,
+    pub rs2: usize,
+}
+
+impl<X: Xlen> Op<X> for SUBW {
+    fn execute(&self, core: &mut Core<X>) {
+        let src1 = core.int_reg.read(self.rs1).to_u32();
+        let src2 = core.int_reg.read(self.rs2).to_u32();
+        let value = src1.wrapping_sub(src2);
+
+        core.int_reg.write(self.rd, X::Uint::sign_extend(value));
+    }
+}
+
+impl ToString for SUBW {
+    fn to_string(&self) -> String {
+        format!("subw {},{},{}", get_int_reg_name(self.rd), get_int_reg_name(self.rs1), get_int_reg_name(self.rs2))
+    }
+}
+
+pub struct SLLW {
+    pub rd: usize,
+    pub rs1: usize,
+    pub rs2: usize,
+}
+
+impl<X: Xlen> Op<X> for SLLW {
+    fn execute(&self, core: &mut Core<X>) {
+        let src1 = core.int_reg.read(self.rs1).to_u32();
+        let src2 = core.int_reg.read(self.rs2).to_u32();
+        let value = src1.wrapping_shl(src2);
+
+        core.int_reg.write(self.rd, X::Uint::sign_extend(value));
+    }
+}
+
+impl ToString for SLLW {
+    fn to_string(&self) -> String {
+        format!("sllw {},{},{}", get_int_reg_name(self.rd), get_int_reg_name(self.rs1), get_int_reg_name(self.rs2))
+    }
+}
+
+pub struct SRLW {
+    pub rd: usize,
+    pub rs1: usize,
+    pub rs2: usize,
+}
+
+impl<X: Xlen> Op<X> for SRLW {
+    fn execute(&self, core: &mut Core<X>) {
+        let src1 = core.int_reg.read(self.rs1).to_u32();
+        let src2 = core.int_reg.read(self.rs2).to_u32();
+        let value = src1.wrapping_shr(src2);
+
+        core.int_reg.write(self.rd, X::Uint::sign_extend(value));
+    }
+}
+
+impl ToString for SRLW {
+    fn to_string(&self) -> String {
+        format!("srlw {},{},{}", get_int_reg_name(self.rd), get_int_reg_name(self.rs1), get_int_reg_name(self.rs2))
+    }
+}
+
+pub struct SRAW {
+    pub rd: usize,
+    pub rs1: usize,
+    pub rs2: usize,
+}
+
+impl<X: Xlen> Op<X> for SRAW {
+    fn execute(&self, core: &mut Core<X>) {
+        let src1 = core.int_reg.read(self.rs1).to_u32();
+        let src2 = core.int_reg.read(self.rs2).to_u32();
+        let value = (src1 as i32).wrapping_shr(src2) as u32;
+
+        core.int_reg.write(self.rd, X::Uint::sign_extend(value));
+    }
+}
+
+impl ToString for SRAW {
+    fn to_string(&self) -> String {
+        format!("sraw {},{},{}", get_int_reg_name(self.rd), get_int_reg_name(self.rs1), get_int_reg_name(self.rs2))
+    }
+}
+
+pub struct MULW {
+    pub rd: usize,
+    pub rs1: usize,
+    pub rs2: usize,
+}
+
+impl<X: Xlen> Op<X> for MULW {
+    fn execute(&self, core: &mut Core<X>) {
+        let src1 = core.int_reg.read(self.rs1).to_u32();
+        let src2 = core.int_reg.read(self.rs2).to_u32();
+        let value = src1.wrapping_mul(src2);
+
+        core.int_reg.write(self.rd, X::Uint::sign_extend(value));
+    }
+}
+
+impl ToString for MULW {
+    fn to_string(&self) -> String {
+        format!("mulw {},{},{}", get_int_reg_name(self.rd), get_int_reg_name(self.rs1), get_int_reg_name(self.rs2))
+    }
+}
+
+pub struct DIVW {
+    pub rd: usize,
+    pub rs1: usize,
+    pub rs2: usize,
+}
+
+impl<X: Xlen> Op<X> for DIVW {
+    fn execute(&self, core: &mut Core<X>) {
+        let src1 = core.int_reg.read(self.rs1).to_u32();
+        let src2 = core.int_reg.read(self.rs2).to_u32();
+        let value = src1.div_signed(src2);
+
+        core.int_reg.write(self.rd, X::Uint::sign_extend(value));
+    }
+}
+
+impl ToString for DIVW {
+    fn to_string(&self) -> String {
+        format!("divw {},{},{}", get_int_reg_name(self.rd), get_int_reg_name(self.rs1), get_int_reg_name(self.rs2))
+    }
+}
+
+pub struct DIVUW {
+    pub rd: usize,
+    pub rs1: usize,
+    pub rs2: usize,
+}
+
+impl<X: Xlen> Op<X> for DIVUW {
+    fn execute(&self, core: &mut Core<X>) {
+        let src1 = core.int_reg.read(self.rs1).to_u32();
+        let src2 = core.int_reg.read(self.rs2).to_u32();
+        let value = src1.div_unsigned(src2);
+
+        core.int_reg.write(self.rd, X::Uint::sign_extend(value));
+    }
+}
+
+impl ToString for DIVUW {
+    fn to_string(&self) -> String {
+        format!("divuw {},{},{}", get_int_reg_name(self.rd), get_int_reg_name(self.rs1), get_int_reg_name(self.rs2))
+    }
+}
+
+pub struct REMW {
+    pub rd: usize,
+    pub rs1: usize,
+    pub rs2: usize,
+}
+
+impl<X: Xlen> Op<X> for REMW {
+    fn execute(&self, core: &mut Core<X>) {
+        let src1 = core.int_reg.read(self.rs1).to_u32();
+        let src2 = core.int_reg.read(self.rs2).to_u32();
+        let value = src1.rem_signed(src2);
+
+        core.int_reg.write(self.rd, X::Uint::sign_extend(value));
+    }
+}
+
+impl ToString for REMW {
+    fn to_string(&self) -> String {
+        format!("remw {},{},{}", get_int_reg_name(self.rd), get_int_reg_name(self.rs1), get_int_reg_name(self.rs2))
+    }
+}
+
+pub struct REMUW {
+    pub rd: usize,
+    pub rs1: usize,
+    pub rs2: usize,
+}
+
+impl<X: Xlen> Op<X> for REMUW {
+    fn execute(&self, core: &mut Core<X>) {
+        let src1 = core.int_reg.read(self.rs1).to_u32();
+        let src2 = core.int_reg.read(self.rs2).to_u32();
+        let value = src1.rem_unsigned(src2);
+
+        core.int_reg.write(self.rd, X::Uint::sign_extend(value));
+    }
+}
+
+impl ToString for REMUW {
+    fn to_string(&self) -> String {
+        format!("remuw {},{},{}", get_int_reg_name(self.rd), get_int_reg_name(self.rs1), get_int_reg_name(self.rs2))
+    }
+}
+
+// Atomic memory operations of the A extension, on words (size 4) or doublewords (size 8). As harts
+// execute ops one at a time, the aq and rl bits need no handling.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AmoKind {
+    Swap,
+    Add,
+    Xor,
+    And,
+    Or,
+    Min,
+    Max,
+    Minu,
+    Maxu,
+}
+
+impl AmoKind {
+    // Returns the value to write from the value in memory and rs2, of size bytes.
+    fn apply(self, old: u64, src: u64, size: u32) -> u64 {
+        let shift = 64 - 8 * size;
+        let (signed_old, signed_src) = (((old << shift) as i64) >> shift, ((src << shift) as i64) >> shift);
+        let (old, src) = ((old << shift) >> shift, (src << shift) >> shift);
+        match self {
+            AmoKind::Swap => src,
+            AmoKind::Add => old.wrapping_add(src),
+            AmoKind::Xor => old ^ src,
+            AmoKind::And => old & src,
+            AmoKind::Or => old | src,
+            AmoKind::Min => if signed_old < signed_src { old } else { src },
+            AmoKind::Max => if signed_old > signed_src { old } else { src },
+            AmoKind::Minu => old.min(src),
+            AmoKind::Maxu => old.max(src),
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            AmoKind::Swap => "amoswap",
+            AmoKind::Add => "amoadd",
+            AmoKind::Xor => "amoxor",
+            AmoKind::And => "amoand",
+            AmoKind::Or => "amoor",
+            AmoKind::Min => "amomin",
+            AmoKind::Max => "amomax",
+            AmoKind::Minu => "amominu",
+            AmoKind::Maxu => "amomaxu",
+        }
+    }
+}
+
+fn read_atomic<X: Xlen>(core: &Core<X>, paddr: u64, size: u32) -> u64 {
+    match size {
+        4 => core.bus.read_u32(paddr) as u64,
+        _ => core.bus.read_u64(paddr),
+    }
+}
+
+fn write_atomic<X: Xlen>(core: &mut Core<X>, paddr: u64, size: u32, value: u64) {
+    match size {
+        4 => core.bus.write_u32(paddr, value as u32),
+        _ => core.bus.write_u64(paddr, value),
+    }
+}
+
+// Word results are sign-extended to XLEN.
+fn atomic_result<X: Xlen>(value: u64, size: u32) -> X::Uint {
+    match size {
+        4 => X::Uint::sign_extend(value as u32),
+        _ => X::Uint::from_u64(value),
+    }
+}
+
+fn atomic_suffix(size: u32) -> &'static str {
+    if size == 4 { "w" } else { "d" }
+}
+
+pub struct LR {
+    pub rd: usize,
+    pub rs1: usize,
+    pub size: u32,
+}
+
+impl<X: Xlen> Op<X> for LR {
+    fn execute(&self, core: &mut Core<X>) {
+        let addr = core.int_reg.read(self.rs1);
+        if let Some(paddr) = core.translate_atomic(addr, self.size, MemoryAccess::Load) {
+            let value = read_atomic(core, paddr, self.size);
+            core.reservation = Some(paddr);
+            core.int_reg.write(self.rd, atomic_result::<X>(value, self.size));
+        }
+    }
+
+    fn class(&self) -> OpClass {
+        OpClass::Load
+    }
+
+    fn memory_access(&self, core: &Core<X>) -> Option<(u64, u32)> {
+        Some((core.int_reg.read(self.rs1).to_u64(), self.size))
+    }
+}
+
+impl ToString for LR {
+    fn to_string(&self) -> String {
+        format!("lr.{} {},({})", atomic_suffix(self.size), get_int_reg_name(self.rd), get_int_reg_name(self.rs1))
+    }
+}
+
+pub struct SC {
+    pub rd: usize,
+    pub rs1: usize,
+    pub rs2: usize,
+    pub size: u32,
+}
+
+impl<X: Xlen> Op<X> for SC {
+    fn execute(&self, core: &mut Core<X>) {
+        let addr = core.int_reg.read(self.rs1);
+        let value = core.int_reg.read(self.rs2).to_u64();
+        // SC fails unless the address is reserved, and clears the reservation either way.
+        if let Some(paddr) = core.translate_atomic(addr, self.size, MemoryAccess::Store) {
+            if core.reservation == Some(paddr) {
+                write_atomic(core, paddr, self.size, value);
+                core.int_reg.write(self.rd, X::Uint::ZERO);
+            } else {
+                core.int_reg.write(self.rd, X::Uint::from_u32(1));
+            }
+        }
+        core.reservation = None;
+    }
+
+    fn class(&self) -> OpClass {
+        OpClass::Store
+    }
+
+    fn memory_access(&self, core: &Core<X>) -> Option<(u64, u32)> {
+        Some((core.int_reg.read(self.rs1).to_u64(), self.size))
+    }
+}
+
+impl ToString for SC {
+    fn to_string(&self) -> String {
+        format!("sc.{} {},{},({})", atomic_suffix(self.size), get_int_reg_name(self.rd), get_int_reg_name(self.rs2), get_int_reg_name(self.rs1))
+    }
+}
+
+pub struct AMO {
+    pub kind: AmoKind,
+    pub rd: usize,
+    pub rs1: usize,
+    pub rs2: usize,
+    pub size: u32,
+}
+
+impl<X: Xlen> Op<X> for AMO {
+    fn execute(&self, core: &mut Core<X>) {
+        let addr = core.int_reg.read(self.rs1);
+        let src = core.int_reg.read(self.rs2).to_u64();
+        if let Some(paddr) = core.translate_atomic(addr, self.size, MemoryAccess::Store) {
+            let old = read_atomic(core, paddr, self.size);
+            write_atomic(core, paddr, self.size, self.kind.apply(old, src, self.size));
+            core.int_reg.write(self.rd, atomic_result::<X>(old, self.size));
+        }
+    }
+
+    fn class(&self) -> OpClass {
+        OpClass::Store
+    }
+
+    fn memory_access(&self, core: &Core<X>) -> Option<(u64, u32)> {
+        Some((core.int_reg.read(self.rs1).to_u64(), self.size))
+    }
+}
+
+impl ToString for AMO {
+    fn to_string(&self) -> String {
+        format!("{}.{} {},{},({})", self.kind.name(), atomic_suffix(self.size), get_int_reg_name(self.rd), get_int_reg_name(self.rs2), get_int_reg_name(self.rs1))
+    }
+}
+
+#[test]
+fn test_rv64_ops() {
+    use bus::*;
+    use memory::*;
+
+    let mut memory = Memory::new();
+    let mut bus = Bus::new(&mut memory);
+    let mut core: Core<Rv64> = Core::new(&mut bus);
+
+    core.int_reg.write(1, 0x7fff_ffff);
+    core.int_reg.write(2, 0xffff_ffff_ffff_fff0);
+
+    ADDIW { rd: 3, rs1: 1, imm: 1 }.execute(&mut core);
+    assert_eq!(core.int_reg.read(3), 0xffff_ffff_8000_0000);
+
+    SRAIW { rd: 3, rs1: 2, shamt: 4 }.execute(&mut core);
+    assert_eq!(core.int_reg.read(3), 0xffff_ffff_ffff_ffff);
+
+    SRLIW { rd: 3, rs1: 2, shamt: 4 }.execute(&mut core);
+    assert_eq!(core.int_reg.read(3), 0x0fff_ffff);
+
+    SRAI { rd: 3, rs1: 2, shamt: 36 }.execute(&mut core);
+    assert_eq!(core.int_reg.read(3), 0xffff_ffff_ffff_ffff);
+
+    SRLI { rd: 3, rs1: 2, shamt: 60 }.execute(&mut core);
+    assert_eq!(core.int_reg.read(3), 0xf);
+
+    DIVW { rd: 3, rs1: 2, rs2: 0 }.execute(&mut core);
+    assert_eq!(core.int_reg.read(3), 0xffff_ffff_ffff_ffff);
+
+    MULHU { rd: 3, rs1: 2, rs2: 2 }.execute(&mut core);
+    assert_eq!(core.int_reg.read(3), 0xffff_ffff_ffff_ffe0);
+
+    LUI { rd: 3, imm: 0x8000_0000 }.execute(&mut core);
+    assert_eq!(core.int_reg.read(3), 0xffff_ffff_8000_0000);
+
+    core.int_reg.write(4, 0x8000_0000);
+    SD { rs1: 4, rs2: 2, imm: 0x100 }.execute(&mut core);
+    LWU { rd: 3, rs1: 4, imm: 0x100 }.execute(&mut core);
+    assert_eq!(core.int_reg.read(3), 0xffff_fff0);
+    LD { rd: 3, rs1: 4, imm: 0x100 }.execute(&mut core);
+    assert_eq!(core.int_reg.read(3), 0xffff_ffff_ffff_fff0);
+}
+
+#[test]
+fn test_atomic_ops() {
+    use bus::*;
+    use decoder::*;
+    use memory::*;
+
+    let mut memory = Memory::new();
+    let mut bus = Bus::new(&mut memory);
+    let mut core: Core<Rv64> = Core::new(&mut bus);
+    core.pc = 0x8000_0000;
+
+    core.int_reg.write(1, 0x8000_0100);
+    core.int_reg.write(2, 0xffff_ffff_ffff_fffe);
+    core.bus.write_u64(0x8000_0100, 0x0000_0001_8000_0000);
+
+    // Word AMOs compare and return sign-extended words, and leave the upper word alone.
+    core.execute(&AMO { kind: AmoKind::Min, rd: 3, rs1: 1, rs2: 2, size: 4 });
+    assert_eq!(core.int_reg.read(3), 0xffff_ffff_8000_0000);
+    assert_eq!(core.bus.read_u64(0x8000_0100), 0x0000_0001_8000_0000);
+    core.execute(&AMO { kind: AmoKind::Minu, rd: 3, rs1: 1, rs2: 2, size: 4 });
+    assert_eq!(core.bus.read_u64(0x8000_0100), 0x0000_0001_8000_0000);
+    core.execute(&AMO { kind: AmoKind::Add, rd: 3, rs1: 1, rs2: 2, size: 8 });
+    assert_eq!(core.int_reg.read(3), 0x0000_0001_8000_0000);
+    assert_eq!(core.bus.read_u64(0x8000_0100), 0x0000_0001_7fff_fffe);
+
+    // SC succeeds only on the address reserved by LR, once.
+    core.execute(&SC { rd: 3, rs1: 1, rs2: 2, size: 8 });
+    assert_eq!(core.int_reg.read(3), 1);
+    core.execute(&LR { rd: 3, rs1: 1, size: 4 });
+    assert_eq!(core.int_reg.read(3), 0x7fff_fffe);
+    core.execute(&SC { rd: 3, rs1: 1, rs2: 0, size: 4 });
+    assert_eq!(core.int_reg.read(3), 0);
+    assert_eq!(core.bus.read_u64(0x8000_0100), 0x0000_0001_0000_0000);
+    core.execute(&SC { rd: 3, rs1: 1, rs2: 2, size: 4 });
+    assert_eq!(core.int_reg.read(3), 1);
+
+    // Misaligned AMOs raise a store access fault.
+    core.int_reg.write(1, 0x8000_0104);
+    core.execute(&AMO { kind: AmoKind::Swap, rd: 3, rs1: 1, rs2: 2, size: 8 });
+    assert_eq!(core.last_trap_cause, Some(7));
+
+    assert_eq!(decode::<Rv64>(&0x0820_b1af).to_string(), "amoswap.d gp,sp,(ra)");
+    assert_eq!(decode::<Rv32>(&0x1000_a1af).to_string(), "lr.w gp,(ra)");
+    assert_eq!(decode::<Rv32>(&0x1000_b1af).to_string(), "unknown");
+}
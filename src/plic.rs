@@ -0,0 +1,207 @@
+// Platform-level interrupt controller (PLIC) compatible with SiFive's, with 31 level-triggered
+// sources and two contexts for each hart: context 2 * hart raises the machine external interrupt of
+// the hart and context 2 * hart + 1 its supervisor external interrupt.
+//
+// A source is pending while its device requests the interrupt, until it is claimed. It is not
+// pending again before the claim is completed.
+
+use bus::*;
+use fdt::*;
+use snapshot::*;
+
+use serde::{Deserialize, Serialize};
+
+pub const PLIC_SIZE: u64 = 0x400_0000;
+pub const PLIC_NUM_SOURCES: u32 = 32;
+
+const PRIORITY: u64 = 0x0;
+const PENDING: u64 = 0x1000;
+const ENABLE: u64 = 0x2000;
+const ENABLE_STRIDE: u64 = 0x80;
+const CONTEXT: u64 = 0x20_0000;
+const CONTEXT_STRIDE: u64 = 0x1000;
+
+const CONTEXTS_PER_HART: usize = 2;
+const MAX_PRIORITY: u32 = 7;
+
+// Bits of mip raised by the contexts of a hart
+const CONTEXT_INTERRUPTS: [u64; CONTEXTS_PER_HART] = [1 << 11, 1 << 9];
+
+#[derive(Serialize, Deserialize)]
+pub struct Plic {
+    priority: [u32; PLIC_NUM_SOURCES as usize],
+    pending: u32,
+    // Sources which are claimed and not completed yet
+    claimed: u32,
+    // Registers of each context
+    enable: Vec<u32>,
+    threshold: Vec<u32>,
+}
+
+impl Plic {
+    pub fn new(num_harts: usize) -> Plic {
+        Plic {
+            priority: [0; PLIC_NUM_SOURCES as usize],
+            pending: 0,
+            claimed: 0,
+            enable: vec![0; CONTEXTS_PER_HART * num_harts],
+            threshold: vec![0; CONTEXTS_PER_HART * num_harts],
+        }
+    }
+
+    fn num_contexts(&self) -> u64 {
+        self.enable.len() as u64
+    }
+
+    // Returns the pending and enabled source of the highest priority above the threshold of the
+    // context. Ties go to the lowest source number.
+    fn best_source(&self, context: usize) -> Option<u32> {
+        let candidates = self.pending & self.enable[context];
+        (1..PLIC_NUM_SOURCES)
+            .filter(|source| candidates & (1 << source) != 0 && self.priority[*source as usize] > self.threshold[context])
+            .fold(None, |best: Option<u32>, source| match best {
+                Some(b) if self.priority[b as usize] >= self.priority[source as usize] => Some(b),
+                _ => Some(source),
+            })
+    }
+
+    fn claim(&mut self, context: usize) -> u32 {
+        match self.best_source(context) {
+            Some(source) => {
+                self.pending &= !(1 << source);
+                self.claimed |= 1 << source;
+                source
+            },
+            None => 0,
+        }
+    }
+
+    fn complete(&mut self, context: usize, source: u32) {
+        if source < PLIC_NUM_SOURCES && self.enable[context] & (1 << source) != 0 {
+            self.claimed &= !(1 << source);
+        }
+    }
+}
+
+impl Device for Plic {
+    fn size(&self) -> u64 {
+        PLIC_SIZE
+    }
+
+    fn read(&mut self, offset: u64, _size: u32) -> u64 {
+        let value = match offset {
+            PRIORITY..=0xfff => self.priority.get((offset / 4) as usize).cloned().unwrap_or(0),
+            PENDING => self.pending,
+            ENABLE..=0x1f_ffff => match ((offset - ENABLE) / ENABLE_STRIDE, (offset - ENABLE) % ENABLE_STRIDE) {
+                (context, 0) if context < self.num_contexts() => self.enable[context as usize],
+                _ => 0,
+            },
+            _ if offset >= CONTEXT => match ((offset - CONTEXT) / CONTEXT_STRIDE, (offset - CONTEXT) % CONTEXT_STRIDE) {
+                (context, 0) if context < self.num_contexts() => self.threshold[context as usize],
+                (context, 4) if context < self.num_contexts() => self.claim(context as usize),
+                _ => 0,
+            },
+            _ => 0,
+        };
+        value as u64
+    }
+
+    fn write(&mut self, offset: u64, _size: u32, value: u64) {
+        let value = value as u32;
+        match offset {
+            PRIORITY..=0xfff => {
+                if let Some(priority) = self.priority.get_mut((offset / 4) as usize) {
+                    *priority = value.min(MAX_PRIORITY);
+                }
+                // Source 0 does not exist.
+                self.priority[0] = 0;
+            },
+            ENABLE..=0x1f_ffff => {
+                if let (context, 0) = ((offset - ENABLE) / ENABLE_STRIDE, (offset - ENABLE) % ENABLE_STRIDE) {
+                    if context < self.num_contexts() {
+                        self.enable[context as usize] = value & !1;
+                    }
+                }
+            },
+            _ if offset >= CONTEXT => match ((offset - CONTEXT) / CONTEXT_STRIDE, (offset - CONTEXT) % CONTEXT_STRIDE) {
+                (context, 0) if context < self.num_contexts() => self.threshold[context as usize] = value.min(MAX_PRIORITY),
+                (context, 4) if context < self.num_contexts() => self.complete(context as usize, value),
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+
+    fn interrupt_lines(&mut self, hart: usize, irqs: u64) -> u64 {
+        self.pending = (irqs as u32) & !self.claimed;
+        (0..CONTEXTS_PER_HART)
+            .filter(|i| CONTEXTS_PER_HART * hart + i < self.enable.len() && self.best_source(CONTEXTS_PER_HART * hart + i).is_some())
+            .map(|i| CONTEXT_INTERRUPTS[i])
+            .fold(0, |lines, l| lines | l)
+    }
+
+    fn reset(&mut self) {
+        *self = Plic::new(self.enable.len() / CONTEXTS_PER_HART);
+    }
+
+    fn save(&self) -> Option<Vec<u8>> {
+        Some(encode_state(self, &[]))
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), String> {
+        let (plic, _): (Plic, _) = decode_state(state)?;
+        if plic.enable.len() != self.enable.len() || plic.threshold.len() != self.enable.len() {
+            return Err(format!("PLIC has {} contexts (machine has {})", plic.enable.len(), self.enable.len()))
+        }
+        *self = plic;
+        Ok(())
+    }
+
+    fn device_tree_node(&self, fdt: &mut Fdt, base: u64) {
+        let num_harts = (self.enable.len() / CONTEXTS_PER_HART) as u32;
+        let interrupts: Vec<u32> = (0..num_harts).flat_map(|hart| vec![cpu_intc_phandle(hart), 11, cpu_intc_phandle(hart), 9]).collect();
+        fdt.begin_node(&format!("plic@{:x}", base));
+        fdt.property_strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"]);
+        fdt.property_cells("reg", &[(base >> 32) as u32, base as u32, 0, PLIC_SIZE as u32]);
+        fdt.property_u32("#address-cells", 0);
+        fdt.property_u32("#interrupt-cells", 1);
+        fdt.property_null("interrupt-controller");
+        fdt.property_u32("riscv,ndev", PLIC_NUM_SOURCES - 1);
+        // The contexts of each hart raise its machine and supervisor external interrupts.
+        fdt.property_cells("interrupts-extended", &interrupts);
+        fdt.property_u32("phandle", PHANDLE_PLIC);
+        fdt.end_node();
+    }
+}
+
+#[test]
+fn test_plic() {
+    let mut plic = Plic::new(2);
+    plic.write(PRIORITY + 4 * 3, 4, 1);
+    plic.write(PRIORITY + 4 * 10, 4, 2);
+    plic.write(ENABLE + ENABLE_STRIDE, 4, 1 << 3 | 1 << 10);
+
+    // Disabled in context 0, and the threshold of context 1 masks priority 1.
+    plic.write(CONTEXT + CONTEXT_STRIDE, 4, 1);
+    assert_eq!(plic.interrupt_lines(0, 1 << 3), 0);
+    assert_eq!(plic.read(PENDING, 4), 1 << 3);
+    plic.write(CONTEXT + CONTEXT_STRIDE, 4, 0);
+    assert_eq!(plic.interrupt_lines(0, 1 << 3 | 1 << 10), 1 << 9);
+
+    // Claims go by priority, and a claimed source is not pending until completed.
+    assert_eq!(plic.read(CONTEXT + CONTEXT_STRIDE + 4, 4), 10);
+    assert_eq!(plic.interrupt_lines(0, 1 << 3 | 1 << 10), 1 << 9);
+    assert_eq!(plic.read(CONTEXT + CONTEXT_STRIDE + 4, 4), 3);
+    assert_eq!(plic.interrupt_lines(0, 1 << 3 | 1 << 10), 0);
+    assert_eq!(plic.read(CONTEXT + CONTEXT_STRIDE + 4, 4), 0);
+    plic.write(CONTEXT + CONTEXT_STRIDE + 4, 4, 10);
+    assert_eq!(plic.interrupt_lines(0, 1 << 3 | 1 << 10), 1 << 9);
+    assert_eq!(plic.read(PENDING, 4), 1 << 10);
+
+    // Context 2 is the M-mode context of hart 1.
+    plic.write(ENABLE + 2 * ENABLE_STRIDE, 4, 1 << 10);
+    assert_eq!(plic.interrupt_lines(1, 1 << 10), 1 << 11);
+    assert_eq!(plic.read(CONTEXT + 2 * CONTEXT_STRIDE + 4, 4), 10);
+    assert_eq!(plic.interrupt_lines(1, 1 << 10), 0);
+    assert_eq!(plic.read(CONTEXT + 4 * CONTEXT_STRIDE + 4, 4), 0);
+}
@@ -0,0 +1,202 @@
+// Physical Memory Protection.
+//
+// Entries are configured through pmpcfg and pmpaddr, and checked for every physical access after
+// address translation, including page table walks.
+
+use core::*;
+use csr::*;
+use mmu::*;
+use xlen::*;
+
+fn address_mode(cfg: u8) -> u8 {
+    (cfg >> PMPCFG_A_SHIFT) & 3
+}
+
+// Returns the matched range of entry i as [start, end).
+fn entry_range<X: Xlen>(csr: &Csr<X>, i: usize) -> Option<(u64, u64)> {
+    let addr = csr.read_pmpaddr(i);
+    match address_mode(csr.read_pmpcfg(i)) {
+        PMP_TOR => {
+            let start = if i == 0 { 0 } else { csr.read_pmpaddr(i - 1) << 2 };
+            Some((start, addr << 2))
+        },
+        PMP_NA4 => Some((addr << 2, (addr << 2) + 4)),
+        PMP_NAPOT => {
+            // The number of trailing ones selects a region of 2^(ones + 3) bytes.
+            let ones = addr.trailing_ones();
+            let start = (addr & !((1 << ones) - 1)) << 2;
+            Some((start, start + (1 << (ones + 3))))
+        },
+        _ => None,
+    }
+}
+
+// Returns true when PMP may deny an access at the current privilege, i.e. the core is not in M-mode,
+// where accesses matching no entry are denied, or a locked entry is active.
+pub fn is_pmp_enforced<X: Xlen>(core: &Core<X>) -> bool {
+    // An entry granting RWX for the whole physical address space decides all accesses if it is the
+    // first active one, e.g. the one set up to run user programs without protection.
+    let physical_bits = if X::XLEN == 32 { 34 } else { 56 };
+    let first = (0..NUM_PMP).find(|i| address_mode(core.csr.read_pmpcfg(*i)) != PMP_OFF);
+    if let Some(i) = first {
+        let rwx = PMPCFG_R | PMPCFG_W | PMPCFG_X;
+        if core.csr.read_pmpcfg(i) & rwx == rwx && entry_range(&core.csr, i).is_some_and(|(start, end)| start == 0 && end >= 1 << physical_bits) {
+            return false
+        }
+    }
+
+    let lower_privilege = core.privilege != PRIV_MACHINE || core.csr.read_mstatus().mprv() == 1;
+    lower_privilege || (0..NUM_PMP).map(|i| core.csr.read_pmpcfg(i)).any(|cfg| {
+        address_mode(cfg) != PMP_OFF && cfg & PMPCFG_L != 0
+    })
+}
+
+// Checks an access of size bytes at the physical address addr made at privilege.
+pub fn check_pmp<X: Xlen>(csr: &Csr<X>, addr: u64, size: u32, access: MemoryAccess, privilege: u32) -> bool {
+    // An access wrapping around the address space cannot match any entry completely.
+    let end = match addr.checked_add(size as u64) {
+        Some(end) => end,
+        None => return false,
+    };
+
+    for i in 0..NUM_PMP {
+        let (start, limit) = match entry_range(csr, i) {
+            Some(range) => range,
+            None => continue,
+        };
+
+        // The lowest-numbered entry matching any byte decides, and must match all of them.
+        if addr >= limit || end <= start {
+            continue
+        }
+        if addr < start || end > limit {
+            return false
+        }
+
+        let cfg = csr.read_pmpcfg(i);
+        if privilege == PRIV_MACHINE && cfg & PMPCFG_L == 0 {
+            return true
+        }
+        let permission = match access {
+            MemoryAccess::Fetch => PMPCFG_X,
+            MemoryAccess::Load => PMPCFG_R,
+            MemoryAccess::Store => PMPCFG_W,
+        };
+        return cfg & permission != 0
+    }
+
+    // Accesses matching no entry succeed only in M-mode, because all entries are implemented.
+    privilege == PRIV_MACHINE
+}
+
+#[test]
+fn test_pmp() {
+    use bus::*;
+    use memory::*;
+    use op::*;
+
+    let mut memory = Memory::new();
+    let mut bus = Bus::new(&mut memory);
+    let mut core: Core = Core::new(&mut bus);
+
+    // Without active entries, all accesses are denied below M-mode.
+    assert!(!is_pmp_enforced(&core));
+    core.privilege = PRIV_USER;
+    assert!(is_pmp_enforced(&core));
+    assert!(!check_pmp(&core.csr, 0x8000_0000, 4, MemoryAccess::Fetch, PRIV_USER));
+
+    // A leading RWX entry for all addresses cannot deny anything.
+    core.csr.write(0x3b0, !0);
+    core.csr.write(0x3a0, 0x1f);
+    assert!(!is_pmp_enforced(&core));
+    core.csr.write(0x3a0, 0x1b);
+    assert!(is_pmp_enforced(&core));
+    core.privilege = PRIV_MACHINE;
+
+    // 0: NA4 at 0x8000_0000 (no permission), 1: TOR [0x8000_0000, 0x8000_2000) read-only,
+    // 2: NAPOT 0x8000_0000-0x8000_ffff RWX, 3: W without R which is legalized to no permission.
+    core.csr.write(0x3b0, 0x8000_0000 >> 2);
+    core.csr.write(0x3b1, 0x8000_2000 >> 2);
+    core.csr.write(0x3b2, (0x8000_0000 >> 2) | 0x1fff);
+    core.csr.write(0x3a0, 0x1a_1f_09_10);
+    assert_eq!(core.csr.read(0x3a0), 0x18_1f_09_10);
+
+    let csr = &core.csr;
+    assert!(!check_pmp(csr, 0x8000_0000, 4, MemoryAccess::Load, PRIV_USER));
+    assert!(check_pmp(csr, 0x8000_0000, 4, MemoryAccess::Load, PRIV_MACHINE));
+    assert!(check_pmp(csr, 0x8000_0004, 4, MemoryAccess::Load, PRIV_USER));
+    assert!(!check_pmp(csr, 0x8000_0004, 4, MemoryAccess::Store, PRIV_USER));
+    // Partially matching the TOR entry
+    assert!(!check_pmp(csr, 0x8000_1ffe, 4, MemoryAccess::Load, PRIV_USER));
+    assert!(check_pmp(csr, 0x8000_2000, 4, MemoryAccess::Store, PRIV_USER));
+    assert!(check_pmp(csr, 0x8000_fffc, 4, MemoryAccess::Fetch, PRIV_SUPERVISOR));
+    assert!(!check_pmp(csr, 0x8001_0000, 4, MemoryAccess::Load, PRIV_USER));
+    assert!(check_pmp(csr, 0x8001_0000, 4, MemoryAccess::Load, PRIV_MACHINE));
+    assert!(!check_pmp(csr, 0xffff_ffff_ffff_fff8, 8, MemoryAccess::Load, PRIV_MACHINE));
+
+    // Locking entry 1 applies it to M-mode and freezes pmpaddr0 (TOR base), pmpaddr1 and its cfg.
+    core.csr.write(0x3a0, 0x18_1f_89_10);
+    core.csr.write(0x3a0, 0);
+    core.csr.write(0x3b0, 0);
+    core.csr.write(0x3b1, 0);
+    assert_eq!(core.csr.read(0x3a0), 0x0000_8900);
+    assert_eq!(core.csr.read(0x3b0), 0x8000_0000 >> 2);
+    assert_eq!(core.csr.read(0x3b1), 0x8000_2000 >> 2);
+    assert!(!check_pmp(&core.csr, 0x8000_0004, 4, MemoryAccess::Store, PRIV_MACHINE));
+    assert!(is_pmp_enforced(&core));
+
+    // Stores from M-mode to the locked region raise a store access fault.
+    core.int_reg.write(1, 0x8000_0100);
+    core.execute(&SW { rs1: 1, rs2: 0, imm: 0 });
+    assert_eq!(core.last_trap_cause, Some(7));
+    assert_eq!(core.csr.read(0x343), 0x8000_0100);
+}
+
+#[test]
+fn test_pmp_edge_cases() {
+    use bus::*;
+    use memory::*;
+
+    let mut memory = Memory::new();
+    let mut bus = Bus::new(&mut memory);
+    let mut core: Core = Core::new(&mut bus);
+
+    // A TOR entry 0 starts at address 0, and a TOR entry whose top is not above its base matches
+    // nothing.
+    core.csr.write(0x3b0, 0x1000 >> 2);
+    core.csr.write(0x3b1, 0x2000 >> 2);
+    core.csr.write(0x3b2, 0x1800 >> 2);
+    core.csr.write(0x3a0, 0x0f_00_09);
+    let csr = &core.csr;
+    assert!(check_pmp(csr, 0, 4, MemoryAccess::Load, PRIV_USER));
+    assert!(check_pmp(csr, 0xffc, 4, MemoryAccess::Load, PRIV_USER));
+    assert!(!check_pmp(csr, 0x1000, 4, MemoryAccess::Load, PRIV_USER));
+    assert!(!check_pmp(csr, 0x1c00, 4, MemoryAccess::Load, PRIV_USER));
+    assert!(check_pmp(csr, 0x1c00, 4, MemoryAccess::Load, PRIV_MACHINE));
+
+    // An access spanning two entries is denied even if both allow it, also in M-mode.
+    core.csr.write(0x3b0, 0x8000_0000 >> 2);
+    core.csr.write(0x3b1, 0x8000_0004 >> 2);
+    core.csr.write(0x3a0, 0x13_13);
+    let csr = &core.csr;
+    assert!(check_pmp(csr, 0x8000_0004, 4, MemoryAccess::Load, PRIV_USER));
+    assert!(!check_pmp(csr, 0x8000_0000, 8, MemoryAccess::Load, PRIV_USER));
+    assert!(!check_pmp(csr, 0x8000_0000, 8, MemoryAccess::Load, PRIV_MACHINE));
+
+    // With MPRV and MPP=U, loads and stores of M-mode are checked as U-mode, but fetches are not.
+    core.csr.write(0x300, 1 << 17);
+    assert!(is_pmp_enforced(&core));
+    assert_eq!(core.translate(0x8000_1000, 4, MemoryAccess::Load), None);
+    assert_eq!(core.translate(0x8000_1000, 4, MemoryAccess::Fetch), Some(0x8000_1000));
+    core.csr.write(0x300, 0);
+
+    // A locked NA4 entry does not freeze pmpaddr of the previous entry, unlike a locked TOR entry,
+    // and locked entries cannot be unlocked.
+    core.csr.write(0x3a0, 0x93_13);
+    core.csr.write(0x3b0, 0x9000_0000 >> 2);
+    core.csr.write(0x3b1, 0);
+    core.csr.write(0x3a0, 0);
+    assert_eq!(core.csr.read(0x3b0), 0x9000_0000 >> 2);
+    assert_eq!(core.csr.read(0x3b1), 0x8000_0004 >> 2);
+    assert_eq!(core.csr.read(0x3a0), 0x93_00);
+}
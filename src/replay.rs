@@ -0,0 +1,244 @@
+// Record and replay of the inputs which make runs nondeterministic: bytes from the host to devices and
+// stdin, values of the host clock, and the points where interrupts are taken. Each is logged with the
+// cycle at which it happened, one JSON object per line after a header.
+//
+// Replaying takes the inputs from the log instead of the host, and does not sleep while harts wait, so
+// that the run repeats the recorded one op for op. The logged interrupts are checked against the ones
+// taken to report when the run diverges, e.g. because a different binary or options are used.
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+
+// Increment when the log format changes.
+pub const REPLAY_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    version: u32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+enum Event {
+    // Bytes received from the host by the device with the index on the bus, in hex
+    Input { device: usize, data: String },
+    // Bytes read from the host stdin by a syscall, in hex
+    Stdin { data: String },
+    // Value read from the host clock
+    Time { value: u64 },
+    Interrupt { hart: usize, cause: u32 },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    cycle: u64,
+    event: Event,
+}
+
+enum Mode {
+    // The log, which is closed when a write fails
+    Record(Option<BufWriter<File>>),
+    Replay(VecDeque<Entry>),
+}
+
+pub struct Replay {
+    path: String,
+    mode: Mode,
+    // The run has diverged from the log, which is reported once.
+    diverged: bool,
+}
+
+fn encode(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode(data: &str) -> Vec<u8> {
+    (0..data.len() / 2).filter_map(|i| u8::from_str_radix(&data[2 * i..2 * i + 2], 16).ok()).collect()
+}
+
+impl Replay {
+    // Starts a log at path.
+    pub fn record(path: &str) -> Result<Replay, String> {
+        let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+        let mut replay = Replay { path: path.to_string(), mode: Mode::Record(Some(BufWriter::new(file))), diverged: false };
+        replay.write_line(&Header { version: REPLAY_VERSION });
+        Ok(replay)
+    }
+
+    // Loads the log at path to replay it.
+    pub fn load(path: &str) -> Result<Replay, String> {
+        let file = File::open(path).map_err(|e| format!("{}: {}", path, e))?;
+        let mut lines = BufReader::new(file).lines();
+
+        let header = lines.next().unwrap_or_else(|| Ok(String::new())).map_err(|e| format!("{}: {}", path, e))?;
+        let header: Header = serde_json::from_str(&header).map_err(|e| format!("{}: {}", path, e))?;
+        if header.version != REPLAY_VERSION {
+            return Err(format!("{}: unsupported replay log version {} (expected {})", path, header.version, REPLAY_VERSION))
+        }
+
+        let mut entries = VecDeque::new();
+        for line in lines {
+            let line = line.map_err(|e| format!("{}: {}", path, e))?;
+            entries.push_back(serde_json::from_str(&line).map_err(|e| format!("{}: {}", path, e))?);
+        }
+        Ok(Replay { path: path.to_string(), mode: Mode::Replay(entries), diverged: false })
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        match self.mode {
+            Mode::Record(_) => false,
+            Mode::Replay(_) => true,
+        }
+    }
+
+    // Writes a line of the log. A failed write stops the recording, which is reported.
+    fn write_line<T: Serialize>(&mut self, value: &T) {
+        let result = match &mut self.mode {
+            // Lines are flushed so that the log is complete when the emulator is killed.
+            Mode::Record(Some(writer)) => serde_json::to_writer(&mut *writer, value)
+                .map_err(|e| e.to_string())
+                .and_then(|_| writer.write_all(b"\n").and_then(|_| writer.flush()).map_err(|e| e.to_string())),
+            _ => Ok(()),
+        };
+        if let Err(message) = result {
+            eprintln!("Failed to record {}: {}", self.path, message);
+            self.mode = Mode::Record(None);
+        }
+    }
+
+    fn log(&mut self, cycle: u64, event: Event) {
+        self.write_line(&Entry { cycle, event });
+    }
+
+    fn diverge(&mut self, cycle: u64) {
+        if !self.diverged {
+            eprintln!("Warning: the run diverged from {} at cycle {}", self.path, cycle);
+            self.diverged = true;
+        }
+    }
+
+    // Takes the next logged event if it happened at cycle and `matches`. Otherwise the run has diverged.
+    fn next_event<F: Fn(&Event) -> bool>(&mut self, cycle: u64, matches: F) -> Option<Event> {
+        let event = match &mut self.mode {
+            Mode::Replay(entries) => match entries.front() {
+                Some(entry) if entry.cycle == cycle && matches(&entry.event) => entries.pop_front().map(|entry| entry.event),
+                _ => None,
+            },
+            Mode::Record(_) => return None,
+        };
+        if event.is_none() {
+            self.diverge(cycle);
+        }
+        event
+    }
+
+    // Records bytes which the device received from the host.
+    pub fn record_input(&mut self, cycle: u64, device: usize, data: &[u8]) {
+        self.log(cycle, Event::Input { device, data: encode(data) });
+    }
+
+    // Returns the bytes which devices received from the host until cycle, as (device, data).
+    pub fn replay_inputs(&mut self, cycle: u64) -> Vec<(usize, Vec<u8>)> {
+        let mut inputs = Vec::new();
+        if let Mode::Replay(entries) = &mut self.mode {
+            while let Some(Entry { cycle: c, event: Event::Input { .. } }) = entries.front() {
+                if *c > cycle {
+                    break
+                }
+                if let Some(Entry { event: Event::Input { device, data }, .. }) = entries.pop_front() {
+                    inputs.push((device, decode(&data)));
+                }
+            }
+        }
+        inputs
+    }
+
+    // Reads stdin into buf, from the host or the log.
+    pub fn read_stdin(&mut self, cycle: u64, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.is_replaying() {
+            let size = io::stdin().read(buf)?;
+            self.log(cycle, Event::Stdin { data: encode(&buf[..size]) });
+            return Ok(size)
+        }
+
+        match self.next_event(cycle, |event| matches!(event, Event::Stdin { .. })) {
+            Some(Event::Stdin { data }) => {
+                let data = decode(&data);
+                let size = data.len().min(buf.len());
+                buf[..size].copy_from_slice(&data[..size]);
+                Ok(size)
+            },
+            _ => io::stdin().read(buf),
+        }
+    }
+
+    // Returns a value of the host clock given by now, or the logged one.
+    pub fn time<F: FnOnce() -> u64>(&mut self, cycle: u64, now: F) -> u64 {
+        if !self.is_replaying() {
+            let value = now();
+            self.log(cycle, Event::Time { value });
+            return value
+        }
+
+        match self.next_event(cycle, |event| matches!(event, Event::Time { .. })) {
+            Some(Event::Time { value }) => value,
+            _ => now(),
+        }
+    }
+
+    // Records an interrupt taken by hart, or checks it against the log.
+    pub fn interrupt(&mut self, cycle: u64, hart: usize, cause: u32) {
+        let event = Event::Interrupt { hart, cause };
+        if !self.is_replaying() {
+            return self.log(cycle, event)
+        }
+        self.next_event(cycle, |e| *e == event);
+    }
+}
+
+#[test]
+fn test_replay() {
+    let path = std::env::temp_dir().join(format!("rafi-emu-replay-{}.json", std::process::id()));
+    let path = path.to_str().unwrap();
+
+    let mut replay = Replay::record(path).unwrap();
+    replay.record_input(10, 2, b"ab");
+    assert_eq!(replay.time(12, || 1234), 1234);
+    replay.interrupt(15, 0, 11);
+    replay.record_input(20, 2, b"c");
+    replay.interrupt(30, 1, 7);
+    drop(replay);
+
+    let mut replay = Replay::load(path).unwrap();
+    std::fs::remove_file(path).unwrap();
+    assert!(replay.is_replaying());
+    assert_eq!(replay.replay_inputs(9), vec![]);
+    assert_eq!(replay.replay_inputs(10), vec![(2, b"ab".to_vec())]);
+    assert_eq!(replay.time(12, || 0), 1234);
+    replay.interrupt(15, 0, 11);
+    assert_eq!(replay.replay_inputs(25), vec![(2, b"c".to_vec())]);
+    assert!(!replay.diverged);
+
+    // An interrupt taken at another cycle is a divergence.
+    replay.interrupt(31, 1, 7);
+    assert!(replay.diverged);
+}
+
+#[test]
+fn test_replay_invalid_logs() {
+    let path = std::env::temp_dir().join(format!("rafi-emu-replay-invalid-{}.json", std::process::id()));
+    let path = path.to_str().unwrap();
+    let error = |log: &str| {
+        std::fs::write(path, log).unwrap();
+        Replay::load(path).err().unwrap()
+    };
+
+    assert!(error("{\"version\":0}\n").contains("unsupported replay log version 0"));
+    assert!(error("").contains(path));
+    assert!(error(&format!("{{\"version\":{}}}\n{{\"cycle\":1}}\n", REPLAY_VERSION)).contains(path));
+    std::fs::remove_file(path).unwrap();
+    assert!(Replay::load(path).is_err());
+}
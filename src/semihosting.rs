@@ -0,0 +1,322 @@
+// RISC-V semihosting, which runs the operations of the Arm semihosting specification on the host.
+//
+// The operation number is in a0 and a1 holds its parameter, usually the address of a block of
+// XLEN-sized fields. The result is returned in a0, where -1 reports an error whose number is then
+// returned by SYS_ERRNO. The program exits by writing (code << 1) | 1 to tohost as HTIF does.
+
+use core::*;
+use syscall::*;
+use xlen::*;
+
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+const SYS_OPEN: u64 = 0x01;
+const SYS_CLOSE: u64 = 0x02;
+const SYS_WRITEC: u64 = 0x03;
+const SYS_WRITE0: u64 = 0x04;
+const SYS_WRITE: u64 = 0x05;
+const SYS_READ: u64 = 0x06;
+const SYS_READC: u64 = 0x07;
+const SYS_ISERROR: u64 = 0x08;
+const SYS_ISTTY: u64 = 0x09;
+const SYS_SEEK: u64 = 0x0a;
+const SYS_FLEN: u64 = 0x0c;
+const SYS_REMOVE: u64 = 0x0e;
+const SYS_RENAME: u64 = 0x0f;
+const SYS_CLOCK: u64 = 0x10;
+const SYS_TIME: u64 = 0x11;
+const SYS_ERRNO: u64 = 0x13;
+const SYS_GET_CMDLINE: u64 = 0x15;
+const SYS_HEAPINFO: u64 = 0x16;
+const SYS_EXIT: u64 = 0x18;
+const SYS_EXIT_EXTENDED: u64 = 0x20;
+const SYS_ELAPSED: u64 = 0x30;
+const SYS_TICKFREQ: u64 = 0x31;
+
+const ADP_STOPPED_APPLICATION_EXIT: u64 = 0x20026;
+
+// SYS_ELAPSED counts microseconds.
+const TICK_FREQUENCY: i64 = 1_000_000;
+
+// SYS_OPEN of ":tt" opens the console: stdin for reading, stdout for writing and stderr for appending.
+const CONSOLE_PATH: &str = ":tt";
+
+// Open flags for the fopen() modes "r", "r+", "w", "w+", "a" and "a+", each followed by its "b" variant
+const OPEN_FLAGS: [u64; 6] = [
+    O_RDONLY,
+    O_RDWR,
+    O_WRONLY | O_CREAT | O_TRUNC,
+    O_RDWR | O_CREAT | O_TRUNC,
+    O_WRONLY | O_CREAT | O_APPEND,
+    O_RDWR | O_CREAT | O_APPEND,
+];
+
+pub struct Semihosting {
+    files: HostFiles,
+    // Returned by SYS_GET_CMDLINE
+    cmdline: String,
+    // Error number of the last failed operation
+    errno: i64,
+    start: Instant,
+}
+
+impl Semihosting {
+    // Files are accessible in `root` only.
+    pub fn new(root: Option<PathBuf>, cmdline: String) -> Semihosting {
+        Semihosting { files: HostFiles::new(root), cmdline, errno: 0, start: Instant::now() }
+    }
+
+    // Converts the result of a file operation, which is negative errno on failure.
+    fn result(&mut self, result: i64) -> i64 {
+        if result < 0 {
+            self.errno = -result;
+            return -1
+        }
+        result
+    }
+
+    // SYS_READ and SYS_WRITE return the number of bytes which were not transferred.
+    fn remaining(&mut self, result: i64, length: u64) -> i64 {
+        match self.result(result) {
+            -1 => -1,
+            size => length as i64 - size,
+        }
+    }
+
+    fn read_path<X: Xlen>(core: &Core<X>, addr: u64, length: u64) -> Result<String, i64> {
+        let bytes = read_bytes(core, addr, length).ok_or(-EFAULT)?;
+        String::from_utf8(bytes).map_err(|_| -EINVAL)
+    }
+
+    fn open<X: Xlen>(&mut self, core: &Core<X>, path: u64, mode: u64, length: u64) -> i64 {
+        let path = match Semihosting::read_path(core, path, length) {
+            Ok(path) => path,
+            Err(e) => return e,
+        };
+        match mode / 2 {
+            _ if path == CONSOLE_PATH => [0, 0, 1, 1, 2, 2].get(mode as usize / 2).cloned().unwrap_or(-EINVAL),
+            i if i < OPEN_FLAGS.len() as u64 => self.files.open_path(&path, OPEN_FLAGS[i as usize]),
+            _ => -EINVAL,
+        }
+    }
+
+    fn rename<X: Xlen>(&self, core: &Core<X>, args: &[u64]) -> i64 {
+        let from = Semihosting::read_path(core, args[0], args[1]).and_then(|path| self.files.resolve_path(&path));
+        let to = Semihosting::read_path(core, args[2], args[3]).and_then(|path| self.files.resolve_path(&path));
+        match (from, to) {
+            (Ok(from), Ok(to)) => fs::rename(from, to).map(|_| 0).unwrap_or_else(errno),
+            (Err(e), _) | (_, Err(e)) => e,
+        }
+    }
+
+    fn get_cmdline<X: Xlen>(&self, core: &mut Core<X>, param: u64, buf: u64, length: u64) -> i64 {
+        let mut cmdline = self.cmdline.clone().into_bytes();
+        cmdline.push(0);
+        if cmdline.len() as u64 > length || !write_bytes(core, buf, &cmdline) {
+            return -EINVAL
+        }
+        // The length excluding the terminator is returned in the second field.
+        write_field(core, param, 1, cmdline.len() as u64 - 1);
+        0
+    }
+
+    fn exit<X: Xlen>(&self, core: &mut Core<X>, reason: u64, subcode: u64) -> i64 {
+        let code = match reason {
+            ADP_STOPPED_APPLICATION_EXIT => subcode,
+            _ => 1,
+        };
+        core.bus.write_u64(core.host_io_addr, (code << 1) | 1);
+        0
+    }
+}
+
+// Fields wrapping around the address space are unmapped.
+fn read_field<X: Xlen>(core: &Core<X>, param: u64, index: u64) -> u64 {
+    let addr = param.checked_add(X::XLEN as u64 / 8 * index);
+    match (X::XLEN, addr) {
+        (32, Some(addr)) if core.bus.is_mapped(addr, 4) => core.bus.read_u32(addr) as u64,
+        (64, Some(addr)) if core.bus.is_mapped(addr, 8) => core.bus.read_u64(addr),
+        _ => 0,
+    }
+}
+
+fn write_field<X: Xlen>(core: &mut Core<X>, param: u64, index: u64, value: u64) {
+    let size = X::XLEN as u64 / 8;
+    if let Some(addr) = param.checked_add(size * index) {
+        write_bytes(core, addr, &value.to_le_bytes()[..size as usize]);
+    }
+}
+
+impl<X: Xlen> SyscallHandler<X> for Semihosting {
+    fn syscall(&mut self, core: &mut Core<X>) {
+        let operation = core.int_reg.read(10).to_u64();
+        let param = core.int_reg.read(11).to_u64();
+        let args: Vec<u64> = (0..4).map(|i| read_field(core, param, i)).collect();
+
+        let result = match operation {
+            SYS_OPEN => {
+                let result = self.open(core, args[0], args[1], args[2]);
+                self.result(result)
+            },
+            SYS_CLOSE => {
+                let result = self.files.close(args[0]);
+                self.result(result)
+            },
+            SYS_WRITEC | SYS_WRITE0 => {
+                let data = match operation {
+                    SYS_WRITEC => read_bytes(core, param, 1).unwrap_or_default(),
+                    _ => read_string(core, param).unwrap_or_default().into_bytes(),
+                };
+                let _ = io::stdout().write_all(&data).and_then(|_| io::stdout().flush());
+                0
+            },
+            SYS_WRITE => {
+                let result = self.files.write(core, args[0], args[1], args[2]);
+                self.remaining(result, args[2])
+            },
+            SYS_READ => {
+                let result = self.files.read(core, args[0], args[1], args[2]);
+                self.remaining(result, args[2])
+            },
+            SYS_READC => {
+                let mut c = [0];
+                match core.bus.read_stdin(core.cycle, &mut c) {
+                    Ok(1) => c[0] as i64,
+                    _ => -1,
+                }
+            },
+            SYS_ISERROR => (signed::<X>(args[0]) < 0) as i64,
+            SYS_ISTTY => (args[0] <= 2) as i64,
+            SYS_SEEK => {
+                let result = self.files.seek(args[0], args[1] as i64, 0);
+                self.result(result).min(0)
+            },
+            SYS_FLEN => {
+                let result = self.files.size(args[0]).map(|size| size as i64).unwrap_or_else(|e| e);
+                self.result(result)
+            },
+            SYS_REMOVE => {
+                let result = Semihosting::read_path(core, args[0], args[1]).map(|path| self.files.unlink_path(&path)).unwrap_or_else(|e| e);
+                self.result(result)
+            },
+            SYS_RENAME => {
+                let result = self.rename(core, &args);
+                self.result(result)
+            },
+            SYS_CLOCK => core.bus.host_time(core.cycle, || self.start.elapsed().as_millis() as u64 / 10) as i64,
+            SYS_TIME => core.bus.host_time(core.cycle, || SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0)) as i64,
+            SYS_ERRNO => self.errno,
+            SYS_GET_CMDLINE => {
+                let result = self.get_cmdline(core, param, args[0], args[1]);
+                self.result(result)
+            },
+            // The heap and stack are unknown, which is reported as zeros.
+            SYS_HEAPINFO => {
+                for i in 0..4 {
+                    write_field(core, args[0], i, 0);
+                }
+                0
+            },
+            // On RV32, the parameter of SYS_EXIT is the reason itself and the exit code is not given.
+            SYS_EXIT if X::XLEN == 32 => self.exit(core, param, 0),
+            SYS_EXIT | SYS_EXIT_EXTENDED => self.exit(core, args[0], args[1]),
+            SYS_ELAPSED => {
+                let ticks = core.bus.host_time(core.cycle, || self.start.elapsed().as_micros() as u64);
+                if write_bytes(core, param, &ticks.to_le_bytes()) { 0 } else { -1 }
+            },
+            SYS_TICKFREQ => TICK_FREQUENCY,
+            _ => {
+                self.errno = ENOSYS;
+                -1
+            },
+        };
+
+        core.int_reg.write(10, X::Uint::from_u64(result as u64));
+    }
+}
+
+#[test]
+fn test_semihosting() {
+    use bus::*;
+    use memory::*;
+
+    const BLOCK: u64 = 0x8000_2000;
+    const PATH: u64 = 0x8000_2100;
+    const BUF: u64 = 0x8000_2200;
+
+    let root = std::env::temp_dir().join(format!("rafi-emu-test-semihosting-{}", std::process::id()));
+    fs::create_dir_all(&root).unwrap();
+
+    let mut memory = Memory::new();
+    let mut bus = Bus::new(&mut memory);
+    let mut core: Core = Core::new(&mut bus);
+    core.host_io_addr = 0x8000_1000;
+    core.bus.write_u64(0x8000_1000, 0);
+    core.bus.write_u32(0x8000_0000, SEMIHOSTING_ENTRY);
+    core.bus.write_u32(0x8000_0004, 0x0010_0073); // ebreak
+    core.bus.write_u32(0x8000_0008, SEMIHOSTING_EXIT);
+    core.bus.write_u32(0x8000_000c, 0x0010_0073);
+
+    // An EBREAK which is not a semihosting call still traps.
+    core.pc = 0x8000_000c;
+    core.step();
+    assert_eq!(core.csr.read(0x342), 3);
+    core.csr.write(0x342, 0);
+
+    core.semihosting_handler = Some(Box::new(Semihosting::new(Some(root.clone()), "prog arg".to_string())));
+    let call = |core: &mut Core, operation: u64, args: &[u64]| {
+        for (i, arg) in args.iter().enumerate() {
+            core.bus.write_u32(BLOCK + 4 * i as u64, *arg as u32);
+        }
+        core.int_reg.write(10, operation as u32);
+        core.int_reg.write(11, BLOCK as u32);
+        core.pc = 0x8000_0000;
+        for _ in 0..3 {
+            core.step();
+        }
+        assert_eq!((core.pc, core.csr.read(0x342)), (0x8000_000c, 0));
+        core.int_reg.read(10) as i32 as i64
+    };
+
+    write_bytes(&mut core, PATH, b"a.txt");
+    write_bytes(&mut core, PATH + 0x10, b"../b.txt");
+    write_bytes(&mut core, BUF, b"hello");
+    assert_eq!(call(&mut core, SYS_OPEN, &[PATH + 0x10, 6, 8]), -1);
+    assert_eq!(call(&mut core, SYS_ERRNO, &[]), EACCES);
+    assert_eq!(call(&mut core, SYS_OPEN, &[PATH, 6, 5]), 3);
+    assert_eq!(call(&mut core, SYS_WRITE, &[3, BUF, 5]), 0);
+    assert_eq!(call(&mut core, SYS_FLEN, &[3]), 5);
+    assert_eq!(call(&mut core, SYS_SEEK, &[3, 1]), 0);
+    // 4 of 8 bytes are read.
+    assert_eq!(call(&mut core, SYS_READ, &[3, BUF + 0x10, 8]), 4);
+    assert_eq!(core.bus.read_u32(BUF + 0x10), u32::from_le_bytes(*b"ello"));
+    assert_eq!(call(&mut core, SYS_CLOSE, &[3]), 0);
+    assert_eq!(call(&mut core, SYS_CLOSE, &[3]), -1);
+    assert_eq!(call(&mut core, SYS_ERRNO, &[]), EBADF);
+    assert_eq!(call(&mut core, SYS_ISERROR, &[-1i64 as u64]), 1);
+    assert_eq!(call(&mut core, SYS_REMOVE, &[PATH, 5]), 0);
+    assert!(!root.join("a.txt").exists());
+
+    assert_eq!(call(&mut core, SYS_GET_CMDLINE, &[BUF, 0x100]), 0);
+    assert_eq!(read_string(&core, BUF).unwrap(), "prog arg");
+    assert_eq!(core.bus.read_u32(BLOCK + 4), 8);
+    assert_eq!(call(&mut core, SYS_TICKFREQ, &[]), TICK_FREQUENCY);
+    assert_eq!(call(&mut core, 0x100, &[]), -1);
+
+    // SYS_EXIT_EXTENDED exits with the subcode.
+    call(&mut core, SYS_EXIT_EXTENDED, &[ADP_STOPPED_APPLICATION_EXIT, 3]);
+    assert_eq!(core.read_host_io(), 7);
+
+    // A parameter block at the top of the address space of RV64 is unmapped.
+    let mut rv64_memory = Memory::new();
+    let mut rv64_bus = Bus::new(&mut rv64_memory);
+    let mut rv64_core: Core<Rv64> = Core::new(&mut rv64_bus);
+    assert_eq!(read_field(&rv64_core, u64::MAX - 7, 1), 0);
+    write_field(&mut rv64_core, u64::MAX - 7, 1, 0);
+
+    fs::remove_dir_all(&root).unwrap();
+}
@@ -0,0 +1,103 @@
+// SiFive test device, which finishes the run when the guest writes a status to it: 0x5555 passes,
+// 0x3333 fails with the exit code in the upper 16 bits (1 if they are 0), and 0x7777 resets the
+// machine. Linux uses it through syscon-poweroff and syscon-reboot.
+//
+// The run ends when the host io, which is read from the device, is not 0. Like the tohost of HTIF, it
+// is (code << 1) | 1 at an exit, and HOST_IO_RESET, which is even, at a reset.
+
+use bus::*;
+use fdt::*;
+use snapshot::*;
+
+pub const SIFIVE_TEST_SIZE: u64 = 0x1000;
+
+const FINISHER_FAIL: u64 = 0x3333;
+const FINISHER_PASS: u64 = 0x5555;
+const FINISHER_RESET: u64 = 0x7777;
+
+pub const HOST_IO_RESET: u64 = FINISHER_RESET << 1;
+
+pub struct SifiveTest {
+    host_io: u64,
+}
+
+impl SifiveTest {
+    pub fn new() -> SifiveTest {
+        SifiveTest { host_io: 0 }
+    }
+}
+
+impl Device for SifiveTest {
+    fn size(&self) -> u64 {
+        SIFIVE_TEST_SIZE
+    }
+
+    fn read(&mut self, offset: u64, _size: u32) -> u64 {
+        match offset {
+            0 => self.host_io,
+            _ => 0,
+        }
+    }
+
+    // Other values are ignored, except 0, which clears a reset once the machine has been reset.
+    fn write(&mut self, offset: u64, _size: u32, value: u64) {
+        if offset != 0 {
+            return
+        }
+        let code = (value >> 16) & 0xffff;
+        match value & 0xffff {
+            FINISHER_PASS => self.host_io = 1,
+            FINISHER_FAIL => self.host_io = (code.max(1) << 1) | 1,
+            FINISHER_RESET => self.host_io = HOST_IO_RESET,
+            _ if value == 0 => self.host_io = 0,
+            _ => (),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.host_io = 0;
+    }
+
+    fn save(&self) -> Option<Vec<u8>> {
+        Some(encode_state(&self.host_io, &[]))
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), String> {
+        self.host_io = decode_state(state)?.0;
+        Ok(())
+    }
+
+    fn device_tree_node(&self, fdt: &mut Fdt, base: u64) {
+        fdt.begin_node(&format!("test@{:x}", base));
+        fdt.property_strings("compatible", &["sifive,test1", "sifive,test0", "syscon"]);
+        fdt.property_cells("reg", &[(base >> 32) as u32, base as u32, 0, SIFIVE_TEST_SIZE as u32]);
+        fdt.property_u32("phandle", PHANDLE_TEST);
+        fdt.end_node();
+
+        for (name, value) in [("poweroff", FINISHER_PASS), ("reboot", FINISHER_RESET)] {
+            fdt.begin_node(name);
+            fdt.property_string("compatible", &format!("syscon-{}", name));
+            fdt.property_u32("regmap", PHANDLE_TEST);
+            fdt.property_u32("offset", 0);
+            fdt.property_u32("value", value as u32);
+            fdt.end_node();
+        }
+    }
+}
+
+#[test]
+fn test_sifive_test() {
+    let mut test = SifiveTest::new();
+    test.write(0, 4, 0x1234);
+    assert_eq!(test.read(0, 8), 0);
+    test.write(0, 4, 0x0003_3333);
+    assert_eq!(test.read(0, 8), 3 << 1 | 1);
+    test.write(0, 4, 0x3333);
+    assert_eq!(test.read(0, 8), 1 << 1 | 1);
+    test.write(0, 4, 0x5555);
+    assert_eq!(test.read(0, 8), 1);
+    test.write(0, 4, 0x7777);
+    assert_eq!(test.read(0, 8), HOST_IO_RESET);
+    test.write(0, 4, 0);
+    assert_eq!(test.read(0, 8), 0);
+}
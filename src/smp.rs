@@ -0,0 +1,160 @@
+// Multi-hart machine. The harts share the bus and run one at a time on the core, which keeps the state
+// of the running hart: each runs for a quantum of cycles, or until it waits for an interrupt, and the
+// next one in round-robin order is switched in.
+//
+// The cycle count of the core is shared, so the time of devices advances with the ops of every hart.
+// The reservation of a hart which is not running is kept by the bus, where writes of other harts
+// clear it.
+
+use block::*;
+use core::*;
+use csr::*;
+use mmu::*;
+use xlen::*;
+
+use std::mem;
+
+pub const DEFAULT_QUANTUM: u64 = 1000;
+
+// State of a hart which is not running
+struct Hart<X: Xlen> {
+    csr: Csr<X>,
+    int_reg: IntReg<X>,
+    pc: X::Uint,
+    next_pc: X::Uint,
+    privilege: u32,
+    mmu: Mmu,
+    last_trap_cause: Option<u32>,
+    waiting: bool,
+}
+
+pub struct Smp<X: Xlen> {
+    // Harts by id, except the running one whose state is in the core
+    harts: Vec<Option<Hart<X>>>,
+    current: usize,
+    quantum: u64,
+}
+
+impl<X: Xlen> Smp<X> {
+    // Adds harts 1 to num_harts - 1 to the core, which is hart 0. They start at the pc of hart 0 in
+    // the same privilege with a0 = hartid and a1 as in hart 0, like harts started by firmware loaders.
+    pub fn new(core: &mut Core<X>, num_harts: usize, quantum: u64) -> Smp<X> {
+        core.wait_on_bus = false;
+        let harts = (0..num_harts).map(|id| match id {
+            0 => None,
+            _ => {
+                let mut hart = Hart {
+                    csr: Csr::new(),
+                    int_reg: IntReg::new(),
+                    pc: core.pc,
+                    next_pc: core.pc,
+                    privilege: core.privilege,
+                    mmu: Mmu::new(),
+                    last_trap_cause: None,
+                    waiting: false,
+                };
+                hart.csr.set_hart_id(id);
+                hart.int_reg.write(10, X::Uint::from_u64(id as u64));
+                hart.int_reg.write(11, core.int_reg.read(11));
+                Some(hart)
+            },
+        }).collect();
+        Smp { harts, current: 0, quantum }
+    }
+
+    // Stores the state of the running hart and loads that of hart `id`.
+    fn switch(&mut self, core: &mut Core<X>, id: usize) {
+        let mut hart = self.harts[id].take().unwrap();
+        mem::swap(&mut core.csr, &mut hart.csr);
+        mem::swap(&mut core.int_reg, &mut hart.int_reg);
+        mem::swap(&mut core.pc, &mut hart.pc);
+        mem::swap(&mut core.next_pc, &mut hart.next_pc);
+        mem::swap(&mut core.privilege, &mut hart.privilege);
+        mem::swap(&mut core.mmu, &mut hart.mmu);
+        mem::swap(&mut core.last_trap_cause, &mut hart.last_trap_cause);
+        mem::swap(&mut core.waiting, &mut hart.waiting);
+
+        if let Some(addr) = core.reservation.take() {
+            core.bus.park_reservation(self.current, addr);
+        }
+        core.reservation = core.bus.unpark_reservation(id);
+
+        self.harts[self.current] = Some(hart);
+        self.current = id;
+    }
+
+    // Runs the harts in turn until max_cycle or until the host io is written. When all of them wait
+    // for an interrupt, the bus passes the time until a device event.
+    pub fn run(&mut self, core: &mut Core<X>, block_engine: &mut Option<BlockEngine<X>>, max_cycle: u64) {
+        let mut num_waiting = 0;
+        while core.cycle < max_cycle && core.read_host_io() == 0 {
+            let end = max_cycle.min(core.cycle.saturating_add(self.quantum));
+            match block_engine {
+                Some(block_engine) => {
+                    block_engine.run(core, end - core.cycle);
+                },
+                None => {
+                    while core.cycle < end && core.read_host_io() == 0 {
+                        core.step();
+                        if core.waiting {
+                            break
+                        }
+                    }
+                },
+            }
+
+            num_waiting = if core.waiting { num_waiting + 1 } else { 0 };
+            if num_waiting >= self.harts.len() {
+                core.bus.wait(core.cycle);
+                num_waiting = 0;
+            }
+
+            if self.harts.len() > 1 {
+                let next = (self.current + 1) % self.harts.len();
+                self.switch(core, next);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_smp() {
+    use bus::*;
+    use memory::*;
+
+    let mut memory = Memory::new();
+    let mut bus = Bus::new(&mut memory);
+    // Each hart increments the counter 100 times with LR/SC, and then reads mhartid.
+    let program = [
+        0x0640_0293u32, // li t0, 100
+        0x1005_a32f,    // lr.w t1, (a1)
+        0x0013_0313,    // addi t1, t1, 1
+        0x1865_a3af,    // sc.w t2, t1, (a1)
+        0xfe03_9ae3,    // bnez t2, 0x4
+        0xfff2_8293,    // addi t0, t0, -1
+        0xfe02_96e3,    // bnez t0, 0x4
+        0xf140_2e73,    // csrr t3, mhartid
+        0x0000_006f,    // j 0x20
+    ];
+    for (i, insn) in program.iter().enumerate() {
+        bus.write_u32(0x8000_0000 + 4 * i as u64, *insn);
+    }
+    bus.write_u32(0x8000_1000, 0);
+
+    let mut core: Core<Rv64> = Core::new(&mut bus);
+    core.pc = 0x8000_0000;
+    core.int_reg.write(11, 0x8000_1000);
+
+    // Switching every few ops makes the harts break the reservations of each other.
+    let mut smp = Smp::new(&mut core, 3, 3);
+    smp.run(&mut core, &mut None, 10_000);
+    assert_eq!(core.bus.read_u32(0x8000_1000), 300);
+
+    for _ in 0..smp.harts.len() {
+        assert_eq!(core.int_reg.read(28), core.csr.hart_id() as u64);
+        assert_eq!(core.int_reg.read(10), core.csr.hart_id() as u64);
+        assert_eq!(core.pc, 0x8000_0020);
+        let next = (smp.current + 1) % smp.harts.len();
+        smp.switch(&mut core, next);
+    }
+}
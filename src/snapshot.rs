@@ -0,0 +1,476 @@
+use core::*;
+use csr::*;
+use memory::*;
+use xlen::*;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use std::fs;
+
+// Increment when the snapshot format changes.
+pub const SNAPSHOT_VERSION: u32 = 3;
+
+const SNAPSHOT_MAGIC: &[u8; 8] = b"RAFISNAP";
+
+const NUM_INT_REG: usize = 32;
+const NUM_CSR: usize = 0x1000;
+const PAGE_SIZE: usize = 4096;
+
+// Complete machine state which can be saved to a file and restored to continue execution.
+//
+// The file starts with SNAPSHOT_MAGIC and the state of the hart as JSON, which are followed by the
+// states of the devices and the memory pages which differ from the initial memory contents. They are
+// binary, compressed by run-length encoding.
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    version: u32,
+    xlen: u32,
+    cycle: u64,
+    pc: u64,
+    privilege: u32,
+    host_io_addr: u64,
+    // Reservation of LR, and whether the hart waits for an interrupt
+    reservation: Option<u64>,
+    waiting: bool,
+    // Time skipped by waiting, which with the cycle gives the time of the devices
+    time_skipped: u64,
+    int_reg: Vec<u64>,
+    // Non-zero CSRs as (index, value).
+    csr: Vec<(usize, u64)>,
+    // (tdata1, tdata2) of each trigger, which are banked behind tselect.
+    triggers: Vec<(u32, u64)>,
+    memory_size: usize,
+    // State of each device on the bus, or None if the device cannot be saved
+    #[serde(skip)]
+    devices: Vec<Option<Vec<u8>>>,
+    // Compressed pages by offset
+    #[serde(skip)]
+    memory: Vec<(usize, Vec<u8>)>,
+}
+
+// Compresses data with PackBits: a byte n < 128 is followed by n + 1 literal bytes, and a byte n > 128
+// by a byte repeated 257 - n times.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut compressed = Vec::new();
+    let mut i = 0;
+
+    while i < data.len() {
+        let run = data[i..].iter().take(128).take_while(|b| **b == data[i]).count();
+        if run >= 3 {
+            compressed.push((257 - run) as u8);
+            compressed.push(data[i]);
+            i += run;
+            continue
+        }
+
+        // Literal bytes up to the next run of 3
+        let start = i;
+        while i < data.len() && i - start < 128 && !(i + 2 < data.len() && data[i] == data[i + 1] && data[i] == data[i + 2]) {
+            i += 1;
+        }
+        compressed.push((i - start - 1) as u8);
+        compressed.extend_from_slice(&data[start..i]);
+    }
+
+    compressed
+}
+
+pub fn decompress(compressed: &[u8]) -> Result<Vec<u8>, String> {
+    let mut data = Vec::new();
+    let mut i = 0;
+
+    while i < compressed.len() {
+        let n = compressed[i] as usize;
+        i += 1;
+        if n < 128 {
+            let literal = compressed.get(i..i + n + 1).ok_or("compressed data is truncated")?;
+            data.extend_from_slice(literal);
+            i += n + 1;
+        } else if n > 128 {
+            let value = *compressed.get(i).ok_or("compressed data is truncated")?;
+            data.resize(data.len() + 257 - n, value);
+            i += 1;
+        }
+    }
+
+    Ok(data)
+}
+
+// Encodes the state of a device as its fields in JSON followed by binary data, e.g. the contents of a
+// buffer, which are long.
+pub fn encode_state<T: Serialize>(fields: &T, data: &[u8]) -> Vec<u8> {
+    let json = serde_json::to_vec(fields).unwrap();
+    let mut state = (json.len() as u32).to_le_bytes().to_vec();
+    state.extend_from_slice(&json);
+    state.extend_from_slice(data);
+    state
+}
+
+// Decodes a state encoded by encode_state() into its fields and data.
+pub fn decode_state<T: DeserializeOwned>(state: &[u8]) -> Result<(T, &[u8]), String> {
+    let mut reader = Reader { data: state };
+    let json = reader.read_block()?;
+    let fields = serde_json::from_slice(json).map_err(|e| e.to_string())?;
+    Ok((fields, reader.data))
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn read(&mut self, size: usize) -> Result<&'a [u8], String> {
+        if self.data.len() < size {
+            return Err("snapshot is truncated".to_string())
+        }
+        let (head, rest) = self.data.split_at(size);
+        self.data = rest;
+        Ok(head)
+    }
+
+    fn read_u32(&mut self) -> Result<u32, String> {
+        let bytes = self.read(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    // Reads a block of data preceded by its length.
+    fn read_block(&mut self) -> Result<&'a [u8], String> {
+        let size = self.read_u32()? as usize;
+        self.read(size)
+    }
+}
+
+fn write_block(file: &mut Vec<u8>, data: &[u8]) {
+    file.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    file.extend_from_slice(data);
+}
+
+impl Snapshot {
+    pub fn take<X: Xlen>(core: &Core<X>) -> Snapshot {
+        let body = &core.bus.memory.body;
+
+        let memory = body.chunks(PAGE_SIZE).enumerate()
+            .filter(|(_, page)| page.iter().any(|b| *b != MEMORY_INITIAL_VALUE))
+            .map(|(i, page)| (i * PAGE_SIZE, compress(page)))
+            .collect();
+
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            xlen: X::XLEN,
+            cycle: core.cycle,
+            pc: core.pc.to_u64(),
+            privilege: core.privilege,
+            host_io_addr: core.host_io_addr,
+            reservation: core.reservation,
+            waiting: core.waiting,
+            time_skipped: core.bus.time_skipped(),
+            int_reg: (0..NUM_INT_REG).map(|i| core.int_reg.read(i).to_u64()).collect(),
+            csr: (0..NUM_CSR).map(|i| (i, core.csr.read_stored(i).to_u64())).filter(|(_, value)| *value != 0).collect(),
+            triggers: (0..NUM_TRIGGER).map(|i| core.csr.read_trigger(i)).map(|(t1, t2)| (t1, t2.to_u64())).collect(),
+            memory_size: body.len(),
+            devices: core.bus.save_devices(),
+            memory,
+        }
+    }
+
+    // Returns an error if a device of the machine cannot be saved, as the snapshot would not continue
+    // the same way.
+    fn check_devices(&self) -> Result<(), String> {
+        match self.devices.iter().position(|state| state.is_none()) {
+            Some(index) => Err(format!("device {} on the bus does not support snapshots", index)),
+            None => Ok(()),
+        }
+    }
+
+    // Overwrites the state of `core`, its memory and devices. Execution engines holding translated code
+    // must be flushed. Nothing is overwritten when an error is returned.
+    pub fn restore<X: Xlen>(&self, core: &mut Core<X>) -> Result<(), String> {
+        if self.xlen != X::XLEN {
+            return Err(format!("snapshot is for RV{} (running RV{})", self.xlen, X::XLEN))
+        }
+        if self.int_reg.len() != NUM_INT_REG {
+            return Err(format!("snapshot has {} integer registers", self.int_reg.len()))
+        }
+        if self.memory_size != core.bus.memory.body.len() {
+            return Err(format!("snapshot has 0x{:x} bytes of memory (machine has 0x{:x})", self.memory_size, core.bus.memory.body.len()))
+        }
+        self.check_devices()?;
+
+        let mut body = vec![MEMORY_INITIAL_VALUE; self.memory_size];
+        for (offset, page) in &self.memory {
+            if *offset >= self.memory_size || offset % PAGE_SIZE != 0 {
+                return Err(format!("memory page at 0x{:x} is out of range", offset))
+            }
+            let end = self.memory_size.min(offset + PAGE_SIZE);
+            let data = decompress(page).map_err(|e| format!("memory page at 0x{:x}: {}", offset, e))?;
+            if data.len() != end - offset {
+                return Err(format!("memory page at 0x{:x} has invalid length", offset))
+            }
+            body[*offset..end].copy_from_slice(&data);
+        }
+
+        let mut csr_values = vec![0; NUM_CSR];
+        for (index, value) in &self.csr {
+            if *index >= NUM_CSR {
+                return Err(format!("CSR index 0x{:x} is out of range", index))
+            }
+            csr_values[*index] = *value;
+        }
+
+        if self.triggers.len() > NUM_TRIGGER {
+            return Err(format!("snapshot has {} triggers", self.triggers.len()))
+        }
+
+        // The devices are restored last as they are validated while being restored.
+        let states: Vec<&[u8]> = self.devices.iter().map(|state| state.as_ref().unwrap().as_slice()).collect();
+        core.bus.restore_devices(&states)?;
+
+        for (i, value) in self.int_reg.iter().enumerate() {
+            core.int_reg.write(i, X::Uint::from_u64(*value));
+        }
+        for (i, value) in csr_values.iter().enumerate() {
+            core.csr.restore(i, X::Uint::from_u64(*value));
+        }
+        for i in 0..NUM_TRIGGER {
+            let (tdata1, tdata2) = self.triggers.get(i).cloned().unwrap_or((0, 0));
+            core.csr.write_trigger(i, tdata1, X::Uint::from_u64(tdata2));
+        }
+        core.bus.memory.body = body;
+        core.bus.last_write_addr = None;
+        core.bus.set_time_skipped(self.time_skipped);
+        core.mmu.flush();
+        core.cycle = self.cycle;
+        core.pc = X::Uint::from_u64(self.pc);
+        core.next_pc = core.pc;
+        core.privilege = self.privilege;
+        core.host_io_addr = self.host_io_addr;
+        core.reservation = self.reservation;
+        core.waiting = self.waiting;
+
+        Ok(())
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        self.check_devices().map_err(|e| format!("{}: {}", path, e))?;
+
+        let mut file = SNAPSHOT_MAGIC.to_vec();
+        write_block(&mut file, &serde_json::to_vec(self).map_err(|e| format!("{}: {}", path, e))?);
+        file.extend_from_slice(&(self.devices.len() as u32).to_le_bytes());
+        for state in self.devices.iter().flatten() {
+            write_block(&mut file, &compress(state));
+        }
+        file.extend_from_slice(&(self.memory.len() as u32).to_le_bytes());
+        for (offset, page) in &self.memory {
+            file.extend_from_slice(&(*offset as u64).to_le_bytes());
+            write_block(&mut file, page);
+        }
+
+        fs::write(path, file).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn load(path: &str) -> Result<Snapshot, String> {
+        let file = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        Snapshot::decode(&file).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    fn decode(file: &[u8]) -> Result<Snapshot, String> {
+        let mut reader = Reader { data: file };
+        if reader.read(SNAPSHOT_MAGIC.len()).ok() != Some(&SNAPSHOT_MAGIC[..]) {
+            return Err("not a snapshot".to_string())
+        }
+
+        // The version is checked before the rest, whose format may differ.
+        #[derive(Deserialize)]
+        struct Version {
+            version: u32,
+        }
+        let header = reader.read_block()?;
+        let version: Version = serde_json::from_slice(header).map_err(|e| e.to_string())?;
+        if version.version != SNAPSHOT_VERSION {
+            return Err(format!("unsupported snapshot version {} (expected {})", version.version, SNAPSHOT_VERSION))
+        }
+        let mut snapshot: Snapshot = serde_json::from_slice(header).map_err(|e| e.to_string())?;
+
+        for _i in 0..reader.read_u32()? {
+            snapshot.devices.push(Some(decompress(reader.read_block()?)?));
+        }
+        for _i in 0..reader.read_u32()? {
+            let offset = reader.read(8)?.iter().rev().fold(0, |value, b| value << 8 | *b as usize);
+            snapshot.memory.push((offset, reader.read_block()?.to_vec()));
+        }
+        if !reader.data.is_empty() {
+            return Err("snapshot has trailing data".to_string())
+        }
+
+        Ok(snapshot)
+    }
+}
+
+#[test]
+fn test_snapshot_restore() {
+    use block::*;
+    use bus::*;
+
+    let program = [
+        0x00a00093, // addi ra,zero,10
+        0x00000113, // addi sp,zero,0
+        0x00110133, // add sp,sp,ra
+        0xfff08093, // addi ra,ra,-1
+        0xfe009ce3, // bnez ra,-8
+        0x0000006f, // j 0
+    ];
+
+    let mut memory = Memory::new();
+    let mut bus = Bus::new(&mut memory);
+    for (i, insn) in program.iter().enumerate() {
+        bus.write_u32(0x8000_0000 + 4 * i as u64, *insn);
+    }
+    bus.write_u64(0x8000_1000, 0);
+    let mut core: Core = Core::new(&mut bus);
+    core.host_io_addr = 0x8000_1000;
+    core.pc = 0x8000_0000;
+    core.csr.write(0x340, 0x1234);
+    for _i in 0..10 {
+        core.step();
+    }
+
+    let path = std::env::temp_dir().join(format!("rafi-emu-snapshot-{}.snap", std::process::id()));
+    let path = path.to_str().unwrap();
+    Snapshot::take(&core).save(path).unwrap();
+
+    for _i in 0..30 {
+        core.step();
+    }
+    let expected: Vec<u32> = (0..32).map(|i| core.int_reg.read(i)).collect();
+
+    let mut other_memory = Memory::new();
+    let mut other_bus = Bus::new(&mut other_memory);
+    let mut other_core: Core = Core::new(&mut other_bus);
+    Snapshot::load(path).unwrap().restore(&mut other_core).unwrap();
+    std::fs::remove_file(path).unwrap();
+
+    assert_eq!(other_core.cycle, 10);
+    assert_eq!(other_core.csr.read(0x340), 0x1234);
+    BlockEngine::new().run(&mut other_core, 30);
+    let actual: Vec<u32> = (0..32).map(|i| other_core.int_reg.read(i)).collect();
+    assert_eq!(actual, expected);
+    assert_eq!(other_core.pc, core.pc);
+}
+
+#[test]
+fn test_compress() {
+    let mut data = vec![0xff; 300];
+    data.extend_from_slice(b"abcc");
+    data.extend_from_slice(&[7; 3]);
+    data.extend((0..200).map(|i| i as u8));
+
+    let compressed = compress(&data);
+    assert!(compressed.len() < 220);
+    assert_eq!(decompress(&compressed).unwrap(), data);
+    assert!(decompress(&[]).unwrap().is_empty());
+    assert!(decompress(&compressed[..compressed.len() - 1]).is_err());
+    assert!(decompress(&[0xfe]).is_err());
+}
+
+#[test]
+fn test_snapshot_devices() {
+    use bus::*;
+    use clint::*;
+    use net::*;
+    use virtio::*;
+    use virtio_net::*;
+
+    let mut memory = Memory::new();
+    let mut bus = Bus::new(&mut memory);
+    bus.add_device(0x200_0000, Box::new(Clint::new(1)));
+    let mut core: Core = Core::new(&mut bus);
+    core.bus.write_u64(0x200_4000, 1234);
+    core.reservation = Some(0x8000_0100);
+    core.waiting = true;
+    let snapshot = Snapshot::take(&core);
+
+    let mut other_memory = Memory::new();
+    let mut other_bus = Bus::new(&mut other_memory);
+    other_bus.add_device(0x200_0000, Box::new(Clint::new(1)));
+    let mut other_core: Core = Core::new(&mut other_bus);
+    snapshot.restore(&mut other_core).unwrap();
+    assert_eq!(other_core.bus.read_u64(0x200_4000), 1234);
+    assert_eq!(other_core.reservation, Some(0x8000_0100));
+    assert!(other_core.waiting);
+
+    // The devices have to be the same as when saved.
+    let mut two_harts_memory = Memory::new();
+    let mut two_harts_bus = Bus::new(&mut two_harts_memory);
+    two_harts_bus.add_device(0x200_0000, Box::new(Clint::new(2)));
+    let mut two_harts_core: Core = Core::new(&mut two_harts_bus);
+    assert!(snapshot.restore(&mut two_harts_core).is_err());
+    let mut no_device_memory = Memory::new();
+    let mut no_device_bus = Bus::new(&mut no_device_memory);
+    let mut no_device_core: Core = Core::new(&mut no_device_bus);
+    assert!(snapshot.restore(&mut no_device_core).is_err());
+
+    // Nothing is restored when a device fails to restore, including the devices before it.
+    core.bus.add_device(0x300_0000, Box::new(Clint::new(1)));
+    let snapshot = Snapshot::take(&core);
+    let mut mixed_memory = Memory::new();
+    let mut mixed_bus = Bus::new(&mut mixed_memory);
+    mixed_bus.add_device(0x200_0000, Box::new(Clint::new(1)));
+    mixed_bus.add_device(0x300_0000, Box::new(Clint::new(2)));
+    let mut mixed_core: Core = Core::new(&mut mixed_bus);
+    assert!(snapshot.restore(&mut mixed_core).unwrap_err().starts_with("device at 0x3000000"));
+    assert_eq!(mixed_core.bus.read_u64(0x200_4000), u64::MAX);
+    assert_eq!(mixed_core.reservation, None);
+    assert!(!mixed_core.waiting);
+
+    // A network device has a peer outside the emulator.
+    core.bus.add_device(0x1000_1000, Box::new(VirtioMmio::new(Box::new(VirtioNet::new([0; 6], Box::new(Loopback::new()))), 1)));
+    let path = std::env::temp_dir().join(format!("rafi-emu-snapshot-net-{}.snap", std::process::id()));
+    let path = path.to_str().unwrap();
+    assert!(Snapshot::take(&core).save(path).unwrap_err().contains("does not support snapshots"));
+}
+
+#[test]
+fn test_snapshot_invalid_files() {
+    use bus::*;
+    use xlen::*;
+
+    let mut memory = Memory::new();
+    let mut bus = Bus::new(&mut memory);
+    bus.write_u32(0x8000_0000, 0x1234);
+    let core: Core<Rv64> = Core::new(&mut bus);
+
+    let path = std::env::temp_dir().join(format!("rafi-emu-snapshot-invalid-{}.snap", std::process::id()));
+    let path = path.to_str().unwrap();
+    Snapshot::take(&core).save(path).unwrap();
+    let file = fs::read(path).unwrap();
+    std::fs::remove_file(path).unwrap();
+    assert!(Snapshot::load(path).is_err());
+
+    let error = |file: &[u8]| Snapshot::decode(file).err().unwrap();
+    assert_eq!(error(b""), "not a snapshot");
+    assert_eq!(error(b"RAFISNAQ"), "not a snapshot");
+    assert_eq!(error(&file[..file.len() - 1]), "snapshot is truncated");
+    assert_eq!(error(&[&file[..], &[0]].concat()), "snapshot has trailing data");
+
+    // The version is checked first.
+    let version = format!("\"version\":{}", SNAPSHOT_VERSION);
+    let position = file.windows(version.len()).position(|w| w == version.as_bytes()).unwrap();
+    let mut old = file.clone();
+    old[position + version.len() - 1] = b'0' + (SNAPSHOT_VERSION - 1) as u8;
+    assert!(error(&old).starts_with("unsupported snapshot version"));
+
+    // The XLEN has to match.
+    let snapshot = Snapshot::decode(&file).unwrap();
+    let mut rv32_memory = Memory::new();
+    let mut rv32_bus = Bus::new(&mut rv32_memory);
+    let mut rv32_core: Core = Core::new(&mut rv32_bus);
+    assert_eq!(snapshot.restore(&mut rv32_core).err().unwrap(), "snapshot is for RV64 (running RV32)");
+
+    // The size of the memory has to match.
+    let mut small_memory = Memory::with_size(0x2000);
+    let mut small_bus = Bus::new(&mut small_memory);
+    let mut small_core: Core<Rv64> = Core::new(&mut small_bus);
+    assert_eq!(snapshot.restore(&mut small_core).err().unwrap(), "snapshot has 0x10000 bytes of memory (machine has 0x2000)");
+    assert_eq!(small_core.bus.memory.body.len(), 0x2000);
+}
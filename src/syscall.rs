@@ -0,0 +1,384 @@
+// Linux file syscalls run on the host, shared by the HTIF syscall proxy and the Linux user mode.
+//
+// Guest buffers are accessed through the bus, and guest paths are resolved in a host root directory.
+// Absolute paths start at the root and ".." is rejected, so that the guest cannot leave it.
+
+use core::*;
+use xlen::*;
+
+use std::collections::HashMap;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+
+// Linux syscall numbers of the generic (asm-generic/unistd.h) table used by RISC-V.
+pub const SYS_FACCESSAT: u64 = 48;
+pub const SYS_UNLINKAT: u64 = 35;
+pub const SYS_OPENAT: u64 = 56;
+pub const SYS_CLOSE: u64 = 57;
+pub const SYS_LSEEK: u64 = 62;
+pub const SYS_READ: u64 = 63;
+pub const SYS_WRITE: u64 = 64;
+pub const SYS_FSTATAT: u64 = 79;
+pub const SYS_FSTAT: u64 = 80;
+pub const SYS_EXIT: u64 = 93;
+pub const SYS_EXIT_GROUP: u64 = 94;
+pub const SYS_STATX: u64 = 291;
+// Older newlib uses these instead of the *at variants.
+pub const SYS_OPEN: u64 = 1024;
+pub const SYS_UNLINK: u64 = 1026;
+pub const SYS_STAT: u64 = 1038;
+
+pub const ENOENT: i64 = 2;
+pub const EIO: i64 = 5;
+pub const EBADF: i64 = 9;
+pub const ENOMEM: i64 = 12;
+pub const EACCES: i64 = 13;
+pub const EFAULT: i64 = 14;
+pub const EEXIST: i64 = 17;
+pub const EINVAL: i64 = 22;
+pub const ENOTTY: i64 = 25;
+pub const ENOSYS: i64 = 38;
+
+pub const AT_FDCWD: i64 = -100;
+const AT_EMPTY_PATH: u64 = 0x1000;
+
+const O_ACCMODE: u64 = 0x3;
+pub const O_RDONLY: u64 = 0x0;
+pub const O_WRONLY: u64 = 0x1;
+pub const O_RDWR: u64 = 0x2;
+pub const O_CREAT: u64 = 0x40;
+const O_EXCL: u64 = 0x80;
+pub const O_TRUNC: u64 = 0x200;
+pub const O_APPEND: u64 = 0x400;
+
+const S_IFCHR: u32 = 0o020000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+// statx.stx_mask for the fields which are filled
+const STATX_BASIC_STATS: u32 = 0x7ff;
+
+const MAX_PATH_LENGTH: u64 = 4096;
+const FIRST_FILE_FD: u64 = 3;
+
+pub fn errno(e: io::Error) -> i64 {
+    match e.kind() {
+        io::ErrorKind::NotFound => -ENOENT,
+        io::ErrorKind::PermissionDenied => -EACCES,
+        io::ErrorKind::AlreadyExists => -EEXIST,
+        io::ErrorKind::InvalidInput => -EINVAL,
+        _ => -EIO,
+    }
+}
+
+// Sign-extends an argument from XLEN bits, as RV32 targets may pass it zero-extended.
+pub fn signed<X: Xlen>(value: u64) -> i64 {
+    if X::XLEN == 32 { value as u32 as i32 as i64 } else { value as i64 }
+}
+
+pub fn is_mapped_range<X: Xlen>(core: &Core<X>, addr: u64, length: u64) -> bool {
+    length <= u32::MAX as u64 && core.bus.is_mapped(addr, length as u32)
+}
+
+pub fn read_bytes<X: Xlen>(core: &Core<X>, addr: u64, length: u64) -> Option<Vec<u8>> {
+    if !is_mapped_range(core, addr, length) {
+        return None
+    }
+    Some((addr..addr + length).map(|a| core.bus.read_u8(a)).collect())
+}
+
+pub fn write_bytes<X: Xlen>(core: &mut Core<X>, addr: u64, data: &[u8]) -> bool {
+    if !is_mapped_range(core, addr, data.len() as u64) {
+        return false
+    }
+    for (i, b) in data.iter().enumerate() {
+        core.bus.write_u8(addr + i as u64, *b);
+    }
+    true
+}
+
+pub fn read_string<X: Xlen>(core: &Core<X>, addr: u64) -> Result<String, i64> {
+    let mut bytes = Vec::new();
+    for a in addr..addr.saturating_add(MAX_PATH_LENGTH) {
+        if !core.bus.is_mapped(a, 1) {
+            return Err(-EFAULT)
+        }
+        match core.bus.read_u8(a) {
+            0 => break,
+            b => bytes.push(b),
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| -EINVAL)
+}
+
+// File type, permission and size reported by stat.
+struct FileStatus {
+    mode: u32,
+    size: u64,
+}
+
+impl FileStatus {
+    fn from_metadata(metadata: &fs::Metadata) -> FileStatus {
+        let permission = if metadata.permissions().readonly() { 0o444 } else { 0o644 };
+        let mode = if metadata.is_dir() { S_IFDIR | 0o755 } else { S_IFREG | permission };
+        FileStatus { mode, size: metadata.len() }
+    }
+
+    fn console() -> FileStatus {
+        FileStatus { mode: S_IFCHR | 0o620, size: 0 }
+    }
+}
+
+// Writes the 128-byte struct stat shared by RV32 and RV64, with st_mode, st_nlink, st_size and st_blksize set.
+fn write_stat<X: Xlen>(core: &mut Core<X>, buf: u64, status: &FileStatus) -> i64 {
+    let mut stat = [0; 128];
+    stat[16..20].copy_from_slice(&status.mode.to_le_bytes());
+    stat[20..24].copy_from_slice(&1u32.to_le_bytes());
+    stat[48..56].copy_from_slice(&status.size.to_le_bytes());
+    stat[56..60].copy_from_slice(&4096u32.to_le_bytes());
+
+    if write_bytes(core, buf, &stat) { 0 } else { -EFAULT }
+}
+
+// Writes the 256-byte struct statx, which RV32 Linux uses instead of stat.
+fn write_statx<X: Xlen>(core: &mut Core<X>, buf: u64, status: &FileStatus) -> i64 {
+    let mut statx = [0; 256];
+    statx[0..4].copy_from_slice(&STATX_BASIC_STATS.to_le_bytes());
+    statx[4..8].copy_from_slice(&4096u32.to_le_bytes());
+    statx[16..20].copy_from_slice(&1u32.to_le_bytes());
+    statx[28..30].copy_from_slice(&(status.mode as u16).to_le_bytes());
+    statx[40..48].copy_from_slice(&status.size.to_le_bytes());
+
+    if write_bytes(core, buf, &statx) { 0 } else { -EFAULT }
+}
+
+// Files opened by the guest. Descriptors 0-2 are the host standard streams.
+pub struct HostFiles {
+    // Host directory which guest paths are resolved in. Files are not accessible without it.
+    root: Option<PathBuf>,
+    files: HashMap<u64, File>,
+    next_fd: u64,
+}
+
+impl HostFiles {
+    pub fn new(root: Option<PathBuf>) -> HostFiles {
+        HostFiles { root, files: HashMap::new(), next_fd: FIRST_FILE_FD }
+    }
+
+    // Runs a file syscall. Returns None if `number` is not one.
+    pub fn syscall<X: Xlen>(&mut self, core: &mut Core<X>, number: u64, args: &[u64]) -> Option<i64> {
+        let at_cwd = signed::<X>(args[0]) == AT_FDCWD;
+
+        let result = match number {
+            SYS_READ => self.read(core, args[0], args[1], args[2]),
+            SYS_WRITE => self.write(core, args[0], args[1], args[2]),
+            SYS_OPENAT if at_cwd => self.open(core, args[1], args[2]),
+            SYS_OPEN => self.open(core, args[0], args[1]),
+            SYS_CLOSE => self.close(args[0]),
+            SYS_LSEEK => self.seek(args[0], signed::<X>(args[1]), args[2]),
+            SYS_FSTAT => self.fstat(core, args[0], args[1]),
+            SYS_FSTATAT if at_cwd => self.stat(core, args[1], args[2]),
+            SYS_STAT => self.stat(core, args[0], args[1]),
+            SYS_STATX if args[2] & AT_EMPTY_PATH != 0 => self.statx_fd(core, args[0], args[4]),
+            SYS_STATX if at_cwd => self.statx(core, args[1], args[4]),
+            SYS_UNLINKAT if at_cwd => self.unlink(core, args[1]),
+            SYS_UNLINK => self.unlink(core, args[0]),
+            SYS_FACCESSAT if at_cwd => self.access(core, args[1]),
+            SYS_OPENAT | SYS_FSTATAT | SYS_STATX | SYS_UNLINKAT | SYS_FACCESSAT => -EINVAL,
+            _ => return None,
+        };
+        Some(result)
+    }
+
+    fn resolve<X: Xlen>(&self, core: &Core<X>, addr: u64) -> Result<PathBuf, i64> {
+        self.resolve_path(&read_string(core, addr)?)
+    }
+
+    pub fn resolve_path(&self, path: &str) -> Result<PathBuf, i64> {
+        let root = self.root.as_ref().ok_or(-EACCES)?;
+        let mut resolved = root.clone();
+        for component in Path::new(&path).components() {
+            match component {
+                Component::Normal(name) => resolved.push(name),
+                Component::CurDir | Component::RootDir => {},
+                _ => return Err(-EACCES),
+            }
+        }
+        Ok(resolved)
+    }
+
+    pub fn read<X: Xlen>(&mut self, core: &mut Core<X>, fd: u64, buf: u64, length: u64) -> i64 {
+        if !is_mapped_range(core, buf, length) {
+            return -EFAULT
+        }
+
+        let mut data = vec![0; length as usize];
+        let result = match fd {
+            0 => core.bus.read_stdin(core.cycle, &mut data),
+            _ => match self.files.get_mut(&fd) {
+                Some(file) => file.read(&mut data),
+                None => return -EBADF,
+            },
+        };
+
+        match result {
+            Ok(size) => {
+                write_bytes(core, buf, &data[..size]);
+                size as i64
+            },
+            Err(e) => errno(e),
+        }
+    }
+
+    pub fn write<X: Xlen>(&mut self, core: &Core<X>, fd: u64, buf: u64, length: u64) -> i64 {
+        let data = match read_bytes(core, buf, length) {
+            Some(data) => data,
+            None => return -EFAULT,
+        };
+
+        let result = match fd {
+            1 => io::stdout().write_all(&data).and_then(|_| io::stdout().flush()),
+            2 => io::stderr().write_all(&data),
+            _ => match self.files.get_mut(&fd) {
+                Some(file) => file.write_all(&data),
+                None => return -EBADF,
+            },
+        };
+
+        match result {
+            Ok(()) => length as i64,
+            Err(e) => errno(e),
+        }
+    }
+
+    // Reads up to `length` bytes at `offset` of an open file without moving its position, e.g. for mmap.
+    pub fn read_at(&mut self, fd: u64, offset: u64, length: u64) -> Result<Vec<u8>, i64> {
+        let file = self.files.get_mut(&fd).ok_or(-EBADF)?;
+        let position = file.stream_position().map_err(errno)?;
+
+        let mut data = Vec::new();
+        let result = file.seek(SeekFrom::Start(offset)).and_then(|_| file.take(length).read_to_end(&mut data));
+        file.seek(SeekFrom::Start(position)).map_err(errno)?;
+        result.map(|_| data).map_err(errno)
+    }
+
+    fn open<X: Xlen>(&mut self, core: &Core<X>, path: u64, flags: u64) -> i64 {
+        match read_string(core, path) {
+            Ok(path) => self.open_path(&path, flags),
+            Err(e) => e,
+        }
+    }
+
+    pub fn open_path(&mut self, path: &str, flags: u64) -> i64 {
+        let path = match self.resolve_path(path) {
+            Ok(path) => path,
+            Err(e) => return e,
+        };
+
+        let mut options = OpenOptions::new();
+        match flags & O_ACCMODE {
+            O_WRONLY => options.write(true),
+            O_RDWR => options.read(true).write(true),
+            _ => options.read(true),
+        };
+        options.append(flags & O_APPEND != 0).truncate(flags & O_TRUNC != 0);
+        if flags & O_CREAT != 0 {
+            if flags & O_EXCL != 0 {
+                options.create_new(true);
+            } else {
+                options.create(true);
+            }
+        }
+
+        match options.open(path) {
+            Ok(file) => {
+                let fd = self.next_fd;
+                self.next_fd += 1;
+                self.files.insert(fd, file);
+                fd as i64
+            },
+            Err(e) => errno(e),
+        }
+    }
+
+    pub fn close(&mut self, fd: u64) -> i64 {
+        match fd {
+            0..=2 => 0,
+            _ => match self.files.remove(&fd) {
+                Some(_) => 0,
+                None => -EBADF,
+            },
+        }
+    }
+
+    pub fn seek(&mut self, fd: u64, offset: i64, whence: u64) -> i64 {
+        let position = match whence {
+            0 if offset >= 0 => SeekFrom::Start(offset as u64),
+            1 => SeekFrom::Current(offset),
+            2 => SeekFrom::End(offset),
+            _ => return -EINVAL,
+        };
+
+        match self.files.get_mut(&fd) {
+            Some(file) => file.seek(position).map(|p| p as i64).unwrap_or_else(errno),
+            None => -EBADF,
+        }
+    }
+
+    fn status_fd(&self, fd: u64) -> Result<FileStatus, i64> {
+        match fd {
+            0..=2 => Ok(FileStatus::console()),
+            _ => match self.files.get(&fd).map(|file| file.metadata()) {
+                Some(Ok(metadata)) => Ok(FileStatus::from_metadata(&metadata)),
+                Some(Err(e)) => Err(errno(e)),
+                None => Err(-EBADF),
+            },
+        }
+    }
+
+    pub fn size(&self, fd: u64) -> Result<u64, i64> {
+        self.status_fd(fd).map(|status| status.size)
+    }
+
+    fn status<X: Xlen>(&self, core: &Core<X>, path: u64) -> Result<FileStatus, i64> {
+        let path = self.resolve(core, path)?;
+        fs::metadata(path).map(|metadata| FileStatus::from_metadata(&metadata)).map_err(errno)
+    }
+
+    fn fstat<X: Xlen>(&self, core: &mut Core<X>, fd: u64, buf: u64) -> i64 {
+        self.status_fd(fd).map(|status| write_stat(core, buf, &status)).unwrap_or_else(|e| e)
+    }
+
+    fn stat<X: Xlen>(&self, core: &mut Core<X>, path: u64, buf: u64) -> i64 {
+        self.status(core, path).map(|status| write_stat(core, buf, &status)).unwrap_or_else(|e| e)
+    }
+
+    fn statx_fd<X: Xlen>(&self, core: &mut Core<X>, fd: u64, buf: u64) -> i64 {
+        self.status_fd(fd).map(|status| write_statx(core, buf, &status)).unwrap_or_else(|e| e)
+    }
+
+    fn statx<X: Xlen>(&self, core: &mut Core<X>, path: u64, buf: u64) -> i64 {
+        self.status(core, path).map(|status| write_statx(core, buf, &status)).unwrap_or_else(|e| e)
+    }
+
+    fn access<X: Xlen>(&self, core: &Core<X>, path: u64) -> i64 {
+        self.status(core, path).map(|_| 0).unwrap_or_else(|e| e)
+    }
+
+    fn unlink<X: Xlen>(&self, core: &Core<X>, path: u64) -> i64 {
+        match read_string(core, path) {
+            Ok(path) => self.unlink_path(&path),
+            Err(e) => e,
+        }
+    }
+
+    pub fn unlink_path(&self, path: &str) -> i64 {
+        match self.resolve_path(path) {
+            Ok(path) => fs::remove_file(path).map(|_| 0).unwrap_or_else(errno),
+            Err(e) => e,
+        }
+    }
+}
@@ -1,66 +1,178 @@
 use core::*;
+use mmu::*;
+use xlen::*;
 
-const PRIV_MACHINE: u32 = 3;
+const CAUSE_INSN_ACCESS_FAULT : u32 = 1;
+const CAUSE_ILLEGAL_INSN      : u32 = 2;
+const CAUSE_EBREAK            : u32 = 3;
+const CAUSE_LOAD_ACCESS_FAULT : u32 = 5;
+const CAUSE_STORE_ACCESS_FAULT: u32 = 7;
+const CAUSE_ECALL_FROM_U      : u32 = 8;
+const CAUSE_INSN_PAGE_FAULT   : u32 = 12;
+const CAUSE_LOAD_PAGE_FAULT   : u32 = 13;
+const CAUSE_STORE_PAGE_FAULT  : u32 = 15;
 
-const CAUSE_EBREAK      : u32 = 3;
-const CAUSE_ECALL_FROM_M: u32 = 11;
+// Interrupt causes, which are also the bits of mip and mie
+pub const INTERRUPT_SSI: u32 = 1;
+pub const INTERRUPT_MSI: u32 = 3;
+pub const INTERRUPT_STI: u32 = 5;
+pub const INTERRUPT_MTI: u32 = 7;
+pub const INTERRUPT_SEI: u32 = 9;
+pub const INTERRUPT_MEI: u32 = 11;
+
+// Interrupts in the order of priority
+const INTERRUPT_PRIORITY: [u32; 6] = [INTERRUPT_MEI, INTERRUPT_MSI, INTERRUPT_MTI, INTERRUPT_SEI, INTERRUPT_SSI, INTERRUPT_STI];
 
 pub enum TrapType {
     Exception,
+    Interrupt,
+    // Return by MRET or SRET, whose privilege is in cause
     TrapReturn,
 }
 
-pub struct Trap {
+pub struct Trap<X: Xlen = Rv32> {
     pub trap_type: TrapType,
     pub cause: u32,
-    pub value: u32,
-    pub pc: u32,
+    pub value: X::Uint,
+    pub pc: X::Uint,
 }
 
-impl Trap {
-    pub fn new_ebreak(pc: u32) -> Trap {
-        Trap { trap_type: TrapType::Exception, cause: CAUSE_EBREAK, value: 0, pc: pc }
+impl<X: Xlen> Trap<X> {
+    pub fn new_illegal_instruction(pc: X::Uint) -> Trap<X> {
+        Trap { trap_type: TrapType::Exception, cause: CAUSE_ILLEGAL_INSN, value: X::Uint::ZERO, pc }
+    }
+
+    pub fn new_ebreak(pc: X::Uint) -> Trap<X> {
+        Trap { trap_type: TrapType::Exception, cause: CAUSE_EBREAK, value: X::Uint::ZERO, pc: pc }
+    }
+
+    // Breakpoint exception raised by a trigger matching `value`.
+    pub fn new_breakpoint(pc: X::Uint, value: X::Uint) -> Trap<X> {
+        Trap { trap_type: TrapType::Exception, cause: CAUSE_EBREAK, value, pc }
+    }
+
+    pub fn new_ecall(pc: X::Uint, privilege: u32) -> Trap<X> {
+        Trap { trap_type: TrapType::Exception, cause: CAUSE_ECALL_FROM_U + privilege, value: X::Uint::ZERO, pc: pc }
+    }
+
+    // Exception raised by a failed translation of `addr`.
+    pub fn new_mmu_fault(pc: X::Uint, addr: X::Uint, access: MemoryAccess, fault: MmuFault) -> Trap<X> {
+        let cause = match (access, fault) {
+            (MemoryAccess::Fetch, MmuFault::Access) => CAUSE_INSN_ACCESS_FAULT,
+            (MemoryAccess::Load, MmuFault::Access) => CAUSE_LOAD_ACCESS_FAULT,
+            (MemoryAccess::Store, MmuFault::Access) => CAUSE_STORE_ACCESS_FAULT,
+            (MemoryAccess::Fetch, MmuFault::Page) => CAUSE_INSN_PAGE_FAULT,
+            (MemoryAccess::Load, MmuFault::Page) => CAUSE_LOAD_PAGE_FAULT,
+            (MemoryAccess::Store, MmuFault::Page) => CAUSE_STORE_PAGE_FAULT,
+        };
+        Trap { trap_type: TrapType::Exception, cause, value: addr, pc }
+    }
+
+    // Interrupt taken before the op at pc
+    pub fn new_interrupt(pc: X::Uint, cause: u32) -> Trap<X> {
+        Trap { trap_type: TrapType::Interrupt, cause, value: X::Uint::ZERO, pc }
     }
 
-    pub fn new_ecall_from_machine(pc: u32) -> Trap {
-        Trap { trap_type: TrapType::Exception, cause: CAUSE_ECALL_FROM_M, value: 0, pc: pc }
+    // Return by MRET (privilege is PRIV_MACHINE) or SRET (PRIV_SUPERVISOR)
+    pub fn new_trap_return(pc: X::Uint, privilege: u32) -> Trap<X> {
+        Trap { trap_type: TrapType::TrapReturn, cause: privilege, value: X::Uint::ZERO, pc: pc }
     }
+}
+
+// Returns the interrupt to be taken at the current privilege, if any. Interrupts delegated by mideleg
+// are taken in S-mode, and are disabled in M-mode. Interrupts are enabled in a lower privilege than
+// their target regardless of the xIE bits.
+pub fn pending_interrupt<X: Xlen>(core: &Core<X>) -> Option<u32> {
+    let pending = core.csr.read_mip().to_u64() & core.csr.read_mie().to_u64();
+    if pending == 0 {
+        return None
+    }
+
+    let mstatus = core.csr.read_mstatus();
+    let mideleg = core.csr.read_mideleg().to_u64();
+    let machine_enabled = core.privilege < PRIV_MACHINE || mstatus.mie() == 1;
+    let supervisor_enabled = core.privilege < PRIV_SUPERVISOR || (core.privilege == PRIV_SUPERVISOR && mstatus.sie() == 1);
+
+    let enabled = (if machine_enabled { pending & !mideleg } else { 0 }) | (if supervisor_enabled { pending & mideleg } else { 0 });
+    INTERRUPT_PRIORITY.iter().cloned().find(|cause| enabled & (1 << cause) != 0)
+}
 
-    pub fn new_trap_return(pc: u32) -> Trap {
-        Trap { trap_type: TrapType::TrapReturn, cause: 0, value: 0, pc: pc }
+// Returns the address of the trap handler in xtvec. Interrupts jump to base + 4 * cause in vectored mode.
+fn trap_vector<X: Xlen>(xtvec: X::Uint, trap: &Trap<X>) -> X::Uint {
+    let base = xtvec & !X::Uint::from_u32(3);
+    match (&trap.trap_type, xtvec.to_u32() & 3) {
+        (TrapType::Interrupt, 1) => base.wrapping_add(X::Uint::from_u32(4 * trap.cause)),
+        _ => base,
     }
 }
 
-fn process_exception(core: &mut Core, trap: &Trap)
+// Takes an exception or interrupt in M-mode, or in S-mode if it is delegated by medeleg or mideleg
+// and occurs below M-mode.
+fn process_exception<X: Xlen>(core: &mut Core<X>, trap: &Trap<X>)
 {
     let mut mstatus = core.csr.read_mstatus();
-    let mtvec = core.csr.read_mtvec();
+    let (cause, delegation) = match trap.trap_type {
+        TrapType::Interrupt => (X::Uint::from_u32(trap.cause) | X::Uint::from_u32(1).shl(X::XLEN - 1), core.csr.read_mideleg()),
+        _ => (X::Uint::from_u32(trap.cause), core.csr.read_medeleg()),
+    };
+
+    if core.privilege <= PRIV_SUPERVISOR && delegation.to_u64() & (1 << trap.cause) != 0 {
+        mstatus.set_spie(mstatus.sie());
+        mstatus.set_sie(0);
+        mstatus.set_spp(core.privilege);
+
+        core.csr.write_mstatus(mstatus);
+        core.csr.write_scause(cause);
+        core.csr.write_sepc(trap.pc);
+        core.csr.write_stval(trap.value);
+
+        core.privilege = PRIV_SUPERVISOR;
+        core.next_pc = trap_vector(core.csr.read_stvec(), trap);
+        return
+    }
 
     mstatus.set_mpie(mstatus.mie());
     mstatus.set_mie(0);
-    mstatus.set_mpp(PRIV_MACHINE);
+    mstatus.set_mpp(core.privilege);
 
     core.csr.write_mstatus(mstatus);
-    core.csr.write_mcause(trap.cause);
+    core.csr.write_mcause(cause);
     core.csr.write_mepc(trap.pc);
     core.csr.write_mtval(trap.value);
 
-    core.next_pc = mtvec.base() << 2;
+    core.privilege = PRIV_MACHINE;
+    core.next_pc = trap_vector(core.csr.read_mtvec(), trap);
 }
 
-fn process_trap_return(core: &mut Core, _trap: &Trap)
+fn process_trap_return<X: Xlen>(core: &mut Core<X>, trap: &Trap<X>)
 {
     let mut mstatus = core.csr.read_mstatus();
-    let mepc = core.csr.read_mepc();
 
-    mstatus.set_mpp(0);
-    mstatus.set_mie(mstatus.mpie());
+    let privilege = if trap.cause == PRIV_SUPERVISOR {
+        let privilege = mstatus.spp();
+        mstatus.set_spp(PRIV_USER);
+        mstatus.set_sie(mstatus.spie());
+        mstatus.set_spie(1);
+        core.next_pc = core.csr.read_sepc();
+        privilege
+    } else {
+        let privilege = mstatus.mpp();
+        mstatus.set_mpp(PRIV_USER);
+        mstatus.set_mie(mstatus.mpie());
+        mstatus.set_mpie(1);
+        core.next_pc = core.csr.read_mepc();
+        privilege
+    };
+    // Returning below M-mode clears MPRV.
+    if privilege != PRIV_MACHINE {
+        mstatus.set_mprv(0);
+    }
 
+    core.privilege = privilege;
     core.csr.write_mstatus(mstatus);
-    core.next_pc = mepc;
 }
 
-pub fn process_trap(core: &mut Core, trap: &Trap)
+pub fn process_trap<X: Xlen>(core: &mut Core<X>, trap: &Trap<X>)
 {
     match trap.trap_type {
         TrapType::TrapReturn => process_trap_return(core, trap),
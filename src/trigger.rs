@@ -0,0 +1,412 @@
+// Breakpoints and watchpoints.
+//
+// Guest triggers are programmed through tselect/tdata1-3 and raise a breakpoint exception before the
+// matching op. Host triggers are set from the command line or a debugger through Debugger and stop
+// Debugger::run() without changing the guest state.
+
+use core::*;
+use csr::*;
+use decoder::*;
+use op::*;
+use trap::*;
+use util::*;
+use xlen::*;
+
+use std::fmt;
+
+const NUM_INT_REG: usize = 32;
+
+fn overlaps(addr: u64, size: u32, begin: u64, end: u64) -> bool {
+    addr <= end && addr.wrapping_add(size as u64 - 1) >= begin
+}
+
+fn match_guest_trigger(mcontrol: &MCONTROL, tdata2: u64, value: u64) -> bool {
+    match mcontrol.match_mode() {
+        MATCH_GREATER_OR_EQUAL => value >= tdata2,
+        MATCH_LESS => value < tdata2,
+        _ => value == tdata2,
+    }
+}
+
+// Checks the triggers programmed by the guest before `op` is executed.
+pub fn check_guest_triggers<X: Xlen>(core: &Core<X>, op: &dyn Op<X>) -> Option<Trap<X>> {
+    if !core.csr.triggers_enabled() {
+        return None
+    }
+
+    for i in 0..NUM_TRIGGER {
+        let (tdata1, tdata2) = core.csr.read_trigger(i);
+        let mcontrol = mcontrol(tdata1);
+        let tdata2 = tdata2.to_u64();
+
+        let enabled = match core.privilege {
+            PRIV_MACHINE => mcontrol.m(),
+            PRIV_SUPERVISOR => mcontrol.s(),
+            _ => mcontrol.u(),
+        };
+        if enabled == 0 {
+            continue
+        }
+
+        if mcontrol.execute() == 1 && match_guest_trigger(&mcontrol, tdata2, core.pc.to_u64()) {
+            return Some(Trap::new_breakpoint(core.pc, core.pc))
+        }
+
+        let data_match = match op.class() {
+            OpClass::Load => mcontrol.load() == 1,
+            OpClass::Store => mcontrol.store() == 1,
+            _ => false,
+        };
+        if data_match {
+            if let Some((addr, _size)) = op.memory_access(core) {
+                if match_guest_trigger(&mcontrol, tdata2, addr) {
+                    return Some(Trap::new_breakpoint(core.pc, X::Uint::from_u64(addr)))
+                }
+            }
+        }
+    }
+
+    None
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TriggerKind {
+    // Address ranges are inclusive.
+    Execute(u64, u64),
+    Read(u64, u64),
+    Write(u64, u64),
+    Access(u64, u64),
+    TrapCause(u32),
+    Class(OpClass),
+}
+
+fn format_range(begin: u64, end: u64) -> String {
+    if begin == end {
+        format!("0x{:08x}", begin)
+    } else {
+        format!("0x{:08x}-0x{:08x}", begin, end)
+    }
+}
+
+// Formats in the specification syntax accepted by Trigger::parse().
+impl fmt::Display for TriggerKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TriggerKind::Execute(begin, end) => write!(f, "exec={}", format_range(*begin, *end)),
+            TriggerKind::Read(begin, end) => write!(f, "read={}", format_range(*begin, *end)),
+            TriggerKind::Write(begin, end) => write!(f, "write={}", format_range(*begin, *end)),
+            TriggerKind::Access(begin, end) => write!(f, "access={}", format_range(*begin, *end)),
+            TriggerKind::TrapCause(cause) => write!(f, "trap={}", cause),
+            TriggerKind::Class(class) => {
+                let name = CLASS_NAMES.iter().find(|c| c.1 == *class).map_or("", |c| c.0);
+                write!(f, "class={}", name)
+            },
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Trigger {
+    pub kind: TriggerKind,
+    // Matches only while the integer register holds the value.
+    pub condition: Option<(usize, u64)>,
+    // Stops from the n-th match on.
+    pub hit_count: u64,
+    hits: u64,
+}
+
+fn parse_u64(s: &str) -> Result<u64, String> {
+    let result = match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    result.map_err(|_| format!("invalid number '{}'", s))
+}
+
+fn parse_range(s: &str) -> Result<(u64, u64), String> {
+    match s.find('-') {
+        Some(i) => {
+            let (begin, end) = (parse_u64(&s[..i])?, parse_u64(&s[i + 1..])?);
+            if begin > end {
+                return Err(format!("invalid range '{}'", s))
+            }
+            Ok((begin, end))
+        },
+        None => parse_u64(s).map(|addr| (addr, addr)),
+    }
+}
+
+fn parse_int_reg(s: &str) -> Result<usize, String> {
+    if let Some(index) = s.strip_prefix('x').and_then(|n| n.parse::<usize>().ok()) {
+        if index < NUM_INT_REG {
+            return Ok(index)
+        }
+    }
+    (0..NUM_INT_REG).find(|i| get_int_reg_name(*i) == s).ok_or(format!("invalid register '{}'", s))
+}
+
+const CLASS_NAMES: [(&str, OpClass); 11] = [
+    ("alu", OpClass::Alu),
+    ("load", OpClass::Load),
+    ("store", OpClass::Store),
+    ("branch", OpClass::Branch),
+    ("jump", OpClass::Jump),
+    ("indirect-jump", OpClass::IndirectJump),
+    ("fence", OpClass::Fence),
+    ("fence-i", OpClass::FenceI),
+    ("csr", OpClass::Csr),
+    ("system", OpClass::System),
+    ("unknown", OpClass::Unknown),
+];
+
+fn parse_class(s: &str) -> Result<OpClass, String> {
+    CLASS_NAMES.iter().find(|c| c.0 == s).map(|c| c.1).ok_or(format!("invalid op class '{}'", s))
+}
+
+impl Trigger {
+    pub fn new(kind: TriggerKind) -> Trigger {
+        Trigger { kind, condition: None, hit_count: 1, hits: 0 }
+    }
+
+    // Parses a comma separated trigger specification, e.g. "write=0x80001000-0x80001003,if=a0=5,hit=2".
+    pub fn parse(spec: &str) -> Result<Trigger, String> {
+        let mut kind = None;
+        let mut condition = None;
+        let mut hit_count = 1;
+
+        for item in spec.split(',') {
+            let (key, value) = match item.find('=') {
+                Some(i) => (&item[..i], &item[i + 1..]),
+                None => return Err(format!("invalid trigger item '{}'", item)),
+            };
+
+            let new_kind = match key {
+                "pc" => Some(TriggerKind::Execute(parse_u64(value)?, parse_u64(value)?)),
+                "exec" => parse_range(value).map(|(b, e)| Some(TriggerKind::Execute(b, e)))?,
+                "read" => parse_range(value).map(|(b, e)| Some(TriggerKind::Read(b, e)))?,
+                "write" => parse_range(value).map(|(b, e)| Some(TriggerKind::Write(b, e)))?,
+                "access" => parse_range(value).map(|(b, e)| Some(TriggerKind::Access(b, e)))?,
+                "trap" => Some(TriggerKind::TrapCause(parse_u64(value)? as u32)),
+                "class" => Some(TriggerKind::Class(parse_class(value)?)),
+                "if" => {
+                    let mut iter = value.splitn(2, '=');
+                    let reg = parse_int_reg(iter.next().unwrap_or(""))?;
+                    let expected = parse_u64(iter.next().ok_or(format!("invalid condition '{}'", value))?)?;
+                    condition = Some((reg, expected));
+                    None
+                },
+                "hit" => {
+                    hit_count = value.parse().map_err(|_| format!("invalid hit count '{}'", value))?;
+                    None
+                },
+                _ => return Err(format!("invalid trigger item '{}'", item)),
+            };
+
+            if new_kind.is_some() {
+                if kind.is_some() {
+                    return Err(format!("trigger '{}' has more than one kind", spec))
+                }
+                kind = new_kind;
+            }
+        }
+
+        match kind {
+            Some(kind) => Ok(Trigger { kind, condition, hit_count, hits: 0 }),
+            None => Err(format!("trigger '{}' has no kind", spec)),
+        }
+    }
+
+    // Matches before `op` is executed. Trap cause triggers never match here.
+    fn match_op<X: Xlen>(&self, core: &Core<X>, op: &dyn Op<X>) -> bool {
+        let access = |load: bool, store: bool, begin: u64, end: u64| {
+            let class_match = match op.class() {
+                OpClass::Load => load,
+                OpClass::Store => store,
+                _ => false,
+            };
+            class_match && op.memory_access(core).is_some_and(|(addr, size)| overlaps(addr, size, begin, end))
+        };
+
+        match self.kind {
+            TriggerKind::Execute(begin, end) => (begin..=end).contains(&core.pc.to_u64()),
+            TriggerKind::Read(begin, end) => access(true, false, begin, end),
+            TriggerKind::Write(begin, end) => access(false, true, begin, end),
+            TriggerKind::Access(begin, end) => access(true, true, begin, end),
+            TriggerKind::Class(class) => op.class() == class,
+            TriggerKind::TrapCause(_) => false,
+        }
+    }
+
+    // Counts a match and returns whether the trigger fires.
+    fn hit<X: Xlen>(&mut self, core: &Core<X>) -> bool {
+        if let Some((reg, value)) = self.condition {
+            if core.int_reg.read(reg).to_u64() != value {
+                return false
+            }
+        }
+        self.hits += 1;
+        self.hits >= self.hit_count
+    }
+}
+
+// Host side breakpoints and watchpoints. Triggers are identified by the index returned from add().
+pub struct Debugger {
+    triggers: Vec<Option<Trigger>>,
+    // Cycle at which run() stopped before executing an op, so that resuming executes it.
+    stopped_cycle: Option<u64>,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger { triggers: Vec::new(), stopped_cycle: None }
+    }
+
+    pub fn add(&mut self, trigger: Trigger) -> usize {
+        self.triggers.push(Some(trigger));
+        self.triggers.len() - 1
+    }
+
+    pub fn remove(&mut self, id: usize) -> bool {
+        match self.triggers.get_mut(id) {
+            Some(trigger) => trigger.take().is_some(),
+            None => false,
+        }
+    }
+
+    pub fn get(&self, id: usize) -> Option<&Trigger> {
+        self.triggers.get(id).and_then(|trigger| trigger.as_ref())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.triggers.iter().all(|trigger| trigger.is_none())
+    }
+
+    // Returns the id of the trigger which fires before `op` is executed.
+    pub fn check<X: Xlen>(&mut self, core: &Core<X>, op: &dyn Op<X>) -> Option<usize> {
+        for (id, trigger) in self.triggers.iter_mut().enumerate() {
+            if let Some(trigger) = trigger {
+                if trigger.match_op(core, op) && trigger.hit(core) {
+                    return Some(id)
+                }
+            }
+        }
+        None
+    }
+
+    // Returns the id of the trigger which fires on the exception raised by the last executed op.
+    pub fn check_trap<X: Xlen>(&mut self, core: &Core<X>) -> Option<usize> {
+        let cause = core.last_trap_cause?;
+        for (id, trigger) in self.triggers.iter_mut().enumerate() {
+            if let Some(trigger) = trigger {
+                if trigger.kind == TriggerKind::TrapCause(cause) && trigger.hit(core) {
+                    return Some(id)
+                }
+            }
+        }
+        None
+    }
+
+    // Executes one op unless a trigger fires before it. Returns the id of the fired trigger.
+    pub fn step<X: Xlen>(&mut self, core: &mut Core<X>) -> Option<usize> {
+        core.check_interrupts();
+        if core.waiting {
+            return None
+        }
+        let op = decode::<X>(&core.fetch());
+
+        if self.stopped_cycle != Some(core.cycle) {
+            if let Some(id) = self.check(core, &*op) {
+                self.stopped_cycle = Some(core.cycle);
+                return Some(id)
+            }
+        }
+        self.stopped_cycle = None;
+
+        core.execute(&*op);
+        self.check_trap(core)
+    }
+
+    // Runs until a trigger fires, the host io is written or `max_cycle` is reached.
+    // Returns the id of the fired trigger.
+    pub fn run<X: Xlen>(&mut self, core: &mut Core<X>, max_cycle: u64) -> Option<usize> {
+        while core.cycle < max_cycle {
+            if core.read_host_io() != 0 {
+                break
+            }
+
+            if let Some(id) = self.step(core) {
+                return Some(id)
+            }
+        }
+        None
+    }
+}
+
+#[test]
+fn test_trigger_parse() {
+    let trigger = Trigger::parse("write=0x80001000-0x80001003,if=a0=5,hit=2").unwrap();
+    assert_eq!(trigger.kind, TriggerKind::Write(0x8000_1000, 0x8000_1003));
+    assert_eq!(trigger.condition, Some((10, 5)));
+    assert_eq!(trigger.hit_count, 2);
+
+    assert_eq!(Trigger::parse("pc=0x80000010").unwrap().kind, TriggerKind::Execute(0x8000_0010, 0x8000_0010));
+    assert_eq!(Trigger::parse("class=fence-i").unwrap().kind, TriggerKind::Class(OpClass::FenceI));
+    assert!(Trigger::parse("pc=1,trap=2").is_err());
+    assert!(Trigger::parse("if=x1=0").is_err());
+    assert!(Trigger::parse("read=10-2").is_err());
+}
+
+#[test]
+fn test_triggers() {
+    use bus::*;
+    use memory::*;
+
+    let program = [
+        0x800001b7, // lui gp,0x80000
+        0x00a00093, // addi ra,zero,10
+        0x1011a023, // sw ra,256(gp)
+        0xfff08093, // addi ra,ra,-1
+        0xfe009ce3, // bnez ra,-8
+        0x7a001073, // csrw tselect,zero
+        0x10018193, // addi gp,gp,256
+        0x7a219073, // csrw tdata2,gp
+        0x04100293, // addi t0,zero,0x41
+        0x7a129073, // csrw tdata1,t0
+        0x0001a303, // lw t1,0(gp)
+        0x0000006f, // j 0
+    ];
+
+    let mut memory = Memory::new();
+    let mut bus = Bus::new(&mut memory);
+    for (i, insn) in program.iter().enumerate() {
+        bus.write_u32(0x8000_0000 + 4 * i as u64, *insn);
+    }
+    bus.write_u64(0x8000_1000, 0);
+    let mut core: Core = Core::new(&mut bus);
+    core.host_io_addr = 0x8000_1000;
+    core.pc = 0x8000_0000;
+    core.csr.write(0x305, 0x8000_0200);
+
+    // Host side: the third store of ra, i.e. ra == 8.
+    let mut debugger = Debugger::new();
+    let write = debugger.add(Trigger::parse("write=0x80000100-0x80000103,hit=3").unwrap());
+    let exec = debugger.add(Trigger::parse("pc=0x80000008,if=ra=5").unwrap());
+    let trap = debugger.add(Trigger::parse("trap=3").unwrap());
+
+    assert_eq!(debugger.run(&mut core, 1000), Some(write));
+    assert_eq!((core.pc, core.int_reg.read(1)), (0x8000_0008, 8));
+
+    // Resuming executes the op which stopped the run.
+    assert!(debugger.remove(write));
+    assert_eq!(debugger.run(&mut core, 1000), Some(exec));
+    assert_eq!(core.int_reg.read(1), 5);
+    assert!(debugger.remove(exec));
+    assert!(!debugger.remove(exec));
+
+    // Guest side: the load trigger programmed through tdata1/tdata2 raises a breakpoint exception.
+    assert_eq!(debugger.run(&mut core, 1000), Some(trap));
+    assert_eq!(core.pc, 0x8000_0200);
+    assert_eq!(core.csr.read(0x341), 0x8000_0028);
+    assert_eq!(core.csr.read(0x343), 0x8000_0100);
+    assert_eq!(core.csr.read_trigger(0), (0x2000_0041, 0x8000_0100));
+}
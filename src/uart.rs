@@ -0,0 +1,291 @@
+// NS16550A UART with byte-wide registers. Transmission completes immediately, and received bytes
+// come from a channel, which is fed by a thread reading the host stdin for the console.
+
+use bus::*;
+use fdt::*;
+use snapshot::*;
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::VecDeque;
+use std::io;
+use std::io::{Read, Write};
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+
+pub const UART_SIZE: u64 = 0x100;
+// Input clock in the device tree, which only matters for the divisor chosen by the guest
+const UART_CLOCK_FREQUENCY: u32 = 3_686_400;
+
+// Registers, some of which are the divisor latch while LCR.DLAB is set
+const RBR_THR_DLL: u64 = 0;
+const IER_DLM: u64 = 1;
+const IIR_FCR: u64 = 2;
+const LCR: u64 = 3;
+const MCR: u64 = 4;
+const LSR: u64 = 5;
+const MSR: u64 = 6;
+const SCR: u64 = 7;
+
+const IER_RDI: u8 = 0x1;
+const IER_THRI: u8 = 0x2;
+
+const IIR_NO_INTERRUPT: u8 = 0x1;
+const IIR_THRI: u8 = 0x2;
+const IIR_RDI: u8 = 0x4;
+const IIR_FIFO_ENABLED: u8 = 0xc0;
+
+const FCR_FIFO_ENABLE: u8 = 0x1;
+const FCR_CLEAR_RX: u8 = 0x2;
+
+const LCR_DLAB: u8 = 0x80;
+
+const LSR_DR: u8 = 0x1;
+const LSR_THRE: u8 = 0x20;
+const LSR_TEMT: u8 = 0x40;
+
+// DCD, DSR and CTS
+const MSR_CONNECTED: u8 = 0xb0;
+
+// Returns a channel of the bytes read from the host stdin by a thread.
+pub fn stdin_channel() -> Receiver<u8> {
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+        let mut c = [0];
+        while let Ok(1) = io::stdin().read(&mut c) {
+            if sender.send(c[0]).is_err() {
+                break
+            }
+        }
+    });
+    receiver
+}
+
+pub struct Uart {
+    irq: u32,
+    input: Receiver<u8>,
+    output: Box<dyn Write>,
+    rx: VecDeque<u8>,
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    divisor: u16,
+    fifo_enabled: bool,
+    // The transmitter has become empty and it is not reported by IIR yet.
+    thr_interrupt: bool,
+}
+
+// State of the UART in a snapshot, without its connections to the host
+#[derive(Serialize, Deserialize)]
+struct UartState {
+    rx: VecDeque<u8>,
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    divisor: u16,
+    fifo_enabled: bool,
+    thr_interrupt: bool,
+}
+
+impl Uart {
+    pub fn new(irq: u32, input: Receiver<u8>, output: Box<dyn Write>) -> Uart {
+        Uart {
+            irq,
+            input,
+            output,
+            rx: VecDeque::new(),
+            ier: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            divisor: 0,
+            fifo_enabled: false,
+            thr_interrupt: false,
+        }
+    }
+
+    // Creates a UART connected to the host stdin and stdout.
+    pub fn with_stdio(irq: u32) -> Uart {
+        Uart::new(irq, stdin_channel(), Box::new(io::stdout()))
+    }
+
+    // Creates a UART which writes to the host stdout and receives nothing, when stdin is connected to
+    // another console.
+    pub fn with_stdout(irq: u32) -> Uart {
+        Uart::new(irq, channel().1, Box::new(io::stdout()))
+    }
+
+    fn interrupt_id(&self) -> u8 {
+        if self.ier & IER_RDI != 0 && !self.rx.is_empty() {
+            IIR_RDI
+        } else if self.ier & IER_THRI != 0 && self.thr_interrupt {
+            IIR_THRI
+        } else {
+            IIR_NO_INTERRUPT
+        }
+    }
+}
+
+impl Device for Uart {
+    fn size(&self) -> u64 {
+        UART_SIZE
+    }
+
+    fn read(&mut self, offset: u64, _size: u32) -> u64 {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        let value = match offset {
+            RBR_THR_DLL if dlab => self.divisor as u8,
+            RBR_THR_DLL => self.rx.pop_front().unwrap_or(0),
+            IER_DLM if dlab => (self.divisor >> 8) as u8,
+            IER_DLM => self.ier,
+            IIR_FCR => {
+                let id = self.interrupt_id();
+                // Reading IIR clears the transmitter interrupt it reports.
+                if id == IIR_THRI {
+                    self.thr_interrupt = false;
+                }
+                id | if self.fifo_enabled { IIR_FIFO_ENABLED } else { 0 }
+            },
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => LSR_THRE | LSR_TEMT | if self.rx.is_empty() { 0 } else { LSR_DR },
+            MSR => MSR_CONNECTED,
+            SCR => self.scr,
+            _ => 0,
+        };
+        value as u64
+    }
+
+    fn write(&mut self, offset: u64, _size: u32, value: u64) {
+        let value = value as u8;
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            RBR_THR_DLL if dlab => self.divisor = (self.divisor & 0xff00) | value as u16,
+            RBR_THR_DLL => {
+                let _ = self.output.write_all(&[value]).and_then(|_| self.output.flush());
+                self.thr_interrupt = true;
+            },
+            IER_DLM if dlab => self.divisor = (self.divisor & 0xff) | (value as u16) << 8,
+            IER_DLM => {
+                // Enabling the transmitter interrupt reports the empty transmitter.
+                if self.ier & IER_THRI == 0 && value & IER_THRI != 0 {
+                    self.thr_interrupt = true;
+                }
+                self.ier = value & 0xf;
+            },
+            IIR_FCR => {
+                self.fifo_enabled = value & FCR_FIFO_ENABLE != 0;
+                if value & FCR_CLEAR_RX != 0 {
+                    self.rx.clear();
+                }
+            },
+            LCR => self.lcr = value,
+            MCR => self.mcr = value & 0x1f,
+            SCR => self.scr = value,
+            _ => (),
+        }
+    }
+
+    fn take_host_input(&mut self) -> Vec<u8> {
+        self.input.try_iter().collect()
+    }
+
+    fn receive(&mut self, data: &[u8]) {
+        self.rx.extend(data);
+    }
+
+    fn update(&mut self, _time: u64) -> bool {
+        self.interrupt_id() != IIR_NO_INTERRUPT
+    }
+
+    fn irq(&self) -> u32 {
+        self.irq
+    }
+
+    // Bytes received before the reset are dropped.
+    fn reset(&mut self) {
+        self.rx.clear();
+        self.ier = 0;
+        self.lcr = 0;
+        self.mcr = 0;
+        self.scr = 0;
+        self.divisor = 0;
+        self.fifo_enabled = false;
+        self.thr_interrupt = false;
+    }
+
+    fn save(&self) -> Option<Vec<u8>> {
+        let state = UartState {
+            rx: self.rx.clone(),
+            ier: self.ier,
+            lcr: self.lcr,
+            mcr: self.mcr,
+            scr: self.scr,
+            divisor: self.divisor,
+            fifo_enabled: self.fifo_enabled,
+            thr_interrupt: self.thr_interrupt,
+        };
+        Some(encode_state(&state, &[]))
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), String> {
+        let (state, _): (UartState, _) = decode_state(state)?;
+        self.rx = state.rx;
+        self.ier = state.ier;
+        self.lcr = state.lcr;
+        self.mcr = state.mcr;
+        self.scr = state.scr;
+        self.divisor = state.divisor;
+        self.fifo_enabled = state.fifo_enabled;
+        self.thr_interrupt = state.thr_interrupt;
+        Ok(())
+    }
+
+    fn device_tree_node(&self, fdt: &mut Fdt, base: u64) {
+        fdt.begin_node(&format!("serial@{:x}", base));
+        fdt.property_string("compatible", "ns16550a");
+        fdt.property_cells("reg", &[(base >> 32) as u32, base as u32, 0, UART_SIZE as u32]);
+        fdt.property_u32("clock-frequency", UART_CLOCK_FREQUENCY);
+        fdt.property_u32("interrupts", self.irq);
+        fdt.property_u32("interrupt-parent", PHANDLE_PLIC);
+        fdt.end_node();
+    }
+}
+
+#[test]
+fn test_uart() {
+    let (sender, receiver) = channel();
+    let mut uart = Uart::new(10, receiver, Box::new(io::sink()));
+    assert_eq!(uart.read(LSR, 1), (LSR_THRE | LSR_TEMT) as u64);
+
+    // Received bytes raise the interrupt while enabled and unread.
+    sender.send(b'a').unwrap();
+    let data = uart.take_host_input();
+    uart.receive(&data);
+    assert!(!uart.update(0));
+    uart.write(IER_DLM, 1, IER_RDI as u64);
+    assert!(uart.update(0));
+    assert_eq!(uart.read(IIR_FCR, 1), IIR_RDI as u64);
+    assert_eq!(uart.read(LSR, 1) & LSR_DR as u64, LSR_DR as u64);
+    assert_eq!(uart.read(RBR_THR_DLL, 1), b'a' as u64);
+    assert!(!uart.update(0));
+
+    // The transmitter interrupt is raised when enabled or by a write, and cleared by reading IIR.
+    uart.write(IER_DLM, 1, (IER_RDI | IER_THRI) as u64);
+    assert!(uart.update(0));
+    assert_eq!(uart.read(IIR_FCR, 1), IIR_THRI as u64);
+    assert!(!uart.update(0));
+    uart.write(RBR_THR_DLL, 1, b'b' as u64);
+    assert!(uart.update(0));
+
+    // The divisor latch shadows RBR/THR and IER.
+    uart.write(LCR, 1, LCR_DLAB as u64);
+    uart.write(RBR_THR_DLL, 1, 0x34);
+    uart.write(IER_DLM, 1, 0x12);
+    assert_eq!(uart.read(IER_DLM, 1), 0x12);
+    uart.write(LCR, 1, 0x3);
+    assert_eq!(uart.read(IER_DLM, 1), (IER_RDI | IER_THRI) as u64);
+    assert_eq!(uart.divisor, 0x1234);
+}
@@ -0,0 +1,511 @@
+// Virtio devices on the MMIO transport, version 2 (virtio 1.x), with split virtqueues in guest memory.
+// The transport implements the registers common to all devices, and a VirtioDevice serves the
+// buffers which the driver makes available in its queues.
+//
+// Buffers are used in the order they are made available, and the device interrupt is requested
+// while the driver has not acknowledged used buffers. Event suppression and indirect descriptors are
+// not offered.
+
+use bus::*;
+use fdt::*;
+use snapshot::*;
+
+use serde::{Deserialize, Serialize};
+
+pub const VIRTIO_MMIO_SIZE: u64 = 0x1000;
+// Largest number of descriptors in a queue
+pub const VIRTQUEUE_SIZE_MAX: u16 = 256;
+// Largest total length of the buffers of a chain, which bounds the data a device handles at once
+const MAX_CHAIN_LENGTH: u64 = 1 << 24;
+
+const MAGIC_VALUE: u64 = 0x000;
+const VERSION: u64 = 0x004;
+const DEVICE_ID: u64 = 0x008;
+const VENDOR_ID: u64 = 0x00c;
+const DEVICE_FEATURES: u64 = 0x010;
+const DEVICE_FEATURES_SEL: u64 = 0x014;
+const DRIVER_FEATURES: u64 = 0x020;
+const DRIVER_FEATURES_SEL: u64 = 0x024;
+const QUEUE_SEL: u64 = 0x030;
+const QUEUE_NUM_MAX: u64 = 0x034;
+const QUEUE_NUM: u64 = 0x038;
+const QUEUE_READY: u64 = 0x044;
+const QUEUE_NOTIFY: u64 = 0x050;
+const INTERRUPT_STATUS: u64 = 0x060;
+const INTERRUPT_ACK: u64 = 0x064;
+const STATUS: u64 = 0x070;
+const QUEUE_DESC_LOW: u64 = 0x080;
+const QUEUE_DESC_HIGH: u64 = 0x084;
+const QUEUE_DRIVER_LOW: u64 = 0x090;
+const QUEUE_DRIVER_HIGH: u64 = 0x094;
+const QUEUE_DEVICE_LOW: u64 = 0x0a0;
+const QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+const CONFIG_GENERATION: u64 = 0x0fc;
+const CONFIG: u64 = 0x100;
+
+const MAGIC: u32 = 0x7472_6976; // "virt"
+const TRANSPORT_VERSION: u32 = 2;
+const VENDOR: u32 = 0x6966_6172; // "rafi"
+
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_FEATURES_OK: u32 = 8;
+const STATUS_DEVICE_NEEDS_RESET: u32 = 64;
+
+const INTERRUPT_USED_BUFFER: u32 = 1;
+const INTERRUPT_CONFIG_CHANGE: u32 = 2;
+
+pub const DESC_F_NEXT: u16 = 1;
+pub const DESC_F_WRITE: u16 = 2;
+
+// Split virtqueue, whose areas are in guest memory
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Virtqueue {
+    size: u16,
+    ready: bool,
+    // Addresses of the descriptor table, the available ring and the used ring
+    desc: u64,
+    driver: u64,
+    device: u64,
+    // Index in the available ring of the next chain to take
+    last_avail: u16,
+    used_idx: u16,
+}
+
+// Chain of descriptors taken from a queue. The device reads the readable buffers, which come first,
+// and writes the writable ones.
+pub struct Chain {
+    head: u16,
+    // Buffers as (addr, len)
+    readable: Vec<(u64, u32)>,
+    writable: Vec<(u64, u32)>,
+}
+
+impl Virtqueue {
+    fn new() -> Virtqueue {
+        Virtqueue { size: VIRTQUEUE_SIZE_MAX, ready: false, desc: 0, driver: 0, device: 0, last_avail: 0, used_idx: 0 }
+    }
+
+    // Returns true if the descriptor table and the rings are in memory. The driver may set them
+    // anywhere, so they are accessed only after this check.
+    fn is_in_memory(&self, dma: &Dma) -> bool {
+        let size = self.size as usize;
+        dma.is_mapped(self.desc, 16 * size) && dma.is_mapped(self.driver, 4 + 2 * size) && dma.is_mapped(self.device, 4 + 8 * size)
+    }
+
+    // Takes the next chain which the driver made available, if any.
+    pub fn pop(&mut self, dma: &Dma) -> Option<Chain> {
+        if !self.ready || self.size == 0 || dma.read_u16(self.driver + 2) == self.last_avail {
+            return None
+        }
+        let head = dma.read_u16(self.driver + 4 + 2 * (self.last_avail % self.size) as u64);
+        self.last_avail = self.last_avail.wrapping_add(1);
+
+        let mut chain = Chain { head, readable: Vec::new(), writable: Vec::new() };
+        let mut index = head;
+        // A malformed chain which loops ends after size descriptors.
+        for _ in 0..self.size {
+            if index >= self.size {
+                break
+            }
+            let desc = self.desc + 16 * index as u64;
+            let buffer = (dma.read_u64(desc), dma.read_u32(desc + 8));
+            let flags = dma.read_u16(desc + 12);
+            if flags & DESC_F_WRITE != 0 {
+                chain.writable.push(buffer);
+            } else {
+                chain.readable.push(buffer);
+            }
+            if flags & DESC_F_NEXT == 0 {
+                break
+            }
+            index = dma.read_u16(desc + 14);
+        }
+
+        // A chain too long to be served is used without buffers.
+        let length: u64 = chain.readable.iter().chain(&chain.writable).map(|(_, len)| *len as u64).sum();
+        if length > MAX_CHAIN_LENGTH {
+            chain.readable.clear();
+            chain.writable.clear();
+        }
+        Some(chain)
+    }
+
+    // Returns a chain to the driver, with len bytes written to its writable buffers.
+    pub fn push(&mut self, dma: &mut Dma, chain: &Chain, len: u32) {
+        let elem = self.device + 4 + 8 * (self.used_idx % self.size) as u64;
+        dma.write_u32(elem, chain.head as u32);
+        dma.write_u32(elem + 4, len);
+        self.used_idx = self.used_idx.wrapping_add(1);
+        dma.write_u16(self.device + 2, self.used_idx);
+    }
+}
+
+impl Chain {
+    // Returns the contents of the readable buffers, or None if they are not in memory.
+    pub fn read(&self, dma: &Dma) -> Option<Vec<u8>> {
+        let mut data = Vec::new();
+        for (addr, len) in &self.readable {
+            if !dma.is_mapped(*addr, *len as usize) {
+                return None
+            }
+            let start = data.len();
+            data.resize(start + *len as usize, 0);
+            dma.read(*addr, &mut data[start..]);
+        }
+        Some(data)
+    }
+
+    pub fn writable_len(&self) -> usize {
+        self.writable.iter().map(|(_, len)| *len as usize).sum()
+    }
+
+    // Writes data at offset in the writable buffers. Returns false if it does not fit or they are not
+    // in memory.
+    pub fn write(&self, dma: &mut Dma, offset: usize, data: &[u8]) -> bool {
+        if offset + data.len() > self.writable_len() {
+            return false
+        }
+        let (mut offset, mut data) = (offset, data);
+        for (addr, len) in &self.writable {
+            let len = *len as usize;
+            if offset >= len {
+                offset -= len;
+                continue
+            }
+            let size = data.len().min(len - offset);
+            if !dma.write(addr + offset as u64, &data[..size]) {
+                return false
+            }
+            data = &data[size..];
+            offset = 0;
+            if data.is_empty() {
+                break
+            }
+        }
+        true
+    }
+}
+
+// Device type on the virtio transport
+pub trait VirtioDevice {
+    fn device_id(&self) -> u32;
+
+    // Device-specific feature bits. VIRTIO_F_VERSION_1 is added by the transport.
+    fn features(&self) -> u64 {
+        0
+    }
+
+    fn num_queues(&self) -> usize;
+
+    // Device-specific configuration space, which the driver reads
+    fn config(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    // Serves the available buffers of the queues after the driver notified one of them, or while
+    // has_pending() is true. Returns true if buffers were used.
+    fn process(&mut self, queues: &mut [Virtqueue], dma: &mut Dma) -> bool;
+
+    // Returns true while the device has data for the driver, e.g. input waiting for buffers.
+    fn has_pending(&self) -> bool {
+        false
+    }
+
+    fn take_host_input(&mut self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn receive(&mut self, _data: &[u8]) {}
+
+    // Advances the device to `time`, in ticks of mtime.
+    fn update(&mut self, _time: u64) {}
+
+    // Returns the device to its initial state when the driver resets it.
+    fn reset(&mut self) {}
+
+    // Returns the state of the device for a snapshot, or None if it cannot be saved.
+    fn save(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    // Restores a state returned by save().
+    fn restore(&mut self, _state: &[u8]) -> Result<(), String> {
+        Err("device does not support snapshots".to_string())
+    }
+}
+
+pub struct VirtioMmio {
+    device: Box<dyn VirtioDevice>,
+    irq: u32,
+    device_features_sel: u32,
+    driver_features: u64,
+    driver_features_sel: u32,
+    queue_sel: u32,
+    queues: Vec<Virtqueue>,
+    // A queue has been notified since the device last processed them
+    notified: bool,
+    interrupt_status: u32,
+    status: u32,
+}
+
+// State of the transport in a snapshot, which is followed by the state of the device
+#[derive(Serialize, Deserialize)]
+struct VirtioMmioState {
+    device_features_sel: u32,
+    driver_features: u64,
+    driver_features_sel: u32,
+    queue_sel: u32,
+    queues: Vec<Virtqueue>,
+    notified: bool,
+    interrupt_status: u32,
+    status: u32,
+}
+
+// Sets the low or high half of value.
+fn set_half(value: &mut u64, high: bool, half: u64) {
+    *value = if high { (*value & 0xffff_ffff) | half << 32 } else { (*value & !0xffff_ffff) | (half & 0xffff_ffff) };
+}
+
+impl VirtioMmio {
+    pub fn new(device: Box<dyn VirtioDevice>, irq: u32) -> VirtioMmio {
+        let queues = (0..device.num_queues()).map(|_| Virtqueue::new()).collect();
+        VirtioMmio {
+            device,
+            irq,
+            device_features_sel: 0,
+            driver_features: 0,
+            driver_features_sel: 0,
+            queue_sel: 0,
+            queues,
+            notified: false,
+            interrupt_status: 0,
+            status: 0,
+        }
+    }
+
+    fn features(&self) -> u64 {
+        self.device.features() | VIRTIO_F_VERSION_1
+    }
+
+    fn read_config(&self, offset: u64, size: u32) -> u64 {
+        let config = self.device.config();
+        (0..size as u64).rev().fold(0, |value, i| value << 8 | config.get((offset + i) as usize).cloned().unwrap_or(0) as u64)
+    }
+}
+
+impl Device for VirtioMmio {
+    fn size(&self) -> u64 {
+        VIRTIO_MMIO_SIZE
+    }
+
+    fn read(&mut self, offset: u64, size: u32) -> u64 {
+        if offset >= CONFIG {
+            return self.read_config(offset - CONFIG, size)
+        }
+        let queue = self.queues.get(self.queue_sel as usize);
+        let value = match offset {
+            MAGIC_VALUE => MAGIC,
+            VERSION => TRANSPORT_VERSION,
+            DEVICE_ID => self.device.device_id(),
+            VENDOR_ID => VENDOR,
+            DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.features() as u32,
+                1 => (self.features() >> 32) as u32,
+                _ => 0,
+            },
+            QUEUE_NUM_MAX => queue.map_or(0, |_| VIRTQUEUE_SIZE_MAX as u32),
+            QUEUE_READY => queue.map_or(0, |q| q.ready as u32),
+            QUEUE_DESC_LOW => queue.map_or(0, |q| q.desc as u32),
+            QUEUE_DESC_HIGH => queue.map_or(0, |q| (q.desc >> 32) as u32),
+            QUEUE_DRIVER_LOW => queue.map_or(0, |q| q.driver as u32),
+            QUEUE_DRIVER_HIGH => queue.map_or(0, |q| (q.driver >> 32) as u32),
+            QUEUE_DEVICE_LOW => queue.map_or(0, |q| q.device as u32),
+            QUEUE_DEVICE_HIGH => queue.map_or(0, |q| (q.device >> 32) as u32),
+            INTERRUPT_STATUS => self.interrupt_status,
+            STATUS => self.status,
+            // The config space does not change.
+            CONFIG_GENERATION => 0,
+            _ => 0,
+        };
+        value as u64
+    }
+
+    fn write(&mut self, offset: u64, _size: u32, value: u64) {
+        let value = value & 0xffff_ffff;
+        let driver_features_sel = self.driver_features_sel;
+        if let Some(queue) = self.queues.get_mut(self.queue_sel as usize) {
+            match offset {
+                QUEUE_NUM => queue.size = (value as u16).min(VIRTQUEUE_SIZE_MAX),
+                QUEUE_READY => queue.ready = value & 1 != 0,
+                QUEUE_DESC_LOW | QUEUE_DESC_HIGH => set_half(&mut queue.desc, offset == QUEUE_DESC_HIGH, value),
+                QUEUE_DRIVER_LOW | QUEUE_DRIVER_HIGH => set_half(&mut queue.driver, offset == QUEUE_DRIVER_HIGH, value),
+                QUEUE_DEVICE_LOW | QUEUE_DEVICE_HIGH => set_half(&mut queue.device, offset == QUEUE_DEVICE_HIGH, value),
+                _ => (),
+            }
+        }
+        match offset {
+            DEVICE_FEATURES_SEL => self.device_features_sel = value as u32,
+            DRIVER_FEATURES if driver_features_sel < 2 => set_half(&mut self.driver_features, driver_features_sel == 1, value),
+            DRIVER_FEATURES_SEL => self.driver_features_sel = value as u32,
+            QUEUE_SEL => self.queue_sel = value as u32,
+            QUEUE_NOTIFY => self.notified = true,
+            INTERRUPT_ACK => self.interrupt_status &= !(value as u32),
+            STATUS if value == 0 => self.reset(),
+            STATUS => {
+                self.status = value as u32;
+                // Features which were not offered are refused.
+                if self.driver_features & !self.features() != 0 {
+                    self.status &= !STATUS_FEATURES_OK;
+                }
+            },
+            _ => (),
+        }
+    }
+
+    fn take_host_input(&mut self) -> Vec<u8> {
+        self.device.take_host_input()
+    }
+
+    fn receive(&mut self, data: &[u8]) {
+        self.device.receive(data)
+    }
+
+    fn update(&mut self, time: u64) -> bool {
+        self.device.update(time);
+        self.interrupt_status != 0
+    }
+
+    fn irq(&self) -> u32 {
+        self.irq
+    }
+
+    fn dma(&mut self, dma: &mut Dma) {
+        // Queues are served once the driver is ready, including notifications which came before.
+        if self.status & STATUS_DRIVER_OK == 0 || self.status & STATUS_DEVICE_NEEDS_RESET != 0 || !(self.notified || self.device.has_pending()) {
+            return
+        }
+        self.notified = false;
+        // Rings outside memory are an error of the driver, which has to reset the device.
+        if self.queues.iter().any(|queue| queue.ready && !queue.is_in_memory(dma)) {
+            self.status |= STATUS_DEVICE_NEEDS_RESET;
+            self.interrupt_status |= INTERRUPT_CONFIG_CHANGE;
+            return
+        }
+        if self.device.process(&mut self.queues, dma) {
+            self.interrupt_status |= INTERRUPT_USED_BUFFER;
+        }
+    }
+
+    // Also when the driver writes 0 to the status.
+    fn reset(&mut self) {
+        self.device_features_sel = 0;
+        self.driver_features = 0;
+        self.driver_features_sel = 0;
+        self.queue_sel = 0;
+        for queue in &mut self.queues {
+            *queue = Virtqueue::new();
+        }
+        self.notified = false;
+        self.interrupt_status = 0;
+        self.status = 0;
+        self.device.reset();
+    }
+
+    fn save(&self) -> Option<Vec<u8>> {
+        let device = self.device.save()?;
+        let state = VirtioMmioState {
+            device_features_sel: self.device_features_sel,
+            driver_features: self.driver_features,
+            driver_features_sel: self.driver_features_sel,
+            queue_sel: self.queue_sel,
+            queues: self.queues.clone(),
+            notified: self.notified,
+            interrupt_status: self.interrupt_status,
+            status: self.status,
+        };
+        Some(encode_state(&state, &device))
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), String> {
+        let (state, device): (VirtioMmioState, _) = decode_state(state)?;
+        if state.queues.len() != self.queues.len() {
+            return Err(format!("virtio device has {} queues (machine has {})", state.queues.len(), self.queues.len()))
+        }
+        self.device.restore(device)?;
+        self.device_features_sel = state.device_features_sel;
+        self.driver_features = state.driver_features;
+        self.driver_features_sel = state.driver_features_sel;
+        self.queue_sel = state.queue_sel;
+        self.queues = state.queues;
+        self.notified = state.notified;
+        self.interrupt_status = state.interrupt_status;
+        self.status = state.status;
+        Ok(())
+    }
+
+    fn device_tree_node(&self, fdt: &mut Fdt, base: u64) {
+        fdt.begin_node(&format!("virtio_mmio@{:x}", base));
+        fdt.property_string("compatible", "virtio,mmio");
+        fdt.property_cells("reg", &[(base >> 32) as u32, base as u32, 0, VIRTIO_MMIO_SIZE as u32]);
+        fdt.property_u32("interrupts", self.irq);
+        fdt.property_u32("interrupt-parent", PHANDLE_PLIC);
+        fdt.end_node();
+    }
+}
+
+// Helpers for the tests of devices, which play the driver. Queue `index` has 8 descriptors at
+// test_queue_addr(index), followed by the available ring at 0x1000 and the used ring at 0x2000.
+#[cfg(test)]
+pub fn test_queue_addr(index: u32) -> u64 {
+    0x8001_0000 + 0x4000 * index as u64
+}
+
+// Sets up the queues, whose areas are cleared, and makes the driver ready.
+#[cfg(test)]
+pub fn setup_test_queues(virtio: &mut VirtioMmio, dma: &mut Dma) {
+    for index in 0..virtio.queues.len() as u32 {
+        let addr = test_queue_addr(index);
+        dma.write(addr, &[0; 0x3000]);
+        virtio.write(QUEUE_SEL, 4, index as u64);
+        virtio.write(QUEUE_NUM, 4, 8);
+        virtio.write(QUEUE_DESC_LOW, 4, addr);
+        virtio.write(QUEUE_DRIVER_LOW, 4, addr + 0x1000);
+        virtio.write(QUEUE_DEVICE_LOW, 4, addr + 0x2000);
+        virtio.write(QUEUE_READY, 4, 1);
+    }
+    virtio.write(STATUS, 4, 0xf);
+}
+
+// Makes a chain of up to 4 buffers, as (addr, len, writable), available in queue `index` and notifies
+// it. Returns the index of the chain in the rings.
+#[cfg(test)]
+pub fn push_test_chain(virtio: &mut VirtioMmio, dma: &mut Dma, index: u32, buffers: &[(u64, u32, bool)]) -> u16 {
+    let addr = test_queue_addr(index);
+    let avail_idx = dma.read_u16(addr + 0x1002);
+    let first = 4 * (avail_idx % 2);
+    for (i, (buffer, len, writable)) in buffers.iter().enumerate() {
+        let desc = addr + 16 * (first as u64 + i as u64);
+        let next = if i + 1 < buffers.len() { DESC_F_NEXT } else { 0 };
+        dma.write(desc, &buffer.to_le_bytes());
+        dma.write_u32(desc + 8, *len);
+        dma.write_u16(desc + 12, next | if *writable { DESC_F_WRITE } else { 0 });
+        dma.write_u16(desc + 14, first + i as u16 + 1);
+    }
+    dma.write_u16(addr + 0x1004 + 2 * (avail_idx % 8) as u64, first);
+    dma.write_u16(addr + 0x1002, avail_idx.wrapping_add(1));
+    virtio.write(QUEUE_NOTIFY, 4, index as u64);
+    virtio.dma(dma);
+    avail_idx
+}
+
+// Returns the length written to chain n of queue `index` if the device has used it.
+#[cfg(test)]
+pub fn test_used_len(dma: &Dma, index: u32, n: u16) -> Option<u32> {
+    let addr = test_queue_addr(index);
+    match dma.read_u16(addr + 0x2002) > n {
+        true => Some(dma.read_u32(addr + 0x2004 + 8 * (n % 8) as u64 + 4)),
+        false => None,
+    }
+}
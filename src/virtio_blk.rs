@@ -0,0 +1,364 @@
+// Virtio block device backed by a host disk image. With copy-on-write, written sectors are kept in
+// memory so that the image is never modified.
+
+use bus::*;
+use snapshot::*;
+use virtio::*;
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+pub const SECTOR_SIZE: usize = 512;
+
+const VIRTIO_ID_BLOCK: u32 = 2;
+
+const VIRTIO_BLK_F_SEG_MAX: u64 = 1 << 2;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+// Size of the request header: type, reserved and sector
+const HEADER_SIZE: usize = 16;
+const ID_SIZE: usize = 20;
+
+pub struct VirtioBlk {
+    file: File,
+    // Number of sectors
+    capacity: u64,
+    // Written sectors by number, with copy-on-write
+    overlay: Option<HashMap<u64, Vec<u8>>>,
+    id: Vec<u8>,
+}
+
+impl VirtioBlk {
+    // Opens the image at path. Its size is rounded down to a multiple of the sector size.
+    pub fn open(path: &str, copy_on_write: bool) -> Result<VirtioBlk, String> {
+        let file = OpenOptions::new().read(true).write(!copy_on_write).open(path).map_err(|e| format!("{}: {}", path, e))?;
+        let size = file.metadata().map_err(|e| format!("{}: {}", path, e))?.len();
+        // The id is the file name, truncated.
+        let name = Path::new(path).file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        Ok(VirtioBlk {
+            file,
+            capacity: size / SECTOR_SIZE as u64,
+            overlay: if copy_on_write { Some(HashMap::new()) } else { None },
+            id: name.bytes().take(ID_SIZE).collect(),
+        })
+    }
+
+    fn check_range(&self, sector: u64, size: usize) -> io::Result<()> {
+        let in_range = size.is_multiple_of(SECTOR_SIZE) && sector.checked_add((size / SECTOR_SIZE) as u64).is_some_and(|end| end <= self.capacity);
+        if !in_range {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "out of the disk"))
+        }
+        Ok(())
+    }
+
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.check_range(sector, buf.len())?;
+        for (i, data) in buf.chunks_mut(SECTOR_SIZE).enumerate() {
+            let sector = sector + i as u64;
+            match self.overlay.as_ref().and_then(|overlay| overlay.get(&sector)) {
+                Some(written) => data.copy_from_slice(written),
+                None => {
+                    self.file.seek(SeekFrom::Start(sector * SECTOR_SIZE as u64))?;
+                    self.file.read_exact(data)?;
+                },
+            }
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&mut self, sector: u64, data: &[u8]) -> io::Result<()> {
+        self.check_range(sector, data.len())?;
+        match &mut self.overlay {
+            Some(overlay) => {
+                for (i, data) in data.chunks(SECTOR_SIZE).enumerate() {
+                    overlay.insert(sector + i as u64, data.to_vec());
+                }
+                Ok(())
+            },
+            None => {
+                self.file.seek(SeekFrom::Start(sector * SECTOR_SIZE as u64))?;
+                self.file.write_all(data)
+            },
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.overlay {
+            Some(_) => Ok(()),
+            None => self.file.sync_data(),
+        }
+    }
+
+    // Serves a request, whose status is the last writable byte. Returns the number of bytes written.
+    fn serve(&mut self, chain: &Chain, dma: &mut Dma) -> u32 {
+        let writable_len = chain.writable_len();
+        let request = match chain.read(dma) {
+            Some(request) if request.len() >= HEADER_SIZE && writable_len > 0 => request,
+            // There is nowhere to write the status.
+            _ => return 0,
+        };
+        let request_type = u32::from_le_bytes([request[0], request[1], request[2], request[3]]);
+        let mut sector = [0; 8];
+        sector.copy_from_slice(&request[8..16]);
+        let sector = u64::from_le_bytes(sector);
+
+        // Data written to the driver, followed by the status
+        let data_len = writable_len - 1;
+        let (status, written) = match request_type {
+            VIRTIO_BLK_T_IN => {
+                let mut data = vec![0; data_len];
+                match self.read_sectors(sector, &mut data) {
+                    Ok(()) if chain.write(dma, 0, &data) => (VIRTIO_BLK_S_OK, data_len),
+                    _ => (VIRTIO_BLK_S_IOERR, 0),
+                }
+            },
+            VIRTIO_BLK_T_OUT => match self.write_sectors(sector, &request[HEADER_SIZE..]) {
+                Ok(()) => (VIRTIO_BLK_S_OK, 0),
+                Err(_) => (VIRTIO_BLK_S_IOERR, 0),
+            },
+            VIRTIO_BLK_T_FLUSH => match self.flush() {
+                Ok(()) => (VIRTIO_BLK_S_OK, 0),
+                Err(_) => (VIRTIO_BLK_S_IOERR, 0),
+            },
+            VIRTIO_BLK_T_GET_ID => {
+                // The id is padded with NULs, unless it takes all 20 bytes.
+                let mut id = self.id.clone();
+                id.resize(ID_SIZE.min(data_len), 0);
+                match chain.write(dma, 0, &id) {
+                    true => (VIRTIO_BLK_S_OK, id.len()),
+                    false => (VIRTIO_BLK_S_IOERR, 0),
+                }
+            },
+            _ => (VIRTIO_BLK_S_UNSUPP, 0),
+        };
+        chain.write(dma, data_len, &[status]);
+        (written + 1) as u32
+    }
+}
+
+impl VirtioDevice for VirtioBlk {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_BLOCK
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_BLK_F_SEG_MAX | VIRTIO_BLK_F_FLUSH
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    // capacity, size_max and seg_max. A chain has the header and the status besides the segments.
+    fn config(&self) -> Vec<u8> {
+        let mut config = self.capacity.to_le_bytes().to_vec();
+        config.extend_from_slice(&0u32.to_le_bytes());
+        config.extend_from_slice(&(VIRTQUEUE_SIZE_MAX as u32 - 2).to_le_bytes());
+        config
+    }
+
+    fn process(&mut self, queues: &mut [Virtqueue], dma: &mut Dma) -> bool {
+        let mut used = false;
+        while let Some(chain) = queues[0].pop(dma) {
+            let len = self.serve(&chain, dma);
+            queues[0].push(dma, &chain, len);
+            used = true;
+        }
+        used
+    }
+
+    // The written sectors, as the sector number followed by its data. An image written in place has
+    // changed since the snapshot, so only copy-on-write disks can be saved.
+    fn save(&self) -> Option<Vec<u8>> {
+        let overlay = self.overlay.as_ref()?;
+        let mut sectors: Vec<&u64> = overlay.keys().collect();
+        sectors.sort();
+        let mut data = Vec::new();
+        for sector in sectors {
+            data.extend_from_slice(&sector.to_le_bytes());
+            data.extend_from_slice(&overlay[sector]);
+        }
+        Some(encode_state(&self.capacity, &data))
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), String> {
+        let (capacity, data): (u64, _) = decode_state(state)?;
+        if capacity != self.capacity {
+            return Err(format!("disk has {} sectors (image has {})", capacity, self.capacity))
+        }
+        if self.overlay.is_none() {
+            return Err("disk is not copy-on-write".to_string())
+        }
+        if !data.len().is_multiple_of(8 + SECTOR_SIZE) {
+            return Err("written sectors are truncated".to_string())
+        }
+        let mut overlay = HashMap::new();
+        for chunk in data.chunks(8 + SECTOR_SIZE) {
+            let mut sector = [0; 8];
+            sector.copy_from_slice(&chunk[..8]);
+            let sector = u64::from_le_bytes(sector);
+            if sector >= self.capacity {
+                return Err(format!("written sector {} is out of the disk", sector))
+            }
+            overlay.insert(sector, chunk[8..].to_vec());
+        }
+        self.overlay = Some(overlay);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_virtio_blk() {
+    use memory::*;
+
+    let path = std::env::temp_dir().join(format!("rafi-emu-disk-{}.img", std::process::id()));
+    let image: Vec<u8> = (0..4 * SECTOR_SIZE).map(|i| (i / SECTOR_SIZE) as u8).collect();
+    std::fs::write(&path, &image).unwrap();
+    let blk = VirtioBlk::open(path.to_str().unwrap(), true);
+    let mut virtio = VirtioMmio::new(Box::new(blk.unwrap()), 1);
+    let mut memory = Memory::with_size(0x20000);
+    let mut dma = Dma::new(&mut memory, 0x8000_0000);
+
+    assert_eq!(virtio.read(0x0, 4), 0x7472_6976);
+    assert_eq!(virtio.read(0x8, 4), VIRTIO_ID_BLOCK as u64);
+    assert_eq!(virtio.read(0x100, 8), 4);
+    virtio.write(0x14, 4, 1);
+    assert_eq!(virtio.read(0x10, 4), 1);
+
+    setup_test_queues(&mut virtio, &mut dma);
+
+    // Requests with a header at 0x80004000, data at 0x80005000 and the status at 0x80006000
+    let request = |dma: &mut Dma, virtio: &mut VirtioMmio, request_type: u32, sector: u64, data_writable: bool| {
+        dma.write_u32(0x8000_4000, request_type);
+        dma.write(0x8000_4008, &sector.to_le_bytes());
+        let buffers = [(0x8000_4000, 16, false), (0x8000_5000, 2 * SECTOR_SIZE as u32, data_writable), (0x8000_6000, 1, true)];
+        let n = push_test_chain(virtio, dma, 0, &buffers);
+        assert!(virtio.update(0));
+        virtio.write(0x64, 4, 1);
+        let mut status = [0xff];
+        dma.read(0x8000_6000, &mut status);
+        (status[0], test_used_len(dma, 0, n).unwrap())
+    };
+
+    assert_eq!(request(&mut dma, &mut virtio, VIRTIO_BLK_T_IN, 1, true), (VIRTIO_BLK_S_OK, 2 * SECTOR_SIZE as u32 + 1));
+    assert_eq!(dma.read_u16(0x8000_5000 + SECTOR_SIZE as u64), 0x0202);
+
+    dma.write(0x8000_5000, &[0xaa; 2 * SECTOR_SIZE]);
+    assert_eq!(request(&mut dma, &mut virtio, VIRTIO_BLK_T_OUT, 2, false), (VIRTIO_BLK_S_OK, 1));
+    dma.write(0x8000_5000, &[0; 2 * SECTOR_SIZE]);
+    assert_eq!(request(&mut dma, &mut virtio, VIRTIO_BLK_T_IN, 1, true), (VIRTIO_BLK_S_OK, 2 * SECTOR_SIZE as u32 + 1));
+    assert_eq!(dma.read_u16(0x8000_5000), 0x0101);
+    assert_eq!(dma.read_u16(0x8000_5000 + SECTOR_SIZE as u64), 0xaaaa);
+
+    // Past the end of the disk
+    assert_eq!(request(&mut dma, &mut virtio, VIRTIO_BLK_T_IN, 3, true), (VIRTIO_BLK_S_IOERR, 1));
+    assert_eq!(request(&mut dma, &mut virtio, VIRTIO_BLK_T_FLUSH, 0, true).0, VIRTIO_BLK_S_OK);
+    assert_eq!(request(&mut dma, &mut virtio, VIRTIO_BLK_T_GET_ID, 0, true).0, VIRTIO_BLK_S_OK);
+    assert_eq!(dma.read_u32(0x8000_5000), u32::from_le_bytes(*b"rafi"));
+    assert_eq!(request(&mut dma, &mut virtio, 99, 0, true), (VIRTIO_BLK_S_UNSUPP, 1));
+
+    // With copy-on-write, the image is not modified.
+    assert_eq!(std::fs::read(&path).unwrap(), image);
+    std::fs::remove_file(&path).unwrap();
+
+    virtio.write(0x70, 4, 0);
+    assert_eq!(virtio.read(0x44, 4), 0);
+}
+
+#[test]
+fn test_virtio_blk_malformed_requests() {
+    use memory::*;
+
+    let path = std::env::temp_dir().join(format!("rafi-emu-disk-malformed-{}.img", std::process::id()));
+    std::fs::write(&path, vec![0; 4 * SECTOR_SIZE]).unwrap();
+    let blk = VirtioBlk::open(path.to_str().unwrap(), true);
+    let mut virtio = VirtioMmio::new(Box::new(blk.unwrap()), 1);
+    let mut memory = Memory::with_size(0x20000);
+    let mut dma = Dma::new(&mut memory, 0x8000_0000);
+    setup_test_queues(&mut virtio, &mut dma);
+
+    dma.write_u32(0x8000_4000, VIRTIO_BLK_T_IN);
+    dma.write(0x8000_4008, &0u64.to_le_bytes());
+    let status = |dma: &mut Dma| {
+        let mut status = [0xff];
+        dma.read(0x8000_6000, &mut status);
+        dma.write(0x8000_6000, &[0xff]);
+        status[0]
+    };
+    status(&mut dma);
+
+    // Chains without a status buffer, or whose header is short or outside memory, are used without
+    // writing anything.
+    let header = (0x8000_4000, HEADER_SIZE as u32, false);
+    let status_buffer = (0x8000_6000, 1, true);
+    let n = push_test_chain(&mut virtio, &mut dma, 0, &[header]);
+    assert_eq!(test_used_len(&dma, 0, n), Some(0));
+    let n = push_test_chain(&mut virtio, &mut dma, 0, &[(0x8000_4000, 8, false), status_buffer]);
+    assert_eq!(test_used_len(&dma, 0, n), Some(0));
+    let n = push_test_chain(&mut virtio, &mut dma, 0, &[(0x1000, 16, false), status_buffer]);
+    assert_eq!(test_used_len(&dma, 0, n), Some(0));
+    assert_eq!(status(&mut dma), 0xff);
+
+    // Data buffers outside memory or of partial sectors fail.
+    let n = push_test_chain(&mut virtio, &mut dma, 0, &[header, (0x1000, SECTOR_SIZE as u32, true), status_buffer]);
+    assert_eq!((status(&mut dma), test_used_len(&dma, 0, n)), (VIRTIO_BLK_S_IOERR, Some(1)));
+    let n = push_test_chain(&mut virtio, &mut dma, 0, &[header, (0x8000_5000, 100, true), status_buffer]);
+    assert_eq!((status(&mut dma), test_used_len(&dma, 0, n)), (VIRTIO_BLK_S_IOERR, Some(1)));
+    dma.write_u32(0x8000_4000, VIRTIO_BLK_T_OUT);
+    let n = push_test_chain(&mut virtio, &mut dma, 0, &[header, (0x8000_5000, 100, false), status_buffer]);
+    assert_eq!((status(&mut dma), test_used_len(&dma, 0, n)), (VIRTIO_BLK_S_IOERR, Some(1)));
+
+    // A chain which loops ends after as many descriptors as the queue has, here the header and 7
+    // status buffers, and a chain whose next descriptor is out of the queue ends there.
+    let addr = test_queue_addr(0);
+    let mut push_chain = |dma: &mut Dma, next: u16| {
+        dma.write(addr, &0x8000_4000u64.to_le_bytes());
+        dma.write_u32(addr + 8, HEADER_SIZE as u32);
+        dma.write_u16(addr + 12, DESC_F_NEXT);
+        dma.write_u16(addr + 14, next);
+        dma.write(addr + 16, &0x8000_6000u64.to_le_bytes());
+        dma.write_u32(addr + 24, 1);
+        dma.write_u16(addr + 28, DESC_F_NEXT | DESC_F_WRITE);
+        dma.write_u16(addr + 30, 1);
+        let avail_idx = dma.read_u16(addr + 0x1002);
+        dma.write_u16(addr + 0x1004 + 2 * (avail_idx % 8) as u64, 0);
+        dma.write_u16(addr + 0x1002, avail_idx + 1);
+        virtio.write(0x50, 4, 0);
+        virtio.dma(dma);
+        test_used_len(dma, 0, avail_idx)
+    };
+    dma.write_u32(0x8000_4000, VIRTIO_BLK_T_IN);
+    assert_eq!(push_chain(&mut dma, 1), Some(1));
+    assert_eq!(status(&mut dma), VIRTIO_BLK_S_IOERR);
+    assert_eq!(push_chain(&mut dma, 100), Some(0));
+
+    // A chain longer than devices handle is used without buffers.
+    let n = push_test_chain(&mut virtio, &mut dma, 0, &[header, (0x8000_5000, 0xffff_ffff, true), status_buffer]);
+    assert_eq!(test_used_len(&dma, 0, n), Some(0));
+    assert_eq!(status(&mut dma), 0xff);
+
+    // Rings outside memory are not accessed, and make the device need a reset.
+    virtio.write(0x64, 4, 1);
+    virtio.write(0x94, 4, 0xffff_ffff);
+    virtio.write(0x90, 4, 0xffff_ffff);
+    virtio.write(0x50, 4, 0);
+    virtio.dma(&mut dma);
+    assert_eq!(virtio.read(0x70, 4), 0x4f);
+    assert_eq!(virtio.read(0x60, 4), 2);
+    virtio.write(0x70, 4, 0);
+    assert_eq!(virtio.read(0x70, 4), 0);
+
+    std::fs::remove_file(&path).unwrap();
+}
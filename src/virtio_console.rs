@@ -0,0 +1,144 @@
+// Virtio console with a single port. Bytes which the driver transmits are written to the output, and
+// received bytes come from a channel like those of the UART, waiting until the driver makes buffers
+// available.
+
+use bus::*;
+use snapshot::*;
+use uart::*;
+use virtio::*;
+
+use std::collections::VecDeque;
+use std::io;
+use std::io::Write;
+use std::sync::mpsc::Receiver;
+
+const VIRTIO_ID_CONSOLE: u32 = 3;
+
+const RECEIVEQ: usize = 0;
+const TRANSMITQ: usize = 1;
+
+pub struct VirtioConsole {
+    input: Receiver<u8>,
+    output: Box<dyn Write>,
+    rx: VecDeque<u8>,
+}
+
+impl VirtioConsole {
+    pub fn new(input: Receiver<u8>, output: Box<dyn Write>) -> VirtioConsole {
+        VirtioConsole { input, output, rx: VecDeque::new() }
+    }
+
+    // Creates a console connected to the host stdin and stdout.
+    pub fn with_stdio() -> VirtioConsole {
+        VirtioConsole::new(stdin_channel(), Box::new(io::stdout()))
+    }
+}
+
+impl VirtioDevice for VirtioConsole {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_CONSOLE
+    }
+
+    fn num_queues(&self) -> usize {
+        2
+    }
+
+    fn process(&mut self, queues: &mut [Virtqueue], dma: &mut Dma) -> bool {
+        let mut used = false;
+        while let Some(chain) = queues[TRANSMITQ].pop(dma) {
+            if let Some(data) = chain.read(dma) {
+                let _ = self.output.write_all(&data);
+            }
+            queues[TRANSMITQ].push(dma, &chain, 0);
+            used = true;
+        }
+        let _ = self.output.flush();
+
+        while !self.rx.is_empty() {
+            let chain = match queues[RECEIVEQ].pop(dma) {
+                Some(chain) => chain,
+                None => break,
+            };
+            let size = chain.writable_len().min(self.rx.len());
+            let data: Vec<u8> = self.rx.drain(..size).collect();
+            chain.write(dma, 0, &data);
+            queues[RECEIVEQ].push(dma, &chain, size as u32);
+            used = true;
+        }
+        used
+    }
+
+    fn has_pending(&self) -> bool {
+        !self.rx.is_empty()
+    }
+
+    fn take_host_input(&mut self) -> Vec<u8> {
+        self.input.try_iter().collect()
+    }
+
+    fn receive(&mut self, data: &[u8]) {
+        self.rx.extend(data);
+    }
+
+    // The received bytes which the driver has not taken yet
+    fn save(&self) -> Option<Vec<u8>> {
+        let rx: Vec<u8> = self.rx.iter().cloned().collect();
+        Some(encode_state(&(), &rx))
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), String> {
+        let ((), rx) = decode_state(state)?;
+        self.rx = rx.iter().cloned().collect();
+        Ok(())
+    }
+}
+
+#[test]
+fn test_virtio_console() {
+    use memory::*;
+    use std::sync::mpsc::channel;
+    use std::sync::{Arc, Mutex};
+
+    // Output which the test can read after the console takes it
+    struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let output = Arc::new(Mutex::new(Vec::new()));
+    let (sender, receiver) = channel();
+    let console = VirtioConsole::new(receiver, Box::new(SharedOutput(output.clone())));
+    let mut virtio = VirtioMmio::new(Box::new(console), 2);
+    let mut memory = Memory::with_size(0x20000);
+    let mut dma = Dma::new(&mut memory, 0x8000_0000);
+    setup_test_queues(&mut virtio, &mut dma);
+
+    dma.write(0x8000_1000, b"hello");
+    let n = push_test_chain(&mut virtio, &mut dma, TRANSMITQ as u32, &[(0x8000_1000, 3, false), (0x8000_1003, 2, false)]);
+    assert_eq!(test_used_len(&dma, TRANSMITQ as u32, n), Some(0));
+    assert_eq!(*output.lock().unwrap(), b"hello");
+    assert!(virtio.update(0));
+    virtio.write(0x64, 4, 1);
+
+    // Input waits for a buffer, and fills buffers in turn.
+    sender.send(b'a').unwrap();
+    sender.send(b'b').unwrap();
+    sender.send(b'c').unwrap();
+    let input = virtio.take_host_input();
+    virtio.receive(&input);
+    virtio.dma(&mut dma);
+    assert!(!virtio.update(0));
+    let n = push_test_chain(&mut virtio, &mut dma, RECEIVEQ as u32, &[(0x8000_2000, 2, true)]);
+    assert_eq!(test_used_len(&dma, RECEIVEQ as u32, n), Some(2));
+    assert_eq!(dma.read_u16(0x8000_2000), u16::from_le_bytes(*b"ab"));
+    let n = push_test_chain(&mut virtio, &mut dma, RECEIVEQ as u32, &[(0x8000_2000, 2, true)]);
+    assert_eq!(test_used_len(&dma, RECEIVEQ as u32, n), Some(1));
+    let n = push_test_chain(&mut virtio, &mut dma, RECEIVEQ as u32, &[(0x8000_2000, 2, true)]);
+    assert_eq!(test_used_len(&dma, RECEIVEQ as u32, n), None);
+    assert!(virtio.update(0));
+}
@@ -0,0 +1,142 @@
+// Virtio network device, whose frames are carried by a backend. Each frame in a buffer follows a
+// virtio_net_hdr, which is ignored on transmission as no offloads are offered.
+//
+// Received frames go through take_host_input() and receive() of the bus, so that they are recorded
+// and replayed. There each frame is preceded by its length as 4 bytes little-endian.
+
+use bus::*;
+use net::*;
+use virtio::*;
+
+use std::collections::VecDeque;
+
+const VIRTIO_ID_NET: u32 = 1;
+
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+
+const RECEIVEQ: usize = 0;
+const TRANSMITQ: usize = 1;
+
+// Size of virtio_net_hdr, including num_buffers
+const NET_HEADER_SIZE: usize = 12;
+// Received frames waiting for buffers, beyond which they are dropped
+const MAX_PENDING_FRAMES: usize = 256;
+
+pub struct VirtioNet {
+    mac: [u8; 6],
+    backend: Box<dyn NetBackend>,
+    rx: VecDeque<Vec<u8>>,
+    // Time of the last update, which timestamps the sent frames
+    time: u64,
+}
+
+impl VirtioNet {
+    pub fn new(mac: [u8; 6], backend: Box<dyn NetBackend>) -> VirtioNet {
+        VirtioNet { mac, backend, rx: VecDeque::new(), time: 0 }
+    }
+}
+
+impl VirtioDevice for VirtioNet {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_NET
+    }
+
+    fn features(&self) -> u64 {
+        VIRTIO_NET_F_MAC
+    }
+
+    fn num_queues(&self) -> usize {
+        2
+    }
+
+    fn config(&self) -> Vec<u8> {
+        self.mac.to_vec()
+    }
+
+    fn process(&mut self, queues: &mut [Virtqueue], dma: &mut Dma) -> bool {
+        let mut used = false;
+        while let Some(chain) = queues[TRANSMITQ].pop(dma) {
+            match chain.read(dma) {
+                Some(data) if data.len() > NET_HEADER_SIZE => self.backend.send(&data[NET_HEADER_SIZE..], self.time),
+                _ => (),
+            }
+            queues[TRANSMITQ].push(dma, &chain, 0);
+            used = true;
+        }
+
+        while !self.rx.is_empty() {
+            let chain = match queues[RECEIVEQ].pop(dma) {
+                Some(chain) => chain,
+                None => break,
+            };
+            let frame = self.rx.pop_front().unwrap_or_default();
+            // The frame is in one buffer (num_buffers = 1), or dropped if it does not fit.
+            let mut data = vec![0; NET_HEADER_SIZE];
+            data[10] = 1;
+            data.extend_from_slice(&frame);
+            let len = if chain.write(dma, 0, &data) { data.len() as u32 } else { 0 };
+            queues[RECEIVEQ].push(dma, &chain, len);
+            used = true;
+        }
+        used
+    }
+
+    fn has_pending(&self) -> bool {
+        !self.rx.is_empty()
+    }
+
+    fn take_host_input(&mut self) -> Vec<u8> {
+        self.backend.receive().iter().flat_map(|frame| (frame.len() as u32).to_le_bytes().iter().chain(frame).cloned().collect::<Vec<u8>>()).collect()
+    }
+
+    fn receive(&mut self, data: &[u8]) {
+        let mut data = data;
+        while data.len() >= 4 {
+            let len = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+            let frame = &data[4..(4 + len).min(data.len())];
+            if self.rx.len() < MAX_PENDING_FRAMES {
+                self.rx.push_back(frame.to_vec());
+            }
+            data = &data[4 + frame.len()..];
+        }
+    }
+
+    fn update(&mut self, time: u64) {
+        self.time = time;
+    }
+
+    fn reset(&mut self) {
+        self.rx.clear();
+    }
+}
+
+#[test]
+fn test_virtio_net() {
+    use memory::*;
+
+    let mut virtio = VirtioMmio::new(Box::new(VirtioNet::new([2, 0, 0, 0, 0, 1], Box::new(Loopback::new()))), 4);
+    let mut memory = Memory::with_size(0x20000);
+    let mut dma = Dma::new(&mut memory, 0x8000_0000);
+    assert_eq!(virtio.read(0x8, 4), VIRTIO_ID_NET as u64);
+    assert_eq!(virtio.read(0x100, 4), 0x0000_0002);
+    assert_eq!(virtio.read(0x104, 2), 0x0100);
+    setup_test_queues(&mut virtio, &mut dma);
+
+    // A frame sent to the loopback comes back in a receive buffer.
+    let frame: Vec<u8> = (0..60).collect();
+    dma.write(0x8000_1000, &[0; NET_HEADER_SIZE]);
+    dma.write(0x8000_1000 + NET_HEADER_SIZE as u64, &frame);
+    let n = push_test_chain(&mut virtio, &mut dma, TRANSMITQ as u32, &[(0x8000_1000, NET_HEADER_SIZE as u32 + 60, false)]);
+    assert_eq!(test_used_len(&dma, TRANSMITQ as u32, n), Some(0));
+    let input = virtio.take_host_input();
+    assert_eq!(input.len(), 4 + 60);
+    virtio.receive(&input);
+
+    let n = push_test_chain(&mut virtio, &mut dma, RECEIVEQ as u32, &[(0x8000_2000, 1526, true)]);
+    assert_eq!(test_used_len(&dma, RECEIVEQ as u32, n), Some(NET_HEADER_SIZE as u32 + 60));
+    let mut received = vec![0; 60];
+    dma.read(0x8000_2000 + NET_HEADER_SIZE as u64, &mut received);
+    assert_eq!(received, frame);
+    assert_eq!(dma.read_u16(0x8000_2000 + 10), 1);
+    assert!(virtio.update(0));
+}
@@ -0,0 +1,90 @@
+// Virtio entropy device. The bytes come from a generator with a seed, so that runs with the same seed
+// get the same bytes.
+
+use bus::*;
+use snapshot::*;
+use virtio::*;
+
+const VIRTIO_ID_ENTROPY: u32 = 4;
+
+pub struct VirtioRng {
+    state: u64,
+}
+
+impl VirtioRng {
+    pub fn new(seed: u64) -> VirtioRng {
+        VirtioRng { state: seed }
+    }
+
+    // splitmix64, which takes any seed
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn fill(&mut self, data: &mut [u8]) {
+        for chunk in data.chunks_mut(8) {
+            let value = self.next().to_le_bytes();
+            chunk.copy_from_slice(&value[..chunk.len()]);
+        }
+    }
+}
+
+impl VirtioDevice for VirtioRng {
+    fn device_id(&self) -> u32 {
+        VIRTIO_ID_ENTROPY
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    fn process(&mut self, queues: &mut [Virtqueue], dma: &mut Dma) -> bool {
+        let mut used = false;
+        while let Some(chain) = queues[0].pop(dma) {
+            let mut data = vec![0; chain.writable_len()];
+            self.fill(&mut data);
+            let len = if chain.write(dma, 0, &data) { data.len() as u32 } else { 0 };
+            queues[0].push(dma, &chain, len);
+            used = true;
+        }
+        used
+    }
+
+    fn save(&self) -> Option<Vec<u8>> {
+        Some(encode_state(&self.state, &[]))
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), String> {
+        self.state = decode_state(state)?.0;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_virtio_rng() {
+    use memory::*;
+
+    let mut memory = Memory::with_size(0x20000);
+    let mut dma = Dma::new(&mut memory, 0x8000_0000);
+    let mut read_entropy = |seed: u64| {
+        let mut virtio = VirtioMmio::new(Box::new(VirtioRng::new(seed)), 3);
+        assert_eq!(virtio.read(0x8, 4), VIRTIO_ID_ENTROPY as u64);
+        setup_test_queues(&mut virtio, &mut dma);
+        dma.write(0x8000_1000, &[0; 16]);
+        let n = push_test_chain(&mut virtio, &mut dma, 0, &[(0x8000_1000, 12, true)]);
+        assert_eq!(test_used_len(&dma, 0, n), Some(12));
+        assert!(virtio.update(0));
+        [dma.read_u64(0x8000_1000), dma.read_u64(0x8000_1008)]
+    };
+
+    // The same seed gives the same bytes, and the buffer is not overrun.
+    let entropy = read_entropy(1);
+    assert_eq!(entropy, read_entropy(1));
+    assert_ne!(entropy, read_entropy(2));
+    assert_ne!(entropy[0], 0);
+    assert_eq!(entropy[1] >> 32, 0);
+}
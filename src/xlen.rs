@@ -0,0 +1,195 @@
+// Register width.
+//
+// Core, CSRs and ops are generic over Xlen, so RV32, RV32E and RV64 share one implementation and
+// are monomorphized separately; the RV32 path keeps operating on u32.
+
+use std::fmt::{Debug, Display, LowerHex};
+use std::hash::Hash;
+use std::ops::{BitAnd, BitOr, BitXor, Not};
+
+// Unsigned integer holding an XLEN-bit register value.
+pub trait Word: 'static + Copy + Eq + Ord + Hash + Debug + Display + LowerHex
+    + BitAnd<Output = Self> + BitOr<Output = Self> + BitXor<Output = Self> + Not<Output = Self>
+{
+    const ZERO: Self;
+
+    fn from_u32(value: u32) -> Self;
+    // Sign-extends a 32-bit value.
+    fn sign_extend(value: u32) -> Self;
+    // Truncates to XLEN bits.
+    fn from_u64(value: u64) -> Self;
+    fn to_u32(self) -> u32;
+    fn to_u64(self) -> u64;
+
+    fn wrapping_add(self, other: Self) -> Self;
+    fn wrapping_sub(self, other: Self) -> Self;
+    fn wrapping_mul(self, other: Self) -> Self;
+
+    // Shift amounts are taken modulo XLEN.
+    fn shl(self, shamt: u32) -> Self;
+    fn shr(self, shamt: u32) -> Self;
+    fn sra(self, shamt: u32) -> Self;
+
+    fn lt_signed(self, other: Self) -> bool;
+
+    // Upper XLEN bits of the product.
+    fn mulh(self, other: Self) -> Self;
+    fn mulhsu(self, other: Self) -> Self;
+    fn mulhu(self, other: Self) -> Self;
+
+    // Division with the results defined by the M extension for division by zero and overflow.
+    fn div_signed(self, other: Self) -> Self;
+    fn div_unsigned(self, other: Self) -> Self;
+    fn rem_signed(self, other: Self) -> Self;
+    fn rem_unsigned(self, other: Self) -> Self;
+}
+
+macro_rules! impl_word {
+    ($u:ty, $i:ty, $wide_u:ty, $wide_i:ty) => {
+        impl Word for $u {
+            const ZERO: $u = 0;
+
+            fn from_u32(value: u32) -> $u {
+                value as $u
+            }
+
+            fn sign_extend(value: u32) -> $u {
+                value as i32 as $i as $u
+            }
+
+            fn from_u64(value: u64) -> $u {
+                value as $u
+            }
+
+            fn to_u32(self) -> u32 {
+                self as u32
+            }
+
+            fn to_u64(self) -> u64 {
+                self as u64
+            }
+
+            fn wrapping_add(self, other: $u) -> $u {
+                <$u>::wrapping_add(self, other)
+            }
+
+            fn wrapping_sub(self, other: $u) -> $u {
+                <$u>::wrapping_sub(self, other)
+            }
+
+            fn wrapping_mul(self, other: $u) -> $u {
+                <$u>::wrapping_mul(self, other)
+            }
+
+            fn shl(self, shamt: u32) -> $u {
+                self.wrapping_shl(shamt)
+            }
+
+            fn shr(self, shamt: u32) -> $u {
+                self.wrapping_shr(shamt)
+            }
+
+            fn sra(self, shamt: u32) -> $u {
+                (self as $i).wrapping_shr(shamt) as $u
+            }
+
+            fn lt_signed(self, other: $u) -> bool {
+                (self as $i) < (other as $i)
+            }
+
+            fn mulh(self, other: $u) -> $u {
+                ((self as $i as $wide_i).wrapping_mul(other as $i as $wide_i) >> <$u>::BITS) as $u
+            }
+
+            fn mulhsu(self, other: $u) -> $u {
+                ((self as $i as $wide_i).wrapping_mul(other as $wide_i) >> <$u>::BITS) as $u
+            }
+
+            fn mulhu(self, other: $u) -> $u {
+                ((self as $wide_u) * (other as $wide_u) >> <$u>::BITS) as $u
+            }
+
+            fn div_signed(self, other: $u) -> $u {
+                match other {
+                    0 => <$u>::MAX,
+                    _ => (self as $i).wrapping_div(other as $i) as $u,
+                }
+            }
+
+            fn div_unsigned(self, other: $u) -> $u {
+                match other {
+                    0 => <$u>::MAX,
+                    _ => self / other,
+                }
+            }
+
+            fn rem_signed(self, other: $u) -> $u {
+                match other {
+                    0 => self,
+                    _ => (self as $i).wrapping_rem(other as $i) as $u,
+                }
+            }
+
+            fn rem_unsigned(self, other: $u) -> $u {
+                match other {
+                    0 => self,
+                    _ => self % other,
+                }
+            }
+        }
+    };
+}
+
+impl_word!(u32, i32, u64, i64);
+impl_word!(u64, i64, u128, i128);
+
+pub trait Xlen: 'static {
+    type Uint: Word;
+
+    const XLEN: u32;
+    // misa.MXL
+    const MXL: u32;
+    // 16 for the RV32E base ISA
+    const NUM_INT_REG: usize = 32;
+}
+
+pub struct Rv32;
+
+impl Xlen for Rv32 {
+    type Uint = u32;
+
+    const XLEN: u32 = 32;
+    const MXL: u32 = 1;
+}
+
+// RV32E, which has only x0-x15.
+pub struct Rv32E;
+
+impl Xlen for Rv32E {
+    type Uint = u32;
+
+    const XLEN: u32 = 32;
+    const MXL: u32 = 1;
+    const NUM_INT_REG: usize = 16;
+}
+
+pub struct Rv64;
+
+impl Xlen for Rv64 {
+    type Uint = u64;
+
+    const XLEN: u32 = 64;
+    const MXL: u32 = 2;
+}
+
+#[test]
+fn test_word() {
+    assert_eq!(<u64 as Word>::sign_extend(0x8000_0000), 0xffff_ffff_8000_0000);
+    assert_eq!(<u32 as Word>::mulh(0xffff_ffff, 0xffff_ffff), 0);
+    assert_eq!(<u32 as Word>::mulhsu(0xffff_ffff, 0xffff_ffff), 0xffff_ffff);
+    assert_eq!(<u64 as Word>::mulhu(0xffff_ffff_ffff_ffff, 2), 1);
+    assert_eq!(<u64 as Word>::div_signed(1 << 63, 0xffff_ffff_ffff_ffff), 1 << 63);
+    assert_eq!(<u64 as Word>::rem_signed(1 << 63, 0xffff_ffff_ffff_ffff), 0);
+    assert_eq!(<u32 as Word>::div_unsigned(5, 0), 0xffff_ffff);
+    assert_eq!(<u32 as Word>::sra(0x8000_0000, 33), 0xc000_0000);
+}